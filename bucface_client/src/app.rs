@@ -1,28 +1,166 @@
-use bucface_utils::{Event, EventDB, EventDBErrorSerde, ServerResponse};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use bucface_client_lib::logs::{comma_separated, parse_conditions, parse_fields};
+use bucface_client_lib::net::backoff::Backoff;
+use bucface_client_lib::net::tls::{parse_fingerprint, TlsOptions};
+use bucface_client_lib::net::ws_client::{
+    ConnectionError, WebSocketError, WebSocketStatus, WsClient,
+};
+use bucface_client_lib::outbox::{self, refused, Outbox, OutboxError};
+use bucface_utils::{
+    Attachment, Channel, ClientMessage, Direction, Event, EventDB, EventDBErrorSerde, EventFilter,
+    PageQuery, Revision, SearchHit, ServerMessage, ServerResponse, Severity, DEFAULT_CHANNEL,
+    PROTOCOL_VERSION,
+};
 use tokio::runtime::Runtime;
+use tokio::task::JoinHandle;
 
-use crate::net::ws_client::{WebSocketError, WebSocketStatus, WsClient};
+use crate::attachment::{save, AfterDownload, Download, Preview, Upload};
 use crate::ui::main_window::body;
 
+/// How many logs are loaded at once, newest first.
+const LOGS_PER_PAGE: u32 = 200;
+/// How many search hits are shown.
+const SEARCH_HITS: u32 = 50;
+/// How long connecting and authenticating may take before the attempt fails.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
 pub struct State<'a> {
-    pub author: &'a str,
+    /// The user the server authenticated us as, which it stamps onto our
+    /// events
+    pub author: String,
     pub machine: &'a str,
 }
 
 pub struct App<'a> {
     pub state: State<'a>,
+    /// The protocol version of the server, 1 until it tells otherwise
+    pub server_version: u32,
+    /// Every channel on the server, by name
+    pub channels: Vec<Channel>,
+    /// The channel the logs are from and sent to
+    pub channel: String,
     pub logs: Vec<EventDB>,
     pub log_ids: Vec<u64>,
+    /// The ids of the logs requested by [get_missing_logs](App::get_missing_logs),
+    /// by the id of the request
+    pub requested_logs: HashMap<u64, u64>,
+    /// Ids the server shows no log for, as their insert failed or the log was
+    /// retracted
+    pub unused_ids: HashSet<u64>,
+    /// The id of the request for a page of logs we are waiting on
+    pub loading_logs: Option<u64>,
+    /// Where the next page of older logs starts, if there are any
+    pub older_logs: Option<u64>,
+    /// The id of the query for the logs sent while we were reconnecting
+    pub resyncing: Option<u64>,
+    /// The logs written but not logged by the server yet, shown greyed out
+    pub outbox: Outbox,
+    /// The keys of the pending logs sent, by the id of the request
+    pub sending: HashMap<u64, String>,
+    /// The id of the hello we are waiting on, before which pending logs are
+    /// not sent as we do not know if the server takes their keys
+    pub greeting: Option<u64>,
+    /// Only the logs matching this are shown, instead of paging through all
+    pub filter: Option<EventFilter>,
+    /// The id of the subscription request for the filter we are waiting on
+    pub subscribing: Option<u64>,
+    /// The server's id of our subscription to the filter's new logs
+    pub subscription: Option<u64>,
+    /// The hits of the last search, best first
+    pub search_hits: Vec<SearchHit>,
+    /// The id of the search request we are waiting on
+    pub searching: Option<u64>,
+    /// The id of the log being edited
+    pub editing: Option<u64>,
+    /// The revisions of the log with the id, while they are shown
+    pub revisions: Option<(u64, Vec<Revision>)>,
+    /// The id of the request for revisions we are waiting on
+    pub loading_revisions: Option<u64>,
+    /// The id of the log the next one replies to
+    pub replying: Option<u64>,
+    /// The logs of the thread shown, oldest first
+    pub thread: Option<Vec<EventDB>>,
+    /// The id of the request for a thread we are waiting on
+    pub loading_thread: Option<u64>,
+    /// The id of the log a reason to retract it is being entered for
+    pub retracting: Option<u64>,
+    /// Whether retracted logs are shown, greyed out, for auditing
+    pub show_retracted: bool,
+    /// The uploaded attachments of the next log
+    pub attachments: Vec<Attachment>,
+    /// The file being uploaded to attach it to the next log
+    pub upload: Option<Upload>,
+    /// The files to upload after it
+    pub queued_files: VecDeque<PathBuf>,
+    /// The attachment being downloaded
+    pub download: Option<Download>,
+    /// The downloaded attachment shown
+    pub preview: Option<(Attachment, Preview)>,
+    /// Where the last download was saved, or why the last upload or
+    /// download failed
+    pub attachment_status: Option<String>,
     pub runtime: Runtime,
     pub ws_client: WebSocketStatus,
+    /// The connection [connect](App::connect) is making in the background
+    pub connecting: Option<JoinHandle<Result<Connection, WebSocketError>>>,
+    /// The url and TLS options of the last connection, which is made again
+    /// when it is lost
+    pub endpoint: Option<(String, TlsOptions)>,
     pub bufs: AppBufs,
 }
 
+/// A connection made and authenticated by [App::connect].
+pub struct Connection {
+    ws_client: WsClient,
+    /// The id of the hello sent
+    greeting: u64,
+    /// Who the server authenticated us as, if we sent a token
+    author: Option<String>,
+}
+
 pub struct AppBufs {
-    pub context: Option<egui::Context>,
     pub log: String,
+    /// Comma separated tags of the log to send
+    pub tags: String,
+    pub severity: Severity,
+    /// Comma separated `key=value` fields of the log to send
+    pub fields: String,
     pub server: String,
     pub port: String,
+    pub tls: bool,
+    /// Path to a PEM certificate to trust when connecting with TLS
+    pub ca_cert: String,
+    /// SHA-256 fingerprint of the server certificate to pin
+    pub pin: String,
+    /// Token to authenticate with, if the server has users
+    pub token: String,
+    /// Comma separated authors to filter the logs by
+    pub filter_authors: String,
+    /// Comma separated machines to filter the logs by
+    pub filter_machines: String,
+    /// Comma separated words of which the filtered logs contain any
+    pub filter_keywords: String,
+    /// Comma separated tags of which the filtered logs have any
+    pub filter_tags: String,
+    /// The least severity of the filtered logs
+    pub filter_severity: Option<Severity>,
+    /// Comma separated conditions the fields of the filtered logs meet, like
+    /// `exit_code != 0`
+    pub filter_fields: String,
+    /// Name of a channel to create
+    pub new_channel: String,
+    pub search: String,
+    /// The new text of the log being edited
+    pub edit: String,
+    /// Why the log being retracted is retracted
+    pub reason: String,
+    /// Path of a file to attach
+    pub attachment: String,
+    /// Directory attachments are saved to
+    pub download_dir: String,
 }
 
 impl App<'_> {
@@ -31,65 +169,148 @@ impl App<'_> {
             runtime: Runtime::new().unwrap(),
             logs: Vec::new(),
             ws_client: WebSocketStatus::Disconnected,
+            connecting: None,
+            endpoint: None,
             state: State {
-                author: "Anonymous",
+                author: String::from("Anonymous"),
                 machine: "Unknown",
             },
+            server_version: 1,
+            channels: Vec::new(),
+            channel: String::from(DEFAULT_CHANNEL),
             log_ids: Vec::new(),
+            requested_logs: HashMap::new(),
+            unused_ids: HashSet::new(),
+            loading_logs: None,
+            older_logs: None,
+            resyncing: None,
+            outbox: open_outbox(),
+            sending: HashMap::new(),
+            greeting: None,
+            filter: None,
+            subscribing: None,
+            subscription: None,
+            search_hits: Vec::new(),
+            searching: None,
+            editing: None,
+            revisions: None,
+            loading_revisions: None,
+            replying: None,
+            thread: None,
+            loading_thread: None,
+            retracting: None,
+            show_retracted: false,
+            attachments: Vec::new(),
+            upload: None,
+            queued_files: VecDeque::new(),
+            download: None,
+            preview: None,
+            attachment_status: None,
             bufs: AppBufs {
-                context: None,
                 log: String::new(),
+                tags: String::new(),
+                severity: Severity::Info,
+                fields: String::new(),
                 server: String::from("localhost"),
                 port: String::from("8080"),
+                tls: false,
+                ca_cert: String::new(),
+                pin: String::new(),
+                token: String::new(),
+                filter_authors: String::new(),
+                filter_machines: String::new(),
+                filter_keywords: String::new(),
+                filter_tags: String::new(),
+                filter_severity: None,
+                filter_fields: String::new(),
+                new_channel: String::new(),
+                search: String::new(),
+                edit: String::new(),
+                reason: String::new(),
+                attachment: String::new(),
+                download_dir: String::from("."),
             },
         }
     }
 
-    pub fn send_log(&mut self) -> Result<(), WebSocketError> {
-        if let WebSocketStatus::Connected(ws_client) = &self.ws_client {
-            let event = self.create_event_from_buf();
-            ws_client.send_log(event).map_err(WebSocketError::SendError)?;
-            self.bufs.log.clear();
-            Ok(())
-        } else {
-            Err(WebSocketError::DoesNotExist)
+    /// Puts the log written into the outbox and sends it if we are connected,
+    /// otherwise once we are. What was written is only cleared once the log
+    /// is safe in the outbox.
+    pub fn send_log(&mut self) -> Result<(), OutboxError> {
+        let event = self.create_event_from_buf();
+        self.outbox.push(&self.channel, event)?;
+        self.bufs.log.clear();
+        self.bufs.tags.clear();
+        self.bufs.severity = Severity::Info;
+        self.bufs.fields.clear();
+        self.attachments.clear();
+        self.replying = None;
+        if let Err(e) = self.flush_outbox() {
+            log::warn!("Error sending pending logs: {:?}", e);
+        }
+        Ok(())
+    }
+
+    /// Sends the pending logs of the channel that are not on their way yet
+    /// and did not fail, with their keys if the server takes them.
+    pub fn flush_outbox(&mut self) -> Result<(), WebSocketError> {
+        let WebSocketStatus::Connected(ws_client) = &self.ws_client else {
+            return Ok(());
+        };
+        if self.greeting.is_some() {
+            return Ok(());
         }
+        let sent = self.sending.values().cloned().collect::<HashSet<String>>();
+        for pending in self.outbox.unsent_in(&self.channel) {
+            if sent.contains(&pending.key) {
+                continue;
+            }
+            log::debug!("Sending pending log {}", pending.key);
+            let request = match self.server_version >= 5 {
+                true => ws_client.send_log_once(
+                    pending.channel.clone(),
+                    pending.key.clone(),
+                    pending.event.clone(),
+                )?,
+                false => ws_client.send_log(pending.event.clone())?,
+            };
+            self.sending.insert(request, pending.key.clone());
+        }
+        Ok(())
     }
 
+    /// Requests the logs missing between the oldest and newest ones we have,
+    /// returning how many are missing. Logs that are already requested are not
+    /// requested again. Filtered logs are expected to have gaps.
     pub fn get_missing_logs(&mut self) -> Result<usize, WebSocketError> {
+        if self.filter.is_some() {
+            return Ok(0);
+        }
         if let WebSocketStatus::Connected(ws_client) = &self.ws_client {
-            // id starts at 0, so we -1
-            if self.logs.is_empty()
-                || self.log_ids.len() - 1 == self.log_ids[self.log_ids.len() - 1] as usize
-            {
+            let (Some(&first_id), Some(&last_id)) = (self.log_ids.first(), self.log_ids.last())
+            else {
+                return Ok(0);
+            };
+            if self.log_ids.len() == (last_id - first_id) as usize + 1 {
                 return Ok(0);
             }
 
-            log::debug!("There are {} logs in the logs vector", self.log_ids.len());
-            log::debug!(
-                "The last log id is {}",
-                self.log_ids[self.log_ids.len() - 1]
-            );
-            log::debug!("{:?}", self.log_ids);
-
-            let missing_amt = self.log_ids[self.log_ids.len() - 1] as usize - (self.log_ids.len() - 1);
-            log::debug!("There are {missing_amt} logs missing");
-
-            let mut id_cursor = 0;
-            let mut counter = 0;
-            for i in 0..self.log_ids.len() {
-                if self.log_ids[i] != id_cursor {
-                    for id in id_cursor..self.log_ids[i] {
-                        log::debug!("Getting missing log {id}");
-                        ws_client.get_log(id).map_err(WebSocketError::SendError)?;
+            let requested = self.requested_logs.values().collect::<HashSet<_>>();
+            let missing = (first_id..last_id)
+                .filter(|id| self.log_ids.binary_search(id).is_err())
+                .filter(|id| !self.unused_ids.contains(id))
+                .collect::<Vec<u64>>();
+            log::trace!("There are {} logs missing", missing.len());
 
-                        counter += 1;
-                    }
-                }
-                id_cursor += self.log_ids[i] + 1;
+            let mut new_requests = Vec::new();
+            for &id in missing.iter().filter(|id| !requested.contains(id)) {
+                log::debug!("Getting missing log {id}");
+                let request_id = ws_client.get_log(id)?;
+                new_requests.push((request_id, id));
             }
+            self.requested_logs.extend(new_requests);
 
-            Ok(missing_amt)
+            Ok(missing.len())
         } else {
             Err(WebSocketError::DoesNotExist)
         }
@@ -97,32 +318,248 @@ impl App<'_> {
 
     pub fn get_logs(&mut self) -> Result<(), WebSocketError> {
         if let WebSocketStatus::Connected(ws_client) = &mut self.ws_client {
-            ws_client.get_buf_logs(|response| {
+            let mut stale_subscriptions = Vec::new();
+            let mut upload_next_chunk = false;
+            let mut finished_download = None;
+            let mut greeted = false;
+            let error = ws_client.get_buf_logs(|message| {
+                let (request_id, response) = match message {
+                    ServerMessage::Reply { id, response } => (Some(id), response),
+                    ServerMessage::Broadcast(response) | ServerMessage::Connection(response) => {
+                        (None, response)
+                    }
+                };
+                let requested_log = request_id.and_then(|id| self.requested_logs.remove(&id));
+                let sent_key = request_id.and_then(|id| self.sending.remove(&id));
+
                 match response {
+                    ServerResponse::Events { events, next }
+                        if request_id.is_some() && request_id == self.resyncing =>
+                    {
+                        log::debug!("Received {} logs sent while reconnecting", events.len());
+                        merge_logs(&mut self.logs, &mut self.log_ids, events);
+                        if next.is_none() {
+                            self.resyncing = None;
+                        }
+                    }
+                    ServerResponse::Events { events, next }
+                        if request_id.is_some() && request_id == self.loading_thread =>
+                    {
+                        log::debug!("Received {} logs of a thread", events.len());
+                        self.thread.get_or_insert_with(Vec::new).extend(events);
+                        if next.is_none() {
+                            self.loading_thread = None;
+                        }
+                    }
+                    ServerResponse::Events { events, next } => {
+                        if request_id.is_none() || request_id != self.loading_logs {
+                            log::debug!("Dropping {} logs of an old request", events.len());
+                            return None;
+                        }
+                        log::debug!("Received a batch of {} logs", events.len());
+                        merge_logs(&mut self.logs, &mut self.log_ids, events);
+                        match self.filter {
+                            // The batches of a query continue until there is no next
+                            Some(_) if next.is_some() => {}
+                            Some(_) => self.loading_logs = None,
+                            None => {
+                                self.loading_logs = None;
+                                self.older_logs = next;
+                            }
+                        }
+                    }
                     ServerResponse::Event(event) => {
                         let id = event._id;
+                        if let Some(key) = sent_key {
+                            log::debug!("Pending log {key} was logged as {id}");
+                            if let Err(e) = self.outbox.remove(&key) {
+                                log::error!("Error removing a logged log: {e}");
+                            }
+                        }
+                        if event.channel != self.channel {
+                            log::debug!("Dropping log {id} of channel {}", event.channel);
+                            return None;
+                        }
+                        if let Some(filter) = &self.filter {
+                            if !filter.matches(&event) {
+                                return None;
+                            }
+                        }
+                        if event.retraction.is_some() && !self.show_retracted {
+                            log::debug!("Log {id} was retracted");
+                            if let Ok(i) = self.log_ids.binary_search(&id) {
+                                self.log_ids.remove(i);
+                                self.logs.remove(i);
+                            }
+                            // Keeps it from being requested as a missing log
+                            self.unused_ids.insert(id);
+                            return None;
+                        }
                         match self.log_ids.binary_search(&id) {
-                            Ok(_) => {
-                                log::warn!("Attempting to insert log {id}, but we already have it")
+                            // An edit replaces the older revision we have
+                            Ok(i) if event.revision > self.logs[i].revision => {
+                                log::debug!("Log {id} was edited");
+                                self.logs[i] = *event;
+                            }
+                            Ok(i) if event.retraction != self.logs[i].retraction => {
+                                log::debug!("Log {id} was retracted");
+                                self.logs[i] = *event;
                             }
+                            // A requested log may have been broadcast meanwhile
+                            Ok(_) => log::debug!("Already have log {id}"),
                             Err(i) => {
                                 self.log_ids.insert(i, id);
-                                self.logs.insert(i, event);
+                                self.logs.insert(i, *event);
+                            }
+                        }
+                    }
+                    ServerResponse::Subscribed(subscription) => {
+                        if request_id.is_some() && request_id == self.subscribing {
+                            log::debug!("Subscribed to the filter as {subscription}");
+                            self.subscribing = None;
+                            self.subscription = Some(subscription);
+                        } else {
+                            // The filter changed while we were subscribing
+                            stale_subscriptions.push(subscription);
+                        }
+                    }
+                    ServerResponse::Channel(channel) => {
+                        match self
+                            .channels
+                            .binary_search_by(|known| known.name.cmp(&channel.name))
+                        {
+                            Ok(i) => self.channels[i] = channel,
+                            Err(i) => self.channels.insert(i, channel),
+                        }
+                    }
+                    ServerResponse::Channels(channels) => {
+                        log::debug!("Received {} channels", channels.len());
+                        self.channels = channels;
+                    }
+                    ServerResponse::Revisions { id, revisions } => {
+                        if request_id.is_some() && request_id == self.loading_revisions {
+                            log::debug!("Received {} revisions of log {id}", revisions.len());
+                            self.loading_revisions = None;
+                            self.revisions = Some((id, revisions));
+                        }
+                    }
+                    ServerResponse::Hello(version) => {
+                        log::info!("The server speaks protocol version {version}");
+                        self.server_version = version;
+                        self.greeting = None;
+                        greeted = true;
+                    }
+                    // Only admins may see them, so it is set once the server agrees
+                    ServerResponse::ShowRetracted(show) => self.show_retracted = show,
+                    ServerResponse::Uploaded { received, .. } => {
+                        if let Some(upload) = self
+                            .upload
+                            .as_mut()
+                            .filter(|upload| request_id == Some(upload.request))
+                        {
+                            upload.received = received;
+                            if upload.is_complete() {
+                                log::info!("Uploaded {}", upload.attachment.name);
+                                self.attachments.push(upload.attachment.clone());
+                                self.upload = None;
+                            } else {
+                                upload_next_chunk = true;
                             }
                         }
                     }
-                    ServerResponse::Error(error) => {
-                        log::error!("Error getting buf logs: {error:?}");
-                        return Some(error);
+                    ServerResponse::Blob { offset, data, .. } => {
+                        if let Some(download) = self
+                            .download
+                            .as_mut()
+                            .filter(|download| request_id == Some(download.request))
+                        {
+                            if download.add(offset, &data) {
+                                finished_download = self.download.take();
+                            }
+                        }
+                    }
+                    ServerResponse::Search(hits) => {
+                        if request_id.is_some() && request_id == self.searching {
+                            log::debug!("Found {} logs", hits.len());
+                            self.searching = None;
+                            self.search_hits = hits;
+                        }
                     }
+                    ServerResponse::Error(error) => match requested_log {
+                        Some(id) if error == EventDBErrorSerde::NotFound => {
+                            log::debug!("Log {id} does not exist");
+                            self.unused_ids.insert(id);
+                        }
+                        _ => {
+                            if request_id.is_some() && request_id == self.loading_logs {
+                                self.loading_logs = None;
+                            }
+                            if request_id.is_some() && request_id == self.searching {
+                                self.searching = None;
+                            }
+                            if request_id.is_some() && request_id == self.subscribing {
+                                self.subscribing = None;
+                            }
+                            if request_id.is_some() && request_id == self.loading_revisions {
+                                self.loading_revisions = None;
+                            }
+                            if request_id.is_some() && request_id == self.loading_thread {
+                                self.loading_thread = None;
+                            }
+                            if request_id.is_some() && request_id == self.resyncing {
+                                self.resyncing = None;
+                            }
+                            // Servers before version 2 do not know hellos
+                            if request_id.is_some() && request_id == self.greeting {
+                                self.greeting = None;
+                                greeted = true;
+                            }
+                            if let Some(key) = sent_key.filter(|_| refused(&error)) {
+                                log::warn!("The server refused pending log {key}: {error:?}");
+                                if let Err(e) = self.outbox.fail(&key, format!("{error:?}")) {
+                                    log::error!("Error keeping a refused log: {e}");
+                                }
+                            }
+                            let failed = |request| request_id == Some(request);
+                            if let Some(upload) = self.upload.take_if(|u| failed(u.request)) {
+                                self.attachment_status = Some(format!(
+                                    "Could not upload {}: {error:?}",
+                                    upload.attachment.name
+                                ));
+                            }
+                            if let Some(download) = self.download.take_if(|d| failed(d.request)) {
+                                self.attachment_status = Some(format!(
+                                    "Could not download {}: {error:?}",
+                                    download.attachment.name
+                                ));
+                            }
+                            log::error!("Error getting buf logs: {error:?}");
+                            return Some(error);
+                        }
+                    },
                     _ => {}
                 }
 
                 None::<EventDBErrorSerde>
             });
-
+            for subscription in stale_subscriptions {
+                ws_client.unsubscribe(subscription)?;
+            }
+            if let Some(upload) = self.upload.as_mut().filter(|_| upload_next_chunk) {
+                upload.request = ws_client.send(upload.next_chunk())?;
+            }
+            if let Some(download) = finished_download {
+                self.finish_download(download);
+            }
+            self.upload_next()?;
+            if greeted {
+                self.flush_outbox()?;
+            }
             assert_eq!(self.log_ids.len(), self.logs.len());
-            Ok(())
+            match error {
+                Some(e) => Err(WebSocketError::ServerError(e)),
+                None => Ok(()),
+            }
         } else {
             Err(WebSocketError::DoesNotExist)
         }
@@ -131,41 +568,586 @@ impl App<'_> {
     pub fn create_event_from_buf(&self) -> Event {
         bucface_utils::Event {
             time: chrono::Utc::now().naive_utc(),
-            author: self.state.author.into(),
+            author: self.state.author.clone(),
             event: self.bufs.log.clone(),
             machine: self.state.machine.into(),
+            parent: self.replying,
+            tags: comma_separated(&self.bufs.tags),
+            severity: self.bufs.severity,
+            attachments: self.attachments.clone(),
+            // Logs are only sent once the fields parse
+            fields: parse_fields(&self.bufs.fields).unwrap_or_default(),
         }
     }
 
     pub fn clear_logs(&mut self) {
         self.logs.clear();
         self.log_ids.clear();
+        self.requested_logs.clear();
+        self.unused_ids.clear();
+        self.loading_logs = None;
+        self.older_logs = None;
+    }
+
+    /// Replaces the logs with the newest page from the server, or with every
+    /// log matching the filter.
+    pub fn refresh_logs(&mut self) -> Result<(), WebSocketError> {
+        self.clear_logs();
+        match self.filter.clone() {
+            Some(filter) => self.query(filter),
+            None => self.get_page(None),
+        }
+    }
+
+    /// Filters the logs by the authors, machines, keywords, tags, severity and
+    /// field conditions entered, or stops filtering if none are. Only new logs matching the
+    /// filter are pushed to us while it is applied.
+    pub fn apply_filter(&mut self) -> Result<(), WebSocketError> {
+        let filter = EventFilter {
+            authors: comma_separated(&self.bufs.filter_authors),
+            machines: comma_separated(&self.bufs.filter_machines),
+            keywords: comma_separated(&self.bufs.filter_keywords),
+            tags: comma_separated(&self.bufs.filter_tags),
+            severity: self.bufs.filter_severity,
+            // The filter is only applied once the conditions parse
+            fields: parse_conditions(&self.bufs.filter_fields).unwrap_or_default(),
+            ..Default::default()
+        };
+        self.filter = match filter == EventFilter::default() {
+            true => None,
+            false => Some(filter),
+        };
+
+        self.subscribe()?;
+        self.refresh_logs()
+    }
+
+    /// Lists the channels of a new connection and rejoins the channel and
+    /// filter of the previous one, showing retracted logs if it did.
+    fn rejoin(&mut self) -> Result<(), WebSocketError> {
+        if let WebSocketStatus::Connected(ws_client) = &self.ws_client {
+            ws_client.list_channels()?;
+            if self.channel != DEFAULT_CHANNEL {
+                ws_client.join_channel(self.channel.clone())?;
+            }
+            if self.show_retracted {
+                ws_client.show_retracted(true)?;
+            }
+        }
+        self.subscribe()
+    }
+
+    /// Replaces our subscription with one to the filter, or drops it if there
+    /// is no filter.
+    fn subscribe(&mut self) -> Result<(), WebSocketError> {
+        if let WebSocketStatus::Connected(ws_client) = &self.ws_client {
+            self.subscribing = None;
+            if let Some(subscription) = self.subscription.take() {
+                ws_client.unsubscribe(subscription)?;
+            }
+            if let Some(filter) = &self.filter {
+                let id = ws_client.subscribe(filter.clone())?;
+                self.subscribing = Some(id);
+            }
+            Ok(())
+        } else {
+            Err(WebSocketError::DoesNotExist)
+        }
+    }
+
+    /// Switches to another channel, replacing the logs with its own.
+    pub fn switch_channel(&mut self, name: String) -> Result<(), WebSocketError> {
+        if let WebSocketStatus::Connected(ws_client) = &self.ws_client {
+            ws_client.join_channel(name.clone())?;
+            self.channel = name;
+            self.search_hits.clear();
+            self.searching = None;
+            self.cancel_edit();
+            self.cancel_retract();
+            self.replying = None;
+            self.revisions = None;
+            self.loading_revisions = None;
+            self.thread = None;
+            self.loading_thread = None;
+            self.flush_outbox()?;
+            self.refresh_logs()
+        } else {
+            Err(WebSocketError::DoesNotExist)
+        }
+    }
+
+    /// Creates a channel with the name entered, which then shows up in
+    /// [channels](App::channels).
+    pub fn create_channel(&mut self) -> Result<(), WebSocketError> {
+        let name = self.bufs.new_channel.trim();
+        if name.is_empty() {
+            return Ok(());
+        }
+
+        if let WebSocketStatus::Connected(ws_client) = &self.ws_client {
+            ws_client.create_channel(name.into())?;
+            self.bufs.new_channel.clear();
+            Ok(())
+        } else {
+            Err(WebSocketError::DoesNotExist)
+        }
+    }
+
+    /// Archives the current channel.
+    pub fn archive_channel(&mut self) -> Result<(), WebSocketError> {
+        if let WebSocketStatus::Connected(ws_client) = &self.ws_client {
+            ws_client.archive_channel(self.channel.clone())?;
+            Ok(())
+        } else {
+            Err(WebSocketError::DoesNotExist)
+        }
+    }
+
+    /// Starts editing the log with the id, from its current text.
+    pub fn start_edit(&mut self, id: u64) {
+        if let Ok(i) = self.log_ids.binary_search(&id) {
+            self.bufs.edit = self.logs[i].event.clone();
+            self.editing = Some(id);
+        }
+    }
+
+    /// Sends the edited text of the log being edited.
+    pub fn save_edit(&mut self) -> Result<(), WebSocketError> {
+        let Some(id) = self.editing else {
+            return Ok(());
+        };
+
+        if let WebSocketStatus::Connected(ws_client) = &self.ws_client {
+            ws_client.edit_log(id, self.bufs.edit.clone())?;
+            self.editing = None;
+            self.bufs.edit.clear();
+            Ok(())
+        } else {
+            Err(WebSocketError::DoesNotExist)
+        }
+    }
+
+    pub fn cancel_edit(&mut self) {
+        self.editing = None;
+        self.bufs.edit.clear();
+    }
+
+    /// Requests the revisions of the log with the id, to show them once they
+    /// arrive.
+    pub fn show_revisions(&mut self, id: u64) -> Result<(), WebSocketError> {
+        if let WebSocketStatus::Connected(ws_client) = &self.ws_client {
+            let request = ws_client.get_revisions(id)?;
+            self.loading_revisions = Some(request);
+            Ok(())
+        } else {
+            Err(WebSocketError::DoesNotExist)
+        }
+    }
+
+    /// Starts entering the reason to retract the log with the id.
+    pub fn start_retract(&mut self, id: u64) {
+        self.bufs.reason.clear();
+        self.retracting = Some(id);
+    }
+
+    /// Retracts the log being retracted with the reason entered.
+    pub fn retract(&mut self) -> Result<(), WebSocketError> {
+        let Some(id) = self.retracting else {
+            return Ok(());
+        };
+
+        if let WebSocketStatus::Connected(ws_client) = &self.ws_client {
+            ws_client.retract_log(id, self.bufs.reason.trim().into())?;
+            self.cancel_retract();
+            Ok(())
+        } else {
+            Err(WebSocketError::DoesNotExist)
+        }
+    }
+
+    pub fn cancel_retract(&mut self) {
+        self.retracting = None;
+        self.bufs.reason.clear();
+    }
+
+    /// Shows or hides retracted logs, reloading the logs to include or leave
+    /// them out. The server refuses to show them to anyone but admins.
+    pub fn set_show_retracted(&mut self, show: bool) -> Result<(), WebSocketError> {
+        if let WebSocketStatus::Connected(ws_client) = &self.ws_client {
+            ws_client.show_retracted(show)?;
+            self.refresh_logs()
+        } else {
+            Err(WebSocketError::DoesNotExist)
+        }
+    }
+
+    /// Requests the thread of the log with the id, to show it once it
+    /// arrives.
+    pub fn show_thread(&mut self, id: u64) -> Result<(), WebSocketError> {
+        if let WebSocketStatus::Connected(ws_client) = &self.ws_client {
+            let request = ws_client.get_thread(id)?;
+            self.thread = None;
+            self.loading_thread = Some(request);
+            Ok(())
+        } else {
+            Err(WebSocketError::DoesNotExist)
+        }
+    }
+
+    /// Uploads the file at the path to attach it to the next log, after the
+    /// files queued before it.
+    pub fn attach_file(&mut self, path: PathBuf) -> Result<(), WebSocketError> {
+        self.queued_files.push_back(path);
+        self.upload_next()
+    }
+
+    /// Attaches the file at the path entered.
+    pub fn attach(&mut self) -> Result<(), WebSocketError> {
+        let path = self.bufs.attachment.trim();
+        if path.is_empty() {
+            return Ok(());
+        }
+
+        self.attach_file(path.into())?;
+        self.bufs.attachment.clear();
+        Ok(())
+    }
+
+    /// Starts uploading the next queued file, unless one is being uploaded.
+    fn upload_next(&mut self) -> Result<(), WebSocketError> {
+        if self.upload.is_some() || self.queued_files.is_empty() {
+            return Ok(());
+        }
+
+        if let WebSocketStatus::Connected(ws_client) = &self.ws_client {
+            while self.upload.is_none() {
+                let Some(path) = self.queued_files.pop_front() else {
+                    break;
+                };
+                match Upload::new(&path) {
+                    Ok(mut upload) => {
+                        upload.request = ws_client.send(upload.next_chunk())?;
+                        self.upload = Some(upload);
+                    }
+                    Err(e) => {
+                        log::warn!("Error reading {}: {e:?}", path.display());
+                        self.attachment_status =
+                            Some(format!("Could not read {}: {e}", path.display()));
+                    }
+                }
+            }
+            Ok(())
+        } else {
+            Err(WebSocketError::DoesNotExist)
+        }
+    }
+
+    /// Downloads an attachment to save it into the directory entered, or to
+    /// preview it.
+    pub fn download(
+        &mut self,
+        attachment: Attachment,
+        then: AfterDownload,
+    ) -> Result<(), WebSocketError> {
+        if let WebSocketStatus::Connected(ws_client) = &self.ws_client {
+            let request = ws_client.get_blob(attachment.digest.clone())?;
+            self.download = Some(Download::new(attachment, then, request));
+            self.attachment_status = None;
+            Ok(())
+        } else {
+            Err(WebSocketError::DoesNotExist)
+        }
+    }
+
+    fn finish_download(&mut self, download: Download) {
+        let attachment = download.attachment.clone();
+        let then = download.then.clone();
+        let Some(data) = download.finish() else {
+            log::error!("{} does not match its digest", attachment.name);
+            self.attachment_status = Some(format!("{} was corrupted", attachment.name));
+            return;
+        };
+
+        match then {
+            AfterDownload::Save(dir) => {
+                self.attachment_status = Some(match save(&dir, &attachment, &data) {
+                    Ok(path) => format!("Saved {}", path.display()),
+                    Err(e) => format!("Could not save {}: {e}", attachment.name),
+                });
+            }
+            AfterDownload::Preview => {
+                let preview = Preview::new(&data);
+                self.preview = Some((attachment, preview));
+            }
+        }
+    }
+
+    /// Whether the current channel is archived and takes no more logs.
+    pub fn channel_archived(&self) -> bool {
+        self.channels
+            .iter()
+            .any(|channel| channel.name == self.channel && channel.archived.is_some())
+    }
+
+    /// Searches the logs for the words entered, or clears the hits if there
+    /// are none.
+    pub fn search(&mut self) -> Result<(), WebSocketError> {
+        self.search_hits.clear();
+        self.searching = None;
+        let terms = self.bufs.search.trim();
+        if terms.is_empty() {
+            return Ok(());
+        }
+
+        if let WebSocketStatus::Connected(ws_client) = &self.ws_client {
+            let id = ws_client.search(terms.into(), SEARCH_HITS)?;
+            self.searching = Some(id);
+            Ok(())
+        } else {
+            Err(WebSocketError::DoesNotExist)
+        }
+    }
+
+    fn query(&mut self, filter: EventFilter) -> Result<(), WebSocketError> {
+        if let WebSocketStatus::Connected(ws_client) = &self.ws_client {
+            let id = ws_client.query(filter, None)?;
+            self.loading_logs = Some(id);
+            Ok(())
+        } else {
+            Err(WebSocketError::DoesNotExist)
+        }
+    }
+
+    /// Requests the page of logs before the oldest one we have, unless we
+    /// have the oldest log or are already waiting on a page.
+    pub fn load_older_logs(&mut self) -> Result<(), WebSocketError> {
+        match (self.loading_logs, self.older_logs) {
+            (None, Some(start)) => self.get_page(Some(start)),
+            _ => Ok(()),
+        }
+    }
+
+    fn get_page(&mut self, start: Option<u64>) -> Result<(), WebSocketError> {
+        if let WebSocketStatus::Connected(ws_client) = &self.ws_client {
+            let query = PageQuery {
+                start,
+                direction: Direction::Descending,
+                limit: LOGS_PER_PAGE,
+            };
+            let id = ws_client.get_page(query)?;
+            self.loading_logs = Some(id);
+            Ok(())
+        } else {
+            Err(WebSocketError::DoesNotExist)
+        }
+    }
+
+    pub fn tls_options(&self) -> Result<TlsOptions, WebSocketError> {
+        let ca_cert = match self.bufs.ca_cert.trim() {
+            "" => None,
+            path => Some(std::fs::read(path).map_err(WebSocketError::CaCertRead)?),
+        };
+        let pin = match self.bufs.pin.trim() {
+            "" => None,
+            pin => Some(parse_fingerprint(pin).map_err(WebSocketError::Tls)?),
+        };
+
+        Ok(TlsOptions { ca_cert, pin })
     }
 
     pub fn set_endpoint(&mut self, context: &egui::Context) {
         let new_endpoint = format!(
-            "ws://{server}:{port}",
+            "{scheme}://{server}:{port}",
+            scheme = if self.bufs.tls { "wss" } else { "ws" },
             server = self.bufs.server,
             port = self.bufs.port
         );
+        let tls = match self.tls_options() {
+            Ok(tls) => tls,
+            Err(e) => {
+                log::error!("Invalid TLS options: {:?}", e);
+                self.ws_client = WebSocketStatus::Error(e);
+                return;
+            }
+        };
+        self.endpoint = Some((new_endpoint, tls));
+        if let Err(e) = self.connect(context) {
+            log::error!("Error connecting to endpoint: {e}");
+            self.ws_client = WebSocketStatus::Disconnected;
+        }
+    }
+
+    /// Starts connecting to the [endpoint](App::endpoint) and authenticating
+    /// in the background, so the window keeps being drawn meanwhile.
+    /// [keep_connected](App::keep_connected) takes the connection over once
+    /// it is made. Replaces a connection still being made.
+    fn connect(&mut self, context: &egui::Context) -> Result<(), WebSocketError> {
+        let Some((endpoint, tls)) = self.endpoint.clone() else {
+            return Err(WebSocketError::DoesNotExist);
+        };
+        let token = self.bufs.token.trim().to_string();
         let context = context.clone();
+        if let Some(connecting) = self.connecting.take() {
+            connecting.abort();
+        }
+        self.connecting = Some(self.runtime.spawn(async move {
+            let connection = async {
+                let ws_client =
+                    WsClient::new(&endpoint, &tls, move || context.request_repaint()).await?;
+                // Answered once the receiver gets to it, or with an error by
+                // servers that predate versioning
+                let greeting = ws_client.send(ClientMessage::Hello(PROTOCOL_VERSION))?;
+                let author = match token.is_empty() {
+                    true => None,
+                    false => match ws_client
+                        .request(ClientMessage::Authenticate(token))
+                        .await?
+                    {
+                        ServerResponse::Authenticated(author) => Some(author),
+                        ServerResponse::Error(e) => return Err(WebSocketError::ServerError(e)),
+                        other => {
+                            log::warn!("Unexpected reply to authentication: {other:?}");
+                            None
+                        }
+                    },
+                };
+                Ok(Connection {
+                    ws_client,
+                    greeting,
+                    author,
+                })
+            };
+            tokio::time::timeout(CONNECT_TIMEOUT, connection)
+                .await
+                .unwrap_or(Err(WebSocketError::Connection(ConnectionError::NoResponse)))
+        }));
+        Ok(())
+    }
+
+    /// Takes over the connection [connect](App::connect) made, rejoining the
+    /// channel and filter of the previous connection, once it is done.
+    /// Leaves the status as it was if connecting failed.
+    fn finish_connecting(&mut self) -> Option<Result<(), WebSocketError>> {
+        if !self.connecting.as_ref()?.is_finished() {
+            return None;
+        }
+        let connecting = self.connecting.take()?;
         let result = self
             .runtime
-            .block_on(WsClient::new(&new_endpoint, context));
-        match result {
-            Ok(ws_client) => {
-                self.ws_client = WebSocketStatus::Connected(ws_client);
-            }
-            Err(e) => {
+            .block_on(connecting)
+            .unwrap_or(Err(WebSocketError::Connection(ConnectionError::NoResponse)));
+        // Requests of the previous connection will not be answered anymore
+        self.requested_logs.clear();
+        self.loading_logs = None;
+        self.resyncing = None;
+        self.sending.clear();
+        self.greeting = None;
+        self.searching = None;
+        self.loading_revisions = None;
+        self.loading_thread = None;
+        self.upload = None;
+        self.download = None;
+        self.server_version = 1;
+        // Neither are subscriptions
+        self.subscribing = None;
+        self.subscription = None;
+        let connection = match result {
+            Ok(connection) => connection,
+            Err(e) => return Some(Err(e)),
+        };
+        if let Some(author) = connection.author {
+            log::info!("Authenticated as {author}");
+            self.state.author = author;
+        }
+
+        self.ws_client = WebSocketStatus::Connected(connection.ws_client);
+        self.greeting = Some(connection.greeting);
+        if let Err(e) = self.rejoin() {
+            log::error!("Error restoring the channel and filter: {:?}", e);
+        }
+        Some(Ok(()))
+    }
+
+    /// Takes over the connection being made once it is, and notices a lost
+    /// connection and makes it again, waiting longer after every failed
+    /// attempt. Servers refusing us are not tried again.
+    fn keep_connected(&mut self, context: &egui::Context) {
+        let backoff = match &self.ws_client {
+            WebSocketStatus::Reconnecting(backoff) => Some(backoff.clone()),
+            _ => None,
+        };
+        match (backoff, self.finish_connecting()) {
+            (_, None) => {}
+            (Some(mut backoff), Some(result)) => match result {
+                Ok(()) => {
+                    log::info!("Reconnected after {} failed attempts", backoff.failures);
+                    if let Err(e) = self.resync() {
+                        log::error!("Error getting the logs missed: {:?}", e);
+                    }
+                }
+                Err(WebSocketError::ServerError(e)) => {
+                    log::error!("The server refused to reconnect us: {e:?}");
+                    self.ws_client = WebSocketStatus::Error(WebSocketError::ServerError(e));
+                }
+                Err(e) => {
+                    log::warn!("Error reconnecting: {:?}", e);
+                    backoff.failed();
+                    self.ws_client = WebSocketStatus::Reconnecting(backoff);
+                }
+            },
+            (None, Some(Ok(()))) => {}
+            (None, Some(Err(e))) => {
                 log::error!("Error connecting to endpoint: {:?}", e);
-                self.ws_client = WebSocketStatus::Disconnected;
+                self.ws_client = WebSocketStatus::Error(e);
             }
         }
+
+        match &self.ws_client {
+            WebSocketStatus::Connected(ws_client) if ws_client.is_closed() => {
+                log::warn!("Lost the connection, reconnecting");
+                self.ws_client = WebSocketStatus::Reconnecting(Backoff::new());
+            }
+            WebSocketStatus::Reconnecting(backoff)
+                if backoff.is_due() && self.connecting.is_none() =>
+            {
+                let mut backoff = backoff.clone();
+                if let Err(e) = self.connect(context) {
+                    log::warn!("Error reconnecting: {:?}", e);
+                    backoff.failed();
+                    self.ws_client = WebSocketStatus::Reconnecting(backoff);
+                }
+            }
+            _ => {}
+        }
+
+        if self.connecting.is_some() {
+            // Checking on it again soon, as it does not wake us when done
+            context.request_repaint_after(Duration::from_millis(100));
+        } else if let WebSocketStatus::Reconnecting(backoff) = &self.ws_client {
+            context.request_repaint_after(backoff.remaining());
+        }
+    }
+
+    /// Gets the logs sent while we were reconnecting, which are those after
+    /// the newest one we have.
+    fn resync(&mut self) -> Result<(), WebSocketError> {
+        let (WebSocketStatus::Connected(ws_client), Some(&last_id)) =
+            (&self.ws_client, self.log_ids.last())
+        else {
+            return Ok(());
+        };
+        let mut filter = self.filter.clone().unwrap_or_default();
+        filter.ids.start = Some(last_id + 1);
+        log::debug!("Getting the logs after {last_id}");
+        self.resyncing = Some(ws_client.query(filter, None)?);
+
+        Ok(())
     }
 }
 
 impl eframe::App for App<'_> {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        self.keep_connected(ctx);
         let start = std::time::Instant::now();
         egui::CentralPanel::default().show(ctx, |ui| {
             body(ui, ctx, self);
@@ -175,10 +1157,93 @@ impl eframe::App for App<'_> {
         let _ = self.get_logs();
         log::trace!("get_logs took: {}ns", update_end.elapsed().as_nanos());
         let get_logs_end = std::time::Instant::now();
-        self.get_missing_logs();
+        let _ = self.get_missing_logs();
         log::trace!(
             "get_missing_logs took: {}ns",
             get_logs_end.elapsed().as_nanos()
         );
     }
 }
+
+/// Opens the outbox `BUCFACE_OUTBOX` names, or the one in the working
+/// directory. Pending logs are only kept in memory if it cannot be opened,
+/// like while another client has it open, leaving the file as it is.
+fn open_outbox() -> Outbox {
+    let path = std::env::var_os("BUCFACE_OUTBOX")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(outbox::DEFAULT_PATH));
+    match Outbox::open(&path) {
+        Ok(outbox) => outbox,
+        Err(e) => {
+            log::error!("Error opening the outbox at {}: {e}", path.display());
+            Outbox::memory()
+        }
+    }
+}
+
+/// Merges a batch of logs into the sorted `logs` and their `log_ids`, keeping
+/// the logs we already have.
+fn merge_logs(logs: &mut Vec<EventDB>, log_ids: &mut Vec<u64>, mut events: Vec<EventDB>) {
+    events.sort_by_key(|event| event._id);
+    let Some(first) = events.first() else {
+        return;
+    };
+
+    // Usually the batch is the history following what we have
+    if log_ids.last().is_none_or(|&last| last < first._id) {
+        log_ids.extend(events.iter().map(|event| event._id));
+        logs.extend(events);
+        return;
+    }
+
+    let mut merged = Vec::with_capacity(logs.len() + events.len());
+    let mut old = std::mem::take(logs).into_iter().peekable();
+    let mut new = events.into_iter().peekable();
+    loop {
+        let next = match (old.peek(), new.peek()) {
+            (Some(a), Some(b)) if a._id == b._id => {
+                new.next();
+                old.next()
+            }
+            (Some(a), Some(b)) if b._id < a._id => new.next(),
+            (Some(_), _) => old.next(),
+            (None, Some(_)) => new.next(),
+            (None, None) => break,
+        };
+        merged.extend(next);
+    }
+    merged.dedup_by_key(|event| event._id);
+
+    *log_ids = merged.iter().map(|event| event._id).collect();
+    *logs = merged;
+}
+
+#[cfg(test)]
+mod app_tests {
+    use super::*;
+
+    fn logs(ids: &[u64]) -> Vec<EventDB> {
+        ids.iter()
+            .map(|&id| EventDB::from(Event::default(), id))
+            .collect()
+    }
+
+    #[test]
+    fn test_merge_logs() {
+        let mut log_ids = vec![1, 4, 5];
+        let mut merged = logs(&log_ids);
+
+        merge_logs(&mut merged, &mut log_ids, logs(&[6, 7]));
+        assert_eq!(log_ids, vec![1, 4, 5, 6, 7]);
+
+        merge_logs(&mut merged, &mut log_ids, logs(&[8, 0, 2, 4, 3, 9]));
+        assert_eq!(log_ids, (0..10).collect::<Vec<u64>>());
+        assert_eq!(
+            merged.iter().map(|log| log._id).collect::<Vec<u64>>(),
+            log_ids
+        );
+
+        merge_logs(&mut merged, &mut log_ids, Vec::new());
+        assert_eq!(merged.len(), 10);
+    }
+}
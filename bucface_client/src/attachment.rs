@@ -0,0 +1,237 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use bucface_utils::{hex, Attachment, ClientMessage, BLOB_CHUNK_SIZE};
+use sha2::{Digest, Sha256};
+
+/// The most text of an attachment shown in a preview.
+const PREVIEW_TEXT_LEN: usize = 64 << 10;
+
+/// A file being uploaded chunk by chunk, each sent once the server confirmed
+/// the one before.
+#[derive(Debug)]
+pub struct Upload {
+    pub attachment: Attachment,
+    data: Vec<u8>,
+    /// How many bytes the server has
+    pub received: u64,
+    /// The id of the request for the last chunk sent
+    pub request: u64,
+}
+
+impl Upload {
+    /// Reads the file at the path to upload it.
+    pub fn new(path: &Path) -> std::io::Result<Self> {
+        let data = std::fs::read(path)?;
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "attachment".into());
+        let attachment = Attachment {
+            name,
+            digest: hex::encode(&Sha256::digest(&data)),
+            size: data.len() as u64,
+        };
+
+        Ok(Self {
+            attachment,
+            data,
+            received: 0,
+            request: 0,
+        })
+    }
+
+    /// The chunk continuing from what the server has.
+    pub fn next_chunk(&self) -> ClientMessage {
+        let start = (self.received as usize).min(self.data.len());
+        let end = (start + BLOB_CHUNK_SIZE).min(self.data.len());
+
+        ClientMessage::UploadChunk {
+            digest: self.attachment.digest.clone(),
+            size: self.attachment.size,
+            offset: start as u64,
+            data: self.data[start..end].to_vec(),
+        }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.received >= self.attachment.size
+    }
+}
+
+/// What to do with a downloaded attachment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AfterDownload {
+    /// Save it into the directory.
+    Save(PathBuf),
+    Preview,
+}
+
+/// An attachment being downloaded, whose chunks the server sends in order.
+#[derive(Debug)]
+pub struct Download {
+    pub attachment: Attachment,
+    pub then: AfterDownload,
+    /// The id of the request for the blob
+    pub request: u64,
+    data: Vec<u8>,
+}
+
+impl Download {
+    pub fn new(attachment: Attachment, then: AfterDownload, request: u64) -> Self {
+        Self {
+            attachment,
+            then,
+            request,
+            data: Vec::new(),
+        }
+    }
+
+    /// Adds a chunk, returning whether the download is complete.
+    pub fn add(&mut self, offset: u64, data: &[u8]) -> bool {
+        if offset == self.data.len() as u64 {
+            self.data.extend_from_slice(data);
+        } else {
+            log::warn!("Dropping chunk at {offset} of {}", self.attachment.name);
+        }
+
+        self.data.len() as u64 >= self.attachment.size
+    }
+
+    /// The downloaded contents, if they match the digest of the attachment.
+    pub fn finish(self) -> Option<Vec<u8>> {
+        let digest = hex::encode(&Sha256::digest(&self.data));
+        digest
+            .eq_ignore_ascii_case(&self.attachment.digest)
+            .then_some(self.data)
+    }
+}
+
+/// Saves an attachment into the directory under its name, never overwriting
+/// a file, and returns where it was saved.
+pub fn save(dir: &Path, attachment: &Attachment, data: &[u8]) -> std::io::Result<PathBuf> {
+    // The server only allows plain names, but do not trust it to
+    let name = Path::new(&attachment.name)
+        .file_name()
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(&attachment.digest));
+    let path = dir.join(name);
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&path)?
+        .write_all(data)?;
+
+    Ok(path)
+}
+
+/// How a downloaded attachment is shown.
+pub enum Preview {
+    /// A PNG, which is only uploaded to the GPU once it is shown.
+    Image {
+        image: egui::ColorImage,
+        texture: Option<egui::TextureHandle>,
+    },
+    Text(String),
+    /// Neither, so it can only be saved.
+    Binary,
+}
+
+impl Preview {
+    pub fn new(data: &[u8]) -> Self {
+        if let Ok(image) = image::load_from_memory_with_format(data, image::ImageFormat::Png) {
+            let image = image.to_rgba8();
+            let size = [image.width() as usize, image.height() as usize];
+            return Preview::Image {
+                image: egui::ColorImage::from_rgba_unmultiplied(size, image.as_raw()),
+                texture: None,
+            };
+        }
+
+        match std::str::from_utf8(data) {
+            Ok(text) if !text.contains('\0') => {
+                let mut end = text.len().min(PREVIEW_TEXT_LEN);
+                while !text.is_char_boundary(end) {
+                    end -= 1;
+                }
+                Preview::Text(text[..end].into())
+            }
+            _ => Preview::Binary,
+        }
+    }
+}
+
+/// A size in bytes the way people read it.
+pub fn human_size(size: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if size < 1024 {
+        return format!("{size} B");
+    }
+
+    let mut scaled = size as f64 / 1024.0;
+    let mut unit = 0;
+    while scaled >= 1024.0 && unit < UNITS.len() - 1 {
+        scaled /= 1024.0;
+        unit += 1;
+    }
+    format!("{scaled:.1} {}", UNITS[unit])
+}
+
+#[cfg(test)]
+mod attachment_tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let path = std::env::temp_dir().join(format!("bucface-{}.log", rand_suffix()));
+        let data = (0..BLOB_CHUNK_SIZE * 2 + 10)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<u8>>();
+        std::fs::write(&path, &data).unwrap();
+        let mut upload = Upload::new(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(upload.attachment.size, data.len() as u64);
+
+        let mut download = Download::new(upload.attachment.clone(), AfterDownload::Preview, 0);
+        let mut complete = false;
+        while !upload.is_complete() {
+            let ClientMessage::UploadChunk { offset, data, .. } = upload.next_chunk() else {
+                panic!("Expected a chunk");
+            };
+            assert!(data.len() <= BLOB_CHUNK_SIZE);
+            upload.received = offset + data.len() as u64;
+            complete = download.add(offset, &data);
+        }
+        assert!(complete);
+        assert_eq!(download.finish(), Some(data));
+
+        let mut corrupt = Download::new(upload.attachment.clone(), AfterDownload::Preview, 0);
+        assert!(!corrupt.add(0, b"not it"));
+        assert_eq!(corrupt.finish(), None);
+    }
+
+    #[test]
+    fn test_preview() {
+        assert!(
+            matches!(Preview::new(b"voltage = 12\n"), Preview::Text(text) if text == "voltage = 12\n")
+        );
+        assert!(matches!(Preview::new(&[0xff, 0x00, 0x12]), Preview::Binary));
+
+        let mut png = Vec::new();
+        image::RgbaImage::new(3, 2)
+            .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+        assert!(matches!(Preview::new(&png), Preview::Image { image, .. } if image.size == [3, 2]));
+
+        assert_eq!(human_size(512), "512 B");
+        assert_eq!(human_size(1536), "1.5 KiB");
+        assert_eq!(human_size(16 << 20), "16.0 MiB");
+    }
+
+    fn rand_suffix() -> u64 {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos() as u64
+    }
+}
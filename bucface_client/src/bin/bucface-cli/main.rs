@@ -0,0 +1,486 @@
+mod output;
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, Read};
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use bucface_client_lib::net::tls::{parse_fingerprint, TlsOptions};
+use bucface_client_lib::{Client, ClientError, ConnectOptions};
+use bucface_utils::{
+    valid_field_key, Direction, Event, EventDB, EventFilter, FieldCondition, FieldValue, IdRange,
+    PageQuery, Severity, TimeBound, TimeRange, DEFAULT_CHANNEL,
+};
+use clap::{Args, Parser, Subcommand};
+use futures_util::StreamExt;
+
+use output::{Format, Printer};
+
+/// How many events [Command::Tail] asks for at once while looking for
+/// matching ones.
+const TAIL_PAGE: u32 = 1000;
+
+/// Reads and writes the events of a Bucface server from scripts and cron
+/// jobs.
+#[derive(Debug, Parser)]
+#[command(version, about = "Bucface event log command line client")]
+struct Cli {
+    /// Server to connect to, `ws://` or `wss://`
+    #[arg(
+        short,
+        long,
+        env = "BUCFACE_URL",
+        default_value = "ws://localhost:8080"
+    )]
+    url: String,
+    /// Token to authenticate with, if the server has users
+    #[arg(short, long, env = "BUCFACE_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+    /// PEM certificate to trust when connecting with TLS
+    #[arg(long, env = "BUCFACE_CA_CERT")]
+    ca_cert: Option<PathBuf>,
+    /// SHA-256 fingerprint of the server certificate to pin
+    #[arg(long, env = "BUCFACE_PIN")]
+    pin: Option<String>,
+    /// Channel to read and write
+    #[arg(short, long, env = "BUCFACE_CHANNEL", default_value = DEFAULT_CHANNEL)]
+    channel: String,
+    /// Include retracted events, which only admins may see
+    #[arg(long)]
+    retracted: bool,
+    /// How events are printed
+    #[arg(short, long, value_enum, default_value_t)]
+    format: Format,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Logs an event and prints it as the server stored it
+    Post {
+        /// Text of the event, read from stdin if not given
+        text: Vec<String>,
+        /// Machine the event is about
+        #[arg(short, long, env = "BUCFACE_MACHINE", default_value = "Unknown")]
+        machine: String,
+        /// Tag of the event, may be repeated
+        #[arg(long = "tag")]
+        tags: Vec<String>,
+        #[arg(short, long, default_value_t)]
+        severity: Severity,
+        /// Field of the event as `key=value`, may be repeated
+        #[arg(long = "field", value_parser = parse_field)]
+        fields: Vec<(String, FieldValue)>,
+        /// Id of the event this one replies to
+        #[arg(long)]
+        reply_to: Option<u64>,
+    },
+    /// Prints the newest events, then the new ones as they are logged with
+    /// `--follow`
+    Tail {
+        /// How many of the newest events to print
+        #[arg(short = 'n', long, default_value_t = 10)]
+        lines: usize,
+        /// Keep printing new events until interrupted
+        #[arg(short, long)]
+        follow: bool,
+        #[command(flatten)]
+        filter: FilterArgs,
+    },
+    /// Prints the events with the ids
+    Get {
+        #[arg(required = true)]
+        ids: Vec<u64>,
+    },
+    /// Prints every event matching the filter, oldest first
+    Query {
+        #[command(flatten)]
+        filter: FilterArgs,
+        /// Print at most this many events
+        #[arg(short, long)]
+        limit: Option<u32>,
+    },
+}
+
+/// The options an [EventFilter] is made of, each of which may be repeated.
+#[derive(Debug, Args)]
+struct FilterArgs {
+    /// Only events by the author
+    #[arg(long = "author")]
+    authors: Vec<String>,
+    /// Only events from the machine
+    #[arg(long = "machine")]
+    machines: Vec<String>,
+    /// Only events containing the word, ignoring case
+    #[arg(long = "keyword")]
+    keywords: Vec<String>,
+    /// Only events with the tag
+    #[arg(long = "tag")]
+    tags: Vec<String>,
+    /// Only events at least this serious
+    #[arg(long)]
+    severity: Option<Severity>,
+    /// Only events whose fields meet the condition, like `exit_code != 0`
+    #[arg(long = "field")]
+    fields: Vec<FieldCondition>,
+    /// Only events logged at or after the time, in UTC
+    #[arg(long, value_parser = parse_time)]
+    since: Option<chrono::NaiveDateTime>,
+    /// Only events logged before the time, in UTC
+    #[arg(long, value_parser = parse_time)]
+    until: Option<chrono::NaiveDateTime>,
+    /// Only events with at least the id
+    #[arg(long)]
+    from_id: Option<u64>,
+    /// Only events with a lower id
+    #[arg(long)]
+    to_id: Option<u64>,
+}
+
+impl FilterArgs {
+    fn filter(&self) -> EventFilter {
+        let bound = |time: Option<chrono::NaiveDateTime>, inclusive: bool| match time {
+            Some(time) if inclusive => TimeBound::Inclusive(time),
+            Some(time) => TimeBound::Exclusive(time),
+            None => TimeBound::Unbounded,
+        };
+
+        EventFilter {
+            authors: self.authors.iter().cloned().collect(),
+            machines: self.machines.iter().cloned().collect(),
+            keywords: self.keywords.iter().cloned().collect(),
+            time: TimeRange {
+                start: bound(self.since, true),
+                end: bound(self.until, false),
+            },
+            ids: IdRange {
+                start: self.from_id,
+                end: self.to_id,
+            },
+            tags: self.tags.iter().cloned().collect(),
+            severity: self.severity,
+            fields: self.fields.clone(),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum CliError {
+    Client(ClientError),
+    Io(io::Error),
+    Usage(String),
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Client(e) => write!(f, "{e}"),
+            Self::Io(e) => write!(f, "{e}"),
+            Self::Usage(message) => f.write_str(message),
+        }
+    }
+}
+
+impl From<ClientError> for CliError {
+    fn from(e: ClientError) -> Self {
+        Self::Client(e)
+    }
+}
+
+impl From<io::Error> for CliError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    env_logger::init();
+    let cli = Cli::parse();
+
+    match run(cli).await {
+        Ok(()) => ExitCode::SUCCESS,
+        // Like `head` closing the pipe
+        Err(CliError::Io(e)) if e.kind() == io::ErrorKind::BrokenPipe => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("bucface-cli: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn run(cli: Cli) -> Result<(), CliError> {
+    let client = connect(&cli).await?;
+    let mut printer = Printer::new(cli.format, io::stdout().lock());
+
+    match cli.command {
+        Command::Post {
+            text,
+            machine,
+            tags,
+            severity,
+            fields,
+            reply_to,
+        } => {
+            let event = match text.is_empty() {
+                true => {
+                    let mut text = String::new();
+                    io::stdin().read_to_string(&mut text)?;
+                    text.trim_end_matches(['\n', '\r']).to_string()
+                }
+                false => text.join(" "),
+            };
+            if event.trim().is_empty() {
+                return Err(CliError::Usage("nothing to post".into()));
+            }
+            let event = Event {
+                author: client.author().into(),
+                machine,
+                event,
+                time: chrono::Utc::now().naive_utc(),
+                parent: reply_to,
+                tags: tags.into_iter().collect(),
+                severity,
+                attachments: Vec::new(),
+                fields: fields.into_iter().collect::<BTreeMap<_, _>>(),
+            };
+            printer.print(&client.post(event).await?)?;
+        }
+        Command::Tail {
+            lines,
+            follow,
+            filter,
+        } => {
+            let filter = filter.filter();
+            // Subscribed first so nothing logged meanwhile is missed
+            let mut subscription = match follow {
+                true => Some(client.subscribe(filter.clone()).await?),
+                false => None,
+            };
+
+            let newest = newest_events(&client, &filter, lines).await?;
+            let mut last = None;
+            for event in newest.iter().rev() {
+                printer.print(event)?;
+                last = Some(event._id);
+            }
+
+            let Some(subscription) = &mut subscription else {
+                return Ok(());
+            };
+            while let Some(event) = subscription.next().await {
+                // Edits and retractions are pushed again under the same id
+                if last.is_some_and(|last| event._id <= last) {
+                    continue;
+                }
+                printer.print(&event)?;
+                last = Some(event._id);
+            }
+            return Err(ClientError::Closed.into());
+        }
+        Command::Get { ids } => {
+            let mut missing = Vec::new();
+            for id in ids {
+                match client.get(id).await {
+                    Ok(event) => printer.print(&event)?,
+                    Err(ClientError::Server(e)) => {
+                        log::debug!("Error getting event {id}: {e:?}");
+                        missing.push(id.to_string());
+                    }
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            if !missing.is_empty() {
+                let missing = missing.join(", ");
+                return Err(CliError::Usage(format!("no events with the ids {missing}")));
+            }
+        }
+        Command::Query { filter, limit } => {
+            for event in client.query(filter.filter(), limit).await? {
+                printer.print(&event)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Connects to the server, authenticating and joining the channel.
+async fn connect(cli: &Cli) -> Result<Client, CliError> {
+    let options = ConnectOptions {
+        tls: TlsOptions {
+            ca_cert: cli.ca_cert.as_ref().map(std::fs::read).transpose()?,
+            pin: cli
+                .pin
+                .as_deref()
+                .map(parse_fingerprint)
+                .transpose()
+                .map_err(|e| CliError::Usage(format!("invalid pin: {e:?}")))?,
+        },
+        token: cli.token.clone(),
+        channel: (cli.channel != DEFAULT_CHANNEL).then(|| cli.channel.clone()),
+        show_retracted: cli.retracted,
+    };
+
+    Ok(Client::connect(&cli.url, &options).await?)
+}
+
+/// The newest `count` events matching the filter, newest first, looking
+/// through the events page by page.
+async fn newest_events(
+    client: &Client,
+    filter: &EventFilter,
+    count: usize,
+) -> Result<Vec<EventDB>, CliError> {
+    let mut newest = Vec::new();
+    let mut start = None;
+    while newest.len() < count {
+        let query = PageQuery {
+            start,
+            direction: Direction::Descending,
+            limit: match filter == &EventFilter::default() {
+                true => count.min(TAIL_PAGE as usize) as u32,
+                false => TAIL_PAGE,
+            },
+        };
+        let (events, next) = client.page(query).await?;
+        newest.extend(events.into_iter().filter(|event| filter.matches(event)));
+        match next {
+            Some(next) => start = Some(next),
+            None => break,
+        }
+    }
+    newest.truncate(count);
+
+    Ok(newest)
+}
+
+/// Parses a `key=value` field, see [FieldValue::parse].
+fn parse_field(field: &str) -> Result<(String, FieldValue), String> {
+    let (key, value) = field
+        .split_once('=')
+        .ok_or_else(|| format!("`{field}` is not key=value"))?;
+    let key = key.trim();
+    if !valid_field_key(key) {
+        return Err(format!("`{key}` is not a field name"));
+    }
+
+    Ok((key.into(), FieldValue::parse(value)))
+}
+
+/// Parses a time in UTC like `2024-03-01T08:00:00`, `2024-03-01 08:00` or
+/// `2024-03-01`, or in RFC 3339 with any offset.
+fn parse_time(time: &str) -> Result<chrono::NaiveDateTime, String> {
+    if let Ok(time) = chrono::DateTime::parse_from_rfc3339(time) {
+        return Ok(time.naive_utc());
+    }
+    for format in [
+        "%Y-%m-%dT%H:%M:%S%.f",
+        "%Y-%m-%d %H:%M:%S%.f",
+        "%Y-%m-%d %H:%M",
+    ] {
+        if let Ok(time) = chrono::NaiveDateTime::parse_from_str(time, format) {
+            return Ok(time);
+        }
+    }
+    chrono::NaiveDate::parse_from_str(time, "%Y-%m-%d")
+        .map(|date| date.and_time(chrono::NaiveTime::MIN))
+        .map_err(|_| format!("`{time}` is not a time like 2024-03-01T08:00:00"))
+}
+
+#[cfg(test)]
+mod cli_tests {
+    use super::*;
+
+    #[test]
+    fn test_args() {
+        let cli = Cli::try_parse_from([
+            "bucface-cli",
+            "--format",
+            "csv",
+            "query",
+            "--tag",
+            "pump",
+            "--field",
+            "exit_code != 0",
+            "--severity",
+            "WARN",
+            "--since",
+            "2024-03-01",
+            "--to-id",
+            "20",
+        ])
+        .unwrap();
+        assert_eq!(cli.format, Format::Csv);
+        assert_eq!(cli.channel, DEFAULT_CHANNEL);
+        let Command::Query { filter, limit } = cli.command else {
+            panic!("Expected a query, got {:?}", cli.command);
+        };
+        assert_eq!(limit, None);
+        let filter = filter.filter();
+        assert_eq!(filter.tags, ["pump".to_string()].into());
+        assert_eq!(filter.fields, vec!["exit_code != 0".parse().unwrap()]);
+        assert_eq!(filter.severity, Some(Severity::Warn));
+        assert_eq!(
+            filter.time.start,
+            TimeBound::Inclusive(parse_time("2024-03-01T00:00:00").unwrap())
+        );
+        assert_eq!(
+            filter.ids,
+            IdRange {
+                start: None,
+                end: Some(20)
+            }
+        );
+
+        let cli = Cli::try_parse_from([
+            "bucface-cli",
+            "post",
+            "--field",
+            "exit_code=2",
+            "--field",
+            "cmd=make test",
+            "Build",
+            "failed",
+        ])
+        .unwrap();
+        let Command::Post { text, fields, .. } = cli.command else {
+            panic!("Expected a post, got {:?}", cli.command);
+        };
+        assert_eq!(text, ["Build", "failed"]);
+        assert_eq!(
+            fields,
+            [
+                ("exit_code".to_string(), FieldValue::Int(2)),
+                ("cmd".to_string(), FieldValue::String("make test".into())),
+            ]
+        );
+
+        assert!(Cli::try_parse_from(["bucface-cli", "post", "--field", "1=2"]).is_err());
+        assert!(Cli::try_parse_from(["bucface-cli", "query", "--severity", "loud"]).is_err());
+        assert!(Cli::try_parse_from(["bucface-cli", "get"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_time() {
+        let expected = chrono::NaiveDate::from_ymd_opt(2024, 3, 1)
+            .unwrap()
+            .and_hms_opt(8, 30, 0)
+            .unwrap();
+        for time in [
+            "2024-03-01T08:30:00",
+            "2024-03-01 08:30:00.000",
+            "2024-03-01 08:30",
+            "2024-03-01T10:30:00+02:00",
+        ] {
+            assert_eq!(parse_time(time), Ok(expected), "{time}");
+        }
+        assert_eq!(
+            parse_time("2024-03-01"),
+            Ok(expected.date().and_time(chrono::NaiveTime::MIN))
+        );
+        assert!(parse_time("yesterday").is_err());
+    }
+}
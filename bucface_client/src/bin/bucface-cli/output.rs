@@ -0,0 +1,224 @@
+use std::io::{self, Write};
+
+use bucface_utils::EventDB;
+use clap::ValueEnum;
+
+/// How events are printed.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    /// A line per event, for people
+    #[default]
+    Text,
+    /// A JSON object per line
+    Json,
+    /// Comma separated values with a header
+    Csv,
+}
+
+/// The columns of [Format::Csv].
+const CSV_HEADER: [&str; 11] = [
+    "id",
+    "channel",
+    "time",
+    "author",
+    "machine",
+    "severity",
+    "tags",
+    "parent",
+    "retracted",
+    "event",
+    "fields",
+];
+
+/// Prints events in a [Format], flushing after each so `tail -f` output
+/// shows up immediately when piped.
+pub struct Printer<W: Write> {
+    format: Format,
+    out: W,
+    header_written: bool,
+}
+
+impl<W: Write> Printer<W> {
+    pub fn new(format: Format, out: W) -> Self {
+        Self {
+            format,
+            out,
+            header_written: false,
+        }
+    }
+
+    pub fn print(&mut self, event: &EventDB) -> io::Result<()> {
+        match self.format {
+            Format::Text => writeln!(self.out, "{}", text(event))?,
+            Format::Json => {
+                serde_json::to_writer(&mut self.out, event)?;
+                writeln!(self.out)?;
+            }
+            Format::Csv => {
+                if !self.header_written {
+                    writeln!(self.out, "{}", CSV_HEADER.join(","))?;
+                    self.header_written = true;
+                }
+                writeln!(self.out, "{}", csv_record(event))?;
+            }
+        }
+
+        self.out.flush()
+    }
+}
+
+/// An event like `12 2024-03-01 08:00:00 WARN alice@buc-07 #pump: Pump
+/// restarted {exit_code=0}`.
+fn text(event: &EventDB) -> String {
+    let mut line = format!(
+        "{} {} {} {}@{}",
+        event._id,
+        event.time.format("%Y-%m-%d %H:%M:%S"),
+        event.severity.to_string().to_uppercase(),
+        event.author,
+        event.machine
+    );
+    for tag in &event.tags {
+        line.push_str(&format!(" #{tag}"));
+    }
+    if let Some(parent) = event.parent {
+        line.push_str(&format!(" (reply to {parent})"));
+    }
+    line.push_str(": ");
+    line.push_str(&event.event);
+    if !event.fields.is_empty() {
+        line.push_str(&format!(" {{{}}}", fields(event, ", ")));
+    }
+    for attachment in &event.attachments {
+        line.push_str(&format!(" [{} {}]", attachment.name, attachment.digest));
+    }
+    if let Some(retraction) = &event.retraction {
+        line.push_str(&format!(
+            " [retracted by {}: {}]",
+            retraction.author, retraction.reason
+        ));
+    }
+
+    line
+}
+
+fn fields(event: &EventDB, separator: &str) -> String {
+    event
+        .fields
+        .iter()
+        .map(|(key, value)| format!("{key}={value}"))
+        .collect::<Vec<String>>()
+        .join(separator)
+}
+
+/// A line of [CSV_HEADER] columns, tags and fields separated by `;`.
+fn csv_record(event: &EventDB) -> String {
+    let tags = event
+        .tags
+        .iter()
+        .cloned()
+        .collect::<Vec<String>>()
+        .join(";");
+    let parent = event.parent.map(|parent| parent.to_string());
+    let retracted = event.retraction.is_some().to_string();
+    [
+        event._id.to_string(),
+        event.channel.clone(),
+        event.time.format("%Y-%m-%dT%H:%M:%S%.f").to_string(),
+        event.author.clone(),
+        event.machine.clone(),
+        event.severity.to_string(),
+        tags,
+        parent.unwrap_or_default(),
+        retracted,
+        event.event.clone(),
+        fields(event, ";"),
+    ]
+    .iter()
+    .map(|value| csv_escape(value))
+    .collect::<Vec<String>>()
+    .join(",")
+}
+
+/// Quotes a value containing separators, quotes or line breaks, as RFC 4180
+/// has it.
+fn csv_escape(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.into()
+    }
+}
+
+#[cfg(test)]
+mod output_tests {
+    use std::collections::BTreeMap;
+
+    use bucface_utils::{Event, FieldValue, Severity};
+
+    use super::*;
+
+    fn event() -> EventDB {
+        EventDB {
+            author: "alice".into(),
+            machine: "buc-07".into(),
+            event: "Pump failed, \"again\"\nRestarting".into(),
+            time: chrono::NaiveDate::from_ymd_opt(2024, 3, 1)
+                .unwrap()
+                .and_hms_opt(8, 0, 0)
+                .unwrap(),
+            tags: ["pump".to_string(), "rig-2".to_string()].into(),
+            severity: Severity::Error,
+            fields: BTreeMap::from([
+                ("exit_code".into(), FieldValue::Int(2)),
+                ("ok".into(), FieldValue::Bool(false)),
+            ]),
+            ..EventDB::from(Event::default(), 12)
+        }
+    }
+
+    fn printed(format: Format, events: &[EventDB]) -> String {
+        let mut out = Vec::new();
+        let mut printer = Printer::new(format, &mut out);
+        for event in events {
+            printer.print(event).unwrap();
+        }
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_text() {
+        assert_eq!(
+            printed(Format::Text, &[event()]),
+            "12 2024-03-01 08:00:00 ERROR alice@buc-07 #pump #rig-2: \
+             Pump failed, \"again\"\nRestarting {exit_code=2, ok=false}\n"
+        );
+    }
+
+    #[test]
+    fn test_csv() {
+        let csv = printed(Format::Csv, &[event(), EventDB::from(Event::default(), 13)]);
+        let mut lines = csv.split_inclusive('\n');
+        assert_eq!(lines.next(), Some(&*format!("{}\n", CSV_HEADER.join(","))));
+        assert_eq!(
+            lines.next(),
+            Some(
+                "12,main,2024-03-01T08:00:00,alice,buc-07,error,pump;rig-2,,false,\
+                 \"Pump failed, \"\"again\"\"\n"
+            )
+        );
+        assert_eq!(lines.next(), Some("Restarting\",exit_code=2;ok=false\n"));
+        // The header is only written once
+        assert!(lines.next().unwrap().starts_with("13,"));
+        assert_eq!(lines.next(), None);
+    }
+
+    #[test]
+    fn test_json() {
+        let json = printed(Format::Json, &[event(), event()]);
+        let lines = json.lines().collect::<Vec<&str>>();
+        assert_eq!(lines.len(), 2);
+        let parsed: EventDB = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(parsed, event());
+    }
+}
@@ -0,0 +1,913 @@
+use std::fmt;
+
+use bucface_client_lib::logs::{comma_separated, parse_fields, threaded};
+use bucface_client_lib::net::backoff::Backoff;
+use bucface_client_lib::net::tls::{parse_fingerprint, TlsOptions};
+use bucface_client_lib::outbox::{refused, Outbox};
+use bucface_client_lib::{Client, ClientError, ConnectOptions, Subscription};
+use bucface_utils::{
+    Direction, Event, EventDB, EventFilter, IdRange, PageQuery, Severity, DEFAULT_CHANNEL,
+    PROTOCOL_VERSION,
+};
+use crossterm::event::{KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+
+/// How many logs are loaded at once, newest first.
+const LOGS_PER_PAGE: u32 = 200;
+/// How many logs Page Up and Page Down move the selection by.
+const PAGE_STEP: isize = 10;
+
+/// A single line text box.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Input {
+    pub text: String,
+    /// Where the cursor is, in chars
+    pub cursor: usize,
+}
+
+impl Input {
+    pub fn new(text: &str) -> Self {
+        Self {
+            text: text.into(),
+            cursor: text.chars().count(),
+        }
+    }
+
+    /// Edits the text as the key says, returning whether the key was for
+    /// the text box.
+    pub fn handle(&mut self, key: KeyEvent) -> bool {
+        let len = self.text.chars().count();
+        match key.code {
+            KeyCode::Char(c)
+                if !key
+                    .modifiers
+                    .intersects(KeyModifiers::CONTROL | KeyModifiers::ALT) =>
+            {
+                let at = self.byte(self.cursor);
+                self.text.insert(at, c);
+                self.cursor += 1;
+            }
+            KeyCode::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                let at = self.byte(self.cursor);
+                self.text.remove(at);
+            }
+            KeyCode::Delete if self.cursor < len => {
+                let at = self.byte(self.cursor);
+                self.text.remove(at);
+            }
+            KeyCode::Left => self.cursor = self.cursor.saturating_sub(1),
+            KeyCode::Right => self.cursor = (self.cursor + 1).min(len),
+            KeyCode::Home => self.cursor = 0,
+            KeyCode::End => self.cursor = len,
+            _ => return false,
+        }
+        true
+    }
+
+    pub fn clear(&mut self) {
+        self.text.clear();
+        self.cursor = 0;
+    }
+
+    /// The text left of the cursor, to place it.
+    pub fn before_cursor(&self) -> &str {
+        &self.text[..self.byte(self.cursor)]
+    }
+
+    /// The byte offset of the char at `cursor`.
+    fn byte(&self, cursor: usize) -> usize {
+        self.text
+            .char_indices()
+            .nth(cursor)
+            .map_or(self.text.len(), |(i, _)| i)
+    }
+}
+
+/// The part of the screen keys go to, in the order Tab moves through them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Focus {
+    Server,
+    Port,
+    Tls,
+    CaCert,
+    Pin,
+    Token,
+    Channel,
+    Logs,
+    Log,
+    Severity,
+    Tags,
+    Fields,
+}
+
+impl Focus {
+    const ORDER: [Focus; 12] = [
+        Focus::Server,
+        Focus::Port,
+        Focus::Tls,
+        Focus::CaCert,
+        Focus::Pin,
+        Focus::Token,
+        Focus::Channel,
+        Focus::Logs,
+        Focus::Log,
+        Focus::Severity,
+        Focus::Tags,
+        Focus::Fields,
+    ];
+
+    fn next(self) -> Self {
+        let i = Self::ORDER.iter().position(|&focus| focus == self).unwrap();
+        Self::ORDER[(i + 1) % Self::ORDER.len()]
+    }
+
+    fn previous(self) -> Self {
+        let i = Self::ORDER.iter().position(|&focus| focus == self).unwrap();
+        Self::ORDER[(i + Self::ORDER.len() - 1) % Self::ORDER.len()]
+    }
+
+    /// Whether it is part of the [ConnectForm].
+    pub fn in_form(self) -> bool {
+        matches!(
+            self,
+            Focus::Server
+                | Focus::Port
+                | Focus::Tls
+                | Focus::CaCert
+                | Focus::Pin
+                | Focus::Token
+                | Focus::Channel
+        )
+    }
+}
+
+/// Where and how to connect, as entered.
+#[derive(Debug, Default)]
+pub struct ConnectForm {
+    pub server: Input,
+    pub port: Input,
+    pub tls: bool,
+    /// Path to a PEM certificate to trust when connecting with TLS
+    pub ca_cert: Input,
+    /// SHA-256 fingerprint of the server certificate to pin
+    pub pin: Input,
+    /// Token to authenticate with, if the server has users
+    pub token: Input,
+    pub channel: Input,
+}
+
+impl ConnectForm {
+    /// The url to connect to.
+    pub fn endpoint(&self) -> String {
+        format!(
+            "{scheme}://{server}:{port}",
+            scheme = if self.tls { "wss" } else { "ws" },
+            server = self.server.text.trim(),
+            port = self.port.text.trim()
+        )
+    }
+
+    /// The channel to read and write, the default one unless another is
+    /// entered.
+    pub fn channel(&self) -> &str {
+        match self.channel.text.trim() {
+            "" => DEFAULT_CHANNEL,
+            channel => channel,
+        }
+    }
+
+    pub fn options(&self) -> Result<ConnectOptions, String> {
+        let ca_cert = match self.ca_cert.text.trim() {
+            "" => None,
+            path => Some(std::fs::read(path).map_err(|e| format!("Error reading {path}: {e}"))?),
+        };
+        let pin = match self.pin.text.trim() {
+            "" => None,
+            pin => Some(parse_fingerprint(pin).map_err(|e| format!("Invalid pin: {e:?}"))?),
+        };
+        let token = Some(self.token.text.trim())
+            .filter(|token| !token.is_empty())
+            .map(String::from);
+        let channel = Some(self.channel())
+            .filter(|&channel| channel != DEFAULT_CHANNEL)
+            .map(String::from);
+
+        Ok(ConnectOptions {
+            tls: TlsOptions { ca_cert, pin },
+            token,
+            channel,
+            show_retracted: false,
+        })
+    }
+}
+
+/// The log being written.
+#[derive(Debug, Default)]
+pub struct Composer {
+    pub log: Input,
+    pub severity: Severity,
+    /// Comma separated tags
+    pub tags: Input,
+    /// Comma separated `key=value` fields
+    pub fields: Input,
+    /// The id of the log it replies to
+    pub replying: Option<u64>,
+}
+
+#[derive(Debug, Clone)]
+pub enum Status {
+    Disconnected,
+    Connecting,
+    /// Connected to the url
+    Connected(String),
+    /// Lost the connection, and waiting to make it again
+    Reconnecting(Backoff),
+    /// Why connecting failed or the connection closed
+    Failed(String),
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Status::Disconnected => write!(f, "Disconnected"),
+            Status::Connecting => write!(f, "Connecting"),
+            Status::Connected(url) => write!(f, "Connected to {url}"),
+            Status::Reconnecting(backoff) => {
+                write!(f, "Reconnecting (attempt {})", backoff.failures + 1)
+            }
+            Status::Failed(e) => write!(f, "Failed: {e}"),
+        }
+    }
+}
+
+/// What a key asks for that the server is needed for, done by the caller.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Connect,
+    Reconnect,
+    Send,
+    /// Send the pending logs, like those marked to be resent
+    Flush,
+    LoadOlder,
+    Quit,
+}
+
+pub struct App {
+    pub form: ConnectForm,
+    pub composer: Composer,
+    /// The machine our logs are about
+    pub machine: String,
+    pub focus: Focus,
+    pub status: Status,
+    pub client: Option<Client>,
+    /// The new logs of the channel as they are logged
+    pub subscription: Option<Subscription>,
+    /// The logs loaded, sorted by id
+    pub logs: Vec<EventDB>,
+    /// Where the next page of older logs starts, if there are any
+    pub older_logs: Option<u64>,
+    /// The id of the selected log, or [None] to follow the newest
+    pub selected: Option<u64>,
+    /// Why the last thing done failed, until the next key
+    pub error: Option<String>,
+    /// The logs written but not logged by the server yet, shown greyed out
+    pub outbox: Outbox,
+}
+
+impl App {
+    pub fn new(form: ConnectForm, machine: String, outbox: Outbox) -> Self {
+        App {
+            form,
+            composer: Composer::default(),
+            machine,
+            focus: Focus::Server,
+            status: Status::Disconnected,
+            client: None,
+            subscription: None,
+            logs: Vec::new(),
+            older_logs: None,
+            selected: None,
+            error: None,
+            outbox,
+        }
+    }
+
+    pub fn handle_key(&mut self, key: KeyEvent) -> Option<Action> {
+        // Terminals that report releases report presses too
+        if key.kind == KeyEventKind::Release {
+            return None;
+        }
+        self.error = None;
+        if key.modifiers.contains(KeyModifiers::CONTROL)
+            && matches!(key.code, KeyCode::Char('c') | KeyCode::Char('q'))
+        {
+            return Some(Action::Quit);
+        }
+        match key.code {
+            KeyCode::Tab => {
+                self.focus = self.focus.next();
+                return None;
+            }
+            KeyCode::BackTab => {
+                self.focus = self.focus.previous();
+                return None;
+            }
+            KeyCode::Esc => {
+                self.composer.replying = None;
+                self.focus = Focus::Logs;
+                return None;
+            }
+            _ => {}
+        }
+
+        match self.focus {
+            Focus::Logs => self.handle_logs_key(key),
+            Focus::Tls => match key.code {
+                KeyCode::Char(' ') => {
+                    self.form.tls = !self.form.tls;
+                    None
+                }
+                KeyCode::Enter => Some(Action::Connect),
+                _ => None,
+            },
+            Focus::Severity => {
+                let i = Severity::ALL
+                    .iter()
+                    .position(|&severity| severity == self.composer.severity)
+                    .unwrap_or(0);
+                let count = Severity::ALL.len();
+                match key.code {
+                    KeyCode::Left => {
+                        self.composer.severity = Severity::ALL[(i + count - 1) % count]
+                    }
+                    KeyCode::Right => self.composer.severity = Severity::ALL[(i + 1) % count],
+                    KeyCode::Enter => return Some(Action::Send),
+                    _ => {}
+                }
+                None
+            }
+            focus => {
+                if key.code == KeyCode::Enter {
+                    return Some(match focus.in_form() {
+                        true => Action::Connect,
+                        false => Action::Send,
+                    });
+                }
+                if let Some(input) = self.input() {
+                    input.handle(key);
+                }
+                None
+            }
+        }
+    }
+
+    /// The text box in focus, if it is one.
+    fn input(&mut self) -> Option<&mut Input> {
+        match self.focus {
+            Focus::Server => Some(&mut self.form.server),
+            Focus::Port => Some(&mut self.form.port),
+            Focus::CaCert => Some(&mut self.form.ca_cert),
+            Focus::Pin => Some(&mut self.form.pin),
+            Focus::Token => Some(&mut self.form.token),
+            Focus::Channel => Some(&mut self.form.channel),
+            Focus::Log => Some(&mut self.composer.log),
+            Focus::Tags => Some(&mut self.composer.tags),
+            Focus::Fields => Some(&mut self.composer.fields),
+            Focus::Tls | Focus::Logs | Focus::Severity => None,
+        }
+    }
+
+    fn handle_logs_key(&mut self, key: KeyEvent) -> Option<Action> {
+        match key.code {
+            KeyCode::Up | KeyCode::Char('k') => self.move_selection(-1),
+            KeyCode::Down | KeyCode::Char('j') => self.move_selection(1),
+            KeyCode::PageUp => self.move_selection(-PAGE_STEP),
+            KeyCode::PageDown => self.move_selection(PAGE_STEP),
+            KeyCode::Home | KeyCode::Char('g') => self.move_selection(isize::MIN),
+            KeyCode::End | KeyCode::Char('G') => {
+                self.selected = None;
+                None
+            }
+            KeyCode::Char('r') if self.selected.is_some() => {
+                self.composer.replying = self.selected;
+                self.focus = Focus::Log;
+                None
+            }
+            KeyCode::Char('R') => self.resend_failed(),
+            KeyCode::Char('X') => {
+                self.discard_failed();
+                None
+            }
+            KeyCode::Char('q') => Some(Action::Quit),
+            _ => None,
+        }
+    }
+
+    /// Marks every log of the channel the server refused to be sent again.
+    fn resend_failed(&mut self) -> Option<Action> {
+        let failed = self.failed_keys();
+        for key in &failed {
+            if let Err(e) = self.outbox.resend(key) {
+                self.error = Some(format!("Error resending log: {e}"));
+                return None;
+            }
+        }
+        (!failed.is_empty()).then_some(Action::Flush)
+    }
+
+    /// Drops the oldest log of the channel the server refused.
+    fn discard_failed(&mut self) {
+        let Some(key) = self.failed_keys().into_iter().next() else {
+            return;
+        };
+        if let Err(e) = self.outbox.remove(&key) {
+            self.error = Some(format!("Error discarding log: {e}"));
+        }
+    }
+
+    fn failed_keys(&self) -> Vec<String> {
+        self.outbox
+            .pending_in(self.form.channel())
+            .filter(|pending| pending.failed.is_some())
+            .map(|pending| pending.key.clone())
+            .collect()
+    }
+
+    /// Moves the selection through the logs in the order they are shown.
+    /// Moving past the newest follows new logs, moving onto the oldest loads
+    /// the page before it.
+    fn move_selection(&mut self, by: isize) -> Option<Action> {
+        let order = threaded(&self.logs);
+        let Some(&(_, first)) = order.first() else {
+            return self.older_logs.map(|_| Action::LoadOlder);
+        };
+        // Following is being just past the newest
+        let at = self
+            .selected
+            .and_then(|id| order.iter().position(|&(_, i)| self.logs[i]._id == id))
+            .unwrap_or(order.len());
+
+        let target = (at as isize).saturating_add(by);
+        if target >= order.len() as isize {
+            self.selected = None;
+            return None;
+        }
+        if target <= 0 {
+            self.selected = Some(self.logs[first]._id);
+            return self.older_logs.map(|_| Action::LoadOlder);
+        }
+        self.selected = Some(self.logs[order[target as usize].1]._id);
+        None
+    }
+
+    /// Connects with the form, loading the newest logs of the channel and
+    /// following the new ones. Drops the previous connection first.
+    pub async fn connect(&mut self) {
+        self.subscription = None;
+        self.client = None;
+        self.logs.clear();
+        self.older_logs = None;
+        self.selected = None;
+        self.composer.replying = None;
+
+        self.status = match self.open(None).await {
+            Ok(()) => Status::Connected(self.form.endpoint()),
+            Err(e) => {
+                self.subscription = None;
+                Status::Failed(e)
+            }
+        };
+        self.flush().await;
+    }
+
+    /// Makes the lost connection again, getting the logs sent meanwhile.
+    /// Waits longer before the next attempt if it fails.
+    pub async fn reconnect(&mut self) {
+        let Status::Reconnecting(backoff) = &self.status else {
+            return;
+        };
+        let mut backoff = backoff.clone();
+        let last_id = self.logs.last().map(|log| log._id);
+
+        self.status = match self.open(last_id).await {
+            Ok(()) => Status::Connected(self.form.endpoint()),
+            Err(_) => {
+                self.subscription = None;
+                backoff.failed();
+                Status::Reconnecting(backoff)
+            }
+        };
+        self.flush().await;
+    }
+
+    /// Connects and subscribes, then loads the logs after `after`, or the
+    /// newest page of them without it.
+    async fn open(&mut self, after: Option<u64>) -> Result<(), String> {
+        let options = self.form.options()?;
+        let client = Client::connect(&self.form.endpoint(), &options)
+            .await
+            .map_err(|e| e.to_string())?;
+        // Subscribed first so nothing logged meanwhile is missed
+        let subscription = client
+            .subscribe(EventFilter::default())
+            .await
+            .map_err(|e| e.to_string())?;
+        self.subscription = Some(subscription);
+        match after {
+            Some(last_id) => {
+                let filter = EventFilter {
+                    ids: IdRange {
+                        start: Some(last_id + 1),
+                        end: None,
+                    },
+                    ..EventFilter::default()
+                };
+                let events = client
+                    .query(filter, None)
+                    .await
+                    .map_err(|e| e.to_string())?;
+                for event in events {
+                    self.insert(event);
+                }
+            }
+            None => {
+                let query = PageQuery {
+                    start: None,
+                    direction: Direction::Descending,
+                    limit: LOGS_PER_PAGE,
+                };
+                let (events, next) = client.page(query).await.map_err(|e| e.to_string())?;
+                for event in events {
+                    self.insert(event);
+                }
+                self.older_logs = next;
+            }
+        }
+        self.client = Some(client);
+        if self.focus.in_form() {
+            self.focus = Focus::Log;
+        }
+
+        Ok(())
+    }
+
+    /// Loads the page of logs before the oldest one loaded.
+    pub async fn load_older(&mut self) {
+        let (Some(client), Some(start)) = (&self.client, self.older_logs) else {
+            return;
+        };
+        let query = PageQuery {
+            start: Some(start),
+            direction: Direction::Descending,
+            limit: LOGS_PER_PAGE,
+        };
+        match client.page(query).await {
+            Ok((events, next)) => {
+                for event in events {
+                    self.insert(event);
+                }
+                self.older_logs = next;
+            }
+            Err(e) => self.error = Some(format!("Error getting older logs: {e}")),
+        }
+    }
+
+    /// Puts the composed log into the outbox and sends it if we are
+    /// connected, otherwise once we are. What the server does not know is left
+    /// out, but logs written while disconnected keep everything.
+    pub async fn send(&mut self) {
+        if self.composer.log.text.trim().is_empty() {
+            return;
+        }
+        let fields = match parse_fields(&self.composer.fields.text) {
+            Ok(fields) => fields,
+            Err(e) => {
+                self.error = Some(e);
+                return;
+            }
+        };
+
+        let version = self
+            .client
+            .as_ref()
+            .map_or(PROTOCOL_VERSION, Client::server_version);
+        let mut event = Event {
+            author: self
+                .client
+                .as_ref()
+                .map(|client| client.author().into())
+                .unwrap_or_default(),
+            machine: self.machine.clone(),
+            event: self.composer.log.text.clone(),
+            time: chrono::Utc::now().naive_utc(),
+            parent: self.composer.replying,
+            ..Event::default()
+        };
+        if version >= 2 {
+            event.tags = comma_separated(&self.composer.tags.text);
+            event.severity = self.composer.severity;
+        }
+        if version >= 4 {
+            event.fields = fields;
+        }
+
+        if let Err(e) = self.outbox.push(self.form.channel(), event) {
+            self.error = Some(format!("Error keeping log: {e}"));
+            return;
+        }
+        self.composer.log.clear();
+        self.composer.replying = None;
+        self.flush().await;
+    }
+
+    /// Sends the pending logs of the channel, oldest first, until one fails.
+    /// Those the server refused are kept as failed, for the user to resend or
+    /// discard.
+    pub async fn flush(&mut self) {
+        let Some(client) = self.client.take() else {
+            return;
+        };
+        let channel = self.form.channel();
+        let pending = self.outbox.unsent_in(channel).cloned().collect::<Vec<_>>();
+        for pending in pending {
+            let posted = client.post_once(pending.channel, pending.key.clone(), pending.event);
+            let kept = match posted.await {
+                // The server does not push our own logs back to us
+                Ok(posted) => {
+                    self.insert(posted);
+                    self.outbox.remove(&pending.key).map(|_| ())
+                }
+                Err(ClientError::Server(e)) if refused(&e) => {
+                    self.error = Some(format!(
+                        "The server refused a log: {e:?}, R to resend it or X to discard it"
+                    ));
+                    self.outbox.fail(&pending.key, format!("{e:?}"))
+                }
+                Err(e) => {
+                    self.error = Some(format!("Error sending log: {e}"));
+                    break;
+                }
+            };
+            if let Err(e) = kept {
+                self.error = Some(format!("Error updating the outbox: {e}"));
+            }
+        }
+        self.client = Some(client);
+    }
+
+    /// Takes in a log pushed by the server, or [None] once the connection
+    /// is lost, which is then made again.
+    pub fn pushed(&mut self, event: Option<EventDB>) {
+        match event {
+            Some(event) => self.insert(event),
+            None => {
+                self.subscription = None;
+                self.client = None;
+                self.status = Status::Reconnecting(Backoff::new());
+            }
+        }
+    }
+
+    /// Adds a log, or replaces the one with its id if it was edited. Retracted
+    /// logs are dropped.
+    pub fn insert(&mut self, event: EventDB) {
+        match self.logs.binary_search_by_key(&event._id, |log| log._id) {
+            Ok(i) if event.retraction.is_some() => {
+                self.logs.remove(i);
+            }
+            Ok(i) if event.revision > self.logs[i].revision => self.logs[i] = event,
+            Ok(_) => {}
+            Err(_) if event.retraction.is_some() => {}
+            Err(i) => self.logs.insert(i, event),
+        }
+    }
+}
+
+#[cfg(test)]
+mod app_tests {
+    use bucface_utils::Retraction;
+
+    use super::*;
+
+    fn key(code: KeyCode) -> KeyEvent {
+        KeyEvent::new(code, KeyModifiers::NONE)
+    }
+
+    fn app() -> App {
+        let form = ConnectForm {
+            server: Input::new("localhost"),
+            port: Input::new("8080"),
+            channel: Input::new(DEFAULT_CHANNEL),
+            ..Default::default()
+        };
+        App::new(form, "buc-07".into(), Outbox::memory())
+    }
+
+    fn logs(parents: &[(u64, Option<u64>)]) -> Vec<EventDB> {
+        parents
+            .iter()
+            .map(|&(id, parent)| EventDB {
+                parent,
+                ..EventDB::from(Event::default(), id)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_input() {
+        let mut input = Input::new("hé");
+        for c in "llo".chars() {
+            assert!(input.handle(key(KeyCode::Char(c))));
+        }
+        assert_eq!((input.text.as_str(), input.cursor), ("héllo", 5));
+
+        input.handle(key(KeyCode::Left));
+        input.handle(key(KeyCode::Left));
+        input.handle(key(KeyCode::Left));
+        input.handle(key(KeyCode::Backspace));
+        assert_eq!((input.text.as_str(), input.cursor), ("hllo", 1));
+        assert_eq!(input.before_cursor(), "h");
+
+        input.handle(key(KeyCode::Home));
+        input.handle(key(KeyCode::Backspace));
+        input.handle(key(KeyCode::Delete));
+        input.handle(key(KeyCode::End));
+        input.handle(key(KeyCode::Right));
+        input.handle(key(KeyCode::Char('!')));
+        assert_eq!((input.text.as_str(), input.cursor), ("llo!", 4));
+
+        assert!(!input.handle(KeyEvent::new(KeyCode::Char('a'), KeyModifiers::CONTROL)));
+        assert!(!input.handle(key(KeyCode::Enter)));
+        assert_eq!(input.text, "llo!");
+    }
+
+    #[test]
+    fn test_keys() {
+        let mut app = app();
+        assert_eq!(app.handle_key(key(KeyCode::BackTab)), None);
+        assert_eq!(app.focus, Focus::Fields);
+        app.handle_key(key(KeyCode::Tab));
+        app.handle_key(key(KeyCode::Tab));
+        assert_eq!(app.focus, Focus::Port);
+        app.handle_key(key(KeyCode::Backspace));
+        app.handle_key(key(KeyCode::Char('1')));
+        app.handle_key(key(KeyCode::Tab));
+        app.handle_key(key(KeyCode::Char(' ')));
+        assert_eq!(app.form.endpoint(), "wss://localhost:8081");
+        assert_eq!(app.handle_key(key(KeyCode::Enter)), Some(Action::Connect));
+
+        app.focus = Focus::Severity;
+        app.handle_key(key(KeyCode::Right));
+        assert_eq!(app.composer.severity, Severity::Warn);
+        app.handle_key(key(KeyCode::Left));
+        app.handle_key(key(KeyCode::Left));
+        assert_eq!(app.composer.severity, Severity::Critical);
+        assert_eq!(app.handle_key(key(KeyCode::Enter)), Some(Action::Send));
+
+        app.composer.replying = Some(3);
+        app.handle_key(key(KeyCode::Esc));
+        assert_eq!((app.focus, app.composer.replying), (Focus::Logs, None));
+        assert_eq!(app.handle_key(key(KeyCode::Char('q'))), Some(Action::Quit));
+
+        // Ctrl-C quits from text boxes too, instead of being typed
+        app.focus = Focus::Log;
+        let quit = KeyEvent::new(KeyCode::Char('c'), KeyModifiers::CONTROL);
+        assert_eq!(app.handle_key(quit), Some(Action::Quit));
+        assert_eq!(app.handle_key(key(KeyCode::Char('q'))), None);
+        assert_eq!(app.composer.log.text, "q");
+    }
+
+    #[test]
+    fn test_selection() {
+        let mut app = app();
+        app.focus = Focus::Logs;
+        assert_eq!(app.handle_key(key(KeyCode::Up)), None);
+
+        // Shown as 1, 3 replying to 1, 2, 4
+        app.logs = logs(&[(1, None), (2, None), (3, Some(1)), (4, None)]);
+        app.older_logs = Some(0);
+        app.handle_key(key(KeyCode::Up));
+        assert_eq!(app.selected, Some(4));
+        app.handle_key(key(KeyCode::Char('k')));
+        assert_eq!(app.selected, Some(2));
+        app.handle_key(key(KeyCode::Up));
+        assert_eq!(app.selected, Some(3));
+        assert_eq!(
+            app.handle_key(key(KeyCode::PageUp)),
+            Some(Action::LoadOlder)
+        );
+        assert_eq!(app.selected, Some(1));
+
+        // The selection stays on the log as older ones are loaded
+        app.logs.splice(0..0, logs(&[(0, None)]));
+        app.older_logs = None;
+        app.handle_key(key(KeyCode::Down));
+        assert_eq!(app.selected, Some(3));
+        assert_eq!(app.handle_key(key(KeyCode::Home)), None);
+        assert_eq!(app.selected, Some(0));
+
+        app.handle_key(key(KeyCode::Char('j')));
+        app.handle_key(key(KeyCode::Char('r')));
+        assert_eq!((app.focus, app.composer.replying), (Focus::Log, Some(1)));
+
+        app.focus = Focus::Logs;
+        app.handle_key(key(KeyCode::PageDown));
+        assert_eq!(app.selected, None);
+        app.handle_key(key(KeyCode::Char('r')));
+        assert_eq!(app.focus, Focus::Logs);
+    }
+
+    #[test]
+    fn test_insert() {
+        let mut app = app();
+        for event in logs(&[(3, None), (1, None), (2, Some(1))]) {
+            app.insert(event);
+        }
+        let ids = |app: &App| app.logs.iter().map(|log| log._id).collect::<Vec<u64>>();
+        assert_eq!(ids(&app), vec![1, 2, 3]);
+
+        let edited = EventDB {
+            event: "edited".into(),
+            revision: 1,
+            ..app.logs[1].clone()
+        };
+        app.insert(edited.clone());
+        app.insert(EventDB {
+            event: "stale".into(),
+            ..edited.clone()
+        });
+        assert_eq!(app.logs[1].event, "edited");
+
+        let retraction = Retraction {
+            author: "bob".into(),
+            reason: "wrong rig".into(),
+            time: chrono::Utc::now().naive_utc(),
+        };
+        for id in [2, 5] {
+            app.insert(EventDB {
+                retraction: Some(retraction.clone()),
+                ..EventDB::from(Event::default(), id)
+            });
+        }
+        assert_eq!(ids(&app), vec![1, 3]);
+
+        app.pushed(None);
+        assert!(matches!(app.status, Status::Reconnecting(_)));
+    }
+
+    #[tokio::test]
+    async fn test_send_offline() {
+        let mut app = app();
+        app.composer.log = Input::new("Pump failed");
+        app.composer.tags = Input::new("pump");
+        app.composer.replying = Some(3);
+        app.send().await;
+        assert_eq!(app.error, None);
+        assert!(app.composer.log.text.is_empty());
+        assert_eq!(app.composer.replying, None);
+
+        let pending = app.outbox.pending();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].channel, DEFAULT_CHANNEL);
+        assert_eq!(pending[0].event.event, "Pump failed");
+        assert_eq!(pending[0].event.parent, Some(3));
+        assert_eq!(pending[0].event.tags, ["pump".to_string()].into());
+
+        // Kept until there is a server to send it to
+        app.flush().await;
+        assert_eq!(app.outbox.pending().len(), 1);
+
+        // Refused logs are kept until resent or discarded
+        let refused = app.outbox.pending()[0].key.clone();
+        app.outbox.fail(&refused, "InvalidField".into()).unwrap();
+        app.focus = Focus::Logs;
+        assert_eq!(app.outbox.unsent_in(DEFAULT_CHANNEL).count(), 0);
+        assert_eq!(app.handle_key(key(KeyCode::Char('R'))), Some(Action::Flush));
+        assert_eq!(app.outbox.unsent_in(DEFAULT_CHANNEL).count(), 1);
+        assert_eq!(app.handle_key(key(KeyCode::Char('R'))), None);
+        app.handle_key(key(KeyCode::Char('X')));
+        assert_eq!(app.outbox.pending().len(), 1);
+        app.outbox.fail(&refused, "InvalidField".into()).unwrap();
+        app.handle_key(key(KeyCode::Char('X')));
+        assert!(app.outbox.pending().is_empty());
+    }
+
+    #[test]
+    fn test_options() {
+        let mut app = app();
+        let options = app.form.options().unwrap();
+        assert_eq!(options.token, None);
+        assert_eq!(options.channel, None);
+
+        app.form.token = Input::new(" alice-token ");
+        app.form.channel = Input::new("rig-2");
+        let options = app.form.options().unwrap();
+        assert_eq!(options.token.as_deref(), Some("alice-token"));
+        assert_eq!(options.channel.as_deref(), Some("rig-2"));
+        app.form.channel = Input::new(" ");
+        assert_eq!(app.form.channel(), DEFAULT_CHANNEL);
+
+        app.form.pin = Input::new("not hex");
+        assert!(app.form.options().is_err());
+    }
+}
@@ -0,0 +1,170 @@
+mod app;
+mod ui;
+
+use std::io::{self, Stdout};
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use bucface_client_lib::outbox::{self, Outbox, OutboxError};
+use bucface_client_lib::Subscription;
+use bucface_utils::{EventDB, DEFAULT_CHANNEL};
+use clap::Parser;
+use crossterm::event::{Event, EventStream};
+use crossterm::execute;
+use crossterm::terminal::{self, EnterAlternateScreen, LeaveAlternateScreen};
+use futures_util::StreamExt;
+use ratatui::backend::CrosstermBackend;
+use ratatui::Terminal;
+
+use app::{Action, App, ConnectForm, Input, Status};
+
+/// Shows and writes the events of a Bucface server in a terminal, for
+/// machines without a display. Nothing is logged, as it would be drawn over.
+#[derive(Debug, Parser)]
+#[command(version, about = "Bucface event log terminal client")]
+struct Args {
+    /// Server to connect to
+    #[arg(short, long, default_value = "localhost")]
+    server: String,
+    #[arg(short, long, default_value_t = 8080)]
+    port: u16,
+    /// Connect with TLS
+    #[arg(long)]
+    tls: bool,
+    /// PEM certificate to trust when connecting with TLS
+    #[arg(long, env = "BUCFACE_CA_CERT")]
+    ca_cert: Option<String>,
+    /// SHA-256 fingerprint of the server certificate to pin
+    #[arg(long, env = "BUCFACE_PIN")]
+    pin: Option<String>,
+    /// Token to authenticate with, if the server has users
+    #[arg(short, long, env = "BUCFACE_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+    /// Channel to read and write
+    #[arg(short, long, env = "BUCFACE_CHANNEL", default_value = DEFAULT_CHANNEL)]
+    channel: String,
+    /// Machine the logs written are about
+    #[arg(short, long, env = "BUCFACE_MACHINE", default_value = "Unknown")]
+    machine: String,
+    /// Connect right away instead of showing the form first
+    #[arg(long)]
+    connect: bool,
+    /// File the logs not logged by the server yet are kept in
+    #[arg(long, env = "BUCFACE_OUTBOX", default_value = outbox::DEFAULT_PATH)]
+    outbox: PathBuf,
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let args = Args::parse();
+    let outbox = match Outbox::open(&args.outbox) {
+        Ok(outbox) => outbox,
+        Err(e @ OutboxError::Locked) => {
+            eprintln!(
+                "bucface-tui: {}: {e}, pass another --outbox",
+                args.outbox.display()
+            );
+            return ExitCode::FAILURE;
+        }
+        Err(e) => {
+            eprintln!("bucface-tui: {}: {e}", args.outbox.display());
+            return ExitCode::FAILURE;
+        }
+    };
+    let form = ConnectForm {
+        server: Input::new(&args.server),
+        port: Input::new(&args.port.to_string()),
+        tls: args.tls,
+        ca_cert: Input::new(args.ca_cert.as_deref().unwrap_or_default()),
+        pin: Input::new(args.pin.as_deref().unwrap_or_default()),
+        token: Input::new(args.token.as_deref().unwrap_or_default()),
+        channel: Input::new(&args.channel),
+    };
+    let app = App::new(form, args.machine, outbox);
+
+    let result = match setup() {
+        Ok(mut terminal) => run(&mut terminal, app, args.connect).await,
+        Err(e) => Err(e),
+    };
+    let restored = restore();
+    match result.and(restored) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("bucface-tui: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn setup() -> io::Result<Terminal<CrosstermBackend<Stdout>>> {
+    terminal::enable_raw_mode()?;
+    execute!(io::stdout(), EnterAlternateScreen)?;
+    // Leaves the terminal usable for the panic message
+    let hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        let _ = restore();
+        hook(info);
+    }));
+
+    Terminal::new(CrosstermBackend::new(io::stdout()))
+}
+
+fn restore() -> io::Result<()> {
+    terminal::disable_raw_mode()?;
+    execute!(io::stdout(), LeaveAlternateScreen)
+}
+
+/// Draws the app and does what the keys ask for until it is quit.
+async fn run(
+    terminal: &mut Terminal<CrosstermBackend<Stdout>>,
+    mut app: App,
+    connect: bool,
+) -> io::Result<()> {
+    let mut keys = EventStream::new();
+    let mut action = connect.then_some(Action::Connect);
+    loop {
+        match action.take() {
+            Some(Action::Connect) => {
+                app.status = Status::Connecting;
+                terminal.draw(|frame| ui::draw(frame, &app))?;
+                app.connect().await;
+            }
+            Some(Action::Reconnect) => app.reconnect().await,
+            Some(Action::Send) => app.send().await,
+            Some(Action::Flush) => app.flush().await,
+            Some(Action::LoadOlder) => app.load_older().await,
+            Some(Action::Quit) => return Ok(()),
+            None => {}
+        }
+        terminal.draw(|frame| ui::draw(frame, &app))?;
+
+        tokio::select! {
+            event = keys.next() => match event {
+                Some(Ok(Event::Key(key))) => action = app.handle_key(key),
+                // Anything else, like a resize, only needs a redraw
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Err(e),
+                None => return Ok(()),
+            },
+            event = next_pushed(&mut app.subscription) => app.pushed(event),
+            () = reconnect_due(&app.status) => action = Some(Action::Reconnect),
+        }
+    }
+}
+
+/// Waits for the next attempt to reconnect, which never comes unless the
+/// connection was lost.
+async fn reconnect_due(status: &Status) {
+    match status {
+        Status::Reconnecting(backoff) => tokio::time::sleep(backoff.remaining()).await,
+        _ => std::future::pending().await,
+    }
+}
+
+/// The next log pushed to the subscription, which never comes without one.
+async fn next_pushed(subscription: &mut Option<Subscription>) -> Option<EventDB> {
+    match subscription {
+        Some(subscription) => subscription.next().await,
+        None => std::future::pending().await,
+    }
+}
@@ -0,0 +1,485 @@
+use bucface_client_lib::logs::{parse_fields, threaded};
+use bucface_client_lib::outbox::Pending;
+use bucface_utils::{EventDB, Severity};
+use ratatui::layout::{Constraint, Direction, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span, Text};
+use ratatui::widgets::{Block, Borders, List, ListItem, ListState, Paragraph};
+use ratatui::Frame;
+
+use crate::app::{App, Focus, Input, Status};
+
+/// How wide the labels in front of the text boxes are.
+const LABEL_WIDTH: u16 = 10;
+
+/// Draws the whole screen: the connect form while it is needed, the logs and
+/// the composer, with the keys that can be pressed at the bottom.
+pub fn draw(frame: &mut Frame, app: &App) {
+    let show_form = app.client.is_none() || app.focus.in_form();
+    let areas = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([
+            Constraint::Length(1),
+            Constraint::Length(if show_form { 9 } else { 0 }),
+            Constraint::Min(3),
+            Constraint::Length(6),
+            Constraint::Length(1),
+        ])
+        .split(frame.size());
+
+    header(frame, areas[0], app);
+    if show_form {
+        connect_form(frame, areas[1], app);
+    }
+    log_panel(frame, areas[2], app);
+    composer(frame, areas[3], app);
+    help(frame, areas[4], app);
+}
+
+fn header(frame: &mut Frame, area: Rect, app: &App) {
+    let colour = match app.status {
+        Status::Connected(_) => Color::Green,
+        Status::Connecting | Status::Reconnecting(_) => Color::Yellow,
+        Status::Disconnected => Color::Gray,
+        Status::Failed(_) => Color::Red,
+    };
+    let mut line = vec![
+        Span::styled("BucFace TUI ", Style::new().add_modifier(Modifier::BOLD)),
+        Span::styled(app.status.to_string(), Style::new().fg(colour)),
+    ];
+    if let Some(client) = &app.client {
+        line.push(Span::raw(format!(
+            " as {} (protocol {})",
+            client.author(),
+            client.server_version()
+        )));
+    }
+    frame.render_widget(Paragraph::new(Line::from(line)), area);
+}
+
+fn connect_form(frame: &mut Frame, area: Rect, app: &App) {
+    let block = Block::default().borders(Borders::ALL).title(" Connect ");
+    let inner = block.inner(area);
+    frame.render_widget(block, area);
+
+    let form = &app.form;
+    let rows = rows(inner, 7);
+    text_box(frame, rows[0], app, Focus::Server, "Server", &form.server);
+    text_box(frame, rows[1], app, Focus::Port, "Port", &form.port);
+    let tls = if form.tls { "[x]" } else { "[ ]" };
+    choice(frame, rows[2], app, Focus::Tls, "TLS", Span::raw(tls));
+    text_box(frame, rows[3], app, Focus::CaCert, "CA", &form.ca_cert);
+    text_box(frame, rows[4], app, Focus::Pin, "Pin", &form.pin);
+    let token = Input {
+        text: "*".repeat(form.token.text.chars().count()),
+        cursor: form.token.cursor,
+    };
+    text_box(frame, rows[5], app, Focus::Token, "Token", &token);
+    text_box(
+        frame,
+        rows[6],
+        app,
+        Focus::Channel,
+        "Channel",
+        &form.channel,
+    );
+}
+
+fn log_panel(frame: &mut Frame, area: Rect, app: &App) {
+    let order = threaded(&app.logs);
+    let mut items = order
+        .iter()
+        .map(|&(depth, i)| ListItem::new(log_text(&app.logs[i], depth)))
+        .collect::<Vec<ListItem>>();
+    let pending = app.outbox.pending_in(app.form.channel());
+    items.extend(pending.map(|pending| ListItem::new(pending_text(pending))));
+    let selected = app
+        .selected
+        .and_then(|id| order.iter().position(|&(_, i)| app.logs[i]._id == id));
+
+    let mut title = format!(" Logs ({}) ", app.logs.len());
+    if app.older_logs.is_some() {
+        title.push_str("- more above ");
+    }
+    let (failed, pending): (Vec<_>, Vec<_>) = app
+        .outbox
+        .pending_in(app.form.channel())
+        .partition(|pending| pending.failed.is_some());
+    if !pending.is_empty() {
+        title.push_str(&format!("- {} pending ", pending.len()));
+    }
+    if !failed.is_empty() {
+        title.push_str(&format!("- {} failed ", failed.len()));
+    }
+    let border = match app.focus {
+        Focus::Logs => Style::new().fg(Color::Yellow),
+        _ => Style::new(),
+    };
+    let highlight = match selected {
+        Some(_) => Style::new().add_modifier(Modifier::REVERSED),
+        None => Style::new(),
+    };
+    let newest = items.len().checked_sub(1);
+    let list = List::new(items)
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .border_style(border)
+                .title(title),
+        )
+        .highlight_style(highlight);
+    // Selecting the newest while following keeps the list scrolled to it
+    let mut state = ListState::default().with_selected(selected.or(newest));
+    frame.render_stateful_widget(list, area, &mut state);
+}
+
+/// A log the server has not logged yet, greyed out, or with why the server
+/// refused it.
+fn pending_text(pending: &Pending) -> Text<'static> {
+    let style = Style::new().fg(Color::DarkGray);
+    let event = &pending.event;
+    let time = event.time.format("%Y-%m-%d %H:%M:%S");
+    let header = match &pending.failed {
+        Some(reason) => Line::from(vec![
+            Span::styled(format!("{time} "), style),
+            Span::styled(format!("failed ({reason})"), Style::new().fg(Color::Red)),
+            Span::styled(format!(" {}", event.machine), style),
+        ]),
+        None => Line::styled(format!("{time} pending {}", event.machine), style),
+    };
+    let mut lines = vec![header];
+    lines.extend(
+        event
+            .event
+            .lines()
+            .map(|line| Line::styled(line.to_string(), style)),
+    );
+
+    Text::from(lines)
+}
+
+/// A log as a header line, its text and what else it has, indented by how
+/// deeply it is nested in its thread.
+fn log_text(log: &EventDB, depth: usize) -> Text<'static> {
+    let indent = "  ".repeat(depth);
+    let mut header = vec![
+        Span::raw(indent.clone()),
+        Span::styled(
+            log.time.format("%Y-%m-%d %H:%M:%S").to_string(),
+            Style::new().fg(Color::Blue),
+        ),
+        Span::raw(" "),
+        severity_badge(log.severity),
+    ];
+    for tag in &log.tags {
+        header.push(Span::raw(" "));
+        header.push(Span::styled(
+            format!("#{tag}"),
+            Style::new().fg(Color::White).bg(Color::DarkGray),
+        ));
+    }
+    header.extend([
+        Span::raw(" "),
+        Span::styled(log.author.clone(), Style::new().fg(Color::Green)),
+        Span::styled("@", Style::new().fg(Color::DarkGray)),
+        Span::styled(log.machine.clone(), Style::new().fg(Color::LightGreen)),
+    ]);
+    if let Some(parent) = log.parent.filter(|_| depth == 0) {
+        header.push(Span::styled(
+            format!(" in reply to log {parent}"),
+            Style::new().fg(Color::DarkGray),
+        ));
+    }
+    if log.revision > 0 {
+        header.push(Span::styled(" (edited)", Style::new().fg(Color::DarkGray)));
+    }
+
+    let mut lines = vec![Line::from(header)];
+    for line in log.event.lines() {
+        lines.push(Line::from(vec![
+            Span::raw(indent.clone()),
+            Span::styled(line.to_string(), Style::new().fg(Color::Cyan)),
+        ]));
+    }
+    if !log.fields.is_empty() {
+        let fields = log
+            .fields
+            .iter()
+            .map(|(key, value)| format!("{key}={value}"))
+            .collect::<Vec<String>>()
+            .join(", ");
+        lines.push(Line::from(vec![
+            Span::raw(indent.clone()),
+            Span::styled(format!("{{{fields}}}"), Style::new().fg(Color::Gray)),
+        ]));
+    }
+    for attachment in &log.attachments {
+        lines.push(Line::from(vec![
+            Span::raw(indent.clone()),
+            Span::styled(
+                format!("[attached {}]", attachment.name),
+                Style::new().fg(Color::Magenta),
+            ),
+        ]));
+    }
+
+    Text::from(lines)
+}
+
+/// The severity in capitals on a background coloured by how serious it is.
+fn severity_badge(severity: Severity) -> Span<'static> {
+    let colour = match severity {
+        Severity::Info => Color::Blue,
+        Severity::Warn => Color::Yellow,
+        Severity::Error => Color::Red,
+        Severity::Critical => Color::Magenta,
+    };
+    Span::styled(
+        format!(" {} ", severity.to_string().to_uppercase()),
+        Style::new().fg(Color::Black).bg(colour),
+    )
+}
+
+fn composer(frame: &mut Frame, area: Rect, app: &App) {
+    let title = match app.composer.replying {
+        Some(parent) => format!(" Reply to log {parent} "),
+        None => " New log ".into(),
+    };
+    let block = Block::default().borders(Borders::ALL).title(title);
+    let inner = block.inner(area);
+    frame.render_widget(block, area);
+
+    let version = app
+        .client
+        .as_ref()
+        .map_or(u32::MAX, |client| client.server_version());
+    let unknown = |what: &str| {
+        Span::styled(
+            format!("the server does not know {what}"),
+            Style::new().fg(Color::DarkGray),
+        )
+    };
+    let composer = &app.composer;
+    let rows = rows(inner, 4);
+    text_box(frame, rows[0], app, Focus::Log, "Log", &composer.log);
+    if version >= 2 {
+        let severity = format!("< {} >", composer.severity.to_string().to_uppercase());
+        choice(
+            frame,
+            rows[1],
+            app,
+            Focus::Severity,
+            "Severity",
+            Span::raw(severity),
+        );
+        text_box(frame, rows[2], app, Focus::Tags, "Tags", &composer.tags);
+    } else {
+        choice(
+            frame,
+            rows[1],
+            app,
+            Focus::Severity,
+            "Severity",
+            unknown("severities"),
+        );
+        choice(frame, rows[2], app, Focus::Tags, "Tags", unknown("tags"));
+    }
+    if version >= 4 {
+        text_box(
+            frame,
+            rows[3],
+            app,
+            Focus::Fields,
+            "Fields",
+            &composer.fields,
+        );
+        if let Err(e) = parse_fields(&composer.fields.text) {
+            let width = LABEL_WIDTH + composer.fields.text.chars().count() as u16 + 2;
+            let error_area = Rect {
+                x: rows[3].x + width.min(rows[3].width),
+                width: rows[3].width.saturating_sub(width),
+                ..rows[3]
+            };
+            let error = Paragraph::new(Span::styled(e, Style::new().fg(Color::Red)));
+            frame.render_widget(error, error_area);
+        }
+    } else {
+        choice(
+            frame,
+            rows[3],
+            app,
+            Focus::Fields,
+            "Fields",
+            unknown("fields"),
+        );
+    }
+}
+
+fn help(frame: &mut Frame, area: Rect, app: &App) {
+    let line = match &app.error {
+        Some(e) => Span::styled(e.clone(), Style::new().fg(Color::Red)),
+        None => {
+            let keys = match app.focus {
+                Focus::Logs => {
+                    "↑↓ select  PgUp/PgDn page  End follow new logs  r reply  R resend failed  \
+                     X discard failed  q quit"
+                }
+                Focus::Tls => "Space toggle  Enter connect  Ctrl-C quit",
+                Focus::Severity => "←→ change  Enter send  Esc cancel reply  Ctrl-C quit",
+                focus if focus.in_form() => "Enter connect  Ctrl-C quit",
+                _ => "Enter send  Esc cancel reply  Ctrl-C quit",
+            };
+            Span::styled(
+                format!("Tab next  Shift-Tab previous  {keys}"),
+                Style::new().fg(Color::DarkGray),
+            )
+        }
+    };
+    frame.render_widget(Paragraph::new(line), area);
+}
+
+/// Splits the area into rows of one line.
+fn rows(area: Rect, count: usize) -> Vec<Rect> {
+    Layout::default()
+        .direction(Direction::Vertical)
+        .constraints(vec![Constraint::Length(1); count])
+        .split(area)
+        .to_vec()
+}
+
+fn label(app: &App, focus: Focus, label: &str) -> Span<'static> {
+    let style = match app.focus == focus {
+        true => Style::new().fg(Color::Yellow).add_modifier(Modifier::BOLD),
+        false => Style::new().fg(Color::Gray),
+    };
+    Span::styled(
+        format!("{label:<width$}", width = LABEL_WIDTH as usize),
+        style,
+    )
+}
+
+/// A labelled text box, with the terminal's cursor in it when it is in focus.
+fn text_box(frame: &mut Frame, area: Rect, app: &App, focus: Focus, name: &str, input: &Input) {
+    let line = Line::from(vec![label(app, focus, name), Span::raw(input.text.clone())]);
+    frame.render_widget(Paragraph::new(line), area);
+    if app.focus == focus {
+        let x = area.x + LABEL_WIDTH + Span::raw(input.before_cursor()).width() as u16;
+        frame.set_cursor(x.min(area.right().saturating_sub(1)), area.y);
+    }
+}
+
+/// A labelled value changed with keys other than typing.
+fn choice(frame: &mut Frame, area: Rect, app: &App, focus: Focus, name: &str, value: Span) {
+    let line = Line::from(vec![label(app, focus, name), value]);
+    frame.render_widget(Paragraph::new(line), area);
+}
+
+#[cfg(test)]
+mod ui_tests {
+    use std::collections::BTreeMap;
+
+    use bucface_utils::{Event, FieldValue};
+    use ratatui::backend::TestBackend;
+    use ratatui::Terminal;
+
+    use bucface_client_lib::outbox::Outbox;
+    use bucface_utils::DEFAULT_CHANNEL;
+
+    use super::*;
+    use crate::app::ConnectForm;
+
+    fn drawn(app: &App) -> Vec<String> {
+        let mut terminal = Terminal::new(TestBackend::new(90, 30)).unwrap();
+        terminal.draw(|frame| draw(frame, app)).unwrap();
+        let buffer = terminal.backend().buffer();
+        (0..buffer.area.height)
+            .map(|y| {
+                (0..buffer.area.width)
+                    .map(|x| buffer.get(x, y).symbol())
+                    .collect::<String>()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_draw() {
+        let form = ConnectForm {
+            server: Input::new("localhost"),
+            token: Input::new("secret"),
+            ..Default::default()
+        };
+        let mut app = App::new(form, "buc-07".into(), Outbox::memory());
+        let lines = drawn(&app);
+        assert!(lines[0].starts_with("BucFace TUI Disconnected"));
+        assert!(lines
+            .iter()
+            .any(|line| line.contains("Server    localhost")));
+        assert!(lines.iter().any(|line| line.contains("Token     ******")));
+        assert!(!lines.iter().any(|line| line.contains("secret")));
+
+        app.logs = vec![
+            EventDB {
+                author: "alice".into(),
+                machine: "buc-07".into(),
+                event: "Pump failed\nRestarting".into(),
+                tags: ["pump".to_string()].into(),
+                severity: Severity::Error,
+                fields: BTreeMap::from([("exit_code".into(), FieldValue::Int(2))]),
+                ..EventDB::from(Event::default(), 1)
+            },
+            EventDB {
+                event: "Restarted".into(),
+                parent: Some(1),
+                time: chrono::NaiveDate::from_ymd_opt(2024, 3, 1)
+                    .unwrap()
+                    .and_hms_opt(8, 0, 0)
+                    .unwrap(),
+                ..EventDB::from(Event::default(), 2)
+            },
+        ];
+        app.composer.replying = Some(2);
+        app.composer.fields = Input::new("exit_code");
+        let lines = drawn(&app);
+        let at = lines
+            .iter()
+            .position(|line| line.contains(" ERROR  #pump alice@buc-07"))
+            .unwrap();
+        assert!(lines[at + 1].contains("│Pump failed"));
+        assert!(lines[at + 2].contains("│Restarting"));
+        assert!(lines[at + 3].contains("│{exit_code=2}"));
+        // The reply is nested under the log it replies to
+        assert!(lines[at + 4].contains("│  2024-03-01 08:00:00 "));
+        assert!(lines[at + 5].contains("│  Restarted"));
+        assert!(lines.iter().any(|line| line.contains(" Reply to log 2 ")));
+        assert!(lines
+            .iter()
+            .any(|line| line.contains("exit_code  `exit_code` is not key=value")));
+
+        // Logs not logged yet come last, until they are
+        let time = chrono::NaiveDate::from_ymd_opt(2024, 3, 2)
+            .unwrap()
+            .and_hms_opt(9, 30, 0)
+            .unwrap();
+        let event = Event {
+            machine: "buc-07".into(),
+            event: "Written offline".into(),
+            time,
+            ..Event::default()
+        };
+        let pending = app.outbox.push(DEFAULT_CHANNEL, event).unwrap();
+        let lines = drawn(&app);
+        let at = lines
+            .iter()
+            .position(|line| line.contains("│2024-03-02 09:30:00 pending buc-07"))
+            .unwrap();
+        assert!(lines[at + 1].contains("│Written offline"));
+        assert!(lines.iter().any(|line| line.contains("- 1 pending ")));
+
+        app.outbox.fail(&pending.key, "Archived".into()).unwrap();
+        let lines = drawn(&app);
+        assert!(lines
+            .iter()
+            .any(|line| line.contains("│2024-03-02 09:30:00 failed (Archived) buc-07")));
+        assert!(lines.iter().any(|line| line.contains("- 1 failed ")));
+    }
+}
@@ -1,7 +1,5 @@
-#![feature(let_chains)]
-#![feature(async_closure)]
 mod app;
-mod net;
+mod attachment;
 mod ui;
 
 use app::App;
@@ -1,35 +1,239 @@
-use bucface_utils::EventDB;
-use egui::{Align, Layout, Rgba};
+use std::path::PathBuf;
+
+use bucface_client_lib::logs::{parse_conditions, parse_fields, threaded};
+use bucface_utils::{Attachment, Change, EventDB, Revision, Severity, Snippet};
+use egui::text::LayoutJob;
+use egui::{Align, Color32, FontId, Layout, Rgba, RichText, TextFormat};
 
 use crate::app::App;
-use crate::net::ws_client::WebSocketStatus;
+use crate::attachment::{human_size, AfterDownload, Preview};
+
+/// How far a reply is indented from the log it replies to.
+const REPLY_INDENT: f32 = 24.0;
 
 pub fn log_entry(ui: &mut egui::Ui, app: &mut App) {
     ui.vertical(|ui| {
         ui.label("Log");
+        if let Some(parent) = app.replying {
+            ui.horizontal(|ui| {
+                ui.label(format!("Replying to log {parent}"));
+                if ui.small_button("Cancel").clicked() {
+                    app.replying = None;
+                }
+            });
+        }
         ui.text_edit_multiline(&mut app.bufs.log);
-        if ui.button("Send Log").clicked() {
-            let _ = app.send_log();
+        ui.add_enabled_ui(app.server_version >= 2, |ui| {
+            ui.horizontal(|ui| {
+                ui.label("Severity");
+                severity_box(ui, "severity", &mut app.bufs.severity);
+                ui.label("Tags");
+                ui.text_edit_singleline(&mut app.bufs.tags);
+            })
+        })
+        .response
+        .on_disabled_hover_text("The server does not know tags and severities");
+        ui.add_enabled_ui(app.server_version >= 3, |ui| attachment_entry(ui, app))
+            .response
+            .on_disabled_hover_text("The server does not know attachments");
+        let fields = ui.add_enabled_ui(app.server_version >= 4, |ui| {
+            ui.horizontal(|ui| {
+                ui.label("Fields");
+                ui.text_edit_singleline(&mut app.bufs.fields)
+                    .on_hover_text("Comma separated key=value, like exit_code=1, ok=false");
+            });
+            let fields = parse_fields(&app.bufs.fields);
+            if let Err(e) = &fields {
+                ui.colored_label(Color32::RED, e);
+            }
+            fields
+        });
+        fields
+            .response
+            .on_disabled_hover_text("The server does not know fields");
+        let archived = app.channel_archived();
+        if ui
+            .add_enabled(
+                !archived && app.upload.is_none() && fields.inner.is_ok(),
+                egui::Button::new("Send Log"),
+            )
+            .on_disabled_hover_text(
+                "The channel is archived, a file is being uploaded or the fields are invalid",
+            )
+            .clicked()
+        {
+            if let Err(e) = app.send_log() {
+                log::error!("Error putting the log into the outbox: {e}");
+            }
         }
     });
 }
 
+/// Attaches files entered or dropped onto the window to the next log.
+fn attachment_entry(ui: &mut egui::Ui, app: &mut App) {
+    ui.horizontal(|ui| {
+        ui.label("Attach");
+        ui.text_edit_singleline(&mut app.bufs.attachment)
+            .on_hover_text("Path of a file, or drop files onto the window");
+        if ui.button("Attach").clicked() {
+            if let Err(e) = app.attach() {
+                log::warn!("Error attaching file: {:?}", e);
+            }
+        }
+    });
+    if app.server_version >= 3 {
+        let dropped = ui.ctx().input(|i| {
+            i.raw
+                .dropped_files
+                .iter()
+                .filter_map(|file| file.path.clone())
+                .collect::<Vec<PathBuf>>()
+        });
+        for path in dropped {
+            if let Err(e) = app.attach_file(path) {
+                log::warn!("Error attaching file: {:?}", e);
+            }
+        }
+    }
+
+    if let Some(upload) = &app.upload {
+        let progress = upload.received as f32 / upload.attachment.size.max(1) as f32;
+        ui.add(
+            egui::ProgressBar::new(progress).text(format!("Uploading {}", upload.attachment.name)),
+        );
+    }
+    if !app.queued_files.is_empty() {
+        ui.small(format!("{} more files queued", app.queued_files.len()));
+    }
+    let mut removed = None;
+    for (i, attachment) in app.attachments.iter().enumerate() {
+        ui.horizontal(|ui| {
+            ui.label(attachment_label(attachment));
+            if ui.small_button("Remove").clicked() {
+                removed = Some(i);
+            }
+        });
+    }
+    if let Some(i) = removed {
+        app.attachments.remove(i);
+    }
+
+    ui.horizontal(|ui| {
+        ui.label("Save attachments to");
+        ui.text_edit_singleline(&mut app.bufs.download_dir);
+    });
+    if let Some(download) = &app.download {
+        ui.horizontal(|ui| {
+            ui.spinner();
+            ui.label(format!("Downloading {}", download.attachment.name));
+        });
+    }
+    if let Some(status) = &app.attachment_status {
+        ui.small(status);
+    }
+}
+
+/// The fields of a log in a table, collapsed until opened.
+fn fields_table(ui: &mut egui::Ui, log: &EventDB) {
+    egui::CollapsingHeader::new(format!("{} fields", log.fields.len()))
+        .id_source(("fields", log._id))
+        .show(ui, |ui| {
+            egui::Grid::new(("fields_grid", log._id))
+                .striped(true)
+                .show(ui, |ui| {
+                    for (key, value) in &log.fields {
+                        ui.monospace(key);
+                        ui.label(value.to_string());
+                        ui.end_row();
+                    }
+                });
+        });
+}
+
+fn attachment_label(attachment: &Attachment) -> String {
+    format!("{} ({})", attachment.name, human_size(attachment.size))
+}
+
 pub fn log_panel(ui: &mut egui::Ui, app: &mut App) {
     ui.vertical(|ui| {
         // create vertical collumn of all logs from App::logs
         ui.label("Logs");
-        if ui.button("Refresh").clicked() {
-            app.clear_logs();
-            if let WebSocketStatus::Connected(ws_client) = &mut app.ws_client {
-                if let Err(e) = ws_client.get_logs_since(0) {
+        channel_panel(ui, app);
+        ui.horizontal(|ui| {
+            if ui.button("Refresh").clicked() {
+                if let Err(e) = app.refresh_logs() {
                     log::warn!("Error getting logs: {:?}", e);
                 }
             }
-        }
+            ui.label("Authors");
+            ui.text_edit_singleline(&mut app.bufs.filter_authors);
+            ui.label("Machines");
+            ui.text_edit_singleline(&mut app.bufs.filter_machines);
+            ui.label("Keywords");
+            ui.text_edit_singleline(&mut app.bufs.filter_keywords);
+            ui.label("Tags");
+            ui.text_edit_singleline(&mut app.bufs.filter_tags);
+            let least = match app.bufs.filter_severity {
+                Some(severity) => format!("{severity} and above"),
+                None => "Any severity".into(),
+            };
+            egui::ComboBox::from_id_source("filter_severity")
+                .selected_text(least)
+                .show_ui(ui, |ui| {
+                    ui.selectable_value(&mut app.bufs.filter_severity, None, "Any severity");
+                    for severity in Severity::ALL {
+                        ui.selectable_value(
+                            &mut app.bufs.filter_severity,
+                            Some(severity),
+                            format!("{severity} and above"),
+                        );
+                    }
+                });
+            let conditions = match app.server_version >= 4 {
+                true => {
+                    ui.label("Fields");
+                    ui.text_edit_singleline(&mut app.bufs.filter_fields)
+                        .on_hover_text("Comma separated conditions, like exit_code != 0");
+                    parse_conditions(&app.bufs.filter_fields)
+                }
+                false => Ok(Vec::new()),
+            };
+            if let Err(e) = &conditions {
+                ui.colored_label(Color32::RED, e.to_string());
+            }
+            if ui
+                .add_enabled(conditions.is_ok(), egui::Button::new("Filter"))
+                .clicked()
+            {
+                if let Err(e) = app.apply_filter() {
+                    log::warn!("Error filtering logs: {:?}", e);
+                }
+            }
+            let mut show_retracted = app.show_retracted;
+            if ui
+                .checkbox(&mut show_retracted, "Show retracted")
+                .on_hover_text("Include retracted logs, for auditing")
+                .changed()
+            {
+                if let Err(e) = app.set_show_retracted(show_retracted) {
+                    log::warn!("Error showing retracted logs: {:?}", e);
+                }
+            }
+            if app.loading_logs.is_some() {
+                ui.spinner();
+                ui.label("Loading");
+            }
+        });
+
+        search_panel(ui, app);
 
         let print_event = |ui: &mut egui::Ui, event: &EventDB| {
             ui.vertical(|ui| {
                 ui.horizontal_wrapped(|ui| {
+                    severity_badge(ui, event.severity);
+                    for tag in &event.tags {
+                        tag_badge(ui, tag);
+                    }
                     ui.colored_label(Rgba::from_rgb(0.0, 1.0, 0.5), &event.author);
                     ui.colored_label(Rgba::from_rgb(0.1, 0.1, 0.1), "@");
                     ui.colored_label(Rgba::from_rgb(0.5, 1.0, 0.5), &event.machine);
@@ -42,25 +246,516 @@ pub fn log_panel(ui: &mut egui::Ui, app: &mut App) {
             ui.colored_label(Rgba::from_rgb(1., 0., 0.), format!("Error: {:?}", error));
         }; */
 
-        for log in &app.logs {
-            let text = |ui: &mut egui::Ui| print_event(ui, log);
+        let archived = app.channel_archived();
+        let mut action = None;
+        egui::ScrollArea::vertical()
+            .stick_to_bottom(true)
+            .show(ui, |ui| {
+                if app.older_logs.is_some() {
+                    // Scrolling up to the oldest log loads the page before it
+                    let older = ui.button("Load older logs");
+                    if older.clicked() || ui.is_rect_visible(older.rect) {
+                        if let Err(e) = app.load_older_logs() {
+                            log::warn!("Error getting older logs: {:?}", e);
+                        }
+                    }
+                }
 
-            let time = |ui: &mut egui::Ui| {
-                ui.colored_label(Rgba::from_rgb(0.5, 0.7, 0.9), log.time.to_string())
-            };
+                for (depth, i) in threaded(&app.logs) {
+                    let log = &app.logs[i];
+                    ui.horizontal(|ui| {
+                        ui.add_space(depth as f32 * REPLY_INDENT);
+                        ui.vertical(|ui| {
+                            // Retracted logs are only shown for auditing, greyed out
+                            let text = |ui: &mut egui::Ui| {
+                                ui.add_enabled_ui(log.retraction.is_none(), |ui| {
+                                    print_event(ui, log)
+                                });
+                            };
 
-            ui.horizontal_wrapped(|ui| {
-                ui.with_layout(Layout::right_to_left(Align::Min), |ui| {
-                    time(ui);
-                    ui.with_layout(
-                        Layout::left_to_right(Align::Min).with_main_wrap(true),
-                        |ui| {
-                            text(ui);
-                        },
-                    );
-                });
+                            let time = |ui: &mut egui::Ui| {
+                                ui.colored_label(
+                                    Rgba::from_rgb(0.5, 0.7, 0.9),
+                                    log.time.to_string(),
+                                )
+                            };
+
+                            ui.horizontal_wrapped(|ui| {
+                                ui.with_layout(Layout::right_to_left(Align::Min), |ui| {
+                                    time(ui);
+                                    ui.with_layout(
+                                        Layout::left_to_right(Align::Min).with_main_wrap(true),
+                                        |ui| {
+                                            text(ui);
+                                        },
+                                    );
+                                });
+                            });
+
+                            if let Some(parent) = log.parent.filter(|_| depth == 0) {
+                                ui.small(format!("In reply to log {parent}"));
+                            }
+                            if !log.fields.is_empty() {
+                                fields_table(ui, log);
+                            }
+                            for attachment in &log.attachments {
+                                ui.horizontal(|ui| {
+                                    ui.label(attachment_label(attachment));
+                                    if ui.small_button("Preview").clicked() {
+                                        action = Some(LogAction::Download(
+                                            attachment.clone(),
+                                            AfterDownload::Preview,
+                                        ));
+                                    }
+                                    if ui.small_button("Save").clicked() {
+                                        let dir = PathBuf::from(&app.bufs.download_dir);
+                                        action = Some(LogAction::Download(
+                                            attachment.clone(),
+                                            AfterDownload::Save(dir),
+                                        ));
+                                    }
+                                });
+                            }
+                            if let Some(retraction) = &log.retraction {
+                                ui.colored_label(
+                                    Rgba::from_rgb(0.9, 0.6, 0.1),
+                                    format!(
+                                        "Retracted by {} at {}: {}",
+                                        retraction.author, retraction.time, retraction.reason
+                                    ),
+                                );
+                            } else if app.retracting == Some(log._id) {
+                                ui.horizontal(|ui| {
+                                    ui.label("Reason");
+                                    ui.text_edit_singleline(&mut app.bufs.reason);
+                                    if ui.button("Retract").clicked() {
+                                        action = Some(LogAction::Retract);
+                                    }
+                                    if ui.button("Cancel").clicked() {
+                                        action = Some(LogAction::CancelRetract);
+                                    }
+                                });
+                            } else if app.editing == Some(log._id) {
+                                ui.text_edit_multiline(&mut app.bufs.edit);
+                                ui.horizontal(|ui| {
+                                    if ui.button("Save").clicked() {
+                                        action = Some(LogAction::SaveEdit);
+                                    }
+                                    if ui.button("Cancel").clicked() {
+                                        action = Some(LogAction::CancelEdit);
+                                    }
+                                });
+                            } else {
+                                ui.horizontal(|ui| {
+                                    if log.revision > 0
+                                        && ui
+                                            .small_button("edited")
+                                            .on_hover_text("Show revisions")
+                                            .clicked()
+                                    {
+                                        action = Some(LogAction::ShowRevisions(log._id));
+                                    }
+                                    let own = log.author == app.state.author;
+                                    if own && !archived && ui.small_button("Edit").clicked() {
+                                        action = Some(LogAction::Edit(log._id));
+                                    }
+                                    if !archived && ui.small_button("Reply").clicked() {
+                                        action = Some(LogAction::Reply(log._id));
+                                    }
+                                    if log.parent.is_some() && ui.small_button("Thread").clicked() {
+                                        action = Some(LogAction::ShowThread(log._id));
+                                    }
+                                    if !archived
+                                        && ui
+                                            .small_button("Retract")
+                                            .on_hover_text(
+                                                "Only the author or an admin may retract",
+                                            )
+                                            .clicked()
+                                    {
+                                        action = Some(LogAction::StartRetract(log._id));
+                                    }
+                                });
+                            }
+                            ui.separator();
+                        });
+                    });
+                }
+
+                // Logs the server has not logged yet, greyed out until it does
+                for pending in app.outbox.pending_in(&app.channel) {
+                    let log = EventDB::from(pending.event.clone(), 0);
+                    ui.horizontal_wrapped(|ui| {
+                        ui.with_layout(Layout::right_to_left(Align::Min), |ui| {
+                            match &pending.failed {
+                                Some(reason) => {
+                                    if ui.small_button("Discard").clicked() {
+                                        action = Some(LogAction::Discard(pending.key.clone()));
+                                    }
+                                    if ui.small_button("Resend").clicked() {
+                                        action = Some(LogAction::Resend(pending.key.clone()));
+                                    }
+                                    ui.colored_label(
+                                        Color32::RED,
+                                        format!("{} (failed)", log.time),
+                                    )
+                                    .on_hover_text(format!("The server refused it: {reason}"));
+                                }
+                                None => {
+                                    ui.weak(format!("{} (pending)", log.time))
+                                        .on_hover_text("Sent once we are connected to the server");
+                                }
+                            }
+                            ui.with_layout(
+                                Layout::left_to_right(Align::Min).with_main_wrap(true),
+                                |ui| {
+                                    ui.add_enabled_ui(false, |ui| print_event(ui, &log));
+                                },
+                            );
+                        });
+                    });
+                    ui.separator();
+                }
             });
-            ui.separator();
+
+        match action {
+            Some(LogAction::Edit(id)) => app.start_edit(id),
+            Some(LogAction::SaveEdit) => {
+                if let Err(e) = app.save_edit() {
+                    log::warn!("Error editing log: {:?}", e);
+                }
+            }
+            Some(LogAction::CancelEdit) => app.cancel_edit(),
+            Some(LogAction::StartRetract(id)) => app.start_retract(id),
+            Some(LogAction::Retract) => {
+                if let Err(e) = app.retract() {
+                    log::warn!("Error retracting log: {:?}", e);
+                }
+            }
+            Some(LogAction::CancelRetract) => app.cancel_retract(),
+            Some(LogAction::ShowRevisions(id)) => {
+                if let Err(e) = app.show_revisions(id) {
+                    log::warn!("Error getting revisions: {:?}", e);
+                }
+            }
+            Some(LogAction::Reply(id)) => app.replying = Some(id),
+            Some(LogAction::ShowThread(id)) => {
+                if let Err(e) = app.show_thread(id) {
+                    log::warn!("Error getting thread: {:?}", e);
+                }
+            }
+            Some(LogAction::Download(attachment, then)) => {
+                if let Err(e) = app.download(attachment, then) {
+                    log::warn!("Error downloading attachment: {:?}", e);
+                }
+            }
+            Some(LogAction::Resend(key)) => match app.outbox.resend(&key) {
+                Ok(()) => {
+                    if let Err(e) = app.flush_outbox() {
+                        log::warn!("Error sending pending logs: {:?}", e);
+                    }
+                }
+                Err(e) => log::error!("Error resending log: {e}"),
+            },
+            Some(LogAction::Discard(key)) => {
+                if let Err(e) = app.outbox.remove(&key) {
+                    log::error!("Error discarding log: {e}");
+                }
+            }
+            None => {}
         }
+
+        revision_window(ui, app);
+        thread_window(ui, app);
+        preview_window(ui, app);
     });
 }
+
+fn severity_box(ui: &mut egui::Ui, id: &str, severity: &mut Severity) {
+    egui::ComboBox::from_id_source(id)
+        .selected_text(severity.to_string())
+        .show_ui(ui, |ui| {
+            for choice in Severity::ALL {
+                ui.selectable_value(severity, choice, choice.to_string());
+            }
+        });
+}
+
+/// A badge coloured by how serious the severity is.
+fn severity_badge(ui: &mut egui::Ui, severity: Severity) {
+    let colour = match severity {
+        Severity::Info => Color32::from_rgb(70, 130, 220),
+        Severity::Warn => Color32::from_rgb(240, 190, 40),
+        Severity::Error => Color32::from_rgb(220, 60, 60),
+        Severity::Critical => Color32::from_rgb(150, 30, 160),
+    };
+    let text = format!(" {} ", severity.to_string().to_uppercase());
+    ui.label(
+        RichText::new(text)
+            .small()
+            .strong()
+            .color(Color32::BLACK)
+            .background_color(colour),
+    );
+}
+
+fn tag_badge(ui: &mut egui::Ui, tag: &str) {
+    ui.label(
+        RichText::new(format!(" {tag} "))
+            .small()
+            .color(Color32::WHITE)
+            .background_color(Color32::from_gray(90)),
+    );
+}
+
+/// What was clicked on a log, done once the logs are drawn.
+enum LogAction {
+    Edit(u64),
+    SaveEdit,
+    CancelEdit,
+    StartRetract(u64),
+    Retract,
+    CancelRetract,
+    ShowRevisions(u64),
+    Reply(u64),
+    ShowThread(u64),
+    Download(Attachment, AfterDownload),
+    /// Send the failed pending log with the key again
+    Resend(String),
+    /// Drop the failed pending log with the key
+    Discard(String),
+}
+
+/// Shows a downloaded attachment: images as they are, text in monospace.
+fn preview_window(ui: &mut egui::Ui, app: &mut App) {
+    let Some((attachment, preview)) = &mut app.preview else {
+        return;
+    };
+
+    let mut open = true;
+    egui::Window::new(attachment_label(attachment))
+        .open(&mut open)
+        .show(ui.ctx(), |ui| {
+            egui::ScrollArea::both().show(ui, |ui| match preview {
+                Preview::Image { image, texture } => {
+                    let texture = texture.get_or_insert_with(|| {
+                        ui.ctx()
+                            .load_texture(&attachment.digest, image.clone(), Default::default())
+                    });
+                    ui.add(egui::Image::new(&*texture).shrink_to_fit());
+                }
+                Preview::Text(text) => {
+                    ui.monospace(text.as_str());
+                }
+                Preview::Binary => {
+                    ui.label("There is no preview of this kind of file, save it instead");
+                }
+            });
+        });
+    if !open {
+        app.preview = None;
+    }
+}
+
+/// Shows a whole thread, with the replies nested under the logs they reply
+/// to, including those older than the logs loaded.
+fn thread_window(ui: &mut egui::Ui, app: &mut App) {
+    let Some(thread) = &app.thread else {
+        return;
+    };
+
+    let mut open = true;
+    egui::Window::new("Thread")
+        .open(&mut open)
+        .show(ui.ctx(), |ui| {
+            egui::ScrollArea::vertical().show(ui, |ui| {
+                for (depth, i) in threaded(thread) {
+                    let log = &thread[i];
+                    ui.horizontal_wrapped(|ui| {
+                        ui.add_space(depth as f32 * REPLY_INDENT);
+                        ui.colored_label(Rgba::from_rgb(0.5, 0.7, 0.9), log.time.to_string());
+                        ui.colored_label(
+                            Rgba::from_rgb(0.0, 1.0, 0.5),
+                            format!("{}@{}", log.author, log.machine),
+                        );
+                        ui.colored_label(Rgba::from_rgb(0.1, 0.7, 0.9), &log.event);
+                    });
+                }
+            });
+        });
+    if !open {
+        app.thread = None;
+    }
+}
+
+/// Shows the revisions of a log, each with what changed from the one before.
+fn revision_window(ui: &mut egui::Ui, app: &mut App) {
+    let Some((id, revisions)) = &app.revisions else {
+        return;
+    };
+
+    let mut open = true;
+    egui::Window::new(format!("Revisions of log {id}"))
+        .open(&mut open)
+        .show(ui.ctx(), |ui| {
+            egui::ScrollArea::vertical().show(ui, |ui| {
+                for revision in revisions {
+                    ui.horizontal_wrapped(|ui| {
+                        ui.strong(format!("Revision {}", revision.revision));
+                        ui.colored_label(Rgba::from_rgb(0.0, 1.0, 0.5), &revision.author);
+                        ui.colored_label(Rgba::from_rgb(0.5, 0.7, 0.9), revision.time.to_string());
+                    });
+                    ui.label(diff_job(revision));
+                    ui.separator();
+                }
+            });
+        });
+    if !open {
+        app.revisions = None;
+    }
+}
+
+/// Lays out the diff of a [Revision], with insertions in green and deletions
+/// struck through in red.
+fn diff_job(revision: &Revision) -> LayoutJob {
+    let plain = TextFormat {
+        font_id: FontId::default(),
+        color: Rgba::from_rgb(0.1, 0.7, 0.9).into(),
+        ..Default::default()
+    };
+    let inserted = TextFormat {
+        color: Color32::from_rgb(40, 200, 80),
+        ..plain.clone()
+    };
+    let deleted = TextFormat {
+        color: Color32::from_rgb(220, 60, 60),
+        strikethrough: egui::Stroke::new(1.0, Color32::from_rgb(220, 60, 60)),
+        ..plain.clone()
+    };
+
+    let mut job = LayoutJob::default();
+    for change in &revision.diff {
+        match change {
+            Change::Equal(text) => job.append(text, 0.0, plain.clone()),
+            Change::Insert(text) => job.append(text, 0.0, inserted.clone()),
+            Change::Delete(text) => job.append(text, 0.0, deleted.clone()),
+        }
+    }
+
+    job
+}
+
+fn channel_panel(ui: &mut egui::Ui, app: &mut App) {
+    ui.horizontal(|ui| {
+        ui.label("Channel");
+        let mut selected = None;
+        egui::ComboBox::from_id_source("channel")
+            .selected_text(&app.channel)
+            .show_ui(ui, |ui| {
+                for channel in &app.channels {
+                    let name = match channel.archived {
+                        Some(_) => format!("{} (archived)", channel.name),
+                        None => channel.name.clone(),
+                    };
+                    if ui
+                        .selectable_label(channel.name == app.channel, name)
+                        .clicked()
+                    {
+                        selected = Some(channel.name.clone());
+                    }
+                }
+            });
+        if let Some(name) = selected.filter(|name| *name != app.channel) {
+            if let Err(e) = app.switch_channel(name) {
+                log::warn!("Error switching channel: {:?}", e);
+            }
+        }
+
+        let archived = app.channel_archived();
+        if archived {
+            ui.colored_label(Rgba::from_rgb(0.9, 0.6, 0.1), "Archived");
+        } else if ui.button("Archive").clicked() {
+            if let Err(e) = app.archive_channel() {
+                log::warn!("Error archiving channel: {:?}", e);
+            }
+        }
+
+        ui.text_edit_singleline(&mut app.bufs.new_channel);
+        if ui.button("New channel").clicked() {
+            if let Err(e) = app.create_channel() {
+                log::warn!("Error creating channel: {:?}", e);
+            }
+        }
+    });
+}
+
+fn search_panel(ui: &mut egui::Ui, app: &mut App) {
+    ui.horizontal(|ui| {
+        ui.label("Search");
+        let terms = ui.text_edit_singleline(&mut app.bufs.search);
+        let entered = terms.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+        if ui.button("Search").clicked() || entered {
+            if let Err(e) = app.search() {
+                log::warn!("Error searching logs: {:?}", e);
+            }
+        }
+        if app.searching.is_some() {
+            ui.spinner();
+        }
+        if !app.search_hits.is_empty() && ui.button("Clear").clicked() {
+            app.bufs.search.clear();
+            app.search_hits.clear();
+        }
+    });
+
+    if app.search_hits.is_empty() {
+        return;
+    }
+    ui.label(format!("{} hits", app.search_hits.len()));
+    egui::ScrollArea::vertical()
+        .id_source("search_hits")
+        .max_height(200.0)
+        .show(ui, |ui| {
+            for hit in &app.search_hits {
+                ui.horizontal_wrapped(|ui| {
+                    ui.colored_label(Rgba::from_rgb(0.5, 0.7, 0.9), hit.event.time.to_string());
+                    ui.colored_label(
+                        Rgba::from_rgb(0.0, 1.0, 0.5),
+                        format!("{}@{}", hit.event.author, hit.event.machine),
+                    );
+                });
+                ui.label(highlighted(&hit.snippet));
+                ui.separator();
+            }
+        });
+    ui.separator();
+}
+
+/// Lays out a [Snippet] with its highlights in bold colour.
+fn highlighted(snippet: &Snippet) -> LayoutJob {
+    let plain = TextFormat {
+        font_id: FontId::default(),
+        color: Rgba::from_rgb(0.1, 0.7, 0.9).into(),
+        ..Default::default()
+    };
+    let highlight = TextFormat {
+        color: Color32::BLACK,
+        background: Color32::from_rgb(255, 210, 0),
+        ..plain.clone()
+    };
+
+    let mut job = LayoutJob::default();
+    let mut end = 0;
+    for range in &snippet.highlights {
+        // The server sends sorted ranges, but do not trust them to be
+        if range.start < end || snippet.text.get(range.clone()).is_none() {
+            continue;
+        }
+        job.append(&snippet.text[end..range.start], 0.0, plain.clone());
+        job.append(&snippet.text[range.clone()], 0.0, highlight.clone());
+        end = range.end;
+    }
+    job.append(&snippet.text[end..], 0.0, plain);
+
+    job
+}
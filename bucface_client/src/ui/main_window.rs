@@ -1,3 +1,5 @@
+use bucface_client_lib::net::ws_client::WebSocketStatus;
+
 use crate::app::App;
 
 use super::log_ui::{log_entry, log_panel};
@@ -23,10 +25,25 @@ fn server_entry(ui: &mut egui::Ui, ctx: &egui::Context, app: &mut App<'_>) {
         ui.text_edit_singleline(&mut app.bufs.server);
         ui.label("Port");
         ui.text_edit_singleline(&mut app.bufs.port);
+        ui.checkbox(&mut app.bufs.tls, "TLS");
+        if app.bufs.tls {
+            ui.label("CA");
+            ui.text_edit_singleline(&mut app.bufs.ca_cert);
+            ui.label("Pin");
+            ui.text_edit_singleline(&mut app.bufs.pin);
+        }
+        ui.label("Token");
+        ui.add(egui::TextEdit::singleline(&mut app.bufs.token).password(true));
         if ui.button("Connect").clicked() {
             app.set_endpoint(ctx);
         }
         ui.label("Status");
         ui.label(app.ws_client.to_string());
+        if app.connecting.is_some() {
+            ui.spinner();
+        }
+        if let WebSocketStatus::Connected(_) = app.ws_client {
+            ui.label(format!("as {}", app.state.author));
+        }
     });
 }
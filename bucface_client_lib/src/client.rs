@@ -0,0 +1,524 @@
+use std::fmt;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use bucface_utils::{
+    ClientMessage, Event, EventDB, EventDBErrorSerde, EventFilter, PageQuery, ServerMessage,
+    ServerResponse, PROTOCOL_VERSION,
+};
+use futures_util::stream::{self, BoxStream};
+use futures_util::{Stream, StreamExt};
+use tokio::sync::broadcast;
+
+use crate::net::tls::TlsOptions;
+use crate::net::ws_client::{WebSocketError, WsClient};
+
+/// How many pushed events a [Subscription] may fall behind by before it
+/// misses some.
+const SUBSCRIPTION_BACKLOG: usize = 1024;
+
+#[derive(Debug)]
+pub enum ClientError {
+    Connection(WebSocketError),
+    Server(EventDBErrorSerde),
+    /// The server replied with something other than what was asked for
+    Unexpected(Box<ServerResponse>),
+    /// The connection closed before the server replied
+    Closed,
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Connection(e) => write!(f, "connection failed: {e:?}"),
+            Self::Server(e) => write!(f, "server error: {e:?}"),
+            Self::Unexpected(response) => write!(f, "unexpected reply: {response:?}"),
+            Self::Closed => write!(f, "the server closed the connection"),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<WebSocketError> for ClientError {
+    fn from(e: WebSocketError) -> Self {
+        match e {
+            WebSocketError::ServerError(e) => Self::Server(e),
+            e => Self::Connection(e),
+        }
+    }
+}
+
+impl From<ServerResponse> for ClientError {
+    fn from(response: ServerResponse) -> Self {
+        match response {
+            ServerResponse::Error(e) => Self::Server(e),
+            other => Self::Unexpected(Box::new(other)),
+        }
+    }
+}
+
+/// How [Client::connect] sets up the connection.
+#[derive(Debug, Clone, Default)]
+pub struct ConnectOptions {
+    pub tls: TlsOptions,
+    /// Token to authenticate with, if the server has users
+    pub token: Option<String>,
+    /// Channel to join instead of the [DEFAULT_CHANNEL](bucface_utils::DEFAULT_CHANNEL)
+    pub channel: Option<String>,
+    /// Whether retracted events are included, which only admins may ask for
+    pub show_retracted: bool,
+}
+
+/// A connection to a Bucface server for programs without a GUI. Every method
+/// takes `&self`, so requests and [subscriptions](Client::subscribe) can run
+/// concurrently.
+#[derive(Debug)]
+pub struct Client {
+    ws: Arc<WsClient>,
+    /// The events the server pushes, fanned out to every [Subscription]. Only
+    /// the task reading them holds the sender, so the subscriptions end once
+    /// the connection does.
+    pushed: broadcast::Receiver<EventDB>,
+    author: String,
+    server_version: u32,
+}
+
+impl Client {
+    /// Connects to a `ws://` or `wss://` url, authenticating and joining a
+    /// channel as the options say.
+    pub async fn connect(url: &str, options: &ConnectOptions) -> Result<Self, ClientError> {
+        let mut ws = WsClient::new(url, &options.tls, || ()).await?;
+
+        // Nothing but pushed events reaches the messages, as every request is
+        // answered through its replies
+        let mut messages = ws.take_messages();
+        let (push, pushed) = broadcast::channel(SUBSCRIPTION_BACKLOG);
+        tokio::spawn(async move {
+            while let Some(message) = messages.recv().await {
+                match message {
+                    ServerMessage::Broadcast(ServerResponse::Event(event)) => {
+                        // Only fails without the client and subscriptions
+                        let _ = push.send(*event);
+                    }
+                    other => log::debug!("Ignoring message {other:?}"),
+                }
+            }
+        });
+
+        // Servers before versioning answer with an error
+        let server_version = match ws.request(ClientMessage::Hello(PROTOCOL_VERSION)).await? {
+            ServerResponse::Hello(version) => version,
+            _ => 1,
+        };
+        log::debug!("Server speaks protocol version {server_version}");
+
+        let mut author = String::from("Anonymous");
+        if let Some(token) = &options.token {
+            match ws
+                .request(ClientMessage::Authenticate(token.clone()))
+                .await?
+            {
+                ServerResponse::Authenticated(name) => author = name,
+                other => return Err(other.into()),
+            }
+        }
+        if let Some(channel) = &options.channel {
+            match ws
+                .request(ClientMessage::JoinChannel(channel.clone()))
+                .await?
+            {
+                ServerResponse::Channel(_) => {}
+                other => return Err(other.into()),
+            }
+        }
+        if options.show_retracted {
+            match ws.request(ClientMessage::ShowRetracted(true)).await? {
+                ServerResponse::ShowRetracted(_) => {}
+                other => return Err(other.into()),
+            }
+        }
+
+        Ok(Self {
+            ws: Arc::new(ws),
+            pushed,
+            author,
+            server_version,
+        })
+    }
+
+    /// The user the server stamps onto our events.
+    pub fn author(&self) -> &str {
+        &self.author
+    }
+
+    /// The [PROTOCOL_VERSION] of the server.
+    pub fn server_version(&self) -> u32 {
+        self.server_version
+    }
+
+    /// Whether the connection closed, after which every request fails.
+    pub fn is_closed(&self) -> bool {
+        self.ws.is_closed()
+    }
+
+    /// Logs an event, returning it as the server stored it.
+    pub async fn post(&self, event: Event) -> Result<EventDB, ClientError> {
+        self.event(ClientMessage::NewEvent(event)).await
+    }
+
+    /// Logs an event to the channel unless we already logged it there with
+    /// the idempotency key, returning it as the server stored it. Servers
+    /// before version 5 log it again every time, and to the joined channel.
+    pub async fn post_once(
+        &self,
+        channel: String,
+        key: String,
+        event: Event,
+    ) -> Result<EventDB, ClientError> {
+        match self.server_version >= 5 {
+            true => {
+                let message = ClientMessage::NewEventOnce {
+                    channel,
+                    key,
+                    event,
+                };
+                self.event(message).await
+            }
+            false => self.post(event).await,
+        }
+    }
+
+    /// The event with the id.
+    pub async fn get(&self, id: u64) -> Result<EventDB, ClientError> {
+        self.event(ClientMessage::GetEvent(id)).await
+    }
+
+    async fn event(&self, message: ClientMessage) -> Result<EventDB, ClientError> {
+        match self.ws.request(message).await? {
+            ServerResponse::Event(event) => Ok(*event),
+            other => Err(other.into()),
+        }
+    }
+
+    /// Every event matching the filter, oldest first, or the `limit` oldest.
+    pub async fn query(
+        &self,
+        filter: EventFilter,
+        limit: Option<u32>,
+    ) -> Result<Vec<EventDB>, ClientError> {
+        let mut replies = self
+            .ws
+            .request_replies(ClientMessage::Query { filter, limit })?;
+        let mut matching = Vec::new();
+        loop {
+            match replies.next().await.ok_or(ClientError::Closed)? {
+                ServerResponse::Events { events, next } => {
+                    matching.extend(events);
+                    if next.is_none() {
+                        return Ok(matching);
+                    }
+                }
+                other => return Err(other.into()),
+            }
+        }
+    }
+
+    /// A page of events, with the id the following page starts at if there
+    /// is one.
+    pub async fn page(&self, query: PageQuery) -> Result<(Vec<EventDB>, Option<u64>), ClientError> {
+        match self.ws.request(ClientMessage::GetPage(query)).await? {
+            ServerResponse::Events { events, next } => Ok((events, next)),
+            other => Err(other.into()),
+        }
+    }
+
+    /// The new events matching the filter as they are logged, until the
+    /// [Subscription] is dropped. Edits and retractions of events are pushed
+    /// again under the same id.
+    pub async fn subscribe(&self, filter: EventFilter) -> Result<Subscription, ClientError> {
+        // Listening before subscribing, so nothing pushed after is missed
+        let pushed = self.pushed.resubscribe();
+        let id = match self
+            .ws
+            .request(ClientMessage::Subscribe(filter.clone()))
+            .await?
+        {
+            ServerResponse::Subscribed(id) => id,
+            other => return Err(other.into()),
+        };
+
+        // The server pushes what any of our subscriptions wants
+        let events = stream::unfold(pushed, move |mut pushed| {
+            let filter = filter.clone();
+            async move {
+                loop {
+                    match pushed.recv().await {
+                        Ok(event) if filter.matches(&event) => return Some((event, pushed)),
+                        Ok(_) => {}
+                        Err(broadcast::error::RecvError::Lagged(missed)) => {
+                            log::warn!("Subscription {id} fell behind, missing {missed} events");
+                        }
+                        Err(broadcast::error::RecvError::Closed) => return None,
+                    }
+                }
+            }
+        });
+
+        Ok(Subscription {
+            id,
+            ws: self.ws.clone(),
+            events: events.boxed(),
+        })
+    }
+}
+
+/// A [Stream] of the events pushed to a [subscription](Client::subscribe),
+/// which ends when the connection does.
+pub struct Subscription {
+    pub id: u64,
+    ws: Arc<WsClient>,
+    events: BoxStream<'static, EventDB>,
+}
+
+impl fmt::Debug for Subscription {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Subscription")
+            .field("id", &self.id)
+            .finish()
+    }
+}
+
+impl Stream for Subscription {
+    type Item = EventDB;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<EventDB>> {
+        self.events.poll_next_unpin(cx)
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        if let Err(e) = self.ws.send(ClientMessage::Unsubscribe(self.id)) {
+            log::debug!("Error unsubscribing {}: {e:?}", self.id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod client_tests {
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    use bucface_utils::{ClientRequest, Severity, DEFAULT_CHANNEL};
+    use futures_util::SinkExt;
+    use tokio::net::TcpListener;
+    use tokio::task::JoinHandle;
+    use tokio_tungstenite::tungstenite;
+
+    use super::*;
+    use crate::net::ws_client::ConnectionError;
+
+    /// Starts a websocket server keeping the events posted to it, once per
+    /// idempotency key, which answers queries in batches of two and pushes an
+    /// info and an error event of another client after every subscription,
+    /// returning its port and the task serving the connection.
+    async fn start_server() -> (u16, JoinHandle<()>) {
+        let socket = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = socket.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (stream, _) = socket.accept().await.unwrap();
+            let mut stream = tokio_tungstenite::accept_async(stream).await.unwrap();
+            let mut events = Vec::<EventDB>::new();
+            let mut keys = HashMap::new();
+            while let Some(Ok(message)) = stream.next().await {
+                let tungstenite::Message::Binary(data) = message else {
+                    continue;
+                };
+                let request = rmp_serde::from_slice::<ClientRequest>(&data).unwrap();
+                let mut responses = Vec::new();
+                let mut pushed = Vec::new();
+                match request.message {
+                    ClientMessage::Hello(_) => responses.push(ServerResponse::Hello(5)),
+                    ClientMessage::Authenticate(token) => {
+                        responses.push(ServerResponse::Authenticated(token.replace("-token", "")))
+                    }
+                    ClientMessage::NewEvent(event) => {
+                        let event = EventDB::from(event, events.len() as u64);
+                        events.push(event.clone());
+                        responses.push(ServerResponse::Event(Box::new(event)));
+                    }
+                    ClientMessage::NewEventOnce { key, event, .. } => {
+                        let id = *keys.entry(key).or_insert_with(|| {
+                            events.push(EventDB::from(event, events.len() as u64));
+                            events.len() - 1
+                        });
+                        responses.push(ServerResponse::Event(Box::new(events[id].clone())));
+                    }
+                    ClientMessage::GetEvent(id) => responses.push(match events.get(id as usize) {
+                        Some(event) => ServerResponse::Event(Box::new(event.clone())),
+                        None => ServerResponse::Error(EventDBErrorSerde::NotFound),
+                    }),
+                    ClientMessage::Query { filter, .. } => {
+                        let matching = events
+                            .iter()
+                            .filter(|event| filter.matches(event))
+                            .cloned()
+                            .collect::<Vec<EventDB>>();
+                        let batches = matching.chunks(2).collect::<Vec<_>>();
+                        for (i, batch) in batches.iter().enumerate() {
+                            responses.push(ServerResponse::Events {
+                                events: batch.to_vec(),
+                                next: batches.get(i + 1).map(|next| next[0]._id),
+                            });
+                        }
+                    }
+                    ClientMessage::Subscribe(_) => {
+                        responses.push(ServerResponse::Subscribed(request.id));
+                        for (text, severity) in
+                            [("fine", Severity::Info), ("failed", Severity::Error)]
+                        {
+                            let event = EventDB::from(event(text, severity), events.len() as u64);
+                            events.push(event.clone());
+                            pushed.push(event);
+                        }
+                    }
+                    ClientMessage::Unsubscribe(id) => {
+                        responses.push(ServerResponse::Unsubscribed(id));
+                    }
+                    other => panic!("Unexpected request {other:?}"),
+                }
+
+                let mut messages = responses
+                    .into_iter()
+                    .map(|response| ServerMessage::Reply {
+                        id: request.id,
+                        response,
+                    })
+                    .collect::<Vec<ServerMessage>>();
+                messages.extend(
+                    pushed.into_iter().map(|event| {
+                        ServerMessage::Broadcast(ServerResponse::Event(Box::new(event)))
+                    }),
+                );
+                for message in messages {
+                    let data = rmp_serde::to_vec(&message).unwrap();
+                    stream
+                        .send(tungstenite::Message::Binary(data))
+                        .await
+                        .unwrap();
+                }
+            }
+        });
+
+        (port, server)
+    }
+
+    fn event(text: &str, severity: Severity) -> Event {
+        Event {
+            event: text.into(),
+            severity,
+            ..Event::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_client() {
+        let _ = env_logger::try_init();
+        let (port, _server) = start_server().await;
+        let options = ConnectOptions {
+            token: Some("alice-token".into()),
+            ..Default::default()
+        };
+        let client = Client::connect(&format!("ws://localhost:{port}"), &options)
+            .await
+            .expect("Failed to connect");
+        assert_eq!(client.author(), "alice");
+        assert_eq!(client.server_version(), 5);
+
+        for (i, text) in ["one", "two", "three"].into_iter().enumerate() {
+            let posted = client.post(event(text, Severity::Warn)).await.unwrap();
+            assert_eq!(posted._id, i as u64);
+        }
+        assert_eq!(client.get(1).await.unwrap().event, "two");
+        assert!(matches!(
+            client.get(5).await,
+            Err(ClientError::Server(EventDBErrorSerde::NotFound))
+        ));
+
+        // The batches are joined
+        let all = client.query(EventFilter::default(), None).await.unwrap();
+        assert_eq!(
+            all.iter().map(|event| event._id).collect::<Vec<u64>>(),
+            vec![0, 1, 2]
+        );
+
+        let filter = EventFilter {
+            severity: Some(Severity::Error),
+            ..Default::default()
+        };
+        let mut serious = client.subscribe(filter).await.unwrap();
+        // Only what the subscription's filter matches reaches it
+        let failed = serious.next().await.unwrap();
+        assert_eq!((failed._id, failed.event.as_str()), (4, "failed"));
+
+        let mut everything = client.subscribe(EventFilter::default()).await.unwrap();
+        assert_ne!(serious.id, everything.id);
+        assert_eq!(serious.next().await.unwrap()._id, 6);
+        // Events pushed before subscribing do not
+        let ids = [
+            everything.next().await.unwrap()._id,
+            everything.next().await.unwrap()._id,
+        ];
+        assert_eq!(ids, [5, 6]);
+
+        // Requests still get their replies while subscribed
+        assert_eq!(client.get(5).await.unwrap().event, "fine");
+        drop(serious);
+        assert_eq!(
+            client
+                .query(EventFilter::default(), None)
+                .await
+                .unwrap()
+                .len(),
+            7
+        );
+
+        // Posted once however often it is sent
+        let channel = || DEFAULT_CHANNEL.to_string();
+        let once = client.post_once(channel(), "k".into(), event("once", Severity::Info));
+        assert_eq!(once.await.unwrap()._id, 7);
+        let again = client.post_once(channel(), "k".into(), event("again", Severity::Info));
+        assert_eq!(again.await.unwrap().event, "once");
+        assert_eq!(client.get(8).await.ok(), None);
+    }
+
+    #[tokio::test]
+    async fn test_server_gone() {
+        let _ = env_logger::try_init();
+        let (port, server) = start_server().await;
+        let client = Client::connect(
+            &format!("ws://localhost:{port}"),
+            &ConnectOptions::default(),
+        )
+        .await
+        .expect("Failed to connect");
+        client.post(event("one", Severity::Info)).await.unwrap();
+
+        server.abort();
+        while !client.is_closed() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        // Fails instead of waiting for a reply that never comes
+        let result = tokio::time::timeout(Duration::from_secs(5), client.get(0)).await;
+        assert!(matches!(
+            result,
+            Ok(Err(ClientError::Connection(WebSocketError::Connection(
+                ConnectionError::NoResponse
+            ))))
+        ));
+        let result = tokio::time::timeout(Duration::from_secs(5), client.post(Event::default()));
+        assert!(matches!(result.await, Ok(Err(ClientError::Connection(_)))));
+    }
+}
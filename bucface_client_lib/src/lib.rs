@@ -0,0 +1,8 @@
+//! A client for Bucface servers without a GUI. [Client] is the async API to
+//! embed in services, [net] the connection it and the GUI are built on.
+pub mod client;
+pub mod logs;
+pub mod net;
+pub mod outbox;
+
+pub use client::{Client, ClientError, ConnectOptions, Subscription};
@@ -0,0 +1,148 @@
+//! What every client does with logs whatever it shows them on: reading what
+//! the user typed and ordering replies under the logs they reply to.
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use bucface_utils::{valid_field_key, EventDB, FieldCondition, FieldConditionError, FieldValue};
+
+/// The trimmed, non-empty values of a comma separated list.
+pub fn comma_separated(buf: &str) -> BTreeSet<String> {
+    buf.split(',')
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// Parses comma separated `key=value` fields, each value read with
+/// [FieldValue::parse].
+pub fn parse_fields(buf: &str) -> Result<BTreeMap<String, FieldValue>, String> {
+    buf.split(',')
+        .map(str::trim)
+        .filter(|field| !field.is_empty())
+        .map(|field| {
+            let (key, value) = field
+                .split_once('=')
+                .ok_or_else(|| format!("`{field}` is not key=value"))?;
+            let key = key.trim();
+            if !valid_field_key(key) {
+                return Err(format!("`{key}` is not a field name"));
+            }
+            Ok((key.into(), FieldValue::parse(value)))
+        })
+        .collect()
+}
+
+/// Parses comma separated [FieldCondition]s.
+pub fn parse_conditions(buf: &str) -> Result<Vec<FieldCondition>, FieldConditionError> {
+    buf.split(',')
+        .map(str::trim)
+        .filter(|condition| !condition.is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+/// The order the sorted `logs` are shown in, as their indices with how deeply
+/// each is nested: every log is followed by its replies and theirs. Replies
+/// to logs we do not have are shown as if they replied to none.
+pub fn threaded(logs: &[EventDB]) -> Vec<(usize, usize)> {
+    let mut roots = Vec::new();
+    let mut replies: HashMap<u64, Vec<usize>> = HashMap::new();
+    for (i, log) in logs.iter().enumerate() {
+        // Parents have lower ids, which also rules out cycles
+        let parent = log.parent.filter(|&parent| {
+            parent < log._id && logs.binary_search_by_key(&parent, |log| log._id).is_ok()
+        });
+        match parent {
+            Some(parent) => replies.entry(parent).or_default().push(i),
+            None => roots.push(i),
+        }
+    }
+
+    let mut order = Vec::with_capacity(logs.len());
+    let mut stack = roots.into_iter().rev().map(|i| (0, i)).collect::<Vec<_>>();
+    while let Some((depth, i)) = stack.pop() {
+        order.push((depth, i));
+        if let Some(replies) = replies.get(&logs[i]._id) {
+            stack.extend(replies.iter().rev().map(|&reply| (depth + 1, reply)));
+        }
+    }
+
+    order
+}
+
+#[cfg(test)]
+mod logs_tests {
+    use bucface_utils::{Comparison, Event};
+
+    use super::*;
+
+    #[test]
+    fn test_parse_fields() {
+        let fields =
+            parse_fields("exit_code=2, duration_s = 1.5,cached=false, cmd=\"make\",").unwrap();
+        assert_eq!(
+            fields,
+            BTreeMap::from([
+                ("exit_code".into(), FieldValue::Int(2)),
+                ("duration_s".into(), FieldValue::Float(1.5)),
+                ("cached".into(), FieldValue::Bool(false)),
+                ("cmd".into(), FieldValue::String("make".into())),
+            ])
+        );
+        assert_eq!(parse_fields(" "), Ok(BTreeMap::new()));
+        assert!(parse_fields("exit_code").is_err());
+        assert!(parse_fields("exit code=1").is_err());
+
+        let conditions = parse_conditions("fields.exit_code != 0, stage == \"build 2\"").unwrap();
+        assert_eq!(
+            conditions
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<String>>(),
+            ["exit_code != 0", "stage == \"build 2\""]
+        );
+        assert_eq!(
+            parse_conditions("exit_code <= 1.5")
+                .unwrap()
+                .pop()
+                .map(|condition| (condition.comparison, condition.value)),
+            Some((Comparison::Le, FieldValue::Float(1.5)))
+        );
+        assert_eq!(
+            parse_conditions("exit_code"),
+            Err(FieldConditionError::NoComparison)
+        );
+        assert_eq!(
+            parse_conditions("2 > 1"),
+            Err(FieldConditionError::InvalidKey("2".into()))
+        );
+        assert_eq!(parse_conditions("ok =="), Err(FieldConditionError::NoValue));
+    }
+
+    #[test]
+    fn test_threaded() {
+        // 1 <- 2 <- 5, 1 <- 4, 3 replies to a log we do not have
+        let parents = [
+            (1, None),
+            (2, Some(1)),
+            (3, Some(0)),
+            (4, Some(1)),
+            (5, Some(2)),
+        ];
+        let logs = parents
+            .iter()
+            .map(|&(id, parent)| EventDB {
+                parent,
+                ..EventDB::from(Event::default(), id)
+            })
+            .collect::<Vec<EventDB>>();
+
+        let order = threaded(&logs)
+            .into_iter()
+            .map(|(depth, i)| (depth, logs[i]._id))
+            .collect::<Vec<(usize, u64)>>();
+        assert_eq!(order, vec![(0, 1), (1, 2), (2, 5), (1, 4), (0, 3)]);
+
+        assert!(threaded(&[]).is_empty());
+    }
+}
@@ -0,0 +1,87 @@
+use std::time::{Duration, Instant};
+
+/// How long to wait before the first attempt to reconnect
+const INITIAL_DELAY: Duration = Duration::from_millis(500);
+/// The longest wait between attempts
+const MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// When to try reconnecting next, waiting twice as long after every failed
+/// attempt. Half of every wait is random, so the clients of a server that
+/// went away do not all come back at the same moment.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    /// How many attempts failed so far
+    pub failures: u32,
+    next: Instant,
+}
+
+impl Backoff {
+    pub fn new() -> Self {
+        Self {
+            failures: 0,
+            next: Instant::now() + delay(0),
+        }
+    }
+
+    /// Whether it is time for the next attempt.
+    pub fn is_due(&self) -> bool {
+        Instant::now() >= self.next
+    }
+
+    /// How long until the next attempt.
+    pub fn remaining(&self) -> Duration {
+        self.next.saturating_duration_since(Instant::now())
+    }
+
+    /// Waits longer before the next attempt, as the last one failed.
+    pub fn failed(&mut self) {
+        self.failures += 1;
+        self.next = Instant::now() + delay(self.failures);
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The wait after `failures` failed attempts, between half and all of
+/// [INITIAL_DELAY] doubled for every failure, up to [MAX_DELAY].
+fn delay(failures: u32) -> Duration {
+    let full = INITIAL_DELAY
+        .saturating_mul(2u32.saturating_pow(failures))
+        .min(MAX_DELAY);
+    let half = full / 2;
+
+    half + half.mul_f64(rand::random::<f64>())
+}
+
+#[cfg(test)]
+mod backoff_tests {
+    use super::*;
+
+    #[test]
+    fn test_delay() {
+        for failures in 0..40 {
+            let full = (INITIAL_DELAY * 2u32.saturating_pow(failures.min(10))).min(MAX_DELAY);
+            for _ in 0..20 {
+                let delay = delay(failures);
+                assert!(delay >= full / 2 && delay <= full, "{failures}: {delay:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_backoff() {
+        let mut backoff = Backoff::new();
+        assert!(!backoff.is_due());
+        assert!(backoff.remaining() <= INITIAL_DELAY);
+
+        backoff.failed();
+        backoff.failed();
+        assert_eq!(backoff.failures, 2);
+        assert!(backoff.remaining() > INITIAL_DELAY);
+        assert!(!backoff.is_due());
+    }
+}
@@ -1,3 +1,5 @@
+pub mod backoff;
+pub mod tls;
 pub mod ws_client;
 pub mod ws_receiver;
 pub mod ws_sender;
@@ -0,0 +1,128 @@
+use std::fmt;
+
+use bucface_utils::hex;
+use bucface_utils::ws::WsStream;
+use sha2::{Digest, Sha256};
+use tokio_native_tls::native_tls::{self, Certificate, TlsConnector};
+use tokio_tungstenite::MaybeTlsStream;
+
+/// How to verify the server when connecting to a `wss://` url.
+#[derive(Debug, Clone, Default)]
+pub struct TlsOptions {
+    /// A PEM encoded certificate to trust in addition to the system roots.
+    pub ca_cert: Option<Vec<u8>>,
+    /// The SHA-256 fingerprint of the server's DER encoded certificate. When
+    /// set without a [ca_cert](TlsOptions::ca_cert), the fingerprint alone
+    /// authenticates the server, so self-signed certificates work.
+    pub pin: Option<[u8; 32]>,
+}
+
+#[derive(Debug)]
+pub enum TlsError {
+    CaCert(native_tls::Error),
+    Connector(native_tls::Error),
+    InvalidPin,
+    NoPeerCertificate,
+    PinMismatch { expected: String, found: String },
+}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::CaCert(e) => write!(f, "invalid CA certificate: {e}"),
+            Self::Connector(e) => write!(f, "error setting up TLS: {e}"),
+            Self::InvalidPin => write!(f, "the pin is not a SHA-256 fingerprint"),
+            Self::NoPeerCertificate => write!(f, "the server sent no certificate"),
+            Self::PinMismatch { expected, found } => {
+                write!(
+                    f,
+                    "the server's certificate is {found}, not the pinned {expected}"
+                )
+            }
+        }
+    }
+}
+
+impl TlsOptions {
+    pub fn connector(&self) -> Result<TlsConnector, TlsError> {
+        let mut builder = TlsConnector::builder();
+
+        if let Some(ca_cert) = &self.ca_cert {
+            let ca_cert = Certificate::from_pem(ca_cert).map_err(TlsError::CaCert)?;
+            builder.add_root_certificate(ca_cert);
+        } else if self.pin.is_some() {
+            log::debug!("Relying on the pinned certificate instead of a CA");
+            builder.danger_accept_invalid_certs(true);
+        }
+
+        builder.build().map_err(TlsError::Connector)
+    }
+
+    /// Checks the certificate the server presented against the
+    /// [pin](TlsOptions::pin), if there is one.
+    pub fn verify_pin(&self, stream: &WsStream) -> Result<(), TlsError> {
+        let Some(pin) = &self.pin else {
+            return Ok(());
+        };
+
+        let cert = match stream.get_ref() {
+            MaybeTlsStream::NativeTls(tls) => tls
+                .get_ref()
+                .peer_certificate()
+                .map_err(TlsError::Connector)?
+                .ok_or(TlsError::NoPeerCertificate)?,
+            _ => return Err(TlsError::NoPeerCertificate),
+        };
+
+        let der = cert.to_der().map_err(TlsError::Connector)?;
+        let fingerprint: [u8; 32] = Sha256::digest(der).into();
+        if fingerprint != *pin {
+            return Err(TlsError::PinMismatch {
+                expected: hex::encode(pin),
+                found: hex::encode(&fingerprint),
+            });
+        }
+
+        log::debug!("Server certificate matches the pin");
+        Ok(())
+    }
+}
+
+/// Parses a SHA-256 fingerprint written as hex, optionally separated by
+/// colons like `openssl x509 -fingerprint -sha256` prints it.
+pub fn parse_fingerprint(fingerprint: &str) -> Result<[u8; 32], TlsError> {
+    hex::decode_sha256(fingerprint).ok_or(TlsError::InvalidPin)
+}
+
+#[cfg(test)]
+mod tls_tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_fingerprint() {
+        let expected: [u8; 32] = std::array::from_fn(|i| i as u8 * 8);
+        let encoded = hex::encode(&expected);
+
+        assert_eq!(parse_fingerprint(&encoded).unwrap(), expected);
+        assert_eq!(
+            parse_fingerprint(&encoded.to_lowercase()).unwrap(),
+            expected
+        );
+
+        let colons = expected
+            .iter()
+            .map(|byte| format!("{byte:02X}"))
+            .collect::<Vec<String>>()
+            .join(":");
+        assert_eq!(parse_fingerprint(&colons).unwrap(), expected);
+
+        assert!(matches!(
+            parse_fingerprint(&encoded[2..]),
+            Err(TlsError::InvalidPin)
+        ));
+        assert!(matches!(
+            parse_fingerprint(&encoded.replace('0', "g")),
+            Err(TlsError::InvalidPin)
+        ));
+    }
+}
@@ -0,0 +1,670 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use bucface_utils::ws::WsStream;
+use bucface_utils::{
+    ClientMessage, ClientRequest, Event, EventDBErrorSerde, EventFilter, PageQuery, ServerMessage,
+    ServerResponse,
+};
+use futures_util::{SinkExt, StreamExt};
+use parking_lot::Mutex;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio_tungstenite::{tungstenite, Connector};
+use url::Url;
+
+use super::backoff::Backoff;
+use super::tls::{TlsError, TlsOptions};
+use super::ws_receiver::start_receiver;
+use super::ws_sender::start_sender;
+
+#[derive(Debug)]
+pub enum ConnectionError {
+    NoResponse,
+    InvalidResponse,
+    IOError(Box<tungstenite::Error>),
+}
+
+#[derive(Debug)]
+pub enum WebSocketError {
+    UrlParseError(url::ParseError),
+    Connection(ConnectionError),
+    NotWsUrl,
+    Tls(TlsError),
+    CaCertRead(std::io::Error),
+    DoesNotExist,
+    SendError(Box<TrySendError<ClientRequest>>),
+    ServerError(EventDBErrorSerde),
+}
+
+impl fmt::Display for ConnectionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoResponse => write!(f, "the server did not answer the ping"),
+            Self::InvalidResponse => write!(f, "the server answered the ping wrongly"),
+            Self::IOError(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl fmt::Display for WebSocketError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UrlParseError(e) => write!(f, "invalid url: {e}"),
+            Self::Connection(e) => write!(f, "error connecting: {e}"),
+            Self::NotWsUrl => write!(f, "not a ws:// or wss:// url"),
+            Self::Tls(e) => write!(f, "{e}"),
+            Self::CaCertRead(e) => write!(f, "error reading the CA certificate: {e}"),
+            Self::DoesNotExist => write!(f, "not connected"),
+            Self::SendError(e) => write!(f, "error sending: {e}"),
+            Self::ServerError(e) => write!(f, "the server failed: {e:?}"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum WebSocketStatus {
+    Connected(WsClient),
+    /// The connection was lost and is being made again
+    Reconnecting(Backoff),
+    Error(WebSocketError),
+    Disconnected,
+}
+
+impl fmt::Display for WebSocketStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WebSocketStatus::Connected(_) => write!(f, "Connected"),
+            WebSocketStatus::Reconnecting(backoff) => {
+                write!(f, "Reconnecting (attempt {})", backoff.failures + 1)
+            }
+            WebSocketStatus::Error(e) => write!(f, "Error: {:?}", e),
+            WebSocketStatus::Disconnected => write!(f, "Disconnected"),
+        }
+    }
+}
+
+/// The [requests](WsClient::request_replies) waiting on replies, by request
+/// id, or [None] once the connection is closed and no reply will come
+pub type Pending = Arc<Mutex<Option<HashMap<u64, UnboundedSender<ServerResponse>>>>>;
+
+#[derive(Debug)]
+pub struct WsClient {
+    pub receiver: Receiver,
+    pub sender: Sender,
+    /// The id of the next [ClientRequest]
+    next_id: AtomicU64,
+    pending: Pending,
+}
+
+#[derive(Debug)]
+pub struct Receiver {
+    /// The thread that is taking and handling the responses from the server
+    pub receiver: tokio::task::JoinHandle<()>,
+    /// A channel to read from to get the messages from the server, except
+    /// for the replies a [request](WsClient::request) is waiting on
+    pub rx: tokio::sync::mpsc::Receiver<ServerMessage>,
+}
+
+#[derive(Debug)]
+pub struct Sender {
+    /// The channel to send events to, which are then sent to the server
+    pub tx: tokio::sync::mpsc::Sender<ClientRequest>,
+}
+
+impl WsClient {
+    /// Connects to a `ws://` or `wss://` url, verifying the latter with the
+    /// given [TlsOptions]. `wake` is called after every message from the
+    /// server, like a GUI repainting to show it.
+    pub async fn new(
+        dest: &str,
+        tls: &TlsOptions,
+        wake: impl Fn() + Send + Sync + 'static,
+    ) -> Result<WsClient, WebSocketError> {
+        let url = dest
+            .parse::<url::Url>()
+            .map_err(WebSocketError::UrlParseError)?;
+        if url.scheme() != "ws" && url.scheme() != "wss" {
+            return Err(WebSocketError::NotWsUrl);
+        }
+
+        let stream = connect(url, tls).await?;
+
+        let (mut write, mut read) = stream.split();
+
+        let pending = Pending::new(Mutex::new(Some(HashMap::new())));
+        let sender_pending = pending.clone();
+        let (sender_tx, mut sender_rx) = tokio::sync::mpsc::channel::<ClientRequest>(128);
+        let (failed_tx, failed_rx) = tokio::sync::oneshot::channel();
+        let sender = tokio::spawn(async move {
+            if let Err(e) = start_sender(&mut write, &mut sender_rx, &sender_pending).await {
+                log::error!("Closing the WebSocket connection: {e}");
+                let _ = failed_tx.send(());
+            }
+        });
+
+        let receiver_pending = pending.clone();
+        let sender_abort = sender.abort_handle();
+        let (receiver_tx, receiver_rx) = tokio::sync::mpsc::channel::<ServerMessage>(128);
+        let receiver = tokio::spawn(async move {
+            let receive = start_receiver(&mut read, &receiver_tx, receiver_pending.clone(), &wake);
+            tokio::select! {
+                result = receive => match result {
+                    Ok(_) => log::info!("WebSocket connection closed"),
+                    Err(e) => log::error!("Error handling WebSocket connection: {:?}", e),
+                },
+                // The sender stopped, dropping the message it failed to send
+                Ok(()) = failed_rx => {}
+            }
+            // Nothing waiting on a reply will get one anymore, and nothing
+            // sent from now on will be answered
+            *receiver_pending.lock() = None;
+            sender_abort.abort();
+            wake();
+        });
+
+        Ok(WsClient {
+            receiver: Receiver {
+                receiver,
+                rx: receiver_rx,
+            },
+            sender: Sender { tx: sender_tx },
+            next_id: AtomicU64::new(0),
+            pending,
+        })
+    }
+
+    /// Whether the connection closed, failed or missed too many pings. It is
+    /// not made again, that is up to the caller.
+    pub fn is_closed(&self) -> bool {
+        self.receiver.receiver.is_finished()
+    }
+
+    /// Sends a message without waiting for the reply, returning the id the
+    /// server tags its [ServerMessage::Reply]s with. The replies are read
+    /// with [get_buf_logs](WsClient::get_buf_logs).
+    pub fn send(&self, message: ClientMessage) -> Result<u64, WebSocketError> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.sender
+            .tx
+            .try_send(ClientRequest { id, message })
+            .map_err(|e| WebSocketError::SendError(Box::new(e)))?;
+
+        Ok(id)
+    }
+
+    /// Sends a message and waits for the server's reply to it. Only meant for
+    /// messages answered with a single [ServerResponse], further replies end
+    /// up in [get_buf_logs](WsClient::get_buf_logs).
+    pub async fn request(&self, message: ClientMessage) -> Result<ServerResponse, WebSocketError> {
+        self.request_replies(message)?
+            .next()
+            .await
+            .ok_or(WebSocketError::Connection(ConnectionError::NoResponse))
+    }
+
+    /// Sends a message and returns its replies, which arrive there instead of
+    /// [get_buf_logs](WsClient::get_buf_logs) until the [Replies] are dropped.
+    pub fn request_replies(&self, message: ClientMessage) -> Result<Replies, WebSocketError> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = mpsc::unbounded_channel();
+        // Registered before sending, so the reply cannot arrive first
+        match self.pending.lock().as_mut() {
+            Some(pending) => pending.insert(id, tx),
+            None => return Err(WebSocketError::Connection(ConnectionError::NoResponse)),
+        };
+
+        if let Err(e) = self.sender.tx.try_send(ClientRequest { id, message }) {
+            if let Some(pending) = self.pending.lock().as_mut() {
+                pending.remove(&id);
+            }
+            return Err(WebSocketError::SendError(Box::new(e)));
+        }
+
+        Ok(Replies {
+            id,
+            rx,
+            pending: self.pending.clone(),
+        })
+    }
+
+    pub fn send_log(&self, log: Event) -> Result<u64, WebSocketError> {
+        let message = ClientMessage::NewEvent(log);
+
+        self.send(message)
+    }
+
+    /// Sends a log the server only logs once for the idempotency key, however
+    /// often it is sent, to the channel even if another one is joined by then.
+    /// Servers before version 5 do not know the key.
+    pub fn send_log_once(
+        &self,
+        channel: String,
+        key: String,
+        log: Event,
+    ) -> Result<u64, WebSocketError> {
+        self.send(ClientMessage::NewEventOnce {
+            channel,
+            key,
+            event: log,
+        })
+    }
+
+    pub fn get_log(&self, id: u64) -> Result<u64, WebSocketError> {
+        self.send(ClientMessage::GetEvent(id))
+    }
+
+    pub fn get_buf_logs<T>(
+        &mut self,
+        mut post: impl FnMut(ServerMessage) -> Option<T>,
+    ) -> Option<T> {
+        while let Ok(res) = self.receiver.rx.try_recv() {
+            if let Some(ret) = post(res) {
+                return Some(ret);
+            }
+        }
+
+        None
+    }
+
+    /// Takes the messages [get_buf_logs](WsClient::get_buf_logs) reads, to
+    /// read them elsewhere. It reads nothing afterwards.
+    pub fn take_messages(&mut self) -> tokio::sync::mpsc::Receiver<ServerMessage> {
+        let (_, closed) = tokio::sync::mpsc::channel(1);
+        std::mem::replace(&mut self.receiver.rx, closed)
+    }
+
+    /// Gets the logs matching the filter, at most `limit` of them if given
+    pub fn query(&self, filter: EventFilter, limit: Option<u32>) -> Result<u64, WebSocketError> {
+        self.send(ClientMessage::Query { filter, limit })
+    }
+
+    /// Searches the logs for the words in `terms`, best matches first
+    pub fn search(&self, terms: String, limit: u32) -> Result<u64, WebSocketError> {
+        self.send(ClientMessage::Search { terms, limit })
+    }
+
+    /// Gets a single page of logs
+    pub fn get_page(&self, query: PageQuery) -> Result<u64, WebSocketError> {
+        let message = ClientMessage::GetPage(query);
+
+        self.send(message)
+    }
+
+    /// Asks the server to only push the new logs matching the filter, or any
+    /// other filter subscribed to
+    pub fn subscribe(&self, filter: EventFilter) -> Result<u64, WebSocketError> {
+        self.send(ClientMessage::Subscribe(filter))
+    }
+
+    /// Drops the subscription with the id the server replied to
+    /// [subscribe](WsClient::subscribe) with
+    pub fn unsubscribe(&self, subscription: u64) -> Result<u64, WebSocketError> {
+        self.send(ClientMessage::Unsubscribe(subscription))
+    }
+
+    /// Replaces the text of one of our logs, keeping the previous one
+    pub fn edit_log(&self, id: u64, event: String) -> Result<u64, WebSocketError> {
+        self.send(ClientMessage::EditEvent { id, event })
+    }
+
+    /// Hides a log from everyone, keeping it with the reason for auditors
+    pub fn retract_log(&self, id: u64, reason: String) -> Result<u64, WebSocketError> {
+        self.send(ClientMessage::RetractEvent { id, reason })
+    }
+
+    /// Whether retracted logs are included in what we get
+    pub fn show_retracted(&self, show: bool) -> Result<u64, WebSocketError> {
+        self.send(ClientMessage::ShowRetracted(show))
+    }
+
+    /// Gets every log of the thread a log belongs to, oldest first
+    pub fn get_thread(&self, id: u64) -> Result<u64, WebSocketError> {
+        self.send(ClientMessage::GetThread(id))
+    }
+
+    /// Gets the contents of an attachment, in chunks
+    pub fn get_blob(&self, digest: String) -> Result<u64, WebSocketError> {
+        self.send(ClientMessage::GetBlob(digest))
+    }
+
+    /// Gets every revision of a log, oldest first
+    pub fn get_revisions(&self, id: u64) -> Result<u64, WebSocketError> {
+        self.send(ClientMessage::GetRevisions(id))
+    }
+
+    /// Creates a channel with the name
+    pub fn create_channel(&self, name: String) -> Result<u64, WebSocketError> {
+        self.send(ClientMessage::CreateChannel(name))
+    }
+
+    /// Gets every channel, archived ones included
+    pub fn list_channels(&self) -> Result<u64, WebSocketError> {
+        self.send(ClientMessage::ListChannels)
+    }
+
+    /// Archives the channel with the name, so no more logs can be sent to it
+    pub fn archive_channel(&self, name: String) -> Result<u64, WebSocketError> {
+        self.send(ClientMessage::ArchiveChannel(name))
+    }
+
+    /// Switches to the channel with the name, which every later request
+    /// concerns
+    pub fn join_channel(&self, name: String) -> Result<u64, WebSocketError> {
+        self.send(ClientMessage::JoinChannel(name))
+    }
+}
+
+/// The replies to a [request](WsClient::request_replies), in the order the
+/// server sent them.
+#[derive(Debug)]
+pub struct Replies {
+    pub id: u64,
+    rx: UnboundedReceiver<ServerResponse>,
+    pending: Pending,
+}
+
+impl Replies {
+    /// The next reply, or [None] once the connection is closed.
+    pub async fn next(&mut self) -> Option<ServerResponse> {
+        self.rx.recv().await
+    }
+}
+
+impl Drop for Replies {
+    fn drop(&mut self) {
+        if let Some(pending) = self.pending.lock().as_mut() {
+            pending.remove(&self.id);
+        }
+    }
+}
+
+pub async fn verify_conn(stream: &mut WsStream) -> Result<(), ConnectionError> {
+    log::debug!("Verifying WebSocket connection");
+
+    const ECHO: &[u8] = b"echo\n";
+    let (mut writer, mut reader) = stream.split();
+
+    log::trace!("Sending ping");
+    writer
+        .send(tungstenite::Message::Ping(ECHO.to_vec()))
+        .await
+        .map_err(|e| ConnectionError::IOError(Box::new(e)))?;
+
+    while let Some(msg) = reader.next().await {
+        log::trace!("Received message: {:?}", msg);
+        let msg = msg.map_err(|e| ConnectionError::IOError(Box::new(e)))?;
+        if msg.is_pong() {
+            let data = msg.into_data();
+            if data != ECHO {
+                log::warn!("Invalid pong response, treating as non-fatal: {data:?}");
+                return Err(ConnectionError::InvalidResponse);
+            }
+
+            log::debug!("WebSocket connection verified");
+            return Ok(());
+        }
+    }
+
+    log::warn!("No response to ping");
+    Err(ConnectionError::NoResponse)
+}
+
+async fn connect(url: Url, tls: &TlsOptions) -> Result<WsStream, WebSocketError> {
+    log::debug!("Connecting to {}", url);
+
+    let connector = match url.scheme() {
+        "wss" => Some(Connector::NativeTls(
+            tls.connector().map_err(WebSocketError::Tls)?,
+        )),
+        _ => None,
+    };
+
+    let (mut stream, _) =
+        tokio_tungstenite::connect_async_tls_with_config(&url, None, false, connector)
+            .await
+            .map_err(|e| WebSocketError::Connection(ConnectionError::IOError(Box::new(e))))?;
+
+    if url.scheme() == "wss" {
+        tls.verify_pin(&stream).map_err(WebSocketError::Tls)?;
+    }
+
+    verify_conn(&mut stream)
+        .await
+        .map_err(WebSocketError::Connection)?;
+
+    log::info!("Connected to {}", url);
+
+    Ok(stream)
+}
+
+#[cfg(test)]
+mod ws_client_tests {
+    use bucface_utils::EventDB;
+    use sha2::{Digest, Sha256};
+    use tokio::net::TcpListener;
+    use tokio_native_tls::native_tls::{self, Identity};
+
+    use super::*;
+
+    /// Starts a websocket server with a fresh self-signed certificate for
+    /// localhost, returning its port and the PEM encoded certificate.
+    async fn start_tls_server() -> (u16, String) {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        // Every serialization signs the certificate again, so only do it once
+        let cert_pem = cert.serialize_pem().unwrap();
+        let key_pem = cert.serialize_private_key_pem();
+        let identity = Identity::from_pkcs8(cert_pem.as_bytes(), key_pem.as_bytes()).unwrap();
+        let acceptor =
+            tokio_native_tls::TlsAcceptor::from(native_tls::TlsAcceptor::new(identity).unwrap());
+
+        let socket = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = socket.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = socket.accept().await {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let Ok(stream) = acceptor.accept(stream).await else {
+                        return;
+                    };
+                    let Ok(mut stream) = tokio_tungstenite::accept_async(stream).await else {
+                        return;
+                    };
+                    // Reading is what answers the pings of verify_conn
+                    while let Some(Ok(_)) = stream.next().await {}
+                });
+            }
+        });
+
+        (port, cert_pem)
+    }
+
+    /// Starts a plain websocket server answering [ClientMessage::GetEvent]s
+    /// in pairs, replying to the second one first and broadcasting an event
+    /// before each pair.
+    async fn start_reply_server() -> u16 {
+        let socket = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = socket.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (stream, _) = socket.accept().await.unwrap();
+            let mut stream = tokio_tungstenite::accept_async(stream).await.unwrap();
+            let mut requests = Vec::new();
+            while let Some(Ok(message)) = stream.next().await {
+                let tungstenite::Message::Binary(data) = message else {
+                    continue;
+                };
+                requests.push(rmp_serde::from_slice::<ClientRequest>(&data).unwrap());
+                if requests.len() < 2 {
+                    continue;
+                }
+
+                let broadcast = EventDB::from(Event::default(), u64::MAX);
+                let mut replies = vec![ServerMessage::Broadcast(ServerResponse::Event(Box::new(
+                    broadcast,
+                )))];
+                for request in requests.drain(..).rev() {
+                    let ClientMessage::GetEvent(event_id) = request.message else {
+                        panic!("Unexpected request {request:?}");
+                    };
+                    let event = EventDB::from(Event::default(), event_id);
+                    replies.push(ServerMessage::Reply {
+                        id: request.id,
+                        response: ServerResponse::Event(Box::new(event)),
+                    });
+                }
+                for reply in replies {
+                    let data = rmp_serde::to_vec(&reply).unwrap();
+                    stream
+                        .send(tungstenite::Message::Binary(data))
+                        .await
+                        .unwrap();
+                }
+            }
+        });
+
+        port
+    }
+
+    /// Starts a plain websocket server which closes the connection after the
+    /// first request, without replying to it.
+    async fn start_closing_server() -> u16 {
+        let socket = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = socket.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (stream, _) = socket.accept().await.unwrap();
+            let mut stream = tokio_tungstenite::accept_async(stream).await.unwrap();
+            while let Some(Ok(message)) = stream.next().await {
+                if message.is_binary() {
+                    break;
+                }
+            }
+            stream.close(None).await.unwrap();
+        });
+
+        port
+    }
+
+    fn event_id(response: ServerResponse) -> u64 {
+        match response {
+            ServerResponse::Event(event) => event._id,
+            other => panic!("Expected an event, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_request_replies() {
+        let _ = env_logger::try_init();
+        let port = start_reply_server().await;
+        let mut client = WsClient::new(
+            &format!("ws://localhost:{port}"),
+            &TlsOptions::default(),
+            || (),
+        )
+        .await
+        .expect("Failed to connect");
+
+        let (first, second) = tokio::join!(
+            client.request(ClientMessage::GetEvent(1)),
+            client.request(ClientMessage::GetEvent(2))
+        );
+        assert_eq!(event_id(first.unwrap()), 1);
+        assert_eq!(event_id(second.unwrap()), 2);
+
+        // Replies nothing waits on are read like any other message
+        let third = client.send(ClientMessage::GetEvent(3)).unwrap();
+        let fourth = client.get_log(4).unwrap();
+        let mut messages = Vec::new();
+        while messages.len() < 4 {
+            messages.push(client.receiver.rx.recv().await.unwrap());
+        }
+        // One broadcast was sent before each pair
+        assert!(matches!(&messages[0], ServerMessage::Broadcast(_)));
+        assert!(matches!(&messages[1], ServerMessage::Broadcast(_)));
+        assert!(matches!(
+            &messages[2],
+            ServerMessage::Reply { id, response } if *id == fourth && event_id(response.clone()) == 4
+        ));
+        assert!(matches!(
+            &messages[3],
+            ServerMessage::Reply { id, response } if *id == third && event_id(response.clone()) == 3
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_closed() {
+        let _ = env_logger::try_init();
+        let port = start_closing_server().await;
+        let client = WsClient::new(
+            &format!("ws://localhost:{port}"),
+            &TlsOptions::default(),
+            || (),
+        )
+        .await
+        .expect("Failed to connect");
+        assert!(!client.is_closed());
+
+        // The request gives up instead of waiting forever
+        let result = client.request(ClientMessage::GetEvent(1)).await;
+        assert!(matches!(
+            result,
+            Err(WebSocketError::Connection(ConnectionError::NoResponse))
+        ));
+        while !client.is_closed() {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    fn fingerprint(cert_pem: &str) -> [u8; 32] {
+        let cert = native_tls::Certificate::from_pem(cert_pem.as_bytes()).unwrap();
+        Sha256::digest(cert.to_der().unwrap()).into()
+    }
+
+    fn url(port: u16) -> Url {
+        format!("wss://localhost:{port}").parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_connect_custom_ca() {
+        let _ = env_logger::try_init();
+        let (port, cert_pem) = start_tls_server().await;
+
+        let tls = TlsOptions {
+            ca_cert: Some(cert_pem.into_bytes()),
+            pin: None,
+        };
+        connect(url(port), &tls).await.expect("Failed to connect");
+    }
+
+    #[tokio::test]
+    async fn test_connect_untrusted() {
+        let _ = env_logger::try_init();
+        let (port, _) = start_tls_server().await;
+
+        let result = connect(url(port), &TlsOptions::default()).await;
+        assert!(matches!(result, Err(WebSocketError::Connection(_))));
+    }
+
+    #[tokio::test]
+    async fn test_connect_pinned() {
+        let _ = env_logger::try_init();
+        let (port, cert_pem) = start_tls_server().await;
+
+        let tls = TlsOptions {
+            ca_cert: None,
+            pin: Some(fingerprint(&cert_pem)),
+        };
+        connect(url(port), &tls).await.expect("Failed to connect");
+
+        let tls = TlsOptions {
+            ca_cert: Some(cert_pem.into_bytes()),
+            pin: Some([0; 32]),
+        };
+        let result = connect(url(port), &tls).await;
+        assert!(matches!(
+            result,
+            Err(WebSocketError::Tls(TlsError::PinMismatch { .. }))
+        ));
+    }
+}
@@ -0,0 +1,101 @@
+use bucface_utils::ws::WsFaucet;
+use bucface_utils::ServerMessage;
+use futures_util::StreamExt;
+use rmp_serde::decode;
+use std::fmt;
+use std::io;
+use std::time::Duration;
+use tokio::sync::mpsc::{self, Sender};
+use tokio_tungstenite::tungstenite::Message;
+
+use super::ws_client::Pending;
+use super::ws_sender::PING_INTERVAL;
+
+/// How many pings may go unanswered before the connection counts as dead
+const MISSED_PINGS: u32 = 3;
+
+/// Receives messages until the connection closes, fails or misses
+/// [MISSED_PINGS] pings in a row, as nothing at all arrived for that long.
+pub async fn start_receiver(
+    read: &mut WsFaucet,
+    tx: &Sender<ServerMessage>,
+    pending: Pending,
+    wake: &impl Fn(),
+) -> Result<(), io::Error> {
+    let timeout: Duration = PING_INTERVAL * MISSED_PINGS;
+    loop {
+        let res = match tokio::time::timeout(timeout, read.next()).await {
+            Ok(Some(res)) => res,
+            Ok(None) => break,
+            Err(_) => {
+                log::warn!("Nothing received for {timeout:?}, the connection is dead");
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "missed pings"));
+            }
+        };
+        match res {
+            Ok(Message::Binary(data)) => {
+                log::debug!("Received binary");
+                if let Err(e) = receive_event(tx.clone(), &pending, data).await {
+                    log::error!("Error receiving event: {e}");
+                }
+                wake();
+            }
+            Ok(t) => {
+                log::debug!("Received non-binary message: {t}");
+            }
+            Err(e) => {
+                log::error!("Error receiving message: {e:?}");
+                return Err(io::Error::new(io::ErrorKind::ConnectionAborted, e));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Debug)]
+enum ReceiveEventError {
+    Decode(decode::Error),
+    Send(Box<mpsc::error::SendError<ServerMessage>>),
+}
+
+impl fmt::Display for ReceiveEventError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Decode(e) => write!(f, "invalid message from the server: {e}"),
+            Self::Send(e) => write!(f, "error passing the message on: {e}"),
+        }
+    }
+}
+
+/// Passes a reply on to the [request](super::ws_client::WsClient::request_replies)
+/// waiting on it, or the message on to `tx` if nothing is.
+async fn receive_event(
+    tx: Sender<ServerMessage>,
+    pending: &Pending,
+    data: Vec<u8>,
+) -> Result<(), ReceiveEventError> {
+    let message =
+        rmp_serde::from_slice::<ServerMessage>(&data).map_err(ReceiveEventError::Decode)?;
+
+    let message = match message {
+        ServerMessage::Reply { id, response } => {
+            match pending.lock().as_ref().and_then(|pending| pending.get(&id)) {
+                Some(waiting) => {
+                    if waiting.send(response).is_err() {
+                        log::debug!("Request {id} was dropped before its reply arrived");
+                    }
+                    return Ok(());
+                }
+                None => ServerMessage::Reply { id, response },
+            }
+        }
+        message => message,
+    };
+
+    tx.send(message)
+        .await
+        .map_err(|e| ReceiveEventError::Send(Box::new(e)))?;
+
+    Ok(())
+}
@@ -0,0 +1,89 @@
+use std::fmt;
+use std::time::Duration;
+
+use bucface_utils::ws::WsSink;
+use bucface_utils::ClientRequest;
+use futures_util::SinkExt;
+use tokio::sync::mpsc::Receiver;
+use tokio_tungstenite::tungstenite::{self, Message};
+
+use super::ws_client::Pending;
+
+/// How often the server is pinged, so a connection that died without closing
+/// is noticed by the [receiver](super::ws_receiver::start_receiver)
+pub const PING_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+pub enum SendLogError {
+    EncodeError(rmp_serde::encode::Error),
+    SendError(tungstenite::Error),
+}
+
+impl fmt::Display for SendLogError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::EncodeError(e) => write!(f, "error encoding the message: {e}"),
+            Self::SendError(e) => write!(f, "error sending to the server: {e}"),
+        }
+    }
+}
+
+/// Start the sender thread, which sends logs to the server and pings it every
+/// [PING_INTERVAL], until the channel is closed or sending fails. Nothing is
+/// sent again, the connection is made again instead. A message that cannot be
+/// encoded is dropped, failing the [request](super::ws_client::WsClient::request)
+/// waiting on its reply, if any.
+///
+/// # Arguments
+/// * `writer` - A [websocket](tokio_tungstenite::WebSocketStream) [writer](futures_util::stream::SplitSink)
+///   connected to the server
+/// * `sender_sink` - A [channel](Receiver) for the thread to receive [ClientRequest]s to send to
+///   the [server](bucface_server)
+/// * `pending` - The requests waiting on replies
+pub async fn start_sender(
+    writer: &mut WsSink,
+    sender_sink: &mut Receiver<ClientRequest>,
+    pending: &Pending,
+) -> Result<(), SendLogError> {
+    let mut ping = tokio::time::interval(PING_INTERVAL);
+    ping.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    // The first tick is right away, when the connection was just verified
+    ping.tick().await;
+
+    loop {
+        let message = tokio::select! {
+            message = sender_sink.recv() => match message {
+                Some(message) => message,
+                None => return Ok(()),
+            },
+            _ = ping.tick() => {
+                log::trace!("Sending ping");
+                writer
+                    .send(Message::Ping(Vec::new()))
+                    .await
+                    .map_err(SendLogError::SendError)?;
+                continue;
+            }
+        };
+        log::trace!("Sending message: {message:?}");
+        match send_message(&message, writer).await {
+            Ok(()) => log::trace!("Sent message: {message:?}"),
+            Err(SendLogError::EncodeError(e)) => {
+                log::error!("Dropping message {message:?} that cannot be encoded: {e}");
+                if let Some(pending) = pending.lock().as_mut() {
+                    pending.remove(&message.id);
+                }
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+pub async fn send_message(
+    message: &ClientRequest,
+    writer: &mut WsSink,
+) -> Result<(), SendLogError> {
+    let encoded_message = bucface_utils::encode(message).map_err(SendLogError::EncodeError)?;
+    let message = Message::Binary(encoded_message);
+    writer.send(message).await.map_err(SendLogError::SendError)
+}
@@ -0,0 +1,276 @@
+//! The logs written but not yet logged by the server, kept on disk so neither
+//! a lost connection nor a restart loses them.
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::{self, File, TryLockError};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use bucface_utils::{Event, EventDBErrorSerde};
+use rand::distributions::{Alphanumeric, DistString};
+use serde::{Deserialize, Serialize};
+
+/// Where the outbox is kept unless `BUCFACE_OUTBOX` says otherwise.
+pub const DEFAULT_PATH: &str = "bucface-outbox";
+
+/// How many characters an idempotency key has.
+const KEY_LENGTH: usize = 32;
+
+/// A log waiting to be logged by the server.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Pending {
+    /// The idempotency key the server knows the log by once it is logged
+    pub key: String,
+    /// The channel it is logged to
+    pub channel: String,
+    /// The log, with the time it was written at
+    pub event: Event,
+    /// Why the server refused the log, which is then kept but not sent again
+    /// until the user [resends](Outbox::resend) or discards it
+    #[serde(default)]
+    pub failed: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum OutboxError {
+    Io(io::Error),
+    Encode(rmp_serde::encode::Error),
+    Decode(rmp_serde::decode::Error),
+    /// Another client has the outbox open
+    Locked,
+}
+
+impl fmt::Display for OutboxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "outbox file error: {e}"),
+            Self::Encode(e) => write!(f, "error encoding outbox: {e}"),
+            Self::Decode(e) => write!(f, "invalid outbox file: {e}"),
+            Self::Locked => write!(f, "the outbox is in use by another client"),
+        }
+    }
+}
+
+impl std::error::Error for OutboxError {}
+
+impl From<io::Error> for OutboxError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+/// Whether the server refused a log, so sending it again as it is would be
+/// of no use, rather than failing to log it this time. A token that expired
+/// is not the log's fault, so it is sent again once authenticated.
+pub fn refused(error: &EventDBErrorSerde) -> bool {
+    !matches!(
+        error,
+        EventDBErrorSerde::Db(_) | EventDBErrorSerde::Blob(_) | EventDBErrorSerde::Unauthorized
+    )
+}
+
+/// The [Pending] logs, oldest first. Each is sent with its key until the
+/// server answers it, which it does with the log it stored the first time if
+/// it was sent before, and only removed then. Those the server refuses are
+/// [failed](Outbox::fail) instead, and only removed by the user.
+#[derive(Debug, Default)]
+pub struct Outbox {
+    /// The file the logs are written to on every change, if any
+    path: Option<PathBuf>,
+    /// Locked while the outbox is open, so no other client writes over it
+    _lock: Option<File>,
+    pending: Vec<Pending>,
+}
+
+impl Outbox {
+    /// Opens the outbox kept in the file, which is created once there are
+    /// pending logs. Fails with [OutboxError::Locked] while another client
+    /// has it open.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self, OutboxError> {
+        let path = path.into();
+        let lock = lock(&path)?;
+        let pending = match fs::read(&path) {
+            Ok(bytes) => rmp_serde::from_slice(&bytes).map_err(OutboxError::Decode)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(Self {
+            path: Some(path),
+            _lock: Some(lock),
+            pending,
+        })
+    }
+
+    /// An outbox that is lost when the client stops.
+    pub fn memory() -> Self {
+        Self::default()
+    }
+
+    /// Every pending log, oldest first.
+    pub fn pending(&self) -> &[Pending] {
+        &self.pending
+    }
+
+    /// The pending logs of the channel, oldest first, failed ones included.
+    pub fn pending_in<'a>(&'a self, channel: &'a str) -> impl Iterator<Item = &'a Pending> {
+        self.pending
+            .iter()
+            .filter(move |pending| pending.channel == channel)
+    }
+
+    /// The pending logs of the channel to send, oldest first, which are those
+    /// that did not fail.
+    pub fn unsent_in<'a>(&'a self, channel: &'a str) -> impl Iterator<Item = &'a Pending> {
+        self.pending_in(channel)
+            .filter(|pending| pending.failed.is_none())
+    }
+
+    /// Adds a log to send to the channel, returning it with the key it is sent
+    /// with.
+    pub fn push(&mut self, channel: &str, event: Event) -> Result<Pending, OutboxError> {
+        let pending = Pending {
+            key: Alphanumeric.sample_string(&mut rand::thread_rng(), KEY_LENGTH),
+            channel: channel.into(),
+            event,
+            failed: None,
+        };
+        self.pending.push(pending.clone());
+        if let Err(e) = self.save() {
+            self.pending.pop();
+            return Err(e);
+        }
+
+        Ok(pending)
+    }
+
+    /// Keeps the log with the key from being sent again, as the server refused
+    /// it for the reason.
+    pub fn fail(&mut self, key: &str, reason: String) -> Result<(), OutboxError> {
+        self.set_failed(key, Some(reason))
+    }
+
+    /// Sends the failed log with the key again.
+    pub fn resend(&mut self, key: &str) -> Result<(), OutboxError> {
+        self.set_failed(key, None)
+    }
+
+    fn set_failed(&mut self, key: &str, failed: Option<String>) -> Result<(), OutboxError> {
+        let Some(pending) = self.pending.iter_mut().find(|pending| pending.key == key) else {
+            return Ok(());
+        };
+        let previous = std::mem::replace(&mut pending.failed, failed);
+        if let Err(e) = self.save() {
+            if let Some(pending) = self.pending.iter_mut().find(|pending| pending.key == key) {
+                pending.failed = previous;
+            }
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    /// Removes the log with the key, once the server logged it or the user
+    /// discarded it.
+    pub fn remove(&mut self, key: &str) -> Result<Option<Pending>, OutboxError> {
+        let Some(i) = self.pending.iter().position(|pending| pending.key == key) else {
+            return Ok(None);
+        };
+        let pending = self.pending.remove(i);
+        self.save()?;
+
+        Ok(Some(pending))
+    }
+
+    /// Replaces the file with the pending logs, writing them next to it first
+    /// so a crash meanwhile leaves the previous ones.
+    fn save(&self) -> Result<(), OutboxError> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let encoded = bucface_utils::encode(&self.pending).map_err(OutboxError::Encode)?;
+        let temporary = beside(path, ".tmp");
+        fs::write(&temporary, encoded)?;
+        fs::rename(&temporary, path)?;
+
+        Ok(())
+    }
+}
+
+/// Locks the file next to the outbox's, which unlike the outbox's is never
+/// replaced, until the returned [File] is dropped.
+fn lock(path: &Path) -> Result<File, OutboxError> {
+    let file = File::options()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(beside(path, ".lock"))?;
+    match file.try_lock() {
+        Ok(()) => Ok(file),
+        Err(TryLockError::WouldBlock) => Err(OutboxError::Locked),
+        Err(TryLockError::Error(e)) => Err(e.into()),
+    }
+}
+
+/// The path with the suffix appended to its file name.
+fn beside(path: &Path, suffix: &str) -> OsString {
+    let mut beside = path.as_os_str().to_owned();
+    beside.push(suffix);
+    beside
+}
+
+#[cfg(test)]
+mod outbox_tests {
+    use super::*;
+
+    #[test]
+    fn test_outbox() {
+        let path = std::env::temp_dir().join(format!("bucface-outbox-{}", rand::random::<u64>()));
+        let mut outbox = Outbox::open(&path).unwrap();
+        assert!(outbox.pending().is_empty());
+        assert!(!path.exists());
+
+        let first = outbox.push("main", rand::random()).unwrap();
+        let second = outbox.push("rig-2", rand::random()).unwrap();
+        let third = outbox.push("main", rand::random()).unwrap();
+        assert_eq!(first.key.len(), KEY_LENGTH);
+        assert_ne!(first.key, third.key);
+        assert_eq!(
+            outbox.pending_in("main").collect::<Vec<_>>(),
+            vec![&first, &third]
+        );
+
+        // Kept across restarts, in order
+        drop(outbox);
+        let mut outbox = Outbox::open(&path).unwrap();
+        assert_eq!(
+            outbox.pending(),
+            [first.clone(), second.clone(), third.clone()]
+        );
+
+        assert_eq!(outbox.remove(&first.key).unwrap(), Some(first.clone()));
+        assert_eq!(outbox.remove(&first.key).unwrap(), None);
+        drop(outbox);
+        let mut outbox = Outbox::open(&path).unwrap();
+        assert_eq!(outbox.pending(), [second.clone(), third.clone()]);
+
+        // Refused logs are kept, but not sent until they are resent
+        outbox.fail(&third.key, "InvalidField".into()).unwrap();
+        drop(outbox);
+        let mut outbox = Outbox::open(&path).unwrap();
+        assert_eq!(outbox.pending_in("main").count(), 1);
+        assert_eq!(outbox.unsent_in("main").count(), 0);
+        assert_eq!(outbox.pending()[1].failed.as_deref(), Some("InvalidField"));
+        outbox.resend(&third.key).unwrap();
+        assert_eq!(outbox.unsent_in("main").collect::<Vec<_>>(), vec![&third]);
+
+        // Only one client at a time writes to it
+        assert!(matches!(Outbox::open(&path), Err(OutboxError::Locked)));
+        drop(outbox);
+
+        fs::write(&path, b"not an outbox").unwrap();
+        assert!(matches!(Outbox::open(&path), Err(OutboxError::Decode(_))));
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(beside(&path, ".lock")).unwrap();
+    }
+}
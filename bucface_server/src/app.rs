@@ -1,33 +1,130 @@
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::Arc;
-
-use bucface_utils::{ClientMessage, EventDB, EventDBError};
+use bucface_utils::{
+    hex, valid_field_key, ClientMessage, Event, EventDB, EventDBError, FieldValue, Retraction,
+    ServerResponse, BLOB_CHUNK_SIZE, DEFAULT_CHANNEL, PROTOCOL_VERSION,
+};
 use surrealdb::Surreal;
 
-use crate::db::{get_event, get_events_since, insert_event};
+use crate::auth::{Session, UserStore};
+use crate::blob::{upload_chunk, BlobStore};
+use crate::channel::{valid_name, IdCounters};
+use crate::db::{
+    archive_channel, create_channel, edit_event, get_channel, get_channels, get_event,
+    get_events_between, get_events_matching, get_events_page, get_events_since, get_keyed_event,
+    get_revisions, get_thread, insert_event, insert_keyed_event, retract_event, search_events,
+    Scope,
+};
+
+/// The most [EventDB]s sent in a single [ServerResponse::Events], which
+/// also bounds the size of a page.
+pub const EVENTS_PER_BATCH: usize = 1000;
 
-/// Handles a [rmp](rmp_serde) encoded [ClientMessage] by updating the database
+/// What [handle_client_message] answers a [ClientMessage] with.
+#[derive(Debug)]
+pub struct Handled {
+    pub responses: Vec<ServerResponse>,
+    /// Whether the message changed events or channels, so every connection
+    /// is told about the responses and not only the one that sent it
+    pub changed: bool,
+}
+
+/// Handles a [ClientMessage] by updating the database
 /// and echoing the updated [EventDB]s or returning the requested [EventDB]s.
+/// Everything but [ClientMessage::Authenticate] and [ClientMessage::Hello] is
+/// refused until the [Session] is authenticated, unless the [UserStore] is
+/// empty. Events are only logged to and read from the channel the [Session]
+/// joined, and retracted events are only read if the [Session] asked to see
+/// them.
 ///
 /// # Arguments
-/// * `message` - A [rmp](rmp_serde) encoded slice of bytes representing a
-/// [ClientMessage]
+/// * `message` - The [ClientMessage] of a decoded
+///   [ClientRequest](bucface_utils::ClientRequest)
 /// * `db` - A [Surreal](surrealdb::Surreal) database connection
-/// * `id_count` - A type of primary key for [EventDB] structs
+/// * `ids` - The primary keys of [EventDB] structs within each channel, which
+///   should start at [next_event_ids](crate::db::next_event_ids)
+/// * `users` - The users allowed to authenticate
+/// * `blobs` - Where the contents of attachments are stored
+/// * `session` - The identity of the connection the message came from
 ///
 /// # Returns
 /// The return is intended to be sent back to the client, but can be handled in
 /// any way the caller sees fit.
 ///
-/// * `Result<EventDB, EventDBError>`
+/// * `Result<Handled, EventDBError>`, whose [responses](Handled::responses)
+///   are:
+/// - In the case of [ClientMessage::Authenticate], returns [Result] containing
+///   [ServerResponse::Authenticated] or [EventDBError::Unauthorized] if the
+///   token is unknown.
+/// - In the case of [ClientMessage::Hello], returns [Result] containing
+///   [ServerResponse::Hello] with the server's [PROTOCOL_VERSION].
 /// - In the case of [ClientMessage::NewEvent], returns [Result] containing
-/// the [EventDB] the [database](Surreal) was updated with or an [EventDBError]
-/// if the operation failed.
+///   the [EventDB] the [database](Surreal) was updated with, authored by the
+///   [Session]'s user, or an [EventDBError] if the operation failed, such as
+///   [EventDBError::Archived] for an archived channel,
+///   [EventDBError::NotFound] for a reply to a missing event or an
+///   attachment that was not uploaded, [EventDBError::InvalidName] for a
+///   blank or overlong tag or an attachment name with directories, or
+///   [EventDBError::InvalidField] for a field whose key is not an identifier
+///   or whose number is not finite.
+/// - In the case of [ClientMessage::NewEventOnce], returns [Result] containing
+///   the [EventDB] like [ClientMessage::NewEvent], or the one the [Session]'s
+///   user inserted with the key before, which is not inserted again and not
+///   [changed](Handled::changed).
 /// - In the case of [ClientMessage::GetEvent], returns [Result] containing
-/// the [EventDB] requested or an [EventDBError] if the operation failed.
+///   the [EventDB] requested or an [EventDBError] if the operation failed.
 /// - In the case of [ClientMessage::GetSince], returns [Result] containing
-/// a [Vec] of [EventDB]s requested or an [EventDBError] if the operation
-/// failed.
+///   the [EventDB]s requested in [ServerResponse::Events] batches or an
+///   [EventDBError] if the operation failed.
+/// - In the case of [ClientMessage::GetPage], returns [Result] containing a
+///   single [ServerResponse::Events] of at most [EVENTS_PER_BATCH] events,
+///   pointing at the start of the following page, or an [EventDBError] if the
+///   operation failed.
+/// - In the case of [ClientMessage::GetTimeRange], returns [Result] containing
+///   the [EventDB]s requested, oldest first, in [ServerResponse::Events]
+///   batches or an [EventDBError] if the operation failed.
+/// - In the case of [ClientMessage::Query], returns [Result] containing the
+///   [EventDB]s matching the filter in [ServerResponse::Events] batches or an
+///   [EventDBError] if the operation failed.
+/// - In the case of [ClientMessage::Search], returns [Result] containing
+///   [ServerResponse::Search] with at most [EVENTS_PER_BATCH] hits or an
+///   [EventDBError] if the operation failed.
+/// - In the case of [ClientMessage::Subscribe], returns [Result] containing
+///   [ServerResponse::Subscribed] with the id of the [Session]'s new
+///   subscription.
+/// - In the case of [ClientMessage::Unsubscribe], returns [Result] containing
+///   [ServerResponse::Unsubscribed] or [EventDBError::NotFound] if the
+///   [Session] has no subscription with the id.
+/// - In the case of [ClientMessage::CreateChannel], returns [Result]
+///   containing the new [ServerResponse::Channel], or
+///   [EventDBError::InvalidName] or [EventDBError::AlreadyExists].
+/// - In the case of [ClientMessage::ListChannels], returns [Result] containing
+///   [ServerResponse::Channels] or an [EventDBError] if the operation failed.
+/// - In the case of [ClientMessage::ArchiveChannel], returns [Result]
+///   containing the archived [ServerResponse::Channel],
+///   [EventDBError::Unauthorized] if the [Session]'s user is not an admin,
+///   [EventDBError::InvalidName] for the [DEFAULT_CHANNEL] or
+///   [EventDBError::NotFound].
+/// - In the case of [ClientMessage::JoinChannel], returns [Result] containing
+///   the joined [ServerResponse::Channel] or [EventDBError::NotFound].
+/// - In the case of [ClientMessage::EditEvent], returns [Result] containing
+///   the edited [EventDB], or [EventDBError::Unauthorized] if the [Session]'s
+///   user is not its author.
+/// - In the case of [ClientMessage::GetRevisions], returns [Result] containing
+///   [ServerResponse::Revisions] or [EventDBError::NotFound].
+/// - In the case of [ClientMessage::GetThread], returns [Result] containing
+///   the [EventDB]s of the thread in [ServerResponse::Events] batches or
+///   [EventDBError::NotFound].
+/// - In the case of [ClientMessage::RetractEvent], returns [Result]
+///   containing the retracted [EventDB], or [EventDBError::Unauthorized] if
+///   the [Session]'s user is neither its author nor an admin.
+/// - In the case of [ClientMessage::ShowRetracted], returns [Result]
+///   containing [ServerResponse::ShowRetracted], or
+///   [EventDBError::Unauthorized] if the [Session]'s user is not an admin.
+/// - In the case of [ClientMessage::UploadChunk], returns [Result] containing
+///   [ServerResponse::Uploaded], or [EventDBError::TooLarge] or
+///   [EventDBError::InvalidUpload].
+/// - In the case of [ClientMessage::GetBlob], returns [Result] containing the
+///   blob in [ServerResponse::Blob] chunks of at most [BLOB_CHUNK_SIZE] bytes
+///   or [EventDBError::NotFound].
 ///
 /// # Notes
 /// This function is kind of dumb. It is tailored to be called in a singular
@@ -35,83 +132,675 @@ use crate::db::{get_event, get_events_since, insert_event};
 /// this function in websockets.rs or put this function back into websocket.rs.
 /// This function was just because the indentation was getting to me.
 pub async fn handle_client_message<T: surrealdb::Connection>(
-    message: &[u8],
+    message: ClientMessage,
     db: &Surreal<T>,
-    id_count: Arc<AtomicU64>,
-) -> Result<Vec<EventDB>, EventDBError> {
-    let message: ClientMessage =
-        rmp_serde::decode::from_slice(message).map_err(EventDBError::RmpDecode)?;
+    ids: &IdCounters,
+    users: &UserStore,
+    blobs: &BlobStore,
+    session: &mut Session,
+) -> Result<Handled, EventDBError> {
+    let reply = |responses| Handled {
+        responses,
+        changed: false,
+    };
+    if let ClientMessage::Authenticate(token) = message {
+        log::debug!("Recieved authenticate message");
+        let name = match users.authenticate(&token) {
+            Some(user) => user.name.clone(),
+            None if !users.is_enabled() => crate::auth::ANONYMOUS.into(),
+            None => {
+                log::warn!("Authentication with an unknown token");
+                return Err(EventDBError::Unauthorized);
+            }
+        };
+        log::info!("Authenticated as {name}");
+        session.user = Some(name.clone());
+        session.subscriptions.authenticate();
+        return Ok(reply(vec![ServerResponse::Authenticated(name)]));
+    }
+
+    if let ClientMessage::Hello(version) = message {
+        log::debug!("Recieved hello message of protocol version {version}");
+        return Ok(reply(vec![ServerResponse::Hello(PROTOCOL_VERSION)]));
+    }
+
+    let Some(author) = session.author(users) else {
+        log::debug!("Refusing message from an unauthenticated connection");
+        return Err(EventDBError::Unauthorized);
+    };
 
-    match message {
+    let scope = session.scope();
+    let channel = &scope.channel;
+    let changed = matches!(
+        message,
+        ClientMessage::NewEvent(_)
+            | ClientMessage::NewEventOnce { .. }
+            | ClientMessage::EditEvent { .. }
+            | ClientMessage::RetractEvent { .. }
+            | ClientMessage::CreateChannel(_)
+            | ClientMessage::ArchiveChannel(_)
+    );
+    let responses = match message {
         ClientMessage::NewEvent(event) => {
-            let id = id_count.fetch_add(1, Ordering::SeqCst);
             log::debug!("Recieved insert event message");
-            log::debug!("Inserting {event:?} into database at {id}");
-            let server_event = EventDB::from(event, id);
+            let server_event = new_event(event, author, &scope, db, ids, blobs).await?;
+            // The id is not handed back on failure: another insert may have
+            // taken the next one already, so a gap is left instead.
             let db_response = insert_event(&server_event, db).await.map_err(|e| {
                 log::error!("Error inserting event into database: {:?}", e);
-                id_count.fetch_sub(1, Ordering::SeqCst);
                 e
             })?;
             assert_eq!(db_response.len(), 1);
             assert_eq!(db_response[0], server_event);
             log::debug!("Inserted {server_event:?} into database");
-            Ok(db_response)
+            Ok(db_response
+                .into_iter()
+                .map(|event| ServerResponse::Event(Box::new(event)))
+                .collect())
+        }
+        ClientMessage::NewEventOnce {
+            channel,
+            key,
+            event,
+        } => {
+            log::debug!("Recieved insert event once message");
+            // Sent again after joining another channel, it still belongs to
+            // the one it was written in
+            let scope = Scope { channel, ..scope };
+            let channel = &scope.channel;
+            if let Some(event) = get_keyed_event(channel, author, &key, db).await? {
+                log::debug!("Already inserted {channel}/{} with key {key}", event._id);
+                return Ok(reply(vec![ServerResponse::Event(Box::new(event))]));
+            }
+            let server_event = new_event(event, author, &scope, db, ids, blobs).await?;
+            let event = match insert_keyed_event(&server_event, &key, db).await {
+                Ok(event) => event,
+                // Sent again meanwhile, and inserted by the other request
+                // first, which makes the key's unique index refuse this one
+                Err(e) => match get_keyed_event(channel, author, &key, db).await? {
+                    Some(event) => {
+                        log::debug!(
+                            "Concurrently inserted {channel}/{} with key {key}",
+                            event._id
+                        );
+                        return Ok(reply(vec![ServerResponse::Event(Box::new(event))]));
+                    }
+                    None => {
+                        log::error!("Error inserting event into database: {:?}", e);
+                        return Err(e);
+                    }
+                },
+            };
+            log::debug!("Inserted {event:?} into database");
+
+            Ok(vec![ServerResponse::Event(Box::new(event))])
         }
         ClientMessage::GetEvent(id) => {
             log::debug!("Recieved get event message");
-            let event = get_event(id, db).await?;
+            let event = get_event(&scope, id, db).await?;
 
-            Ok(vec![event])
+            Ok(vec![ServerResponse::Event(Box::new(event))])
         }
         ClientMessage::GetSince(timestamp) => {
             log::debug!("Recieved get since message");
-            get_events_since(timestamp, db).await
+            let events = get_events_since(&scope, timestamp, db).await?;
+
+            Ok(batches(events, EVENTS_PER_BATCH))
+        }
+        ClientMessage::GetPage(query) => {
+            log::debug!("Recieved get page message");
+            let limit = (query.limit as usize).min(EVENTS_PER_BATCH);
+            // One more than asked for tells where the following page starts
+            let mut events =
+                get_events_page(&scope, query.start, query.direction, limit + 1, db).await?;
+            let next = match events.len() > limit {
+                true => events.pop().map(|event| event._id),
+                false => None,
+            };
+
+            Ok(vec![ServerResponse::Events { events, next }])
+        }
+        ClientMessage::GetTimeRange { range, limit } => {
+            log::debug!("Recieved get time range message");
+            let limit = limit.map(|limit| limit as usize);
+            let events = get_events_between(&scope, &range, limit, db).await?;
+
+            Ok(batches(events, EVENTS_PER_BATCH))
+        }
+        ClientMessage::Query { filter, limit } => {
+            log::debug!("Recieved query message");
+            let limit = limit.map(|limit| limit as usize);
+            let events = get_events_matching(&scope, &filter, limit, db).await?;
+
+            Ok(batches(events, EVENTS_PER_BATCH))
+        }
+        ClientMessage::Search { terms, limit } => {
+            log::debug!("Recieved search message");
+            let limit = (limit as usize).min(EVENTS_PER_BATCH);
+            let hits = search_events(&scope, &terms, limit, db).await?;
+
+            Ok(vec![ServerResponse::Search(hits)])
+        }
+        ClientMessage::Subscribe(filter) => {
+            log::debug!("Recieved subscribe message");
+            let id = session.subscriptions.subscribe(filter);
+
+            Ok(vec![ServerResponse::Subscribed(id)])
+        }
+        ClientMessage::Unsubscribe(id) => {
+            log::debug!("Recieved unsubscribe message");
+            match session.subscriptions.unsubscribe(id) {
+                true => Ok(vec![ServerResponse::Unsubscribed(id)]),
+                false => Err(EventDBError::NotFound),
+            }
+        }
+        ClientMessage::EditEvent { id, event } => {
+            log::debug!("Recieved edit event message");
+            let current = get_event(
+                &Scope {
+                    retracted: false,
+                    ..scope.clone()
+                },
+                id,
+                db,
+            )
+            .await?;
+            if current.author != author {
+                log::warn!("{author} may not edit {channel}/{id} of {}", current.author);
+                return Err(EventDBError::Unauthorized);
+            }
+            if get_channel(channel, db).await?.archived.is_some() {
+                return Err(EventDBError::Archived);
+            }
+            let edited = edit_event(&current, author, &event, db).await?;
+            log::debug!("Edited {channel}/{id} to revision {}", edited.revision);
+
+            Ok(vec![ServerResponse::Event(Box::new(edited))])
+        }
+        ClientMessage::GetRevisions(id) => {
+            log::debug!("Recieved get revisions message");
+            let revisions = get_revisions(&scope, id, db).await?;
+
+            Ok(vec![ServerResponse::Revisions { id, revisions }])
+        }
+        ClientMessage::GetThread(id) => {
+            log::debug!("Recieved get thread message");
+            let thread = get_thread(&scope, id, db).await?;
+
+            Ok(batches(thread, EVENTS_PER_BATCH))
+        }
+        ClientMessage::RetractEvent { id, reason } => {
+            log::debug!("Recieved retract event message");
+            let current = get_event(
+                &Scope {
+                    retracted: true,
+                    ..scope.clone()
+                },
+                id,
+                db,
+            )
+            .await?;
+            if current.author != author && !session.is_admin(users) {
+                log::warn!(
+                    "{author} may not retract {channel}/{id} of {}",
+                    current.author
+                );
+                return Err(EventDBError::Unauthorized);
+            }
+            if get_channel(channel, db).await?.archived.is_some() {
+                return Err(EventDBError::Archived);
+            }
+            let retraction = Retraction {
+                author: author.into(),
+                time: chrono::Utc::now().naive_utc(),
+                reason,
+            };
+            let retracted = retract_event(&current, &retraction, db).await?;
+            log::info!("{author} retracted {channel}/{id}");
+
+            Ok(vec![ServerResponse::Event(Box::new(retracted))])
+        }
+        ClientMessage::ShowRetracted(show) => {
+            log::debug!("Recieved show retracted message");
+            // Hiding them again is always allowed
+            if show && !session.is_admin(users) {
+                log::warn!("{author} may not see retracted events");
+                return Err(EventDBError::Unauthorized);
+            }
+            session.show_retracted = show;
+
+            Ok(vec![ServerResponse::ShowRetracted(show)])
+        }
+        ClientMessage::UploadChunk {
+            digest,
+            size,
+            offset,
+            data,
+        } => {
+            log::debug!("Recieved upload chunk message");
+            let received =
+                upload_chunk(blobs, &mut session.upload, &digest, size, offset, data).await?;
+
+            Ok(vec![ServerResponse::Uploaded { digest, received }])
+        }
+        ClientMessage::GetBlob(digest) => {
+            log::debug!("Recieved get blob message");
+            let data = match hex::decode_sha256(&digest) {
+                Some(decoded) => blobs.get(&decoded).await.map_err(EventDBError::Blob)?,
+                None => None,
+            };
+            let data = data.ok_or(EventDBError::NotFound)?;
+
+            Ok(blob_chunks(digest, data, BLOB_CHUNK_SIZE))
+        }
+        ClientMessage::CreateChannel(name) => {
+            log::debug!("Recieved create channel message");
+            if !valid_name(&name) {
+                return Err(EventDBError::InvalidName);
+            }
+            let channel = create_channel(&name, db).await?;
+            log::info!("Created channel {name}");
+
+            Ok(vec![ServerResponse::Channel(channel)])
+        }
+        ClientMessage::ListChannels => {
+            log::debug!("Recieved list channels message");
+            let channels = get_channels(db).await?;
+
+            Ok(vec![ServerResponse::Channels(channels)])
+        }
+        ClientMessage::ArchiveChannel(name) => {
+            log::debug!("Recieved archive channel message");
+            if !session.is_admin(users) {
+                log::warn!("{author} may not archive {name}");
+                return Err(EventDBError::Unauthorized);
+            }
+            // Nothing could be logged without joining another channel first
+            if name == DEFAULT_CHANNEL {
+                return Err(EventDBError::InvalidName);
+            }
+            let channel = archive_channel(&name, db).await?;
+            log::info!("Archived channel {name}");
+
+            Ok(vec![ServerResponse::Channel(channel)])
         }
+        ClientMessage::JoinChannel(name) => {
+            log::debug!("Recieved join channel message");
+            let channel = get_channel(&name, db).await?;
+            session.subscriptions.join(&channel.name);
+
+            Ok(vec![ServerResponse::Channel(channel)])
+        }
+        ClientMessage::Authenticate(_) | ClientMessage::Hello(_) => unreachable!("Handled above"),
         ClientMessage::Ping(_) => unreachable!("Should be covered in websocket.rs"),
+    }?;
+
+    Ok(Handled { responses, changed })
+}
+
+/// Checks a new [Event] for the channel of the [Scope], giving it the next id
+/// of the channel and the `author`. Its attachments have to be uploaded
+/// already and the event it replies to must not be retracted.
+async fn new_event<T: surrealdb::Connection>(
+    mut event: Event,
+    author: &str,
+    scope: &Scope,
+    db: &Surreal<T>,
+    ids: &IdCounters,
+    blobs: &BlobStore,
+) -> Result<EventDB, EventDBError> {
+    let channel = &scope.channel;
+    if get_channel(channel, db).await?.archived.is_some() {
+        return Err(EventDBError::Archived);
+    }
+    if !event.tags.iter().all(|tag| valid_name(tag)) {
+        return Err(EventDBError::InvalidName);
+    }
+    let valid_field = |(key, value): (&String, &FieldValue)| {
+        valid_field_key(key) && !matches!(value, FieldValue::Float(float) if !float.is_finite())
+    };
+    if !event.fields.iter().all(valid_field) {
+        return Err(EventDBError::InvalidField);
     }
+    for attachment in &mut event.attachments {
+        let digest = blobs.check(attachment).await?;
+        attachment.digest = hex::encode(&digest);
+    }
+    // Retracted events take no more replies
+    if let Some(parent) = event.parent {
+        let visible = Scope {
+            retracted: false,
+            ..scope.clone()
+        };
+        get_event(&visible, parent, db).await?;
+    }
+    let id = ids.next(channel);
+    log::debug!("Inserting {event:?} into database at {channel}/{id}");
+    let mut server_event = EventDB::from(event, id);
+    server_event.author = author.into();
+    server_event.channel = channel.clone();
+
+    Ok(server_event)
+}
+
+/// Splits [EventDB]s into [ServerResponse::Events] of at most `size` events,
+/// each pointing at the id the next one starts with. No events
+/// still make a single, empty batch.
+fn batches(events: Vec<EventDB>, size: usize) -> Vec<ServerResponse> {
+    let mut batches = Vec::with_capacity(events.len().div_ceil(size));
+    let mut events = events.into_iter().peekable();
+    while events.peek().is_some() {
+        let batch = events.by_ref().take(size).collect::<Vec<EventDB>>();
+        let next = events.peek().map(|event| event._id);
+        batches.push(ServerResponse::Events {
+            events: batch,
+            next,
+        });
+    }
+
+    if batches.is_empty() {
+        batches.push(ServerResponse::Events {
+            events: Vec::new(),
+            next: None,
+        });
+    }
+
+    batches
+}
+
+/// Splits a blob into [ServerResponse::Blob]s of at most `size` bytes. An
+/// empty blob still makes a single, empty chunk.
+fn blob_chunks(digest: String, data: Vec<u8>, size: usize) -> Vec<ServerResponse> {
+    let total = data.len() as u64;
+    if data.is_empty() {
+        return vec![ServerResponse::Blob {
+            digest,
+            size: 0,
+            offset: 0,
+            data,
+        }];
+    }
+
+    data.chunks(size)
+        .enumerate()
+        .map(|(i, chunk)| ServerResponse::Blob {
+            digest: digest.clone(),
+            size: total,
+            offset: (i * size) as u64,
+            data: chunk.to_vec(),
+        })
+        .collect()
 }
 
 #[cfg(test)]
 mod app_tests {
-    use bucface_utils::Event;
+    use bucface_utils::{Attachment, Direction, Event, EventFilter, PageQuery, Severity};
     use rand::Rng;
     use surrealdb::engine::local::Mem;
 
-    use crate::db::start_db;
+    use sha2::{Digest, Sha256};
+
+    use crate::auth::{User, ANONYMOUS};
+    use crate::db::{next_event_ids, start_db};
+
+    use std::sync::Arc;
 
     use super::*;
 
+    fn events(responses: Vec<ServerResponse>) -> Vec<EventDB> {
+        responses
+            .into_iter()
+            .flat_map(|response| match response {
+                ServerResponse::Event(event) => vec![*event],
+                ServerResponse::Events { events, .. } => events,
+                other => panic!("Expected events, got {other:?}"),
+            })
+            .collect()
+    }
+
     #[tokio::test]
     async fn test_handle_new_event() {
         let mut rng = rand::thread_rng();
 
         for _ in 0..100 {
             let mut db = Surreal::new::<Mem>(()).await.unwrap();
-            let id_counter = Arc::new(AtomicU64::new(0));
-            start_db(&mut db).await.unwrap();
+            let ids = Arc::new(IdCounters::default());
+            start_db(&mut db, "Bucface", "Events").await.unwrap();
 
             let event: Event = rng.gen();
             let client_message = ClientMessage::NewEvent(event.clone());
-            let buf = rmp_serde::encode::to_vec(&client_message).unwrap();
-            let result = handle_client_message(&buf, &db, id_counter).await.unwrap();
+            let result = handle_client_message(
+                client_message,
+                &db,
+                &ids,
+                &UserStore::default(),
+                &BlobStore::memory(0),
+                &mut Session::default(),
+            )
+            .await
+            .unwrap();
+            assert!(result.changed);
+            let result = events(result.responses);
             assert_eq!(result.len(), 1);
             assert_eq!(result[0].event, event.event);
+            assert_eq!(result[0].author, ANONYMOUS);
         }
     }
 
+    #[tokio::test]
+    async fn test_handle_new_event_once() {
+        let mut db = Surreal::new::<Mem>(()).await.unwrap();
+        let ids = IdCounters::default();
+        let users = UserStore::default();
+        start_db(&mut db, "Bucface", "Events").await.unwrap();
+
+        let event: Event = rand::random();
+        let once = |key: &str| ClientMessage::NewEventOnce {
+            channel: DEFAULT_CHANNEL.into(),
+            key: key.into(),
+            event: event.clone(),
+        };
+        let mut session = Session::default();
+        let first = send(once("a"), &db, &ids, &users, &mut session)
+            .await
+            .map(events)
+            .unwrap();
+        assert_eq!(first[0].event, event.event);
+
+        // Sending it again, even from another connection, inserts nothing
+        let mut session = Session::default();
+        let again = send(once("a"), &db, &ids, &users, &mut session)
+            .await
+            .map(events)
+            .unwrap();
+        assert_eq!(again, first);
+        let other = send(once("b"), &db, &ids, &users, &mut session)
+            .await
+            .map(events)
+            .unwrap();
+        assert_eq!(other[0]._id, first[0]._id + 1);
+        let all = send(ClientMessage::GetSince(0), &db, &ids, &users, &mut session)
+            .await
+            .map(events)
+            .unwrap();
+        assert_eq!(all.len(), 2);
+
+        // Refused events do not use up their key
+        let invalid = ClientMessage::NewEventOnce {
+            channel: DEFAULT_CHANNEL.into(),
+            key: "c".into(),
+            event: Event {
+                fields: [("not a key".to_string(), FieldValue::Int(1))].into(),
+                ..event.clone()
+            },
+        };
+        let refused = send(invalid, &db, &ids, &users, &mut session).await;
+        assert!(matches!(refused, Err(EventDBError::InvalidField)));
+        let accepted = send(once("c"), &db, &ids, &users, &mut session)
+            .await
+            .map(events)
+            .unwrap();
+        assert_eq!(accepted[0].event, event.event);
+
+        // Sent again before the first one is inserted, it is answered with
+        // the event of whichever is inserted first
+        let blobs = BlobStore::memory(0);
+        let (mut first_session, mut second_session) = (Session::default(), Session::default());
+        let (first, second) = tokio::join!(
+            handle_client_message(once("d"), &db, &ids, &users, &blobs, &mut first_session),
+            handle_client_message(once("d"), &db, &ids, &users, &blobs, &mut second_session),
+        );
+        let (first, second) = (first.unwrap(), second.unwrap());
+        assert_ne!(first.changed, second.changed);
+        assert_eq!(events(first.responses), events(second.responses));
+    }
+
+    #[tokio::test]
+    async fn test_handle_new_event_once_scope() {
+        let mut db = Surreal::new::<Mem>(()).await.unwrap();
+        let ids = IdCounters::default();
+        start_db(&mut db, "Bucface", "Events").await.unwrap();
+        let users = UserStore::new(
+            ["alice", "bob"]
+                .map(|name| User {
+                    name: name.into(),
+                    token_sha256: Sha256::digest(format!("{name}-token")).into(),
+                    admin: false,
+                })
+                .to_vec(),
+        );
+        let mut alice = Session {
+            user: Some("alice".into()),
+            ..Default::default()
+        };
+        let mut bob = Session {
+            user: Some("bob".into()),
+            ..Default::default()
+        };
+        let once = |channel: &str, key: &str, text: &str| ClientMessage::NewEventOnce {
+            channel: channel.into(),
+            key: key.into(),
+            event: Event {
+                event: text.into(),
+                ..rand::random()
+            },
+        };
+
+        // Keys are only those of their author
+        let pump = send(
+            once(DEFAULT_CHANNEL, "a", "Pump failed"),
+            &db,
+            &ids,
+            &users,
+            &mut alice,
+        )
+        .await
+        .map(events)
+        .unwrap();
+        let cooling = send(
+            once(DEFAULT_CHANNEL, "a", "Cooling failed"),
+            &db,
+            &ids,
+            &users,
+            &mut bob,
+        )
+        .await
+        .map(events)
+        .unwrap();
+        assert_eq!(cooling[0].author, "bob");
+        assert_eq!(cooling[0].event, "Cooling failed");
+        assert_eq!(cooling[0]._id, pump[0]._id + 1);
+
+        // Logs go to the channel they name, whichever one is joined
+        send(
+            ClientMessage::CreateChannel("rig-2".into()),
+            &db,
+            &ids,
+            &users,
+            &mut alice,
+        )
+        .await
+        .unwrap();
+        send(
+            ClientMessage::JoinChannel("rig-2".into()),
+            &db,
+            &ids,
+            &users,
+            &mut alice,
+        )
+        .await
+        .unwrap();
+        let again = send(
+            once(DEFAULT_CHANNEL, "a", "Pump failed"),
+            &db,
+            &ids,
+            &users,
+            &mut alice,
+        )
+        .await
+        .map(events)
+        .unwrap();
+        assert_eq!(again, pump);
+        let valve = send(
+            once(DEFAULT_CHANNEL, "b", "Valve stuck"),
+            &db,
+            &ids,
+            &users,
+            &mut alice,
+        )
+        .await
+        .map(events)
+        .unwrap();
+        assert_eq!(valve[0].channel, DEFAULT_CHANNEL);
+        assert_eq!(valve[0]._id, cooling[0]._id + 1);
+        let rig = send(
+            once("rig-2", "b", "Valve stuck"),
+            &db,
+            &ids,
+            &users,
+            &mut alice,
+        )
+        .await
+        .map(events)
+        .unwrap();
+        assert_eq!(rig[0].channel, "rig-2");
+        assert_eq!(rig[0]._id, 0);
+
+        let missing = send(
+            once("rig-3", "c", "Valve stuck"),
+            &db,
+            &ids,
+            &users,
+            &mut alice,
+        )
+        .await;
+        assert!(matches!(missing, Err(EventDBError::NotFound)));
+    }
+
     #[tokio::test]
     async fn test_handle_get_since() {
         let mut rng = rand::thread_rng();
         let mut db = Surreal::new::<Mem>(()).await.unwrap();
-        let id_counter = Arc::new(AtomicU64::new(0));
-        start_db(&mut db).await.unwrap();
+        let ids = Arc::new(IdCounters::default());
+        start_db(&mut db, "Bucface", "Events").await.unwrap();
 
         let send_message = |message: ClientMessage| {
-            let buf = rmp_serde::encode::to_vec(&message).unwrap();
             let db = db.clone();
-            let id_counter = id_counter.clone();
-            async move { handle_client_message(&buf, &db, id_counter).await }
+            let ids = ids.clone();
+            async move {
+                let mut session = Session::default();
+                let blobs = BlobStore::memory(0);
+                handle_client_message(
+                    message,
+                    &db,
+                    &ids,
+                    &UserStore::default(),
+                    &blobs,
+                    &mut session,
+                )
+                .await
+                .map(|handled| events(handled.responses))
+            }
         };
 
         let mut events = (0..10).map(|_| rng.gen()).collect::<Vec<Event>>();
@@ -129,10 +818,1030 @@ mod app_tests {
         let events_db = events
             .drain(..)
             .enumerate()
-            .map(|(i, event)| EventDB::from(event, i.try_into().unwrap()))
+            .map(|(i, event)| EventDB {
+                author: ANONYMOUS.into(),
+                ..EventDB::from(event, i.try_into().unwrap())
+            })
             .collect::<Vec<EventDB>>();
 
         assert_eq!(result.len(), events_db.len());
         assert_eq!(result, events_db);
     }
+
+    #[tokio::test]
+    async fn test_handle_get_page() {
+        let mut rng = rand::thread_rng();
+        let mut db = Surreal::new::<Mem>(()).await.unwrap();
+        let ids = Arc::new(IdCounters::default());
+        let users = UserStore::default();
+        let mut session = Session::default();
+        start_db(&mut db, "Bucface", "Events").await.unwrap();
+
+        let empty = send(ClientMessage::GetSince(0), &db, &ids, &users, &mut session)
+            .await
+            .unwrap();
+        assert_eq!(
+            empty,
+            vec![ServerResponse::Events {
+                events: Vec::new(),
+                next: None
+            }]
+        );
+
+        for _ in 0..5 {
+            let message = ClientMessage::NewEvent(rng.gen());
+            send(message, &db, &ids, &users, &mut session)
+                .await
+                .unwrap();
+        }
+
+        let mut start = None;
+        let mut pages = Vec::new();
+        loop {
+            let query = PageQuery {
+                start,
+                direction: Direction::Descending,
+                limit: 2,
+            };
+            let mut response = send(
+                ClientMessage::GetPage(query),
+                &db,
+                &ids,
+                &users,
+                &mut session,
+            )
+            .await
+            .unwrap();
+            assert_eq!(response.len(), 1);
+            let Some(ServerResponse::Events { events, next }) = response.pop() else {
+                panic!("Expected a page");
+            };
+            pages.push(events.iter().map(|event| event._id).collect::<Vec<u64>>());
+            match next {
+                Some(next) => start = Some(next),
+                None => break,
+            }
+        }
+        assert_eq!(pages, vec![vec![4, 3], vec![2, 1], vec![0]]);
+    }
+
+    #[tokio::test]
+    async fn test_ids_unique_across_restart() {
+        let mut rng = rand::thread_rng();
+        let mut db = Surreal::new::<Mem>(()).await.unwrap();
+        start_db(&mut db, "Bucface", "Events").await.unwrap();
+
+        let insert_all = |events: Vec<Event>, ids: Arc<IdCounters>| {
+            let db = db.clone();
+            async move {
+                let inserts = events.into_iter().map(|event| {
+                    let message = ClientMessage::NewEvent(event);
+                    let db = db.clone();
+                    let ids = ids.clone();
+                    async move {
+                        let mut session = Session::default();
+                        let users = UserStore::default();
+                        let blobs = BlobStore::memory(0);
+                        handle_client_message(message, &db, &ids, &users, &blobs, &mut session)
+                            .await
+                    }
+                });
+                futures::future::join_all(inserts).await
+            }
+        };
+
+        let ids = Arc::new(IdCounters::new(next_event_ids(&db).await.unwrap()));
+        let events = (0..20).map(|_| rng.gen()).collect::<Vec<Event>>();
+        for response in insert_all(events, ids).await {
+            response.unwrap();
+        }
+
+        // A restarted server only has the database to go on
+        let next_ids = next_event_ids(&db).await.unwrap();
+        assert_eq!(next_ids[DEFAULT_CHANNEL], 20);
+        let ids = Arc::new(IdCounters::new(next_ids));
+        let events = (0..20).map(|_| rng.gen()).collect::<Vec<Event>>();
+        for response in insert_all(events, ids).await {
+            response.unwrap();
+        }
+
+        let mut ids = get_events_since(&Scope::default(), 0, &db)
+            .await
+            .unwrap()
+            .iter()
+            .map(|event| event._id)
+            .collect::<Vec<u64>>();
+        ids.sort();
+        assert_eq!(ids, (0..40).collect::<Vec<u64>>());
+    }
+
+    #[test]
+    fn test_batches() {
+        let events = (0..7)
+            .map(|id| EventDB::from(rand::random(), id * 2))
+            .collect::<Vec<EventDB>>();
+
+        let result = batches(events.clone(), 3);
+        assert_eq!(
+            result,
+            vec![
+                ServerResponse::Events {
+                    events: events[0..3].to_vec(),
+                    next: Some(6),
+                },
+                ServerResponse::Events {
+                    events: events[3..6].to_vec(),
+                    next: Some(12),
+                },
+                ServerResponse::Events {
+                    events: events[6..].to_vec(),
+                    next: None,
+                },
+            ]
+        );
+
+        let result = batches(events[..3].to_vec(), 3);
+        assert_eq!(
+            result,
+            vec![ServerResponse::Events {
+                events: events[..3].to_vec(),
+                next: None,
+            }]
+        );
+        assert_eq!(
+            batches(Vec::new(), 3),
+            vec![ServerResponse::Events {
+                events: Vec::new(),
+                next: None,
+            }]
+        );
+    }
+
+    async fn send<C: surrealdb::Connection>(
+        message: ClientMessage,
+        db: &Surreal<C>,
+        ids: &IdCounters,
+        users: &UserStore,
+        session: &mut Session,
+    ) -> Result<Vec<ServerResponse>, EventDBError> {
+        handle_client_message(message, db, ids, users, &BlobStore::memory(0), session)
+            .await
+            .map(|handled| handled.responses)
+    }
+
+    #[tokio::test]
+    async fn test_authentication() {
+        let mut rng = rand::thread_rng();
+        let mut db = Surreal::new::<Mem>(()).await.unwrap();
+        let ids = Arc::new(IdCounters::default());
+        start_db(&mut db, "Bucface", "Events").await.unwrap();
+
+        let users = UserStore::new(vec![User {
+            name: "alice".into(),
+            token_sha256: Sha256::digest(b"alice-token").into(),
+            admin: false,
+        }]);
+        let mut session = Session::default();
+
+        let event = Event {
+            author: "mallory".into(),
+            ..rng.gen()
+        };
+
+        let unauthenticated = send(
+            ClientMessage::NewEvent(event.clone()),
+            &db,
+            &ids,
+            &users,
+            &mut session,
+        )
+        .await;
+        assert!(matches!(unauthenticated, Err(EventDBError::Unauthorized)));
+        let unauthenticated =
+            send(ClientMessage::GetSince(0), &db, &ids, &users, &mut session).await;
+        assert!(matches!(unauthenticated, Err(EventDBError::Unauthorized)));
+
+        let wrong_token = send(
+            ClientMessage::Authenticate("mallory-token".into()),
+            &db,
+            &ids,
+            &users,
+            &mut session,
+        )
+        .await;
+        assert!(matches!(wrong_token, Err(EventDBError::Unauthorized)));
+
+        let authenticated = send(
+            ClientMessage::Authenticate("alice-token".into()),
+            &db,
+            &ids,
+            &users,
+            &mut session,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            authenticated,
+            vec![ServerResponse::Authenticated("alice".into())]
+        );
+
+        let inserted = events(
+            send(
+                ClientMessage::NewEvent(event.clone()),
+                &db,
+                &ids,
+                &users,
+                &mut session,
+            )
+            .await
+            .unwrap(),
+        );
+        assert_eq!(inserted[0].author, "alice");
+        assert_eq!(inserted[0].event, event.event);
+        assert_eq!(
+            get_event(&Scope::default(), 0, &db).await.unwrap().author,
+            "alice"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_subscriptions() {
+        let mut db = Surreal::new::<Mem>(()).await.unwrap();
+        let ids = Arc::new(IdCounters::default());
+        let users = UserStore::default();
+        let mut session = Session::default();
+        start_db(&mut db, "Bucface", "Events").await.unwrap();
+
+        let filter = EventFilter {
+            keywords: ["pump".to_string()].into(),
+            ..Default::default()
+        };
+        let subscribed = send(
+            ClientMessage::Subscribe(filter),
+            &db,
+            &ids,
+            &users,
+            &mut session,
+        )
+        .await
+        .unwrap();
+        let [ServerResponse::Subscribed(id)] = subscribed[..] else {
+            panic!("Expected a subscription, got {subscribed:?}");
+        };
+
+        let pump = EventDB {
+            event: "Pump failed".into(),
+            ..EventDB::from(rand::random(), 0)
+        };
+        let cooling = EventDB {
+            event: "Cooling failed".into(),
+            ..pump.clone()
+        };
+        assert!(session.subscriptions.wants(&pump));
+        assert!(!session.subscriptions.wants(&cooling));
+
+        let unsubscribed = send(
+            ClientMessage::Unsubscribe(id),
+            &db,
+            &ids,
+            &users,
+            &mut session,
+        )
+        .await
+        .unwrap();
+        assert_eq!(unsubscribed, vec![ServerResponse::Unsubscribed(id)]);
+        assert!(session.subscriptions.wants(&cooling));
+
+        let unknown = send(
+            ClientMessage::Unsubscribe(id),
+            &db,
+            &ids,
+            &users,
+            &mut session,
+        )
+        .await;
+        assert!(matches!(unknown, Err(EventDBError::NotFound)));
+    }
+
+    #[tokio::test]
+    async fn test_edit_event() {
+        let mut db = Surreal::new::<Mem>(()).await.unwrap();
+        let ids = IdCounters::default();
+        start_db(&mut db, "Bucface", "Events").await.unwrap();
+
+        let users = UserStore::new(
+            ["alice", "bob", "root"]
+                .map(|name| User {
+                    name: name.into(),
+                    token_sha256: Sha256::digest(format!("{name}-token")).into(),
+                    admin: name == "root",
+                })
+                .to_vec(),
+        );
+        let mut alice = Session {
+            user: Some("alice".into()),
+            ..Default::default()
+        };
+        let mut bob = Session {
+            user: Some("bob".into()),
+            ..Default::default()
+        };
+
+        let inserted = events(
+            send(
+                ClientMessage::NewEvent(rand::random()),
+                &db,
+                &ids,
+                &users,
+                &mut alice,
+            )
+            .await
+            .unwrap(),
+        );
+        let edit = |event: &str| ClientMessage::EditEvent {
+            id: inserted[0]._id,
+            event: event.into(),
+        };
+
+        let refused = send(edit("bob was here"), &db, &ids, &users, &mut bob).await;
+        assert!(matches!(refused, Err(EventDBError::Unauthorized)));
+        let edited = events(
+            send(edit("corrected"), &db, &ids, &users, &mut alice)
+                .await
+                .unwrap(),
+        );
+        assert_eq!(edited[0].event, "corrected");
+        assert_eq!(edited[0].revision, 1);
+
+        // Anyone may read the history
+        let revisions = send(
+            ClientMessage::GetRevisions(inserted[0]._id),
+            &db,
+            &ids,
+            &users,
+            &mut bob,
+        )
+        .await
+        .unwrap();
+        let [ServerResponse::Revisions { id, revisions }] = &revisions[..] else {
+            panic!("Expected revisions, got {revisions:?}");
+        };
+        assert_eq!(*id, inserted[0]._id);
+        assert_eq!(revisions.len(), 2);
+        assert_eq!(revisions[0].event, inserted[0].event);
+        assert_eq!(revisions[1].event, "corrected");
+
+        let missing = send(
+            ClientMessage::EditEvent {
+                id: 42,
+                event: "nothing".into(),
+            },
+            &db,
+            &ids,
+            &users,
+            &mut alice,
+        )
+        .await;
+        assert!(matches!(missing, Err(EventDBError::NotFound)));
+
+        let mut root = Session {
+            user: Some("root".into()),
+            ..Default::default()
+        };
+        for (message, session) in [
+            (ClientMessage::CreateChannel("rig-2".into()), &mut root),
+            (ClientMessage::JoinChannel("rig-2".into()), &mut alice),
+        ] {
+            send(message, &db, &ids, &users, session).await.unwrap();
+        }
+        let inserted = events(
+            send(
+                ClientMessage::NewEvent(rand::random()),
+                &db,
+                &ids,
+                &users,
+                &mut alice,
+            )
+            .await
+            .unwrap(),
+        );
+        send(
+            ClientMessage::ArchiveChannel("rig-2".into()),
+            &db,
+            &ids,
+            &users,
+            &mut root,
+        )
+        .await
+        .unwrap();
+        let edit = ClientMessage::EditEvent {
+            id: inserted[0]._id,
+            event: "too late".into(),
+        };
+        let archived = send(edit, &db, &ids, &users, &mut alice).await;
+        assert!(matches!(archived, Err(EventDBError::Archived)));
+    }
+
+    #[tokio::test]
+    async fn test_retract_event() {
+        let mut db = Surreal::new::<Mem>(()).await.unwrap();
+        let ids = IdCounters::default();
+        start_db(&mut db, "Bucface", "Events").await.unwrap();
+
+        let users = UserStore::new(
+            ["alice", "bob", "root"]
+                .map(|name| User {
+                    name: name.into(),
+                    token_sha256: Sha256::digest(format!("{name}-token")).into(),
+                    admin: name == "root",
+                })
+                .to_vec(),
+        );
+        let session = |name: &str| Session {
+            user: Some(name.into()),
+            ..Default::default()
+        };
+        let (mut alice, mut bob, mut root) = (session("alice"), session("bob"), session("root"));
+
+        let mut inserted = Vec::new();
+        for _ in 0..3 {
+            inserted.extend(events(
+                send(
+                    ClientMessage::NewEvent(rand::random()),
+                    &db,
+                    &ids,
+                    &users,
+                    &mut alice,
+                )
+                .await
+                .unwrap(),
+            ));
+        }
+        let retract = |id: u64, reason: &str| ClientMessage::RetractEvent {
+            id,
+            reason: reason.into(),
+        };
+
+        let refused = send(retract(0, "spam"), &db, &ids, &users, &mut bob).await;
+        assert!(matches!(refused, Err(EventDBError::Unauthorized)));
+
+        let retracted = events(
+            send(retract(0, "wrong rig"), &db, &ids, &users, &mut alice)
+                .await
+                .unwrap(),
+        );
+        let retraction = retracted[0].retraction.clone().expect("Not retracted");
+        assert_eq!(retraction.author, "alice");
+        assert_eq!(retraction.reason, "wrong rig");
+        assert_eq!(retracted[0].event, inserted[0].event);
+
+        // Admins may retract the events of everyone
+        let retracted = events(
+            send(retract(1, "duplicate"), &db, &ids, &users, &mut root)
+                .await
+                .unwrap(),
+        );
+        assert_eq!(retracted[0].retraction.as_ref().unwrap().author, "root");
+
+        // Retracting again keeps the first retraction
+        let again = events(
+            send(retract(0, "changed my mind"), &db, &ids, &users, &mut root)
+                .await
+                .unwrap(),
+        );
+        assert_eq!(again[0].retraction, Some(retraction));
+
+        let visible = events(
+            send(ClientMessage::GetSince(0), &db, &ids, &users, &mut bob)
+                .await
+                .unwrap(),
+        );
+        assert_eq!(visible, inserted[2..]);
+        let hidden = send(ClientMessage::GetEvent(0), &db, &ids, &users, &mut bob).await;
+        assert!(matches!(hidden, Err(EventDBError::NotFound)));
+        let edit = ClientMessage::EditEvent {
+            id: 0,
+            event: "edited".into(),
+        };
+        let edited = send(edit, &db, &ids, &users, &mut alice).await;
+        assert!(matches!(edited, Err(EventDBError::NotFound)));
+
+        // Only admins may audit them
+        let refused = send(
+            ClientMessage::ShowRetracted(true),
+            &db,
+            &ids,
+            &users,
+            &mut bob,
+        )
+        .await;
+        assert!(matches!(refused, Err(EventDBError::Unauthorized)));
+        assert!(!bob.show_retracted);
+
+        // Auditors see everything, with who retracted it and why
+        let shown = send(
+            ClientMessage::ShowRetracted(true),
+            &db,
+            &ids,
+            &users,
+            &mut root,
+        )
+        .await
+        .unwrap();
+        assert_eq!(shown, vec![ServerResponse::ShowRetracted(true)]);
+        let audited = events(
+            send(ClientMessage::GetSince(0), &db, &ids, &users, &mut root)
+                .await
+                .unwrap(),
+        );
+        assert_eq!(audited.len(), 3);
+        assert!(audited[0].retraction.is_some());
+        assert!(audited[1].retraction.is_some());
+        assert!(audited[2].retraction.is_none());
+
+        let missing = send(retract(42, "gone"), &db, &ids, &users, &mut root).await;
+        assert!(matches!(missing, Err(EventDBError::NotFound)));
+    }
+
+    #[tokio::test]
+    async fn test_threads() {
+        let mut db = Surreal::new::<Mem>(()).await.unwrap();
+        let ids = IdCounters::default();
+        let users = UserStore::default();
+        let mut session = Session::default();
+        start_db(&mut db, "Bucface", "Events").await.unwrap();
+
+        let reply = |parent: Option<u64>| {
+            ClientMessage::NewEvent(Event {
+                parent,
+                ..rand::random()
+            })
+        };
+        for parent in [None, Some(0), None, Some(1), Some(0)] {
+            send(reply(parent), &db, &ids, &users, &mut session)
+                .await
+                .unwrap();
+        }
+        let missing = send(reply(Some(42)), &db, &ids, &users, &mut session).await;
+        assert!(matches!(missing, Err(EventDBError::NotFound)));
+
+        // Any event of a thread gets all of it
+        for id in [0, 1, 3, 4] {
+            let thread = events(
+                send(
+                    ClientMessage::GetThread(id),
+                    &db,
+                    &ids,
+                    &users,
+                    &mut session,
+                )
+                .await
+                .unwrap(),
+            );
+            assert_eq!(
+                thread.iter().map(|event| event._id).collect::<Vec<u64>>(),
+                vec![0, 1, 3, 4]
+            );
+            assert_eq!(thread[2].parent, Some(1));
+        }
+        let alone = events(
+            send(ClientMessage::GetThread(2), &db, &ids, &users, &mut session)
+                .await
+                .unwrap(),
+        );
+        assert_eq!(alone.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_tags_and_severity() {
+        let mut db = Surreal::new::<Mem>(()).await.unwrap();
+        let ids = IdCounters::default();
+        start_db(&mut db, "Bucface", "Events").await.unwrap();
+        let users = UserStore::new(vec![User {
+            name: "alice".into(),
+            token_sha256: Sha256::digest(b"alice-token").into(),
+            admin: false,
+        }]);
+        let mut session = Session::default();
+
+        // The version is told before authenticating
+        let hello = send(ClientMessage::Hello(1), &db, &ids, &users, &mut session)
+            .await
+            .unwrap();
+        assert_eq!(hello, vec![ServerResponse::Hello(PROTOCOL_VERSION)]);
+        session.user = Some("alice".into());
+
+        let event = |tags: &[&str], severity| {
+            ClientMessage::NewEvent(Event {
+                tags: tags.iter().map(|tag| tag.to_string()).collect(),
+                severity,
+                ..rand::random()
+            })
+        };
+        for (tags, severity) in [
+            (&["psu"][..], Severity::Critical),
+            (&[], Severity::Info),
+            (&["psu", "rig-2"], Severity::Warn),
+        ] {
+            send(event(tags, severity), &db, &ids, &users, &mut session)
+                .await
+                .unwrap();
+        }
+        for tags in [&[""][..], &[" psu"], &["a\nb"]] {
+            let invalid = send(event(tags, Severity::Info), &db, &ids, &users, &mut session).await;
+            assert!(matches!(invalid, Err(EventDBError::InvalidName)));
+        }
+
+        let query = |filter| ClientMessage::Query {
+            filter,
+            limit: None,
+        };
+        let serious = events(
+            send(
+                query(EventFilter {
+                    severity: Some(Severity::Warn),
+                    ..Default::default()
+                }),
+                &db,
+                &ids,
+                &users,
+                &mut session,
+            )
+            .await
+            .unwrap(),
+        );
+        assert_eq!(
+            serious.iter().map(|event| event._id).collect::<Vec<u64>>(),
+            vec![0, 2]
+        );
+        assert_eq!(serious[0].severity, Severity::Critical);
+
+        let tagged = events(
+            send(
+                query(EventFilter {
+                    tags: ["rig-2".to_string()].into(),
+                    ..Default::default()
+                }),
+                &db,
+                &ids,
+                &users,
+                &mut session,
+            )
+            .await
+            .unwrap(),
+        );
+        assert_eq!(tagged.len(), 1);
+        assert_eq!(
+            tagged[0].tags,
+            ["psu".to_string(), "rig-2".to_string()].into()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_channels() {
+        let mut rng = rand::thread_rng();
+        let mut db = Surreal::new::<Mem>(()).await.unwrap();
+        let ids = IdCounters::default();
+        let users = UserStore::new(vec![User {
+            name: "root".into(),
+            token_sha256: Sha256::digest("root-token").into(),
+            admin: true,
+        }]);
+        let mut session = Session {
+            user: Some("root".into()),
+            ..Default::default()
+        };
+        start_db(&mut db, "Bucface", "Events").await.unwrap();
+
+        for _ in 0..3 {
+            let message = ClientMessage::NewEvent(rng.gen());
+            send(message, &db, &ids, &users, &mut session)
+                .await
+                .unwrap();
+        }
+
+        let invalid = send(
+            ClientMessage::CreateChannel(" rig-2".into()),
+            &db,
+            &ids,
+            &users,
+            &mut session,
+        )
+        .await;
+        assert!(matches!(invalid, Err(EventDBError::InvalidName)));
+        let created = send(
+            ClientMessage::CreateChannel("rig-2".into()),
+            &db,
+            &ids,
+            &users,
+            &mut session,
+        )
+        .await
+        .unwrap();
+        let [ServerResponse::Channel(rig)] = &created[..] else {
+            panic!("Expected a channel, got {created:?}");
+        };
+        assert_eq!(rig.name, "rig-2");
+
+        let unknown = send(
+            ClientMessage::JoinChannel("rig-3".into()),
+            &db,
+            &ids,
+            &users,
+            &mut session,
+        )
+        .await;
+        assert!(matches!(unknown, Err(EventDBError::NotFound)));
+        send(
+            ClientMessage::JoinChannel("rig-2".into()),
+            &db,
+            &ids,
+            &users,
+            &mut session,
+        )
+        .await
+        .unwrap();
+
+        // The channel starts its own ids and only shows its own events
+        let inserted = events(
+            send(
+                ClientMessage::NewEvent(rng.gen()),
+                &db,
+                &ids,
+                &users,
+                &mut session,
+            )
+            .await
+            .unwrap(),
+        );
+        assert_eq!(inserted[0]._id, 0);
+        assert_eq!(inserted[0].channel, "rig-2");
+        let since = send(ClientMessage::GetSince(0), &db, &ids, &users, &mut session)
+            .await
+            .unwrap();
+        assert_eq!(events(since), inserted);
+
+        send(
+            ClientMessage::ArchiveChannel("rig-2".into()),
+            &db,
+            &ids,
+            &users,
+            &mut session,
+        )
+        .await
+        .unwrap();
+        let archived = send(
+            ClientMessage::NewEvent(rng.gen()),
+            &db,
+            &ids,
+            &users,
+            &mut session,
+        )
+        .await;
+        assert!(matches!(archived, Err(EventDBError::Archived)));
+
+        let channels = send(ClientMessage::ListChannels, &db, &ids, &users, &mut session)
+            .await
+            .unwrap();
+        let [ServerResponse::Channels(channels)] = &channels[..] else {
+            panic!("Expected channels, got {channels:?}");
+        };
+        assert_eq!(channels.len(), 2);
+        assert_eq!(channels[0].name, DEFAULT_CHANNEL);
+        assert!(channels[1].archived.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_archive_channel() {
+        let mut db = Surreal::new::<Mem>(()).await.unwrap();
+        let ids = IdCounters::default();
+        start_db(&mut db, "Bucface", "Events").await.unwrap();
+
+        let users = UserStore::new(
+            ["alice", "root"]
+                .map(|name| User {
+                    name: name.into(),
+                    token_sha256: Sha256::digest(format!("{name}-token")).into(),
+                    admin: name == "root",
+                })
+                .to_vec(),
+        );
+        let session = |name: &str| Session {
+            user: Some(name.into()),
+            ..Default::default()
+        };
+        let (mut alice, mut root) = (session("alice"), session("root"));
+        let archive = |name: &str| ClientMessage::ArchiveChannel(name.into());
+
+        send(
+            ClientMessage::CreateChannel("rig-2".into()),
+            &db,
+            &ids,
+            &users,
+            &mut alice,
+        )
+        .await
+        .unwrap();
+        let refused = send(archive("rig-2"), &db, &ids, &users, &mut alice).await;
+        assert!(matches!(refused, Err(EventDBError::Unauthorized)));
+        // Without users there are no admins either
+        let anonymous = send(
+            archive("rig-2"),
+            &db,
+            &ids,
+            &UserStore::default(),
+            &mut Session::default(),
+        )
+        .await;
+        assert!(matches!(anonymous, Err(EventDBError::Unauthorized)));
+
+        // Not even admins may stop the logs of the default channel
+        let default = send(archive(DEFAULT_CHANNEL), &db, &ids, &users, &mut root).await;
+        assert!(matches!(default, Err(EventDBError::InvalidName)));
+        let logged = send(
+            ClientMessage::NewEvent(rand::random()),
+            &db,
+            &ids,
+            &users,
+            &mut alice,
+        )
+        .await;
+        assert!(logged.is_ok());
+
+        let archived = send(archive("rig-2"), &db, &ids, &users, &mut root)
+            .await
+            .unwrap();
+        let [ServerResponse::Channel(channel)] = &archived[..] else {
+            panic!("Expected a channel, got {archived:?}");
+        };
+        assert!(channel.archived.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_attachments() {
+        let mut db = Surreal::new::<Mem>(()).await.unwrap();
+        let ids = IdCounters::default();
+        start_db(&mut db, "Bucface", "Events").await.unwrap();
+        let users = UserStore::default();
+        let blobs = BlobStore::memory(1024);
+        let mut session = Session::default();
+
+        let data = b"[rig]\nvoltage = 12".to_vec();
+        let digest = hex::encode(&Sha256::digest(&data)).to_lowercase();
+        let mut offset = 0;
+        for chunk in data.chunks(10) {
+            let message = ClientMessage::UploadChunk {
+                digest: digest.clone(),
+                size: data.len() as u64,
+                offset,
+                data: chunk.to_vec(),
+            };
+            let uploaded = handle_client_message(message, &db, &ids, &users, &blobs, &mut session)
+                .await
+                .unwrap()
+                .responses;
+            offset += chunk.len() as u64;
+            assert_eq!(
+                uploaded,
+                vec![ServerResponse::Uploaded {
+                    digest: digest.clone(),
+                    received: offset
+                }]
+            );
+        }
+        let message = ClientMessage::UploadChunk {
+            digest: digest.clone(),
+            size: 2048,
+            offset: 0,
+            data: Vec::new(),
+        };
+        let result = handle_client_message(message, &db, &ids, &users, &blobs, &mut session).await;
+        assert!(matches!(result, Err(EventDBError::TooLarge)));
+
+        let attachment = Attachment {
+            name: "rig.toml".into(),
+            digest: digest.clone(),
+            size: data.len() as u64,
+        };
+        let message = ClientMessage::NewEvent(Event {
+            attachments: vec![attachment.clone()],
+            ..rand::random()
+        });
+        let logged = handle_client_message(message, &db, &ids, &users, &blobs, &mut session)
+            .await
+            .map(|handled| events(handled.responses))
+            .unwrap();
+        assert_eq!(
+            logged[0].attachments,
+            vec![Attachment {
+                digest: digest.to_uppercase(),
+                ..attachment.clone()
+            }]
+        );
+        let message = ClientMessage::GetEvent(logged[0]._id);
+        let stored = handle_client_message(message, &db, &ids, &users, &blobs, &mut session)
+            .await
+            .map(|handled| events(handled.responses))
+            .unwrap();
+        assert_eq!(stored, logged);
+
+        // Only uploaded blobs can be attached
+        let message = ClientMessage::NewEvent(Event {
+            attachments: vec![Attachment {
+                digest: hex::encode(&[0; 32]),
+                ..attachment.clone()
+            }],
+            ..rand::random()
+        });
+        let result = handle_client_message(message, &db, &ids, &users, &blobs, &mut session).await;
+        assert!(matches!(result, Err(EventDBError::NotFound)));
+
+        let message = ClientMessage::GetBlob(digest.clone());
+        let chunks = handle_client_message(message, &db, &ids, &users, &blobs, &mut session)
+            .await
+            .unwrap()
+            .responses;
+        assert_eq!(chunks, blob_chunks(digest, data, BLOB_CHUNK_SIZE));
+        let message = ClientMessage::GetBlob(hex::encode(&[0; 32]));
+        let result = handle_client_message(message, &db, &ids, &users, &blobs, &mut session).await;
+        assert!(matches!(result, Err(EventDBError::NotFound)));
+    }
+
+    #[tokio::test]
+    async fn test_fields() {
+        let mut db = Surreal::new::<Mem>(()).await.unwrap();
+        let ids = IdCounters::default();
+        start_db(&mut db, "Bucface", "Events").await.unwrap();
+        let users = UserStore::default();
+        let mut session = Session::default();
+
+        let event = |fields: &[(&str, FieldValue)]| {
+            ClientMessage::NewEvent(Event {
+                fields: fields
+                    .iter()
+                    .map(|(key, value)| (key.to_string(), value.clone()))
+                    .collect(),
+                ..rand::random()
+            })
+        };
+        for exit_code in [0, 2, 0] {
+            let fields = [
+                ("exit_code", FieldValue::Int(exit_code)),
+                ("duration_s", FieldValue::Float(1.5)),
+                ("command", FieldValue::String("make test".into())),
+                ("cached", FieldValue::Bool(false)),
+            ];
+            let logged = send(event(&fields), &db, &ids, &users, &mut session)
+                .await
+                .map(events)
+                .unwrap();
+            assert_eq!(logged[0].fields.len(), 4);
+        }
+        for fields in [
+            [("exit code", FieldValue::Int(1))],
+            [("1st", FieldValue::Int(1))],
+            [("duration_s", FieldValue::Float(f64::NAN))],
+        ] {
+            let invalid = send(event(&fields), &db, &ids, &users, &mut session).await;
+            assert!(matches!(invalid, Err(EventDBError::InvalidField)));
+        }
+
+        let query = ClientMessage::Query {
+            filter: EventFilter {
+                fields: vec!["fields.exit_code != 0".parse().unwrap()],
+                ..Default::default()
+            },
+            limit: None,
+        };
+        let failed = send(query, &db, &ids, &users, &mut session)
+            .await
+            .map(events)
+            .unwrap();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0]._id, 1);
+        assert_eq!(failed[0].fields["exit_code"], FieldValue::Int(2));
+        assert_eq!(failed[0].fields["duration_s"], FieldValue::Float(1.5));
+    }
+
+    #[test]
+    fn test_blob_chunks() {
+        let chunks = blob_chunks("digest".into(), b"abcdefghij".to_vec(), 4)
+            .into_iter()
+            .map(|chunk| match chunk {
+                ServerResponse::Blob {
+                    size, offset, data, ..
+                } => (size, offset, data),
+                other => panic!("Expected a blob, got {other:?}"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            chunks,
+            vec![
+                (10, 0, b"abcd".to_vec()),
+                (10, 4, b"efgh".to_vec()),
+                (10, 8, b"ij".to_vec()),
+            ]
+        );
+        assert_eq!(blob_chunks("digest".into(), Vec::new(), 4).len(), 1);
+    }
 }
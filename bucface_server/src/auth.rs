@@ -0,0 +1,149 @@
+use sha2::{Digest, Sha256};
+
+use crate::blob::Upload;
+use crate::db::Scope;
+use crate::subscription::Subscriptions;
+
+/// The author events are stamped with when the server has no users.
+pub const ANONYMOUS: &str = "Anonymous";
+
+/// A user allowed to log events, known by the SHA-256 digest of their token.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct User {
+    pub name: String,
+    pub token_sha256: [u8; 32],
+    /// Admins may archive channels and retract the events of everyone.
+    pub admin: bool,
+}
+
+/// The users allowed to connect. Without any users authentication is
+/// disabled and every connection logs as [ANONYMOUS].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UserStore {
+    users: Vec<User>,
+}
+
+impl UserStore {
+    pub fn new(users: Vec<User>) -> Self {
+        Self { users }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.users.is_empty()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&User> {
+        self.users.iter().find(|user| user.name == name)
+    }
+
+    /// Finds the user the token belongs to.
+    pub fn authenticate(&self, token: &str) -> Option<&User> {
+        let digest: [u8; 32] = Sha256::digest(token.as_bytes()).into();
+
+        // Compare against every user without short circuiting, so the time
+        // taken does not depend on the token
+        self.users.iter().fold(None, |found, user| {
+            let diff = user
+                .token_sha256
+                .iter()
+                .zip(digest.iter())
+                .fold(0, |diff, (a, b)| diff | (a ^ b));
+            if diff == 0 {
+                Some(user)
+            } else {
+                found
+            }
+        })
+    }
+}
+
+/// The identity of a single connection.
+#[derive(Debug, Default)]
+pub struct Session {
+    /// The name of the user the connection authenticated as.
+    pub user: Option<String>,
+    /// The filters the connection's new events are pushed through.
+    pub subscriptions: Subscriptions,
+    /// Whether queries include retracted events.
+    pub show_retracted: bool,
+    /// The blob the connection is uploading.
+    pub upload: Option<Upload>,
+}
+
+impl Session {
+    /// The author to stamp onto events from this connection, or [None] if it
+    /// has to authenticate first.
+    pub fn author<'a>(&'a self, users: &UserStore) -> Option<&'a str> {
+        if users.is_enabled() {
+            self.user.as_deref()
+        } else {
+            Some(ANONYMOUS)
+        }
+    }
+
+    /// Whether the connection authenticated as an admin.
+    pub fn is_admin(&self, users: &UserStore) -> bool {
+        self.user
+            .as_deref()
+            .is_some_and(|name| users.get(name).is_some_and(|user| user.admin))
+    }
+
+    /// Which events the connection's queries see.
+    pub fn scope(&self) -> Scope {
+        Scope {
+            channel: self.subscriptions.channel(),
+            retracted: self.show_retracted,
+        }
+    }
+}
+
+#[cfg(test)]
+mod auth_tests {
+    use super::*;
+
+    fn user(name: &str, token: &str) -> User {
+        User {
+            name: name.into(),
+            token_sha256: Sha256::digest(token.as_bytes()).into(),
+            admin: false,
+        }
+    }
+
+    #[test]
+    fn test_authenticate() {
+        let users = UserStore::new(vec![user("alice", "a-token"), user("bob", "b-token")]);
+
+        assert_eq!(users.authenticate("a-token").unwrap().name, "alice");
+        assert_eq!(users.authenticate("b-token").unwrap().name, "bob");
+        assert!(users.authenticate("c-token").is_none());
+        assert!(users.authenticate("").is_none());
+    }
+
+    #[test]
+    fn test_session_author() {
+        let mut session = Session::default();
+        assert_eq!(session.author(&UserStore::default()), Some(ANONYMOUS));
+
+        let users = UserStore::new(vec![user("alice", "a-token")]);
+        assert_eq!(session.author(&users), None);
+        session.user = Some("alice".into());
+        assert_eq!(session.author(&users), Some("alice"));
+    }
+
+    #[test]
+    fn test_session_admin() {
+        let users = UserStore::new(vec![
+            user("alice", "a-token"),
+            User {
+                admin: true,
+                ..user("root", "r-token")
+            },
+        ]);
+        let mut session = Session::default();
+        assert!(!session.is_admin(&users));
+        session.user = Some("alice".into());
+        assert!(!session.is_admin(&users));
+        session.user = Some("root".into());
+        assert!(session.is_admin(&users));
+    }
+}
@@ -0,0 +1,303 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use bucface_utils::{hex, Attachment, EventDBError};
+use parking_lot::Mutex;
+use sha2::{Digest, Sha256};
+
+/// The longest name of an [Attachment], in bytes.
+const MAX_NAME_LEN: usize = 255;
+
+/// The contents of attachments by their SHA-256 digest, so a file attached
+/// to many events is only stored once.
+#[derive(Debug, Clone)]
+pub struct BlobStore {
+    storage: BlobStorage,
+    /// The largest blob accepted, in bytes.
+    pub max_size: u64,
+}
+
+/// Blobs kept in memory, by digest.
+type Blobs = HashMap<[u8; 32], Arc<[u8]>>;
+
+#[derive(Debug, Clone)]
+enum BlobStorage {
+    Memory(Arc<Mutex<Blobs>>),
+    /// A file per blob named by its hex digest.
+    Directory(PathBuf),
+}
+
+impl BlobStore {
+    /// A store that is lost when the server stops.
+    pub fn memory(max_size: u64) -> Self {
+        Self {
+            storage: BlobStorage::Memory(Arc::default()),
+            max_size,
+        }
+    }
+
+    /// A store in the directory, which is created if it does not exist.
+    pub async fn directory(path: &Path, max_size: u64) -> io::Result<Self> {
+        tokio::fs::create_dir_all(path).await?;
+
+        Ok(Self {
+            storage: BlobStorage::Directory(path.into()),
+            max_size,
+        })
+    }
+
+    /// The size of the blob with the digest, if there is one.
+    pub async fn size(&self, digest: &[u8; 32]) -> io::Result<Option<u64>> {
+        match &self.storage {
+            BlobStorage::Memory(blobs) => {
+                Ok(blobs.lock().get(digest).map(|blob| blob.len() as u64))
+            }
+            BlobStorage::Directory(path) => {
+                match tokio::fs::metadata(path.join(hex::encode(digest))).await {
+                    Ok(metadata) => Ok(Some(metadata.len())),
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+                    Err(e) => Err(e),
+                }
+            }
+        }
+    }
+
+    /// The contents of the blob with the digest, if there is one.
+    pub async fn get(&self, digest: &[u8; 32]) -> io::Result<Option<Vec<u8>>> {
+        match &self.storage {
+            BlobStorage::Memory(blobs) => Ok(blobs.lock().get(digest).map(|blob| blob.to_vec())),
+            BlobStorage::Directory(path) => {
+                match tokio::fs::read(path.join(hex::encode(digest))).await {
+                    Ok(data) => Ok(Some(data)),
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+                    Err(e) => Err(e),
+                }
+            }
+        }
+    }
+
+    /// Stores the blob under its digest, which is returned.
+    pub async fn put(&self, data: &[u8]) -> io::Result<[u8; 32]> {
+        let digest: [u8; 32] = Sha256::digest(data).into();
+        match &self.storage {
+            BlobStorage::Memory(blobs) => {
+                blobs.lock().entry(digest).or_insert_with(|| data.into());
+            }
+            BlobStorage::Directory(path) => {
+                // Written aside and renamed, so a blob is never seen partially
+                let name = hex::encode(&digest);
+                let part = path.join(format!("{name}.{}.part", rand::random::<u64>()));
+                tokio::fs::write(&part, data).await?;
+                tokio::fs::rename(&part, path.join(name)).await?;
+            }
+        }
+
+        Ok(digest)
+    }
+
+    /// Checks that the blob of the attachment is stored with the size it
+    /// claims, and that its name is a plain file name, returning the digest.
+    pub async fn check(&self, attachment: &Attachment) -> Result<[u8; 32], EventDBError> {
+        if !valid_file_name(&attachment.name) {
+            return Err(EventDBError::InvalidName);
+        }
+        let digest = hex::decode_sha256(&attachment.digest).ok_or(EventDBError::NotFound)?;
+        match self.size(&digest).await.map_err(EventDBError::Blob)? {
+            Some(size) if size == attachment.size => Ok(digest),
+            _ => Err(EventDBError::NotFound),
+        }
+    }
+}
+
+/// Whether the name is not blank, at most [MAX_NAME_LEN] bytes and free of
+/// directories.
+fn valid_file_name(name: &str) -> bool {
+    !name.trim().is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && !name.contains(['/', '\\', '\0'])
+        && name != "."
+        && name != ".."
+}
+
+/// A blob a connection is uploading in chunks.
+#[derive(Debug)]
+pub struct Upload {
+    digest: [u8; 32],
+    size: u64,
+    data: Vec<u8>,
+}
+
+/// Adds a [ClientMessage::UploadChunk](bucface_utils::ClientMessage) to the
+/// connection's `upload`, storing the blob once it is complete. Returns how
+/// many bytes of the blob there are, which is its size if it is stored.
+///
+/// # Errors
+/// [EventDBError::TooLarge] if the blob is larger than the store accepts, or
+/// [EventDBError::InvalidUpload] if the digest is not hex SHA-256, the chunk
+/// does not continue the upload or the complete blob does not match the
+/// digest. An invalid chunk drops the upload.
+pub async fn upload_chunk(
+    blobs: &BlobStore,
+    upload: &mut Option<Upload>,
+    digest: &str,
+    size: u64,
+    offset: u64,
+    data: Vec<u8>,
+) -> Result<u64, EventDBError> {
+    let digest = hex::decode_sha256(digest).ok_or(EventDBError::InvalidUpload)?;
+    if size > blobs.max_size {
+        return Err(EventDBError::TooLarge);
+    }
+
+    if offset == 0 {
+        if blobs
+            .size(&digest)
+            .await
+            .map_err(EventDBError::Blob)?
+            .is_some()
+        {
+            log::debug!("Already have blob {}", hex::encode(&digest));
+            *upload = None;
+            return Ok(size);
+        }
+        *upload = Some(Upload {
+            digest,
+            size,
+            data: Vec::new(),
+        });
+    }
+
+    let current = upload.take().filter(|current| {
+        current.digest == digest
+            && current.size == size
+            && current.data.len() as u64 == offset
+            && offset + data.len() as u64 <= size
+    });
+    let Some(mut current) = current else {
+        return Err(EventDBError::InvalidUpload);
+    };
+    current.data.extend(data);
+    let received = current.data.len() as u64;
+    if received < size {
+        *upload = Some(current);
+        return Ok(received);
+    }
+
+    if <[u8; 32]>::from(Sha256::digest(&current.data)) != digest {
+        return Err(EventDBError::InvalidUpload);
+    }
+    blobs.put(&current.data).await.map_err(EventDBError::Blob)?;
+    log::info!("Stored blob {} of {size} bytes", hex::encode(&digest));
+
+    Ok(received)
+}
+
+#[cfg(test)]
+mod blob_tests {
+    use super::*;
+
+    fn digest(data: &[u8]) -> [u8; 32] {
+        Sha256::digest(data).into()
+    }
+
+    #[tokio::test]
+    async fn test_upload_chunk() {
+        let blobs = BlobStore::memory(8);
+        let data = b"abcdefg";
+        let hex = hex::encode(&digest(data));
+
+        let mut upload = None;
+        let received = upload_chunk(&blobs, &mut upload, &hex, 7, 0, data[..3].to_vec())
+            .await
+            .unwrap();
+        assert_eq!(received, 3);
+        assert_eq!(blobs.size(&digest(data)).await.unwrap(), None);
+        let received = upload_chunk(&blobs, &mut upload, &hex, 7, 3, data[3..].to_vec())
+            .await
+            .unwrap();
+        assert_eq!(received, 7);
+        assert!(upload.is_none());
+        assert_eq!(blobs.get(&digest(data)).await.unwrap(), Some(data.to_vec()));
+
+        // A blob that is already stored is not uploaded again
+        let received = upload_chunk(&blobs, &mut upload, &hex, 7, 0, data[..1].to_vec())
+            .await
+            .unwrap();
+        assert_eq!(received, 7);
+        assert!(upload.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_invalid_upload() {
+        let blobs = BlobStore::memory(8);
+        let data = b"abcdefg";
+        let hex = hex::encode(&digest(data));
+        let mut upload = None;
+
+        let result = upload_chunk(&blobs, &mut upload, &hex, 9, 0, Vec::new()).await;
+        assert!(matches!(result, Err(EventDBError::TooLarge)));
+        let result = upload_chunk(&blobs, &mut upload, "abc", 7, 0, Vec::new()).await;
+        assert!(matches!(result, Err(EventDBError::InvalidUpload)));
+
+        // Skipping a chunk drops the upload
+        upload_chunk(&blobs, &mut upload, &hex, 7, 0, data[..2].to_vec())
+            .await
+            .unwrap();
+        let result = upload_chunk(&blobs, &mut upload, &hex, 7, 4, data[4..].to_vec()).await;
+        assert!(matches!(result, Err(EventDBError::InvalidUpload)));
+        assert!(upload.is_none());
+        let result = upload_chunk(&blobs, &mut upload, &hex, 7, 2, data[2..].to_vec()).await;
+        assert!(matches!(result, Err(EventDBError::InvalidUpload)));
+
+        // So does sending more than the size
+        let result = upload_chunk(&blobs, &mut upload, &hex, 7, 0, b"abcdefgh".to_vec()).await;
+        assert!(matches!(result, Err(EventDBError::InvalidUpload)));
+        assert!(upload.is_none());
+
+        // Contents not matching the digest are not stored
+        let result = upload_chunk(&blobs, &mut upload, &hex, 7, 0, b"gfedcba".to_vec()).await;
+        assert!(matches!(result, Err(EventDBError::InvalidUpload)));
+        assert_eq!(blobs.size(&digest(data)).await.unwrap(), None);
+        assert_eq!(blobs.size(&digest(b"gfedcba")).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_check() {
+        let blobs = BlobStore::memory(1024);
+        blobs.put(b"config dump").await.unwrap();
+        let attachment = |name: &str, size: u64| Attachment {
+            name: name.into(),
+            digest: hex::encode(&digest(b"config dump")),
+            size,
+        };
+
+        blobs.check(&attachment("config.toml", 11)).await.unwrap();
+        let result = blobs.check(&attachment("config.toml", 12)).await;
+        assert!(matches!(result, Err(EventDBError::NotFound)));
+        let result = blobs.check(&attachment("../etc/passwd", 11)).await;
+        assert!(matches!(result, Err(EventDBError::InvalidName)));
+        let result = blobs.check(&attachment(" ", 11)).await;
+        assert!(matches!(result, Err(EventDBError::InvalidName)));
+    }
+
+    #[tokio::test]
+    async fn test_directory() {
+        let path = std::env::temp_dir().join(format!("bucface-blobs-{}", rand::random::<u64>()));
+        let blobs = BlobStore::directory(&path, 1024).await.unwrap();
+        let stored = blobs.put(b"config dump").await.unwrap();
+        assert_eq!(stored, digest(b"config dump"));
+
+        // The blobs outlive the store
+        let blobs = BlobStore::directory(&path, 1024).await.unwrap();
+        let size = blobs.size(&stored).await;
+        let data = blobs.get(&stored).await;
+        let missing = blobs.get(&[0; 32]).await;
+        std::fs::remove_dir_all(path).unwrap();
+
+        assert_eq!(size.unwrap(), Some(11));
+        assert_eq!(data.unwrap(), Some(b"config dump".to_vec()));
+        assert_eq!(missing.unwrap(), None);
+    }
+}
@@ -0,0 +1,67 @@
+use std::collections::HashMap;
+
+use parking_lot::Mutex;
+
+/// The longest name a channel may have, in characters.
+pub const MAX_NAME_CHARS: usize = 64;
+
+/// Whether a channel or tag may be called `name`: it must not be blank, padded,
+/// longer than [MAX_NAME_CHARS] or contain control characters.
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.trim() == name
+        && name.chars().count() <= MAX_NAME_CHARS
+        && !name.chars().any(char::is_control)
+}
+
+/// The id the next event of every channel gets, as every channel has its own
+/// sequence of ids. Channels without an entry start at 0.
+#[derive(Debug, Default)]
+pub struct IdCounters {
+    next: Mutex<HashMap<String, u64>>,
+}
+
+impl IdCounters {
+    /// Counters continuing from the ids of [next_event_ids](crate::db::next_event_ids).
+    pub fn new(next: HashMap<String, u64>) -> Self {
+        Self {
+            next: Mutex::new(next),
+        }
+    }
+
+    /// Takes the next id of the channel.
+    pub fn next(&self, channel: &str) -> u64 {
+        let mut next = self.next.lock();
+        let id = next.entry(channel.into()).or_default();
+        *id += 1;
+        *id - 1
+    }
+}
+
+#[cfg(test)]
+mod channel_tests {
+    use super::*;
+
+    #[test]
+    fn test_valid_name() {
+        assert!(valid_name("main"));
+        assert!(valid_name("Rig 2 – Ölpumpe"));
+        assert!(valid_name(&"a".repeat(MAX_NAME_CHARS)));
+
+        assert!(!valid_name(""));
+        assert!(!valid_name(" main"));
+        assert!(!valid_name("main\n"));
+        assert!(!valid_name("ma\u{7}in"));
+        assert!(!valid_name(&"a".repeat(MAX_NAME_CHARS + 1)));
+    }
+
+    #[test]
+    fn test_id_counters() {
+        let ids = IdCounters::new(HashMap::from([("main".to_string(), 5)]));
+        assert_eq!(ids.next("main"), 5);
+        assert_eq!(ids.next("main"), 6);
+        assert_eq!(ids.next("rig-2"), 0);
+        assert_eq!(ids.next("main"), 7);
+        assert_eq!(ids.next("rig-2"), 1);
+    }
+}
@@ -0,0 +1,439 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::net::{AddrParseError, SocketAddr};
+use std::path::{Path, PathBuf};
+
+use bucface_utils::hex;
+use clap::Parser;
+use log::LevelFilter;
+use serde::Deserialize;
+
+use crate::auth::{User, UserStore};
+use crate::db::{Storage, StorageParseError};
+
+/// Command line arguments of the server. Every option can also be set in the
+/// TOML file given with `--config`, with the command line taking precedence.
+#[derive(Debug, Default, Parser)]
+#[command(version, about = "Bucface event log server")]
+pub struct Args {
+    /// Path to a TOML configuration file
+    #[arg(short, long, env = "BUCFACE_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// Address to listen on [default: 0.0.0.0:8080]
+    #[arg(short, long)]
+    pub bind: Option<String>,
+    /// Storage engine: `mem`, `rocksdb:<path>` or `speedb:<path>` [default: mem]
+    #[arg(short, long, env = "BUCFACE_STORAGE")]
+    pub storage: Option<String>,
+    /// Database namespace [default: Bucface]
+    #[arg(long)]
+    pub namespace: Option<String>,
+    /// Database name [default: Events]
+    #[arg(long)]
+    pub database: Option<String>,
+    /// Largest websocket message accepted from clients, in bytes [default: 67108864]
+    #[arg(long)]
+    pub max_message_size: Option<usize>,
+    /// Directory to store attachments in [default: in memory]
+    #[arg(long, env = "BUCFACE_BLOBS")]
+    pub blobs: Option<PathBuf>,
+    /// Largest attachment accepted, in bytes [default: 16777216]
+    #[arg(long)]
+    pub max_attachment_size: Option<u64>,
+    /// Log level: off, error, warn, info, debug or trace [default: info]
+    #[arg(short, long)]
+    pub log_level: Option<String>,
+    /// PEM certificate chain to serve wss:// with, requires `--tls-key`
+    #[arg(long)]
+    pub tls_cert: Option<PathBuf>,
+    /// PEM PKCS #8 private key of `--tls-cert`
+    #[arg(long)]
+    pub tls_key: Option<PathBuf>,
+}
+
+/// The configuration file, all keys are optional.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileConfig {
+    bind: Option<String>,
+    storage: Option<String>,
+    namespace: Option<String>,
+    database: Option<String>,
+    max_message_size: Option<usize>,
+    blobs: Option<PathBuf>,
+    max_attachment_size: Option<u64>,
+    log_level: Option<String>,
+    tls_cert: Option<PathBuf>,
+    tls_key: Option<PathBuf>,
+    #[serde(default)]
+    users: Vec<UserConfig>,
+}
+
+/// A `[[users]]` entry of the configuration file.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UserConfig {
+    name: String,
+    /// Hex SHA-256 digest of the user's token, e.g. the output of
+    /// `printf %s "$TOKEN" | sha256sum`
+    token_sha256: String,
+    /// Whether the user may archive channels and retract the events of
+    /// everyone
+    #[serde(default)]
+    admin: bool,
+}
+
+/// Certificate and key to terminate TLS with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// The validated server configuration.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub bind: SocketAddr,
+    pub storage: Storage,
+    pub namespace: String,
+    pub database: String,
+    pub max_message_size: usize,
+    /// Where attachments are stored, in memory if [None].
+    pub blobs: Option<PathBuf>,
+    pub max_attachment_size: u64,
+    pub log_level: LevelFilter,
+    /// Serve wss:// instead of ws:// when set.
+    pub tls: Option<TlsConfig>,
+    /// Only read from the configuration file, as tokens do not belong on the
+    /// command line.
+    pub users: UserStore,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            bind: SocketAddr::from(([0, 0, 0, 0], 8080)),
+            storage: Storage::Memory,
+            namespace: "Bucface".into(),
+            database: "Events".into(),
+            max_message_size: 64 << 20,
+            blobs: None,
+            max_attachment_size: 16 << 20,
+            log_level: LevelFilter::Info,
+            tls: None,
+            users: UserStore::default(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Bind(String, AddrParseError),
+    Storage(StorageParseError),
+    LogLevel(String),
+    Empty(&'static str),
+    MaxMessageSize,
+    MaxAttachmentSize,
+    TlsIncomplete,
+    UserToken(String),
+    DuplicateUser(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Read(path, e) => write!(f, "cannot read {}: {e}", path.display()),
+            Self::Parse(path, e) => write!(f, "invalid config file {}: {e}", path.display()),
+            Self::Bind(bind, e) => write!(f, "invalid bind address `{bind}`: {e}"),
+            Self::Storage(e) => write!(f, "invalid storage: {e}"),
+            Self::LogLevel(level) => write!(f, "invalid log level `{level}`"),
+            Self::Empty(key) => write!(f, "`{key}` must not be empty"),
+            Self::MaxMessageSize => write!(f, "`max_message_size` must be greater than 0"),
+            Self::MaxAttachmentSize => {
+                write!(f, "`max_attachment_size` must be greater than 0")
+            }
+            Self::TlsIncomplete => write!(f, "`tls_cert` and `tls_key` must be set together"),
+            Self::UserToken(name) => {
+                write!(
+                    f,
+                    "`token_sha256` of user `{name}` is not a hex SHA-256 digest"
+                )
+            }
+            Self::DuplicateUser(name) => write!(f, "user `{name}` is defined more than once"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// Builds the configuration from the defaults, overridden by the config
+    /// file if one is given, overridden by the command line.
+    pub fn load(args: Args) -> Result<Config, ConfigError> {
+        let file = match &args.config {
+            Some(path) => read_file(path)?,
+            None => FileConfig::default(),
+        };
+
+        let mut config = Config::default();
+
+        if let Some(bind) = args.bind.or(file.bind) {
+            config.bind = bind.parse().map_err(|e| ConfigError::Bind(bind, e))?;
+        }
+        if let Some(storage) = args.storage.or(file.storage) {
+            config.storage = storage.parse().map_err(ConfigError::Storage)?;
+        }
+        if let Some(namespace) = args.namespace.or(file.namespace) {
+            config.namespace = namespace;
+        }
+        if let Some(database) = args.database.or(file.database) {
+            config.database = database;
+        }
+        if let Some(max_message_size) = args.max_message_size.or(file.max_message_size) {
+            config.max_message_size = max_message_size;
+        }
+        if let Some(blobs) = args.blobs.or(file.blobs) {
+            config.blobs = Some(blobs);
+        }
+        if let Some(max_attachment_size) = args.max_attachment_size.or(file.max_attachment_size) {
+            config.max_attachment_size = max_attachment_size;
+        }
+        if let Some(log_level) = args.log_level.or(file.log_level) {
+            config.log_level = log_level
+                .parse()
+                .map_err(|_| ConfigError::LogLevel(log_level))?;
+        }
+        config.tls = match (
+            args.tls_cert.or(file.tls_cert),
+            args.tls_key.or(file.tls_key),
+        ) {
+            (Some(cert), Some(key)) => Some(TlsConfig { cert, key }),
+            (None, None) => None,
+            _ => return Err(ConfigError::TlsIncomplete),
+        };
+
+        config.users = users(file.users)?;
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.namespace.trim().is_empty() {
+            return Err(ConfigError::Empty("namespace"));
+        }
+        if self.database.trim().is_empty() {
+            return Err(ConfigError::Empty("database"));
+        }
+        if self.max_message_size == 0 {
+            return Err(ConfigError::MaxMessageSize);
+        }
+        if self.max_attachment_size == 0 {
+            return Err(ConfigError::MaxAttachmentSize);
+        }
+
+        Ok(())
+    }
+}
+
+fn users(users: Vec<UserConfig>) -> Result<UserStore, ConfigError> {
+    let mut names = HashSet::new();
+    let users = users
+        .into_iter()
+        .map(|user| {
+            if user.name.trim().is_empty() {
+                return Err(ConfigError::Empty("users.name"));
+            }
+            if !names.insert(user.name.clone()) {
+                return Err(ConfigError::DuplicateUser(user.name));
+            }
+            let token_sha256 = hex::decode_sha256(&user.token_sha256)
+                .ok_or_else(|| ConfigError::UserToken(user.name.clone()))?;
+
+            Ok(User {
+                name: user.name,
+                token_sha256,
+                admin: user.admin,
+            })
+        })
+        .collect::<Result<Vec<User>, ConfigError>>()?;
+
+    Ok(UserStore::new(users))
+}
+
+fn read_file(path: &Path) -> Result<FileConfig, ConfigError> {
+    let contents = std::fs::read_to_string(path).map_err(|e| ConfigError::Read(path.into(), e))?;
+    toml::from_str(&contents).map_err(|e| ConfigError::Parse(path.into(), e))
+}
+
+#[cfg(test)]
+mod config_tests {
+    use super::*;
+
+    fn write_config(contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("bucface-{}.toml", rand::random::<u64>()));
+        std::fs::write(&path, contents).expect("Failed to write config");
+        path
+    }
+
+    #[test]
+    fn test_defaults() {
+        assert_eq!(Config::load(Args::default()).unwrap(), Config::default());
+    }
+
+    #[test]
+    fn test_file_and_args() {
+        let path = write_config(
+            r#"
+            bind = "127.0.0.1:9000"
+            storage = "rocksdb:/var/lib/bucface"
+            namespace = "Lab"
+            database = "Rig1"
+            max_message_size = 1024
+            blobs = "/var/lib/bucface/blobs"
+            max_attachment_size = 512
+            log_level = "debug"
+            tls_cert = "cert.pem"
+            tls_key = "key.pem"
+
+            [[users]]
+            name = "alice"
+            token_sha256 = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+            admin = true
+            "#,
+        );
+
+        let config = Config::load(Args {
+            config: Some(path.clone()),
+            database: Some("Rig2".into()),
+            ..Default::default()
+        })
+        .unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(
+            config,
+            Config {
+                bind: "127.0.0.1:9000".parse().unwrap(),
+                storage: Storage::RocksDb("/var/lib/bucface".into()),
+                namespace: "Lab".into(),
+                database: "Rig2".into(),
+                max_message_size: 1024,
+                blobs: Some("/var/lib/bucface/blobs".into()),
+                max_attachment_size: 512,
+                log_level: LevelFilter::Debug,
+                tls: Some(TlsConfig {
+                    cert: "cert.pem".into(),
+                    key: "key.pem".into(),
+                }),
+                users: UserStore::new(vec![User {
+                    name: "alice".into(),
+                    token_sha256: hex::decode_sha256(
+                        "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+                    )
+                    .unwrap(),
+                    admin: true,
+                }]),
+            }
+        );
+    }
+
+    #[test]
+    fn test_invalid() {
+        let load = |args: Args| Config::load(args).unwrap_err();
+
+        assert!(matches!(
+            load(Args {
+                bind: Some("localhost".into()),
+                ..Default::default()
+            }),
+            ConfigError::Bind(..)
+        ));
+        assert!(matches!(
+            load(Args {
+                storage: Some("sqlite:data".into()),
+                ..Default::default()
+            }),
+            ConfigError::Storage(StorageParseError::UnknownEngine(_))
+        ));
+        assert!(matches!(
+            load(Args {
+                log_level: Some("loud".into()),
+                ..Default::default()
+            }),
+            ConfigError::LogLevel(_)
+        ));
+        assert!(matches!(
+            load(Args {
+                namespace: Some(" ".into()),
+                ..Default::default()
+            }),
+            ConfigError::Empty("namespace")
+        ));
+        assert!(matches!(
+            load(Args {
+                max_message_size: Some(0),
+                ..Default::default()
+            }),
+            ConfigError::MaxMessageSize
+        ));
+        assert!(matches!(
+            load(Args {
+                max_attachment_size: Some(0),
+                ..Default::default()
+            }),
+            ConfigError::MaxAttachmentSize
+        ));
+        assert!(matches!(
+            load(Args {
+                tls_cert: Some("cert.pem".into()),
+                ..Default::default()
+            }),
+            ConfigError::TlsIncomplete
+        ));
+        assert!(matches!(
+            load(Args {
+                config: Some("/nonexistent/bucface.toml".into()),
+                ..Default::default()
+            }),
+            ConfigError::Read(..)
+        ));
+
+        let path = write_config(
+            r#"
+            [[users]]
+            name = "alice"
+            token_sha256 = "not a digest"
+            "#,
+        );
+        let error = load(Args {
+            config: Some(path.clone()),
+            ..Default::default()
+        });
+        std::fs::remove_file(path).unwrap();
+        assert!(matches!(error, ConfigError::UserToken(name) if name == "alice"));
+
+        let user = r#"
+            [[users]]
+            name = "alice"
+            token_sha256 = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+            "#;
+        let path = write_config(&user.repeat(2));
+        let error = load(Args {
+            config: Some(path.clone()),
+            ..Default::default()
+        });
+        std::fs::remove_file(path).unwrap();
+        assert!(matches!(error, ConfigError::DuplicateUser(name) if name == "alice"));
+
+        let path = write_config("port = 8080");
+        let error = load(Args {
+            config: Some(path.clone()),
+            ..Default::default()
+        });
+        std::fs::remove_file(path).unwrap();
+        assert!(matches!(error, ConfigError::Parse(..)));
+    }
+}
@@ -1,7 +1,104 @@
-use bucface_utils::{EventDB, EventDBError};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use bucface_utils::{
+    Change, Channel, Direction, EventDB, EventDBError, EventFilter, FieldValue, Retraction,
+    Revision, SearchHit, Severity, TimeBound, TimeRange, DEFAULT_CHANNEL,
+};
+use serde::{Deserialize, Serialize};
+use surrealdb::engine::any::{self, Any};
 use surrealdb::Surreal;
 
+use crate::{diff, search};
+
 pub const EVENTS_TABLE: &str = "events";
+pub const CHANNELS_TABLE: &str = "channels";
+pub const REVISIONS_TABLE: &str = "revisions";
+pub const KEYS_TABLE: &str = "event_keys";
+
+/// The storage engine backing the [database](Surreal), selected at startup.
+///
+/// Only [Storage::Memory] is always available. The durable engines need the
+/// `rocksdb` or `speedb` feature of this crate, otherwise [connect] returns
+/// an error for them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Storage {
+    /// Volatile storage, everything is lost when the server stops.
+    Memory,
+    /// Durable RocksDB storage in the given directory.
+    RocksDb(PathBuf),
+    /// Durable SpeeDB storage in the given directory.
+    SpeeDb(PathBuf),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StorageParseError {
+    UnknownEngine(String),
+    MissingPath(String),
+}
+
+impl fmt::Display for StorageParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownEngine(engine) => write!(f, "unknown storage engine `{engine}`"),
+            Self::MissingPath(engine) => {
+                write!(
+                    f,
+                    "storage engine `{engine}` requires a path, e.g. `{engine}:./data`"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for StorageParseError {}
+
+impl Storage {
+    /// The [SurrealDB endpoint](surrealdb::engine::any) for this storage.
+    pub fn endpoint(&self) -> String {
+        match self {
+            Storage::Memory => "mem://".into(),
+            Storage::RocksDb(path) => format!("rocksdb://{}", path.display()),
+            Storage::SpeeDb(path) => format!("speedb://{}", path.display()),
+        }
+    }
+}
+
+/// Parses `mem`, `rocksdb:<path>` or `speedb:<path>`.
+impl FromStr for Storage {
+    type Err = StorageParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (engine, path) = match s.split_once(':') {
+            Some((engine, path)) => (engine, path.trim_start_matches("//")),
+            None => (s, ""),
+        };
+
+        let path = || {
+            if path.is_empty() {
+                Err(StorageParseError::MissingPath(engine.into()))
+            } else {
+                Ok(PathBuf::from(path))
+            }
+        };
+
+        match engine {
+            "mem" | "memory" => Ok(Storage::Memory),
+            "rocksdb" | "file" => Ok(Storage::RocksDb(path()?)),
+            "speedb" => Ok(Storage::SpeeDb(path()?)),
+            _ => Err(StorageParseError::UnknownEngine(engine.into())),
+        }
+    }
+}
+
+/// Opens a [database](Surreal) connection to the given [Storage]. The
+/// connection still has to be initialized with [start_db].
+pub async fn connect(storage: &Storage) -> Result<Surreal<Any>, surrealdb::Error> {
+    log::info!("Opening database at {}", storage.endpoint());
+    any::connect(storage.endpoint()).await
+}
 
 /// Inserts an [EventDB](EventDB) into the [database](Surreal), returning the
 /// [database](Surreal) response, which contains the [Vec] of [EventDB] that
@@ -12,55 +109,572 @@ pub async fn insert_event<T: surrealdb::Connection>(
 ) -> Result<Vec<EventDB>, EventDBError> {
     log::debug!("Inserting event: {event:?}");
 
-    log::debug!(
-        "events: {:?}",
-        db.select::<Vec<EventDB>>(EVENTS_TABLE).await.unwrap()
-    );
-
     db.create::<Vec<EventDB>>(EVENTS_TABLE)
         .content(event)
         .await
         .map_err(EventDBError::Db)
 }
 
-/// Initializes the [database](Surreal) by setting the namespace to "Bucface"
-/// and the database to "Events".
+/// The idempotency key an [EventDB] was inserted with.
+#[derive(Debug, Serialize, Deserialize)]
+struct EventKey {
+    channel: String,
+    author: String,
+    key: String,
+    event_id: u64,
+}
+
+/// Inserts an [EventDB] along with the idempotency key it was sent with,
+/// returning the inserted event. Fails without inserting it if its author
+/// already used the key in its channel.
+pub async fn insert_keyed_event<T: surrealdb::Connection>(
+    event: &EventDB,
+    key: &str,
+    db: &Surreal<T>,
+) -> Result<EventDB, EventDBError> {
+    log::debug!("Inserting event with key {key}: {event:?}");
+
+    let event_key = EventKey {
+        channel: event.channel.clone(),
+        author: event.author.clone(),
+        key: key.into(),
+        event_id: event._id,
+    };
+    db.query(
+        "BEGIN TRANSACTION; \
+         CREATE type::table($table) CONTENT $event; \
+         CREATE type::table($keys) CONTENT $key; \
+         COMMIT TRANSACTION;",
+    )
+    .bind(("table", EVENTS_TABLE))
+    .bind(("keys", KEYS_TABLE))
+    .bind(("event", event))
+    .bind(("key", event_key))
+    .await
+    .map_err(EventDBError::Db)?
+    .check()
+    .map_err(EventDBError::Db)?;
+
+    let scope = Scope {
+        channel: event.channel.clone(),
+        retracted: true,
+    };
+    get_event(&scope, event._id, db).await
+}
+
+/// Gets the [EventDB] of the channel the author inserted with the
+/// idempotency key, even if it was retracted since, or [None] if they did not
+/// use the key yet.
+pub async fn get_keyed_event<T: surrealdb::Connection>(
+    channel: &str,
+    author: &str,
+    key: &str,
+    db: &Surreal<T>,
+) -> Result<Option<EventDB>, EventDBError> {
+    let mut response = db
+        .query(
+            "SELECT * FROM type::table($table) \
+             WHERE channel == $channel AND author == $author AND key == $key",
+        )
+        .bind(("table", KEYS_TABLE))
+        .bind(("channel", channel))
+        .bind(("author", author))
+        .bind(("key", key))
+        .await
+        .map_err(EventDBError::Db)?;
+    let Some(event_key) = response
+        .take::<Option<EventKey>>(0)
+        .map_err(EventDBError::Db)?
+    else {
+        return Ok(None);
+    };
+
+    let scope = Scope {
+        channel: channel.into(),
+        retracted: true,
+    };
+    get_event(&scope, event_key.event_id, db).await.map(Some)
+}
+
+/// Splits [EventDB::event](EventDB) into lowercase, ASCII folded and stemmed
+/// words for the full-text index.
+const EVENTS_ANALYZER: &str = "events_analyzer";
+
+/// Initializes the [database](Surreal) by setting the namespace and the
+/// database, defining the events table with a unique index on
+/// [EventDB::_id](EventDB) within each channel, a full-text index on
+/// [EventDB::event](EventDB) and an index on the replies to each event, the
+/// revisions table of edited events, the idempotency keys of inserted events,
+/// unique for each author within each channel, and the channels table with the
+/// [DEFAULT_CHANNEL]. This is safe to call on a database that already holds
+/// events, moves those logged before there were channels into the
+/// [DEFAULT_CHANNEL] and gives those logged before there were severities the
+/// default one.
 pub async fn start_db<T: surrealdb::Connection>(
     db: &mut Surreal<T>,
+    namespace: &str,
+    database: &str,
 ) -> Result<(), surrealdb::Error> {
-    db.use_ns("Bucface").await?;
-    db.use_db("Events").await
+    db.use_ns(namespace).await?;
+    db.use_db(database).await?;
+
+    db.query(format!("DEFINE TABLE {EVENTS_TABLE} SCHEMALESS"))
+        .query(format!(
+            "DEFINE ANALYZER {EVENTS_ANALYZER} TOKENIZERS blank,class,punct \
+             FILTERS lowercase,ascii,snowball(english)"
+        ))
+        .query(format!("DEFINE TABLE {CHANNELS_TABLE} SCHEMALESS"))
+        .query(format!(
+            "DEFINE INDEX {CHANNELS_TABLE}_name ON TABLE {CHANNELS_TABLE} COLUMNS name UNIQUE"
+        ))
+        .query(format!("DEFINE TABLE {REVISIONS_TABLE} SCHEMALESS"))
+        .query(format!(
+            "DEFINE INDEX {REVISIONS_TABLE}_event ON TABLE {REVISIONS_TABLE} \
+             COLUMNS channel, event_id, revision UNIQUE"
+        ))
+        .query(format!("DEFINE TABLE {KEYS_TABLE} SCHEMALESS"))
+        .query(format!(
+            "DEFINE INDEX {KEYS_TABLE}_key ON TABLE {KEYS_TABLE} \
+             COLUMNS channel, author, key UNIQUE"
+        ))
+        .query("UPDATE type::table($table) SET channel = $channel WHERE channel == NONE")
+        .query("UPDATE type::table($table) SET tags = [] WHERE tags == NONE")
+        .query("UPDATE type::table($table) SET severity = $severity WHERE severity == NONE")
+        .bind(("table", EVENTS_TABLE))
+        .bind(("channel", DEFAULT_CHANNEL))
+        .bind(("severity", Severity::default()))
+        .await?
+        .check()?;
+    let default = db
+        .query("SELECT * FROM type::table($table) WHERE name == $name")
+        .bind(("table", CHANNELS_TABLE))
+        .bind(("name", DEFAULT_CHANNEL))
+        .await?
+        .take::<Option<Channel>>(0)?;
+    if default.is_none() {
+        log::info!("Creating channel {DEFAULT_CHANNEL}");
+        db.create::<Vec<Channel>>(CHANNELS_TABLE)
+            .content(Channel {
+                name: DEFAULT_CHANNEL.into(),
+                created: chrono::Utc::now().naive_utc(),
+                archived: None,
+            })
+            .await?;
+    }
+
+    // Defining an index again rebuilds it, which takes a while for the
+    // full-text index of a large table, so only missing ones are defined
+    let indexes = [
+        (
+            format!("{EVENTS_TABLE}_channel_id"),
+            "COLUMNS channel, _id UNIQUE".to_string(),
+        ),
+        (
+            format!("{EVENTS_TABLE}_event"),
+            format!("COLUMNS event SEARCH ANALYZER {EVENTS_ANALYZER} BM25 HIGHLIGHTS"),
+        ),
+        (
+            format!("{EVENTS_TABLE}_parent"),
+            "COLUMNS channel, parent".to_string(),
+        ),
+    ];
+    let defined = db
+        .query(format!("INFO FOR TABLE {EVENTS_TABLE}"))
+        .await?
+        .take::<Option<HashMap<String, String>>>((0, "indexes"))?
+        .unwrap_or_default();
+    // Ids were unique across all events before there were channels
+    let legacy = format!("{EVENTS_TABLE}_id");
+    if defined.contains_key(&legacy) {
+        log::info!("Removing index {legacy}");
+        db.query(format!("REMOVE INDEX {legacy} ON TABLE {EVENTS_TABLE}"))
+            .await?
+            .check()?;
+    }
+    for (name, definition) in indexes {
+        if !defined.contains_key(&name) {
+            log::info!("Defining index {name}");
+            db.query(format!(
+                "DEFINE INDEX {name} ON TABLE {EVENTS_TABLE} {definition}"
+            ))
+            .await?
+            .check()?;
+        }
+    }
+
+    Ok(())
 }
 
-/// Gets all [EventDB]s since and including the given id.
+/// Which [EventDB]s a query sees: those of one channel, leaving out the
+/// retracted ones unless `retracted` is set.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Scope {
+    pub channel: String,
+    pub retracted: bool,
+}
+
+impl Default for Scope {
+    fn default() -> Self {
+        Self {
+            channel: DEFAULT_CHANNEL.into(),
+            retracted: false,
+        }
+    }
+}
+
+impl Scope {
+    /// The conditions limiting a query to the scope, comparing against the
+    /// `$channel` parameter bound to [Scope::channel].
+    fn conditions(&self) -> Vec<&'static str> {
+        match self.retracted {
+            true => vec!["channel == $channel"],
+            false => vec!["channel == $channel", "retraction == NONE"],
+        }
+    }
+}
+
+/// Gets all [EventDB]s of the channel since and including the given id, in
+/// ascending id order.
 pub async fn get_events_since<T: surrealdb::Connection>(
+    scope: &Scope,
     id: u64,
     db: &Surreal<T>,
 ) -> Result<Vec<EventDB>, EventDBError> {
-    log::debug!("Getting events after id: {id}");
+    log::debug!("Getting events of {scope:?} after id: {id}");
 
+    let scope_condition = scope.conditions().join(" AND ");
     let mut response = db
-        .query("SELECT * FROM type::table($table) WHERE _id >= type::number($id)")
+        .query(format!(
+            "SELECT * FROM type::table($table) \
+             WHERE {scope_condition} AND _id >= type::number($id) ORDER BY _id"
+        ))
         .bind(("table", EVENTS_TABLE))
+        .bind(("channel", &scope.channel))
         .bind(("id", id))
         .await
         .map_err(EventDBError::Db)?;
 
     let events: Vec<EventDB> = response.take(0).map_err(EventDBError::Db)?;
-    if events.is_empty() {
-        return Err(EventDBError::NotFound);
+
+    Ok(events)
+}
+
+/// Gets at most `limit` [EventDB]s of the channel from the id `start` onward
+/// in the given [Direction], or from the oldest or newest event without a
+/// `start`. The unique index on `channel, _id` keeps this cheap however large
+/// the table is.
+pub async fn get_events_page<T: surrealdb::Connection>(
+    scope: &Scope,
+    start: Option<u64>,
+    direction: Direction,
+    limit: usize,
+    db: &Surreal<T>,
+) -> Result<Vec<EventDB>, EventDBError> {
+    log::debug!("Getting {limit} events from {start:?} {direction:?}");
+
+    let (compare, order) = match direction {
+        Direction::Ascending => (">=", "ASC"),
+        Direction::Descending => ("<=", "DESC"),
+    };
+    let filter = match start {
+        Some(_) => format!("AND _id {compare} type::number($start)"),
+        None => String::new(),
+    };
+    let scope_condition = scope.conditions().join(" AND ");
+    let mut response = db
+        .query(format!(
+            "SELECT * FROM type::table($table) WHERE {scope_condition} {filter} \
+             ORDER BY _id {order} LIMIT $limit"
+        ))
+        .bind(("table", EVENTS_TABLE))
+        .bind(("channel", &scope.channel))
+        .bind(("start", start))
+        .bind(("limit", limit))
+        .await
+        .map_err(EventDBError::Db)?;
+
+    let events: Vec<EventDB> = response.take(0).map_err(EventDBError::Db)?;
+
+    Ok(events)
+}
+
+/// Gets the [EventDB]s of the channel logged within the [TimeRange], oldest
+/// first, and at most `limit` of them if given.
+pub async fn get_events_between<T: surrealdb::Connection>(
+    scope: &Scope,
+    range: &TimeRange,
+    limit: Option<usize>,
+    db: &Surreal<T>,
+) -> Result<Vec<EventDB>, EventDBError> {
+    log::debug!("Getting events of {scope:?} within {range:?}");
+
+    let mut conditions = scope.conditions();
+    time_conditions(range, &mut conditions);
+    let where_clause = where_clause(&conditions);
+    let limit_clause = match limit {
+        Some(_) => "LIMIT $limit",
+        None => "",
+    };
+
+    let mut response = db
+        .query(format!(
+            "SELECT * FROM type::table($table) {where_clause} ORDER BY time, _id {limit_clause}"
+        ))
+        .bind(("table", EVENTS_TABLE))
+        .bind(("channel", &scope.channel))
+        .bind(("start", time_bound(range.start)))
+        .bind(("end", time_bound(range.end)))
+        .bind(("limit", limit))
+        .await
+        .map_err(EventDBError::Db)?;
+
+    let events: Vec<EventDB> = response.take(0).map_err(EventDBError::Db)?;
+
+    Ok(events)
+}
+
+/// Gets the [EventDB]s of the channel matching the [EventFilter] in ascending
+/// id order, and at most `limit` of them if given. Every value of the filter
+/// is bound as a parameter rather than written into the query.
+pub async fn get_events_matching<T: surrealdb::Connection>(
+    scope: &Scope,
+    filter: &EventFilter,
+    limit: Option<usize>,
+    db: &Surreal<T>,
+) -> Result<Vec<EventDB>, EventDBError> {
+    log::debug!("Getting events of {scope:?} matching {filter:?}");
+
+    let mut conditions = scope.conditions();
+    if !filter.authors.is_empty() {
+        conditions.push("author INSIDE $authors");
+    }
+    if !filter.machines.is_empty() {
+        conditions.push("machine INSIDE $machines");
+    }
+    // Any of the keywords, each matched against the lowercased text
+    let keywords = filter
+        .keywords
+        .iter()
+        .map(|keyword| keyword.to_lowercase())
+        .collect::<Vec<String>>();
+    let keyword_condition = format!(
+        "({})",
+        (0..keywords.len())
+            .map(|i| format!("string::lowercase(event) CONTAINS $keywords[{i}]"))
+            .collect::<Vec<String>>()
+            .join(" OR ")
+    );
+    if !keywords.is_empty() {
+        conditions.push(&keyword_condition);
+    }
+    time_conditions(&filter.time, &mut conditions);
+    if filter.ids.start.is_some() {
+        conditions.push("_id >= type::number($first_id)");
+    }
+    if filter.ids.end.is_some() {
+        conditions.push("_id < type::number($end_id)");
+    }
+    if !filter.tags.is_empty() {
+        conditions.push("tags CONTAINSANY $tags");
+    }
+    let severities = filter
+        .severity
+        .map(|severity| severity.and_above().collect::<Vec<Severity>>());
+    if severities.is_some() {
+        conditions.push("severity INSIDE $severities");
     }
+    // Every field condition, only met by a field of the same kind
+    let field_conditions = filter
+        .fields
+        .iter()
+        .enumerate()
+        .map(|(i, condition)| {
+            let kind = match condition.value {
+                FieldValue::Bool(_) => "bool",
+                FieldValue::Int(_) | FieldValue::Float(_) => "number",
+                FieldValue::String(_) => "string",
+            };
+            format!(
+                "(type::is::{kind}(fields[$field_key_{i}]) AND fields[$field_key_{i}] {} $field_value_{i})",
+                condition.comparison.operator()
+            )
+        })
+        .collect::<Vec<String>>();
+    conditions.extend(field_conditions.iter().map(String::as_str));
+    let where_clause = where_clause(&conditions);
+    let limit_clause = match limit {
+        Some(_) => "LIMIT $limit",
+        None => "",
+    };
+
+    let mut query = db
+        .query(format!(
+            "SELECT * FROM type::table($table) {where_clause} ORDER BY _id {limit_clause}"
+        ))
+        .bind(("table", EVENTS_TABLE))
+        .bind(("channel", &scope.channel))
+        .bind(("authors", &filter.authors))
+        .bind(("machines", &filter.machines))
+        .bind(("keywords", &keywords))
+        .bind(("start", time_bound(filter.time.start)))
+        .bind(("end", time_bound(filter.time.end)))
+        .bind(("first_id", filter.ids.start))
+        .bind(("end_id", filter.ids.end))
+        .bind(("tags", &filter.tags))
+        .bind(("severities", severities))
+        .bind(("limit", limit));
+    for (i, condition) in filter.fields.iter().enumerate() {
+        query = query
+            .bind((format!("field_key_{i}"), &condition.key))
+            .bind((format!("field_value_{i}"), &condition.value));
+    }
+    let mut response = query.await.map_err(EventDBError::Db)?;
+
+    let events: Vec<EventDB> = response.take(0).map_err(EventDBError::Db)?;
 
     Ok(events)
 }
 
+/// A row of [search_events], an [EventDB] with how well it matched.
+#[derive(Debug, Deserialize)]
+struct SearchRow {
+    #[serde(flatten)]
+    event: EventDB,
+    score: f64,
+    /// The matched character ranges of every searched field, by the field's
+    /// position in the index
+    #[serde(default)]
+    offsets: HashMap<String, Vec<Offset>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Offset {
+    s: usize,
+    e: usize,
+}
+
+/// Gets at most `limit` [SearchHit]s for the [EventDB]s of the channel whose
+/// [event](EventDB) contains every word in `terms`, best matches first. The
+/// words are matched case-insensitively and by their stem, so `failed` finds
+/// `failing`.
+pub async fn search_events<T: surrealdb::Connection>(
+    scope: &Scope,
+    terms: &str,
+    limit: usize,
+    db: &Surreal<T>,
+) -> Result<Vec<SearchHit>, EventDBError> {
+    log::debug!("Searching events of {scope:?} for {terms:?}");
+
+    if terms.trim().is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let scope_condition = scope.conditions().join(" AND ");
+    let mut response = db
+        .query(format!(
+            "SELECT *, search::score(1) AS score, search::offsets(1) AS offsets \
+             FROM type::table($table) WHERE {scope_condition} AND event @1@ $terms \
+             ORDER BY score DESC LIMIT $limit"
+        ))
+        .bind(("table", EVENTS_TABLE))
+        .bind(("channel", &scope.channel))
+        .bind(("terms", terms))
+        .bind(("limit", limit))
+        .await
+        .map_err(EventDBError::Db)?;
+
+    let rows: Vec<SearchRow> = response.take(0).map_err(EventDBError::Db)?;
+    let hits = rows
+        .into_iter()
+        .map(|mut row| {
+            let matches = row
+                .offsets
+                .remove("0")
+                .unwrap_or_default()
+                .into_iter()
+                .map(|offset| offset.s..offset.e)
+                .collect();
+            SearchHit {
+                snippet: search::snippet(&row.event.event, matches),
+                event: row.event,
+                score: row.score,
+            }
+        })
+        .collect();
+
+    Ok(hits)
+}
+
+/// Adds the conditions on `time` of a [TimeRange], comparing against the
+/// `$start` and `$end` parameters bound to [time_bound]s.
+fn time_conditions(range: &TimeRange, conditions: &mut Vec<&str>) {
+    // Times are stored as ISO 8601 strings, which sort like the times they
+    // represent, so they are compared as they are
+    match range.start {
+        TimeBound::Inclusive(_) => conditions.push("time >= $start"),
+        TimeBound::Exclusive(_) => conditions.push("time > $start"),
+        TimeBound::Unbounded => {}
+    }
+    match range.end {
+        TimeBound::Inclusive(_) => conditions.push("time <= $end"),
+        TimeBound::Exclusive(_) => conditions.push("time < $end"),
+        TimeBound::Unbounded => {}
+    }
+}
+
+fn time_bound(bound: TimeBound) -> Option<chrono::NaiveDateTime> {
+    match bound {
+        TimeBound::Inclusive(time) | TimeBound::Exclusive(time) => Some(time),
+        TimeBound::Unbounded => None,
+    }
+}
+
+fn where_clause(conditions: &[&str]) -> String {
+    match conditions.is_empty() {
+        true => String::new(),
+        false => format!("WHERE {}", conditions.join(" AND ")),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct LastId {
+    channel: String,
+    last: u64,
+}
+
+/// Gets the id the next inserted [EventDB] of every channel with events
+/// should use, which is one past the highest stored id. A channel without
+/// events starts at 0.
+pub async fn next_event_ids<T: surrealdb::Connection>(
+    db: &Surreal<T>,
+) -> Result<HashMap<String, u64>, EventDBError> {
+    let mut response = db
+        .query("SELECT channel, math::max(_id) AS last FROM type::table($table) GROUP BY channel")
+        .bind(("table", EVENTS_TABLE))
+        .await
+        .map_err(EventDBError::Db)?;
+
+    let last_ids: Vec<LastId> = response.take(0).map_err(EventDBError::Db)?;
+
+    Ok(last_ids
+        .into_iter()
+        .map(|LastId { channel, last }| (channel, last + 1))
+        .collect())
+}
+
 pub async fn get_event<T: surrealdb::Connection>(
+    scope: &Scope,
     id: u64,
     db: &Surreal<T>,
 ) -> Result<EventDB, EventDBError> {
+    let scope_condition = scope.conditions().join(" AND ");
     let mut query = db
-        .query("SELECT * FROM type::table($table) WHERE _id == type::number($id)")
+        .query(format!(
+            "SELECT * FROM type::table($table) \
+             WHERE {scope_condition} AND _id == type::number($id)"
+        ))
         .bind(("table", EVENTS_TABLE))
+        .bind(("channel", &scope.channel))
         .bind(("id", id))
         .await
         .map_err(EventDBError::Db)?;
@@ -73,11 +687,286 @@ pub async fn get_event<T: surrealdb::Connection>(
     Ok(event)
 }
 
+/// Gets the thread of the [EventDB] of the channel with the id: the event it
+/// started with and every reply to that, however deeply nested, in ascending
+/// id order. Replies to events outside the [Scope] are left out with them.
+pub async fn get_thread<T: surrealdb::Connection>(
+    scope: &Scope,
+    id: u64,
+    db: &Surreal<T>,
+) -> Result<Vec<EventDB>, EventDBError> {
+    log::debug!("Getting thread of {id} in {scope:?}");
+
+    // Parents have lower ids than their replies, so this ends
+    let mut root = get_event(scope, id, db).await?;
+    while let Some(parent) = root.parent {
+        match get_event(scope, parent, db).await {
+            Ok(event) => root = event,
+            Err(EventDBError::NotFound) => break,
+            Err(e) => return Err(e),
+        }
+    }
+
+    let scope_condition = scope.conditions().join(" AND ");
+    let mut parents = vec![root._id];
+    let mut thread = vec![root];
+    while !parents.is_empty() {
+        let mut response = db
+            .query(format!(
+                "SELECT * FROM type::table($table) \
+                 WHERE {scope_condition} AND parent INSIDE $parents"
+            ))
+            .bind(("table", EVENTS_TABLE))
+            .bind(("channel", &scope.channel))
+            .bind(("parents", &parents))
+            .await
+            .map_err(EventDBError::Db)?;
+        let replies: Vec<EventDB> = response.take(0).map_err(EventDBError::Db)?;
+        parents = replies.iter().map(|reply| reply._id).collect();
+        thread.extend(replies);
+    }
+    thread.sort_by_key(|event| event._id);
+
+    Ok(thread)
+}
+
+/// A stored [Revision] of an event.
+#[derive(Debug, Serialize, Deserialize)]
+struct RevisionRow {
+    channel: String,
+    event_id: u64,
+    revision: u32,
+    author: String,
+    time: chrono::NaiveDateTime,
+    event: String,
+    diff: Vec<Change>,
+}
+
+/// Replaces the text of an [EventDB] by `text`, written by `author`. The
+/// previous texts are kept in the revisions table, the original as revision
+/// 0 on the first edit, each with its diff from the one before, so it is
+/// only computed once. Concurrent edits of an event fail, all but one.
+pub async fn edit_event<T: surrealdb::Connection>(
+    event: &EventDB,
+    author: &str,
+    text: &str,
+    db: &Surreal<T>,
+) -> Result<EventDB, EventDBError> {
+    log::debug!("Editing event {}/{}", event.channel, event._id);
+
+    let revision = |revision, author: &str, time, previous: &str, text: &str| RevisionRow {
+        channel: event.channel.clone(),
+        event_id: event._id,
+        revision,
+        author: author.into(),
+        time,
+        event: text.into(),
+        diff: diff::diff(previous, text),
+    };
+    let original = match event.revision {
+        0 => Some(revision(0, &event.author, event.time, "", &event.event)),
+        _ => None,
+    };
+    let number = event.revision + 1;
+    let now = chrono::Utc::now().naive_utc();
+    let edit = revision(number, author, now, &event.event, text);
+
+    let create_original = match original {
+        Some(_) => "CREATE type::table($revisions) CONTENT $original;",
+        None => "",
+    };
+    db.query(format!(
+        "BEGIN TRANSACTION; \
+         {create_original} \
+         CREATE type::table($revisions) CONTENT $edit; \
+         UPDATE type::table($table) SET event = $text, revision = $number \
+         WHERE channel == $channel AND _id == type::number($id); \
+         COMMIT TRANSACTION;"
+    ))
+    .bind(("table", EVENTS_TABLE))
+    .bind(("revisions", REVISIONS_TABLE))
+    .bind(("original", original))
+    .bind(("edit", edit))
+    .bind(("text", text))
+    .bind(("number", number))
+    .bind(("channel", &event.channel))
+    .bind(("id", event._id))
+    .await
+    .map_err(EventDBError::Db)?
+    .check()
+    .map_err(EventDBError::Db)?;
+
+    let scope = Scope {
+        channel: event.channel.clone(),
+        retracted: true,
+    };
+    get_event(&scope, event._id, db).await
+}
+
+/// Retracts an [EventDB], hiding it from every [Scope] but those including
+/// retracted events. The event stays stored along with the [Retraction];
+/// retracting it again keeps the first one.
+pub async fn retract_event<T: surrealdb::Connection>(
+    event: &EventDB,
+    retraction: &Retraction,
+    db: &Surreal<T>,
+) -> Result<EventDB, EventDBError> {
+    log::debug!("Retracting event {}/{}", event.channel, event._id);
+
+    db.query(
+        "UPDATE type::table($table) SET retraction = $retraction \
+         WHERE channel == $channel AND _id == type::number($id) AND retraction == NONE",
+    )
+    .bind(("table", EVENTS_TABLE))
+    .bind(("retraction", retraction))
+    .bind(("channel", &event.channel))
+    .bind(("id", event._id))
+    .await
+    .map_err(EventDBError::Db)?
+    .check()
+    .map_err(EventDBError::Db)?;
+
+    let scope = Scope {
+        channel: event.channel.clone(),
+        retracted: true,
+    };
+    get_event(&scope, event._id, db).await
+}
+
+/// Gets every [Revision] of the [EventDB] of the channel with the id, oldest
+/// first, each with the diff from the one before stored by [edit_event]. An
+/// event that was never edited only has its original.
+pub async fn get_revisions<T: surrealdb::Connection>(
+    scope: &Scope,
+    id: u64,
+    db: &Surreal<T>,
+) -> Result<Vec<Revision>, EventDBError> {
+    let event = get_event(scope, id, db).await?;
+
+    let mut response = db
+        .query(
+            "SELECT * FROM type::table($table) \
+             WHERE channel == $channel AND event_id == type::number($id) ORDER BY revision",
+        )
+        .bind(("table", REVISIONS_TABLE))
+        .bind(("channel", &scope.channel))
+        .bind(("id", id))
+        .await
+        .map_err(EventDBError::Db)?;
+    let mut rows: Vec<RevisionRow> = response.take(0).map_err(EventDBError::Db)?;
+    if rows.is_empty() {
+        rows.push(RevisionRow {
+            channel: event.channel,
+            event_id: event._id,
+            revision: 0,
+            author: event.author,
+            time: event.time,
+            diff: diff::diff("", &event.event),
+            event: event.event,
+        });
+    }
+
+    let revisions = rows
+        .into_iter()
+        .map(|row| Revision {
+            revision: row.revision,
+            author: row.author,
+            time: row.time,
+            event: row.event,
+            diff: row.diff,
+        })
+        .collect();
+
+    Ok(revisions)
+}
+
+/// Creates a [Channel], unless one with the name already exists.
+pub async fn create_channel<T: surrealdb::Connection>(
+    name: &str,
+    db: &Surreal<T>,
+) -> Result<Channel, EventDBError> {
+    log::debug!("Creating channel {name}");
+
+    match get_channel(name, db).await {
+        Ok(_) => return Err(EventDBError::AlreadyExists),
+        Err(EventDBError::NotFound) => {}
+        Err(e) => return Err(e),
+    }
+
+    let channel = Channel {
+        name: name.into(),
+        created: chrono::Utc::now().naive_utc(),
+        archived: None,
+    };
+    db.create::<Vec<Channel>>(CHANNELS_TABLE)
+        .content(&channel)
+        .await
+        .map_err(EventDBError::Db)?;
+
+    Ok(channel)
+}
+
+pub async fn get_channel<T: surrealdb::Connection>(
+    name: &str,
+    db: &Surreal<T>,
+) -> Result<Channel, EventDBError> {
+    let mut response = db
+        .query("SELECT * FROM type::table($table) WHERE name == $name")
+        .bind(("table", CHANNELS_TABLE))
+        .bind(("name", name))
+        .await
+        .map_err(EventDBError::Db)?;
+
+    response
+        .take::<Option<Channel>>(0)
+        .map_err(EventDBError::Db)?
+        .ok_or(EventDBError::NotFound)
+}
+
+/// Gets every [Channel], archived ones included, by name.
+pub async fn get_channels<T: surrealdb::Connection>(
+    db: &Surreal<T>,
+) -> Result<Vec<Channel>, EventDBError> {
+    let mut response = db
+        .query("SELECT * FROM type::table($table) ORDER BY name")
+        .bind(("table", CHANNELS_TABLE))
+        .await
+        .map_err(EventDBError::Db)?;
+
+    response.take(0).map_err(EventDBError::Db)
+}
+
+/// Marks a [Channel] as archived now, unless it already is.
+pub async fn archive_channel<T: surrealdb::Connection>(
+    name: &str,
+    db: &Surreal<T>,
+) -> Result<Channel, EventDBError> {
+    log::debug!("Archiving channel {name}");
+
+    db.query(
+        "UPDATE type::table($table) SET archived = $now \
+         WHERE name == $name AND archived == NONE",
+    )
+    .bind(("table", CHANNELS_TABLE))
+    .bind(("name", name))
+    .bind(("now", chrono::Utc::now().naive_utc()))
+    .await
+    .map_err(EventDBError::Db)?
+    .check()
+    .map_err(EventDBError::Db)?;
+
+    get_channel(name, db).await
+}
+
 #[cfg(test)]
 mod db_tests {
+    use std::collections::BTreeMap;
+
+    use bucface_utils::{Event, IdRange};
     use rand::Rng;
 
     use super::*;
+    use crate::auth::ANONYMOUS;
 
     #[tokio::test]
     async fn test_start_db() {
@@ -86,7 +975,73 @@ mod db_tests {
         let mut db = Surreal::new::<surrealdb::engine::local::Mem>(())
             .await
             .expect("Failed to start db");
-        start_db(&mut db).await.expect("Failed to initialize db");
+        start_db(&mut db, "Bucface", "Events")
+            .await
+            .expect("Failed to initialize db");
+    }
+
+    #[tokio::test]
+    async fn test_start_db_migrates_events() {
+        let _ = env_logger::try_init();
+
+        let mut db = Surreal::new::<surrealdb::engine::local::Mem>(())
+            .await
+            .expect("Failed to start db");
+        db.use_ns("Bucface").use_db("Events").await.unwrap();
+        // An event and the index of a server from before there were channels and
+        // severities
+        db.query(format!(
+            "DEFINE INDEX {EVENTS_TABLE}_id ON TABLE {EVENTS_TABLE} COLUMNS _id UNIQUE"
+        ))
+        .query("CREATE type::table($table) CONTENT $event")
+        .bind(("table", EVENTS_TABLE))
+        .bind((
+            "event",
+            Event {
+                author: "alice".into(),
+                ..rand::random()
+            },
+        ))
+        .await
+        .unwrap()
+        .check()
+        .unwrap();
+        db.query("UPDATE type::table($table) SET _id = 0, tags = NONE, severity = NONE")
+            .bind(("table", EVENTS_TABLE))
+            .await
+            .unwrap()
+            .check()
+            .unwrap();
+
+        start_db(&mut db, "Bucface", "Events")
+            .await
+            .expect("Failed to initialize db");
+        let event = get_event(&Scope::default(), 0, &db)
+            .await
+            .expect("Failed to get event");
+        assert_eq!(event.author, "alice");
+        assert_eq!(event.channel, DEFAULT_CHANNEL);
+        let filter = EventFilter {
+            severity: Some(Severity::Info),
+            ..Default::default()
+        };
+        assert_eq!(
+            get_events_matching(&Scope::default(), &filter, None, &db)
+                .await
+                .expect("Failed to get events"),
+            vec![event]
+        );
+
+        // The same id may now be used by another channel
+        insert_event(
+            &EventDB {
+                channel: "rig-2".into(),
+                ..EventDB::from(rand::random(), 0)
+            },
+            &db,
+        )
+        .await
+        .expect("Failed to insert event");
     }
 
     #[tokio::test]
@@ -96,7 +1051,9 @@ mod db_tests {
         let mut db = Surreal::new::<surrealdb::engine::local::Mem>(())
             .await
             .expect("Failed to start db");
-        start_db(&mut db).await.expect("Failed to initialize db");
+        start_db(&mut db, "Bucface", "Events")
+            .await
+            .expect("Failed to initialize db");
 
         let mut rng = rand::thread_rng();
 
@@ -107,6 +1064,86 @@ mod db_tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_keyed_event() {
+        let _ = env_logger::try_init();
+
+        let mut db = Surreal::new::<surrealdb::engine::local::Mem>(())
+            .await
+            .expect("Failed to start db");
+        start_db(&mut db, "Bucface", "Events")
+            .await
+            .expect("Failed to initialize db");
+
+        assert_eq!(
+            get_keyed_event(DEFAULT_CHANNEL, ANONYMOUS, "k1", &db)
+                .await
+                .unwrap(),
+            None
+        );
+        let event = EventDB {
+            author: ANONYMOUS.into(),
+            ..EventDB::from(rand::random(), 0)
+        };
+        let inserted = insert_keyed_event(&event, "k1", &db)
+            .await
+            .expect("Failed to insert event");
+        assert_eq!(inserted, event);
+        assert_eq!(
+            get_keyed_event(DEFAULT_CHANNEL, ANONYMOUS, "k1", &db)
+                .await
+                .unwrap(),
+            Some(event.clone())
+        );
+
+        // A used key inserts nothing, even for another event
+        let again = EventDB {
+            author: ANONYMOUS.into(),
+            ..EventDB::from(rand::random(), 1)
+        };
+        let again = insert_keyed_event(&again, "k1", &db).await;
+        assert!(again.is_err());
+        assert!(matches!(
+            get_event(&Scope::default(), 1, &db).await,
+            Err(EventDBError::NotFound)
+        ));
+
+        // Keys are only unique within a channel, and for each author
+        let other = EventDB {
+            author: ANONYMOUS.into(),
+            channel: "rig-2".into(),
+            ..EventDB::from(rand::random(), 0)
+        };
+        insert_keyed_event(&other, "k1", &db)
+            .await
+            .expect("Failed to insert event");
+        let others = EventDB {
+            author: "alice".into(),
+            ..EventDB::from(rand::random(), 2)
+        };
+        insert_keyed_event(&others, "k1", &db)
+            .await
+            .expect("Failed to insert event");
+        assert_eq!(
+            get_keyed_event("rig-2", ANONYMOUS, "k1", &db)
+                .await
+                .unwrap(),
+            Some(other)
+        );
+        assert_eq!(
+            get_keyed_event(DEFAULT_CHANNEL, "alice", "k1", &db)
+                .await
+                .unwrap(),
+            Some(others)
+        );
+        assert_eq!(
+            get_keyed_event(DEFAULT_CHANNEL, ANONYMOUS, "k1", &db)
+                .await
+                .unwrap(),
+            Some(event)
+        );
+    }
+
     #[tokio::test]
     async fn test_get_events() {
         let _ = env_logger::try_init();
@@ -114,7 +1151,9 @@ mod db_tests {
         let mut db = Surreal::new::<surrealdb::engine::local::Mem>(())
             .await
             .expect("Failed to start db");
-        start_db(&mut db).await.expect("Failed to initialize db");
+        start_db(&mut db, "Bucface", "Events")
+            .await
+            .expect("Failed to initialize db");
 
         let mut rng = rand::thread_rng();
 
@@ -130,7 +1169,7 @@ mod db_tests {
             assert_eq!(response[0], *event);
         }
 
-        let mut new_events = get_events_since(0, &db)
+        let mut new_events = get_events_since(&Scope::default(), 0, &db)
             .await
             .expect("Failed to get events");
 
@@ -141,4 +1180,808 @@ mod db_tests {
             assert_eq!(event, new_event);
         }
     }
+
+    #[tokio::test]
+    async fn test_get_events_page() {
+        let _ = env_logger::try_init();
+
+        let mut db = Surreal::new::<surrealdb::engine::local::Mem>(())
+            .await
+            .expect("Failed to start db");
+        start_db(&mut db, "Bucface", "Events")
+            .await
+            .expect("Failed to initialize db");
+
+        let page = |start, direction, limit| {
+            let db = db.clone();
+            async move {
+                get_events_page(&Scope::default(), start, direction, limit, &db)
+                    .await
+                    .expect("Failed to get page")
+                    .iter()
+                    .map(|event| event._id)
+                    .collect::<Vec<u64>>()
+            }
+        };
+
+        assert!(page(None, Direction::Descending, 10).await.is_empty());
+        assert!(get_events_since(&Scope::default(), 0, &db)
+            .await
+            .unwrap()
+            .is_empty());
+
+        let mut rng = rand::thread_rng();
+        for i in 0..20 {
+            insert_event(&EventDB::from(rng.gen(), i), &db)
+                .await
+                .expect("Failed to insert event");
+        }
+
+        assert_eq!(page(None, Direction::Ascending, 3).await, vec![0, 1, 2]);
+        assert_eq!(page(Some(5), Direction::Ascending, 3).await, vec![5, 6, 7]);
+        assert_eq!(page(Some(18), Direction::Ascending, 3).await, vec![18, 19]);
+        assert_eq!(page(None, Direction::Descending, 3).await, vec![19, 18, 17]);
+        assert_eq!(page(Some(1), Direction::Descending, 3).await, vec![1, 0]);
+        assert!(page(Some(20), Direction::Ascending, 3).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_get_events_between() {
+        let _ = env_logger::try_init();
+
+        let mut db = Surreal::new::<surrealdb::engine::local::Mem>(())
+            .await
+            .expect("Failed to start db");
+        start_db(&mut db, "Bucface", "Events")
+            .await
+            .expect("Failed to initialize db");
+
+        let date = chrono::NaiveDate::from_ymd_opt(2024, 2, 1).unwrap();
+        let at = |minute: u32, milli: u32| date.and_hms_milli_opt(0, minute, 0, milli).unwrap();
+        let mut rng = rand::thread_rng();
+        let times = [
+            at(0, 0),
+            at(0, 500),
+            at(1, 0),
+            at(1, 123),
+            at(2, 0),
+            at(59, 0),
+        ];
+        // Inserted out of order to check the events come back oldest first
+        for (id, time) in times.iter().enumerate().rev() {
+            let event = EventDB {
+                time: *time,
+                ..EventDB::from(rng.gen(), id as u64)
+            };
+            insert_event(&event, &db)
+                .await
+                .expect("Failed to insert event");
+        }
+
+        let between = |range: TimeRange, limit: Option<usize>| {
+            let db = db.clone();
+            async move {
+                let events = get_events_between(&Scope::default(), &range, limit, &db)
+                    .await
+                    .expect("Failed to get events");
+                for event in &events {
+                    assert!(range.contains(&event.time));
+                }
+                events.iter().map(|event| event._id).collect::<Vec<u64>>()
+            }
+        };
+
+        let range = |start, end| TimeRange { start, end };
+        assert_eq!(
+            between(TimeRange::default(), None).await,
+            vec![0, 1, 2, 3, 4, 5]
+        );
+        assert_eq!(
+            between(
+                range(
+                    TimeBound::Inclusive(at(0, 500)),
+                    TimeBound::Inclusive(at(2, 0))
+                ),
+                None
+            )
+            .await,
+            vec![1, 2, 3, 4]
+        );
+        assert_eq!(
+            between(
+                range(
+                    TimeBound::Exclusive(at(0, 500)),
+                    TimeBound::Exclusive(at(2, 0))
+                ),
+                None
+            )
+            .await,
+            vec![2, 3]
+        );
+        assert_eq!(
+            between(
+                range(TimeBound::Exclusive(at(0, 0)), TimeBound::Unbounded),
+                Some(2)
+            )
+            .await,
+            vec![1, 2]
+        );
+        assert_eq!(
+            between(
+                range(TimeBound::Unbounded, TimeBound::Exclusive(at(0, 0))),
+                None
+            )
+            .await,
+            Vec::<u64>::new()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_events_matching() {
+        let _ = env_logger::try_init();
+
+        let mut db = Surreal::new::<surrealdb::engine::local::Mem>(())
+            .await
+            .expect("Failed to start db");
+        start_db(&mut db, "Bucface", "Events")
+            .await
+            .expect("Failed to initialize db");
+
+        let mut rng = rand::thread_rng();
+        let authors = ["alice", "bob", "carol"];
+        let machines = ["buc-07", "buc-08", "\" OR true OR \""];
+        // Every fifth event has no fields, and the temperature is an integer
+        // in every other one
+        let fields = |id: usize| -> BTreeMap<String, FieldValue> {
+            if id.is_multiple_of(5) {
+                return BTreeMap::new();
+            }
+            let temperature = match id % 2 {
+                0 => FieldValue::Int(30 + id as i64),
+                _ => FieldValue::Float(30.5 + id as f64),
+            };
+            BTreeMap::from([
+                ("exit_code".into(), FieldValue::Int(id as i64 % 3)),
+                ("temperature".into(), temperature),
+                (
+                    "stage".into(),
+                    FieldValue::String(format!("stage-{}", id % 4)),
+                ),
+                ("ok".into(), FieldValue::Bool(id.is_multiple_of(3))),
+            ])
+        };
+        let texts = [
+            "Pump FAILED",
+            "pump restarted",
+            "Cooling failed",
+            "all fine",
+        ];
+        let tags = [vec![], vec!["pump"], vec!["pump", "rig-2"], vec!["shift"]];
+        let mut events = Vec::new();
+        for id in 0..30 {
+            let event = EventDB {
+                author: authors[rng.gen_range(0..authors.len())].into(),
+                machine: machines[id % machines.len()].into(),
+                event: texts[id % texts.len()].into(),
+                tags: tags[id % tags.len()]
+                    .iter()
+                    .map(|tag| tag.to_string())
+                    .collect(),
+                severity: Severity::ALL[id % Severity::ALL.len()],
+                fields: fields(id),
+                ..EventDB::from(rng.gen(), id as u64)
+            };
+            insert_event(&event, &db)
+                .await
+                .expect("Failed to insert event");
+            events.push(event);
+        }
+        // Events of other channels never match
+        let other = EventDB {
+            channel: "rig-2".into(),
+            ..events[0].clone()
+        };
+        insert_event(&other, &db)
+            .await
+            .expect("Failed to insert event");
+
+        let set = |values: &[&str]| values.iter().map(|value| value.to_string()).collect();
+        let filters = [
+            EventFilter::default(),
+            EventFilter {
+                machines: set(&["buc-07"]),
+                ..Default::default()
+            },
+            EventFilter {
+                authors: set(&["alice", "carol"]),
+                machines: set(&["buc-08", machines[2]]),
+                ..Default::default()
+            },
+            EventFilter {
+                machines: set(&["buc-07"]),
+                ids: IdRange {
+                    start: Some(3),
+                    end: Some(20),
+                },
+                time: TimeRange {
+                    start: TimeBound::Inclusive(events[0].time),
+                    end: TimeBound::Unbounded,
+                },
+                ..Default::default()
+            },
+            EventFilter {
+                machines: set(&["buc-09"]),
+                ..Default::default()
+            },
+            EventFilter {
+                keywords: set(&["failed", "Restart"]),
+                ..Default::default()
+            },
+            EventFilter {
+                machines: set(&["buc-08"]),
+                keywords: set(&["PUMP"]),
+                ..Default::default()
+            },
+            EventFilter {
+                tags: set(&["rig-2", "shift"]),
+                ..Default::default()
+            },
+            EventFilter {
+                severity: Some(Severity::Error),
+                ..Default::default()
+            },
+            EventFilter {
+                tags: set(&["pump"]),
+                severity: Some(Severity::Warn),
+                ..Default::default()
+            },
+            EventFilter {
+                fields: vec!["fields.exit_code != 0".parse().unwrap()],
+                ..Default::default()
+            },
+            EventFilter {
+                fields: vec![
+                    "temperature > 40".parse().unwrap(),
+                    "temperature <= 50.5".parse().unwrap(),
+                    "stage = stage-1".parse().unwrap(),
+                ],
+                ..Default::default()
+            },
+            EventFilter {
+                machines: set(&["buc-08"]),
+                fields: vec!["ok == true".parse().unwrap()],
+                ..Default::default()
+            },
+            // Fields of another kind never match
+            EventFilter {
+                fields: vec!["exit_code != \"0\"".parse().unwrap()],
+                ..Default::default()
+            },
+        ];
+
+        for filter in filters {
+            let expected = events
+                .iter()
+                .filter(|event| filter.matches(event))
+                .cloned()
+                .collect::<Vec<EventDB>>();
+            let result = get_events_matching(&Scope::default(), &filter, None, &db)
+                .await
+                .expect("Failed to get events");
+            assert_eq!(result, expected, "{filter:?}");
+
+            let limited = get_events_matching(&Scope::default(), &filter, Some(2), &db)
+                .await
+                .expect("Failed to get events");
+            assert_eq!(limited, expected.into_iter().take(2).collect::<Vec<_>>());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_search_events() {
+        let _ = env_logger::try_init();
+
+        let mut db = Surreal::new::<surrealdb::engine::local::Mem>(())
+            .await
+            .expect("Failed to start db");
+        start_db(&mut db, "Bucface", "Events")
+            .await
+            .expect("Failed to initialize db");
+
+        let mut rng = rand::thread_rng();
+        let texts = [
+            "Pump FAILED at stage 3, restarting pump",
+            "Restarted the pump, still failing",
+            "Pump running fine",
+            "Cooling failed",
+        ];
+        for (id, text) in texts.iter().enumerate() {
+            let event = EventDB {
+                event: text.to_string(),
+                ..EventDB::from(rng.gen(), id as u64)
+            };
+            insert_event(&event, &db)
+                .await
+                .expect("Failed to insert event");
+        }
+        // Indexes are not defined again, and still index new events
+        start_db(&mut db, "Bucface", "Events")
+            .await
+            .expect("Failed to initialize db again");
+
+        let search = |terms: &'static str, limit| {
+            let db = db.clone();
+            async move {
+                search_events(&Scope::default(), terms, limit, &db)
+                    .await
+                    .expect("Failed to search")
+            }
+        };
+
+        let hits = search("pump fail", 10).await;
+        let mut ids = hits.iter().map(|hit| hit.event._id).collect::<Vec<u64>>();
+        ids.sort();
+        assert_eq!(ids, vec![0, 1]);
+        assert!(hits[0].score >= hits[1].score);
+        let hit = hits.iter().find(|hit| hit.event._id == 0).unwrap();
+        let highlighted = hit
+            .snippet
+            .highlights
+            .iter()
+            .map(|range| &hit.snippet.text[range.clone()])
+            .collect::<Vec<&str>>();
+        assert_eq!(highlighted, vec!["Pump", "FAILED", "pump"]);
+
+        assert_eq!(search("PUMP", 1).await.len(), 1);
+        assert_eq!(search("cooling", 10).await[0].event._id, 3);
+        assert!(search("heater", 10).await.is_empty());
+        assert!(search("  ", 10).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_next_event_ids() {
+        let _ = env_logger::try_init();
+
+        let mut db = Surreal::new::<surrealdb::engine::local::Mem>(())
+            .await
+            .expect("Failed to start db");
+        start_db(&mut db, "Bucface", "Events")
+            .await
+            .expect("Failed to initialize db");
+
+        assert!(next_event_ids(&db)
+            .await
+            .expect("Failed to get ids")
+            .is_empty());
+
+        let mut rng = rand::thread_rng();
+        for (channel, i) in [("main", 3), ("main", 0), ("rig-2", 17), ("main", 5)] {
+            let event = EventDB {
+                channel: channel.into(),
+                ..EventDB::from(rng.gen(), i)
+            };
+            insert_event(&event, &db)
+                .await
+                .expect("Failed to insert event");
+        }
+
+        assert_eq!(
+            next_event_ids(&db).await.expect("Failed to get ids"),
+            HashMap::from([("main".to_string(), 6), ("rig-2".to_string(), 18)])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_edit_event() {
+        let _ = env_logger::try_init();
+
+        let mut db = Surreal::new::<surrealdb::engine::local::Mem>(())
+            .await
+            .expect("Failed to start db");
+        start_db(&mut db, "Bucface", "Events")
+            .await
+            .expect("Failed to initialize db");
+
+        let original = EventDB {
+            author: "alice".into(),
+            event: "Pump failed at stage 3".into(),
+            ..EventDB::from(rand::random(), 7)
+        };
+        insert_event(&original, &db)
+            .await
+            .expect("Failed to insert event");
+        let revisions = get_revisions(&Scope::default(), 7, &db)
+            .await
+            .expect("Failed to get revisions");
+        assert_eq!(revisions.len(), 1);
+        assert_eq!(revisions[0].event, original.event);
+
+        let edited = edit_event(&original, "alice", "Pump failed at stage 4", &db)
+            .await
+            .expect("Failed to edit event");
+        assert_eq!(edited.revision, 1);
+        assert_eq!(edited.event, "Pump failed at stage 4");
+        assert_eq!(edited.time, original.time);
+        let edited = edit_event(&edited, "bob", "Pump failed at stage 4, fixed", &db)
+            .await
+            .expect("Failed to edit event");
+        assert_eq!(edited.revision, 2);
+        assert_eq!(get_event(&Scope::default(), 7, &db).await.unwrap(), edited);
+
+        // Editing an outdated revision loses against the edit before it
+        assert!(edit_event(&original, "alice", "conflict", &db)
+            .await
+            .is_err());
+        assert_eq!(get_event(&Scope::default(), 7, &db).await.unwrap(), edited);
+
+        let revisions = get_revisions(&Scope::default(), 7, &db)
+            .await
+            .expect("Failed to get revisions");
+        assert_eq!(
+            revisions
+                .iter()
+                .map(|revision| (revision.revision, revision.author.as_str()))
+                .collect::<Vec<_>>(),
+            vec![(0, "alice"), (1, "alice"), (2, "bob")]
+        );
+        assert_eq!(
+            revisions[0],
+            Revision {
+                revision: 0,
+                author: "alice".into(),
+                time: original.time,
+                event: original.event.clone(),
+                diff: vec![Change::Insert(original.event.clone())],
+            }
+        );
+        assert_eq!(
+            revisions[2].diff,
+            vec![
+                Change::Equal("Pump failed at stage ".into()),
+                Change::Delete("4".into()),
+                Change::Insert("4, fixed".into()),
+            ]
+        );
+
+        // The search index follows the edits
+        let hits = search_events(&Scope::default(), "fixed", 10, &db)
+            .await
+            .expect("Failed to search");
+        assert_eq!(hits.len(), 1);
+        assert!(matches!(
+            get_revisions(&Scope::default(), 8, &db).await,
+            Err(EventDBError::NotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_retract_event() {
+        let _ = env_logger::try_init();
+
+        let mut db = Surreal::new::<surrealdb::engine::local::Mem>(())
+            .await
+            .expect("Failed to start db");
+        start_db(&mut db, "Bucface", "Events")
+            .await
+            .expect("Failed to initialize db");
+
+        let events = (0..3)
+            .map(|id| EventDB {
+                event: format!("Pump failed at stage {id}"),
+                ..EventDB::from(rand::random(), id)
+            })
+            .collect::<Vec<EventDB>>();
+        for event in &events {
+            insert_event(event, &db)
+                .await
+                .expect("Failed to insert event");
+        }
+
+        let retraction = Retraction {
+            author: "alice".into(),
+            time: chrono::Utc::now().naive_utc(),
+            reason: "wrong rig".into(),
+        };
+        let retracted = retract_event(&events[1], &retraction, &db)
+            .await
+            .expect("Failed to retract event");
+        assert_eq!(retracted.retraction, Some(retraction.clone()));
+        assert_eq!(retracted.event, events[1].event);
+
+        // The first retraction is kept
+        let again = Retraction {
+            reason: "again".into(),
+            ..retraction.clone()
+        };
+        let retracted = retract_event(&events[1], &again, &db)
+            .await
+            .expect("Failed to retract event");
+        assert_eq!(retracted.retraction, Some(retraction));
+
+        let hidden = Scope::default();
+        let audit = Scope {
+            retracted: true,
+            ..Scope::default()
+        };
+        let ids = |events: Vec<EventDB>| events.iter().map(|e| e._id).collect::<Vec<u64>>();
+        assert_eq!(
+            ids(get_events_since(&hidden, 0, &db).await.unwrap()),
+            vec![0, 2]
+        );
+        assert_eq!(
+            ids(get_events_since(&audit, 0, &db).await.unwrap()),
+            vec![0, 1, 2]
+        );
+        assert_eq!(
+            ids(
+                get_events_page(&hidden, None, Direction::Descending, 10, &db)
+                    .await
+                    .unwrap()
+            ),
+            vec![2, 0]
+        );
+        assert_eq!(
+            ids(
+                get_events_matching(&hidden, &EventFilter::default(), None, &db)
+                    .await
+                    .unwrap()
+            ),
+            vec![0, 2]
+        );
+        assert_eq!(
+            ids(
+                get_events_between(&hidden, &TimeRange::default(), None, &db)
+                    .await
+                    .unwrap()
+            )
+            .len(),
+            2
+        );
+        assert_eq!(
+            search_events(&hidden, "pump", 10, &db).await.unwrap().len(),
+            2
+        );
+        assert_eq!(
+            search_events(&audit, "pump", 10, &db).await.unwrap().len(),
+            3
+        );
+        assert!(matches!(
+            get_event(&hidden, 1, &db).await,
+            Err(EventDBError::NotFound)
+        ));
+        assert!(get_event(&audit, 1, &db)
+            .await
+            .unwrap()
+            .retraction
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn test_get_thread() {
+        let _ = env_logger::try_init();
+
+        let mut db = Surreal::new::<surrealdb::engine::local::Mem>(())
+            .await
+            .expect("Failed to start db");
+        start_db(&mut db, "Bucface", "Events")
+            .await
+            .expect("Failed to initialize db");
+
+        // 0 <- 1 <- 3 <- 5, 0 <- 4 and 2 on its own, and 6 in another channel
+        let parents = [None, Some(0), None, Some(1), Some(0), Some(3)];
+        let mut events = parents
+            .into_iter()
+            .enumerate()
+            .map(|(id, parent)| EventDB {
+                parent,
+                ..EventDB::from(rand::random(), id as u64)
+            })
+            .collect::<Vec<EventDB>>();
+        events.push(EventDB {
+            channel: "rig-2".into(),
+            parent: Some(0),
+            ..EventDB::from(rand::random(), 6)
+        });
+        for event in &events {
+            insert_event(event, &db)
+                .await
+                .expect("Failed to insert event");
+        }
+
+        let thread = |id| {
+            let db = db.clone();
+            async move {
+                get_thread(&Scope::default(), id, &db)
+                    .await
+                    .map(|events| events.iter().map(|e| e._id).collect::<Vec<u64>>())
+            }
+        };
+        for id in [0, 1, 3, 4, 5] {
+            assert_eq!(thread(id).await.unwrap(), vec![0, 1, 3, 4, 5]);
+        }
+        assert_eq!(thread(2).await.unwrap(), vec![2]);
+        assert!(matches!(thread(6).await, Err(EventDBError::NotFound)));
+
+        // A retracted event hides its replies
+        let retraction = Retraction {
+            author: "alice".into(),
+            time: chrono::Utc::now().naive_utc(),
+            reason: "wrong rig".into(),
+        };
+        retract_event(&events[1], &retraction, &db)
+            .await
+            .expect("Failed to retract event");
+        assert_eq!(thread(0).await.unwrap(), vec![0, 4]);
+        assert_eq!(thread(3).await.unwrap(), vec![3, 5]);
+    }
+
+    #[tokio::test]
+    async fn test_channels() {
+        let _ = env_logger::try_init();
+
+        let mut db = Surreal::new::<surrealdb::engine::local::Mem>(())
+            .await
+            .expect("Failed to start db");
+        start_db(&mut db, "Bucface", "Events")
+            .await
+            .expect("Failed to initialize db");
+
+        let names = |channels: Vec<Channel>| {
+            channels
+                .into_iter()
+                .map(|channel| channel.name)
+                .collect::<Vec<String>>()
+        };
+        assert_eq!(names(get_channels(&db).await.unwrap()), vec!["main"]);
+
+        let rig = create_channel("rig-2", &db)
+            .await
+            .expect("Failed to create channel");
+        assert_eq!(rig.archived, None);
+        create_channel("alpha", &db)
+            .await
+            .expect("Failed to create channel");
+        assert!(matches!(
+            create_channel("rig-2", &db).await,
+            Err(EventDBError::AlreadyExists)
+        ));
+        assert_eq!(
+            names(get_channels(&db).await.unwrap()),
+            vec!["alpha", "main", "rig-2"]
+        );
+
+        let archived = archive_channel("rig-2", &db)
+            .await
+            .expect("Failed to archive channel");
+        assert!(archived.archived.is_some());
+        assert_eq!(get_channel("rig-2", &db).await.unwrap(), archived);
+        // Archiving again keeps the original time
+        assert_eq!(archive_channel("rig-2", &db).await.unwrap(), archived);
+        assert!(matches!(
+            archive_channel("nothing", &db).await,
+            Err(EventDBError::NotFound)
+        ));
+
+        // Starting again keeps the channels
+        start_db(&mut db, "Bucface", "Events")
+            .await
+            .expect("Failed to initialize db");
+        assert_eq!(get_channels(&db).await.unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_insert_duplicate_id() {
+        let _ = env_logger::try_init();
+
+        let mut db = Surreal::new::<surrealdb::engine::local::Mem>(())
+            .await
+            .expect("Failed to start db");
+        start_db(&mut db, "Bucface", "Events")
+            .await
+            .expect("Failed to initialize db");
+
+        let mut rng = rand::thread_rng();
+        insert_event(&EventDB::from(rng.gen(), 0), &db)
+            .await
+            .expect("Failed to insert event");
+        assert!(insert_event(&EventDB::from(rng.gen(), 0), &db)
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn test_parse_storage() {
+        assert_eq!("mem".parse::<Storage>(), Ok(Storage::Memory));
+        assert_eq!(
+            "rocksdb:/var/lib/bucface".parse::<Storage>(),
+            Ok(Storage::RocksDb("/var/lib/bucface".into()))
+        );
+        assert_eq!(
+            "speedb://data".parse::<Storage>(),
+            Ok(Storage::SpeeDb("data".into()))
+        );
+        assert_eq!(
+            "rocksdb".parse::<Storage>(),
+            Err(StorageParseError::MissingPath("rocksdb".into()))
+        );
+        assert_eq!(
+            "sqlite:data".parse::<Storage>(),
+            Err(StorageParseError::UnknownEngine("sqlite".into()))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_connect_memory() {
+        let _ = env_logger::try_init();
+
+        let mut db = connect(&Storage::Memory).await.expect("Failed to start db");
+        start_db(&mut db, "Bucface", "Events")
+            .await
+            .expect("Failed to initialize db");
+
+        let event = EventDB::from(rand::thread_rng().gen(), 0);
+        insert_event(&event, &db)
+            .await
+            .expect("Failed to insert event");
+        assert_eq!(
+            get_event(&Scope::default(), 0, &db)
+                .await
+                .expect("Failed to get event"),
+            event
+        );
+    }
+
+    #[cfg(feature = "rocksdb")]
+    #[test]
+    fn test_restart_rocksdb() {
+        let _ = env_logger::try_init();
+
+        let dir = std::env::temp_dir().join(format!("bucface-{}", rand::random::<u64>()));
+        let storage = Storage::RocksDb(dir.clone());
+        let mut rng = rand::thread_rng();
+
+        let events = (0..100)
+            .map(|i| EventDB::from(rng.gen(), i))
+            .collect::<Vec<EventDB>>();
+
+        // Dropping the runtime drops the tasks that keep the database open,
+        // so it can be opened again
+        let runtime = || tokio::runtime::Runtime::new().expect("Failed to start runtime");
+        runtime().block_on(async {
+            let mut db = connect(&storage).await.expect("Failed to start db");
+            start_db(&mut db, "Bucface", "Events")
+                .await
+                .expect("Failed to initialize db");
+            for event in &events {
+                insert_event(event, &db)
+                    .await
+                    .expect("Failed to insert event");
+            }
+        });
+
+        runtime().block_on(async {
+            let mut db = connect(&storage).await.expect("Failed to restart db");
+            start_db(&mut db, "Bucface", "Events")
+                .await
+                .expect("Failed to reinitialize db");
+
+            let mut new_events = get_events_since(&Scope::default(), 0, &db)
+                .await
+                .expect("Failed to get events");
+            new_events.sort_by(|a, b| a._id.cmp(&b._id));
+            assert_eq!(events, new_events);
+            assert_eq!(
+                get_event(&Scope::default(), 42, &db)
+                    .await
+                    .expect("Failed to get event"),
+                events[42]
+            );
+            assert_eq!(
+                next_event_ids(&db).await.expect("Failed to get ids")[DEFAULT_CHANNEL],
+                100
+            );
+        });
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
 }
@@ -0,0 +1,188 @@
+use bucface_utils::Change;
+
+/// How many pairs of words [diff] compares at most, each taking a `usize`.
+/// Texts differing in more are diffed as a whole.
+const MAX_CELLS: usize = 1 << 20;
+
+/// The word-level [Change]s turning `old` into `new`. Words keep the
+/// whitespace following them, so joining the [Change::Equal] and
+/// [Change::Insert] texts gives `new` back, and joining the
+/// [Change::Equal] and [Change::Delete] texts gives `old`. Past
+/// [MAX_CELLS], the words between the common start and end are all deleted
+/// and inserted.
+pub fn diff(old: &str, new: &str) -> Vec<Change> {
+    let old = words(old);
+    let new = words(new);
+
+    let prefix = old.iter().zip(&new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let mut changes = Vec::new();
+    for word in &old[..prefix] {
+        push(&mut changes, Change::Equal((*word).into()));
+    }
+    let (old_rest, new_rest) = (
+        &old[prefix..old.len() - suffix],
+        &new[prefix..new.len() - suffix],
+    );
+    match old_rest.len().saturating_mul(new_rest.len()) <= MAX_CELLS {
+        true => common_subsequence(&mut changes, old_rest, new_rest),
+        false => {
+            push(&mut changes, Change::Delete(old_rest.concat()));
+            push(&mut changes, Change::Insert(new_rest.concat()));
+        }
+    }
+    for word in &old[old.len() - suffix..] {
+        push(&mut changes, Change::Equal((*word).into()));
+    }
+
+    changes
+}
+
+/// Appends the [Change]s keeping the longest common subsequence of the
+/// words.
+fn common_subsequence(changes: &mut Vec<Change>, old: &[&str], new: &[&str]) {
+    // The length of the longest common subsequence of every pair of suffixes
+    let mut common = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            common[i][j] = match old[i] == new[j] {
+                true => common[i + 1][j + 1] + 1,
+                false => common[i + 1][j].max(common[i][j + 1]),
+            };
+        }
+    }
+
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            push(changes, Change::Equal(old[i].into()));
+            i += 1;
+            j += 1;
+        } else if j < new.len() && (i == old.len() || common[i][j + 1] > common[i + 1][j]) {
+            push(changes, Change::Insert(new[j].into()));
+            j += 1;
+        } else {
+            push(changes, Change::Delete(old[i].into()));
+            i += 1;
+        }
+    }
+}
+
+/// Splits text into words, each with the whitespace that follows it.
+fn words(text: &str) -> Vec<&str> {
+    let mut words = Vec::new();
+    let mut start = 0;
+    let mut in_space = false;
+    for (i, c) in text.char_indices() {
+        if in_space && !c.is_whitespace() {
+            words.push(&text[start..i]);
+            start = i;
+        }
+        in_space = c.is_whitespace();
+    }
+    if start < text.len() {
+        words.push(&text[start..]);
+    }
+
+    words
+}
+
+/// Appends a change, merging it into the last one if they are of a kind.
+fn push(changes: &mut Vec<Change>, change: Change) {
+    match (changes.last_mut(), change) {
+        (Some(Change::Equal(last)), Change::Equal(text))
+        | (Some(Change::Insert(last)), Change::Insert(text))
+        | (Some(Change::Delete(last)), Change::Delete(text)) => last.push_str(&text),
+        (_, change) => changes.push(change),
+    }
+}
+
+#[cfg(test)]
+mod diff_tests {
+    use super::*;
+
+    fn texts(changes: &[Change]) -> (String, String) {
+        let mut old = String::new();
+        let mut new = String::new();
+        for change in changes {
+            match change {
+                Change::Equal(text) => {
+                    old.push_str(text);
+                    new.push_str(text);
+                }
+                Change::Insert(text) => new.push_str(text),
+                Change::Delete(text) => old.push_str(text),
+            }
+        }
+        (old, new)
+    }
+
+    #[test]
+    fn test_diff() {
+        let changes = diff(
+            "Pump failed at stage 3",
+            "Pump failed at stage 4, restarted",
+        );
+        assert_eq!(
+            changes,
+            vec![
+                Change::Equal("Pump failed at stage ".into()),
+                Change::Delete("3".into()),
+                Change::Insert("4, restarted".into()),
+            ]
+        );
+
+        let changes = diff("the pump  is down", "the cooling pump is down\n");
+        assert_eq!(
+            texts(&changes),
+            (
+                "the pump  is down".into(),
+                "the cooling pump is down\n".into()
+            )
+        );
+        assert_eq!(changes[0], Change::Equal("the ".into()));
+        // Deletions come before the insertions replacing them
+        assert_eq!(changes[1], Change::Delete("pump  ".into()));
+        assert_eq!(changes[2], Change::Insert("cooling pump ".into()));
+
+        assert_eq!(diff("", "new"), vec![Change::Insert("new".into())]);
+        assert_eq!(diff("old", ""), vec![Change::Delete("old".into())]);
+        assert!(diff("", "").is_empty());
+    }
+
+    #[test]
+    fn test_diff_large() {
+        let text = |word: &str| {
+            (0..20_000)
+                .map(|i| format!("{word}{i}"))
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+        let old = format!("Pump failed: {} at stage 3", text("a"));
+        let new = format!("Pump failed: {} at stage 3", text("b"));
+
+        let changes = diff(&old, &new);
+        assert_eq!(texts(&changes), (old, new));
+        assert_eq!(
+            changes,
+            vec![
+                Change::Equal("Pump failed: ".into()),
+                Change::Delete(format!("{} ", text("a"))),
+                Change::Insert(format!("{} ", text("b"))),
+                Change::Equal("at stage 3".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_words() {
+        assert_eq!(words("a  b\tc "), vec!["a  ", "b\t", "c "]);
+        assert_eq!(words("  lead"), vec!["  ", "lead"]);
+        assert!(words("").is_empty());
+    }
+}
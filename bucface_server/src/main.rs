@@ -1,13 +1,47 @@
-use surrealdb::engine::local::Mem;
-use surrealdb::Surreal;
+use clap::Parser;
+use config::{Args, Config};
 
 mod app;
+mod auth;
+mod blob;
+mod channel;
+mod config;
 mod db;
+mod diff;
+mod search;
+mod subscription;
+mod tls;
 mod websocket;
 
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
-    env_logger::init();
-    let mut db = Surreal::new::<Mem>(()).await.unwrap();
-    websocket::start(&mut db, "0.0.0.0:8080").await
+    let config = match Config::load(Args::parse()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid configuration: {e}");
+            std::process::exit(2);
+        }
+    };
+
+    env_logger::Builder::new()
+        .filter_level(config.log_level)
+        .parse_default_env()
+        .init();
+    log::debug!("Starting with {config:?}");
+
+    let tls = match &config.tls {
+        Some(tls) => match tls::acceptor(&tls.cert, &tls.key) {
+            Ok(acceptor) => Some(acceptor),
+            Err(e) => {
+                eprintln!("Invalid TLS configuration: {e}");
+                std::process::exit(2);
+            }
+        },
+        None => None,
+    };
+
+    let mut db = db::connect(&config.storage)
+        .await
+        .map_err(std::io::Error::other)?;
+    websocket::start(&mut db, &config, tls).await
 }
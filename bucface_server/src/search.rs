@@ -0,0 +1,107 @@
+use std::ops::Range;
+
+use bucface_utils::Snippet;
+
+/// The most characters of an event a [Snippet] shows.
+pub const SNIPPET_CHARS: usize = 160;
+/// How many characters before the first match a shortened [Snippet] starts.
+const CONTEXT_CHARS: usize = 40;
+const ELLIPSIS: &str = "…";
+
+/// Cuts a [Snippet] of at most [SNIPPET_CHARS] characters out of `text`,
+/// starting a little before the first of the `matches`. The matches are
+/// character ranges of `text`, as the search index reports them, and become
+/// byte ranges of the snippet. Matches cut off by the snippet are dropped.
+pub fn snippet(text: &str, mut matches: Vec<Range<usize>>) -> Snippet {
+    // The byte offset of every character, and of the end of the text
+    let bytes = text
+        .char_indices()
+        .map(|(i, _)| i)
+        .chain([text.len()])
+        .collect::<Vec<usize>>();
+    let chars = bytes.len() - 1;
+
+    matches.retain(|m| m.start < m.end && m.end <= chars);
+    matches.sort_by_key(|m| m.start);
+
+    let start = match matches.first() {
+        Some(first) if chars > SNIPPET_CHARS => first
+            .start
+            .saturating_sub(CONTEXT_CHARS)
+            .min(chars - SNIPPET_CHARS),
+        _ => 0,
+    };
+    let end = (start + SNIPPET_CHARS).min(chars);
+
+    let mut excerpt = String::new();
+    if start > 0 {
+        excerpt.push_str(ELLIPSIS);
+    }
+    let prefix = excerpt.len();
+    excerpt.push_str(&text[bytes[start]..bytes[end]]);
+    if end < chars {
+        excerpt.push_str(ELLIPSIS);
+    }
+
+    let byte = |char_index: usize| prefix + bytes[char_index] - bytes[start];
+    let highlights = matches
+        .into_iter()
+        .filter(|m| m.start >= start && m.end <= end)
+        .map(|m| byte(m.start)..byte(m.end))
+        .collect();
+
+    Snippet {
+        text: excerpt,
+        highlights,
+    }
+}
+
+#[cfg(test)]
+mod search_tests {
+    use super::*;
+
+    fn highlighted(snippet: &Snippet) -> Vec<&str> {
+        snippet
+            .highlights
+            .iter()
+            .map(|range| &snippet.text[range.clone()])
+            .collect()
+    }
+
+    #[test]
+    fn test_short_snippet() {
+        let hit = snippet(
+            "Pump FAILED at stage 3, restarting pump",
+            vec![35..39, 0..4],
+        );
+        assert_eq!(hit.text, "Pump FAILED at stage 3, restarting pump");
+        assert_eq!(highlighted(&hit), vec!["Pump", "pump"]);
+
+        let hit = snippet("Größe der Pumpe", vec![10..15, 0..5]);
+        assert_eq!(highlighted(&hit), vec!["Größe", "Pumpe"]);
+
+        let hit = snippet("nothing", Vec::new());
+        assert_eq!(hit.text, "nothing");
+        assert!(hit.highlights.is_empty());
+    }
+
+    #[test]
+    fn test_long_snippet() {
+        let text = format!("{}pümp{}", "a".repeat(200), "b".repeat(200));
+        let hit = snippet(&text, vec![200..204, 403..404]);
+
+        assert!(hit.text.starts_with(ELLIPSIS));
+        assert!(hit.text.ends_with(ELLIPSIS));
+        assert_eq!(
+            hit.text.chars().count(),
+            SNIPPET_CHARS + 2 * ELLIPSIS.chars().count()
+        );
+        assert_eq!(highlighted(&hit), vec!["pümp"]);
+
+        // Matches at the very end show the end of the text
+        let hit = snippet(&text, vec![400..402, 403..404]);
+        assert!(hit.text.starts_with(ELLIPSIS));
+        assert!(hit.text.ends_with("bbb"));
+        assert_eq!(highlighted(&hit), vec!["bb", "b"]);
+    }
+}
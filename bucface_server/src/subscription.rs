@@ -0,0 +1,127 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use bucface_utils::{EventDB, EventFilter, DEFAULT_CHANNEL};
+use parking_lot::Mutex;
+
+/// The channel a single connection joined, the [EventFilter]s it subscribed
+/// to and whether it may hear of other connections' changes at all, shared
+/// between the task reading its requests and the one writing its broadcasts.
+#[derive(Debug, Clone, Default)]
+pub struct Subscriptions {
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    authenticated: bool,
+    channel: String,
+    next_id: u64,
+    filters: HashMap<u64, EventFilter>,
+}
+
+impl Default for Inner {
+    fn default() -> Self {
+        Self {
+            authenticated: false,
+            channel: DEFAULT_CHANNEL.into(),
+            next_id: 0,
+            filters: HashMap::new(),
+        }
+    }
+}
+
+impl Subscriptions {
+    /// Whether broadcasts are sent to the connection, which they only are
+    /// once it [authenticated](Self::authenticate).
+    pub fn is_authenticated(&self) -> bool {
+        self.inner.lock().authenticated
+    }
+
+    /// Lets broadcasts through to the connection.
+    pub fn authenticate(&self) {
+        self.inner.lock().authenticated = true;
+    }
+
+    /// The channel the connection's messages concern.
+    pub fn channel(&self) -> String {
+        self.inner.lock().channel.clone()
+    }
+
+    /// Switches the connection to another channel, keeping its filters.
+    pub fn join(&self, channel: &str) {
+        self.inner.lock().channel = channel.into();
+    }
+
+    /// Adds a filter, returning the id to [unsubscribe](Self::unsubscribe) it
+    /// with.
+    pub fn subscribe(&self, filter: EventFilter) -> u64 {
+        let mut inner = self.inner.lock();
+        let id = inner.next_id;
+        inner.next_id += 1;
+        inner.filters.insert(id, filter);
+        id
+    }
+
+    /// Drops a filter, returning whether there was one with the id.
+    pub fn unsubscribe(&self, id: u64) -> bool {
+        self.inner.lock().filters.remove(&id).is_some()
+    }
+
+    /// Whether a new event should be pushed to the connection: it has to be
+    /// of the joined channel, and either the connection has no subscriptions
+    /// or the event matches any of them.
+    pub fn wants(&self, event: &EventDB) -> bool {
+        let inner = self.inner.lock();
+        event.channel == inner.channel
+            && (inner.filters.is_empty()
+                || inner.filters.values().any(|filter| filter.matches(event)))
+    }
+}
+
+#[cfg(test)]
+mod subscription_tests {
+    use super::*;
+
+    #[test]
+    fn test_subscriptions() {
+        let event = |machine: &str, text: &str| EventDB {
+            machine: machine.into(),
+            event: text.into(),
+            ..EventDB::from(rand::random(), 0)
+        };
+        let subscriptions = Subscriptions::default();
+        assert!(!subscriptions.is_authenticated());
+        subscriptions.authenticate();
+        assert!(subscriptions.is_authenticated());
+        assert!(subscriptions.wants(&event("buc-07", "anything")));
+
+        let machine = subscriptions.subscribe(EventFilter {
+            machines: ["buc-07".to_string()].into(),
+            ..Default::default()
+        });
+        let keyword = subscriptions.subscribe(EventFilter {
+            keywords: ["Pump".to_string()].into(),
+            ..Default::default()
+        });
+        assert_ne!(machine, keyword);
+        assert!(subscriptions.wants(&event("buc-07", "anything")));
+        assert!(subscriptions.wants(&event("buc-08", "pump failed")));
+        assert!(!subscriptions.wants(&event("buc-08", "anything")));
+
+        assert!(subscriptions.unsubscribe(machine));
+        assert!(!subscriptions.unsubscribe(machine));
+        assert!(!subscriptions.wants(&event("buc-07", "anything")));
+
+        assert!(subscriptions.unsubscribe(keyword));
+        assert!(subscriptions.wants(&event("buc-08", "anything")));
+
+        subscriptions.join("rig-2");
+        assert_eq!(subscriptions.channel(), "rig-2");
+        assert!(!subscriptions.wants(&event("buc-08", "anything")));
+        assert!(subscriptions.wants(&EventDB {
+            channel: "rig-2".into(),
+            ..event("buc-08", "anything")
+        }));
+    }
+}
@@ -0,0 +1,35 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use tokio_native_tls::native_tls::{self, Identity};
+use tokio_native_tls::TlsAcceptor;
+
+#[derive(Debug)]
+pub enum TlsError {
+    Read(PathBuf, std::io::Error),
+    Identity(native_tls::Error),
+    Acceptor(native_tls::Error),
+}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Read(path, e) => write!(f, "cannot read {}: {e}", path.display()),
+            Self::Identity(e) => write!(f, "invalid TLS certificate or key: {e}"),
+            Self::Acceptor(e) => write!(f, "cannot set up TLS: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for TlsError {}
+
+/// Creates a [TlsAcceptor] from a PEM encoded certificate chain and a PEM
+/// encoded PKCS #8 private key.
+pub fn acceptor(cert: &Path, key: &Path) -> Result<TlsAcceptor, TlsError> {
+    let read = |path: &Path| std::fs::read(path).map_err(|e| TlsError::Read(path.into(), e));
+
+    let identity = Identity::from_pkcs8(&read(cert)?, &read(key)?).map_err(TlsError::Identity)?;
+    let acceptor = native_tls::TlsAcceptor::new(identity).map_err(TlsError::Acceptor)?;
+
+    Ok(TlsAcceptor::from(acceptor))
+}
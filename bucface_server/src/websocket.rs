@@ -1,39 +1,69 @@
-use bucface_utils::{EventDBErrorSerde, ServerResponse};
-use futures::stream::{SplitSink, SplitStream};
+use bucface_utils::ws::{WsFaucet, WsSink, WsStream};
+use bucface_utils::{
+    ClientRequest, EventDBError, EventDBErrorSerde, ServerMessage, ServerResponse,
+};
 use futures::{SinkExt, StreamExt};
+use std::fmt;
 use std::io;
-use std::sync::atomic::AtomicU64;
-use std::sync::mpsc::{self, Receiver, Sender};
 use std::sync::Arc;
 use surrealdb::Surreal;
-use tokio::net::TcpListener;
-use tokio::sync;
-use tokio_tungstenite::tungstenite::Message;
-use tokio_tungstenite::WebSocketStream;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, mpsc};
+use tokio_native_tls::{native_tls, TlsAcceptor};
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+use tokio_tungstenite::tungstenite::{self, Message};
+use tokio_tungstenite::MaybeTlsStream;
 
 use crate::app::handle_client_message;
+use crate::auth::{Session, UserStore};
+use crate::blob::BlobStore;
+use crate::channel::IdCounters;
+use crate::config::Config;
 use crate::db;
+use crate::subscription::Subscriptions;
 
+/// Capacity of the queue of messages to a single connection.
+const OUTBOUND_QUEUE: usize = 128;
+/// How many broadcasts a slow connection may fall behind before it misses
+/// some.
+const BROADCAST_QUEUE: usize = 1024;
+
+/// A [ServerMessage] for every connection but the one it originates from.
+#[derive(Debug, Clone)]
+pub struct Broadcast {
+    pub from: u64,
+    pub message: ServerMessage,
+}
+
+/// Reads the requests of a single connection, answering them through its
+/// [Outbound] queue and sharing newly inserted events with the others.
 pub async fn handle_connection<T: surrealdb::Connection>(
     mut read: ClientWsFaucet,
-    write: Sender<ServerResponse>,
+    outbound: Outbound,
+    mut session: Session,
     db: Surreal<T>,
-    id_counter: Arc<AtomicU64>,
+    ids: Arc<IdCounters>,
+    users: Arc<UserStore>,
+    blobs: Arc<BlobStore>,
 ) -> Result<(), io::Error> {
     while let Some(Ok(msg)) = read.next().await {
         match msg {
             Message::Ping(inner_msg) => {
                 log::debug!("Received ping: {inner_msg:?}");
-                write.send(ServerResponse::Pong(inner_msg)).unwrap();
+                outbound
+                    .reply(ServerMessage::Connection(ServerResponse::Pong(inner_msg)))
+                    .await?;
             }
             Message::Pong(inner_msg) => {
                 log::debug!("Unexpectedly received pong: {inner_msg:?}",);
             }
             Message::Close(inner_msg) => {
                 log::debug!("Received close with message: {inner_msg:?}",);
-                write
-                    .send(ServerResponse::Close("Received close request".into()))
-                    .unwrap();
+                outbound
+                    .reply(ServerMessage::Connection(ServerResponse::Close(
+                        "Received close request".into(),
+                    )))
+                    .await?;
                 break;
             }
             Message::Text(inner_msg) => {
@@ -41,7 +71,16 @@ pub async fn handle_connection<T: surrealdb::Connection>(
             }
             Message::Binary(inner_msg) => {
                 log::debug!("Received binary: {inner_msg:?}",);
-                handle_binary_message(&inner_msg, &db, id_counter.clone(), &write).await
+                handle_binary_message(
+                    &inner_msg,
+                    &db,
+                    &ids,
+                    &users,
+                    &blobs,
+                    &mut session,
+                    &outbound,
+                )
+                .await?
             }
             Message::Frame(inner_msg) => {
                 log::debug!("Received frame: {inner_msg}",);
@@ -52,83 +91,816 @@ pub async fn handle_connection<T: surrealdb::Connection>(
     Ok(())
 }
 
-async fn start_sender(read: Receiver<ServerResponse>, sinks: Arc<sync::Mutex<Vec<ClientWsSink>>>) {
-    while let Ok(res) = read.recv() {
-        for (i, sink) in sinks.lock().await.iter_mut().enumerate() {
-            let result_encoded = rmp_serde::encode::to_vec(&res).expect("Error encoding response");
-            match sink.send(Message::Binary(result_encoded)).await {
-                Ok(_) => {}
-                Err(tokio_tungstenite::tungstenite::Error::AlreadyClosed) => {
-                    log::info!("Removing closed sink");
-                    let _ = sinks.lock().await.remove(i);
-                }
-                Err(e) => {
-                    log::error!("Error sending message: {e:?}");
+/// Where the messages of a connection go: its own queue `write`, and
+/// `broadcast` for every other connection.
+pub struct Outbound {
+    connection: u64,
+    write: mpsc::Sender<ServerMessage>,
+    broadcast: broadcast::Sender<Broadcast>,
+}
+
+impl Outbound {
+    /// Queues a message for this connection only.
+    async fn reply(&self, message: ServerMessage) -> Result<(), io::Error> {
+        self.write
+            .send(message)
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Connection writer stopped"))
+    }
+
+    /// Queues a message for every other connection.
+    fn broadcast(&self, message: ServerMessage) {
+        let broadcast = Broadcast {
+            from: self.connection,
+            message,
+        };
+        // Failing only means no connection is listening
+        let _ = self.broadcast.send(broadcast);
+    }
+}
+
+/// Writes the replies to a single connection and the broadcasts of the
+/// others to its sink, until the connection's reader stops. Broadcasts are
+/// only sent once the connection is
+/// [authenticated](Subscriptions::is_authenticated), and new events only if
+/// they match its [Subscriptions].
+async fn start_sender(
+    mut sink: ClientWsSink,
+    mut replies: mpsc::Receiver<ServerMessage>,
+    mut broadcasts: broadcast::Receiver<Broadcast>,
+    subscriptions: Subscriptions,
+    connection: u64,
+) {
+    loop {
+        let message = tokio::select! {
+            reply = replies.recv() => match reply {
+                Some(reply) => reply,
+                None => break,
+            },
+            broadcast = broadcasts.recv() => match broadcast {
+                Ok(Broadcast { from, .. }) if from == connection => continue,
+                Ok(_) if !subscriptions.is_authenticated() => continue,
+                Ok(Broadcast {
+                    message: ServerMessage::Broadcast(ServerResponse::Event(event)),
+                    ..
+                }) if !subscriptions.wants(&event) => continue,
+                Ok(Broadcast { message, .. }) => message,
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    log::warn!("Connection {connection} missed {missed} broadcasts");
+                    continue;
                 }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+        };
+
+        let encoded = bucface_utils::encode(&message).expect("Error encoding response");
+        match sink.send(Message::Binary(encoded)).await {
+            Ok(_) => {}
+            Err(tungstenite::Error::AlreadyClosed | tungstenite::Error::ConnectionClosed) => {
+                log::info!("Connection {connection} is closed");
+                break;
+            }
+            Err(e) => {
+                log::error!("Error sending message: {e:?}");
             }
         }
     }
 }
 
+/// Decodes a [ClientRequest] and answers it with [ServerMessage::Reply]s
+/// carrying its id. Newly inserted, edited or retracted events and created or
+/// archived channels are also sent to every other connection as a
+/// [ServerMessage::Broadcast].
 async fn handle_binary_message<T: surrealdb::Connection>(
     message: &[u8],
     db: &Surreal<T>,
-    id_counter: Arc<AtomicU64>,
-    sender_writer: &Sender<ServerResponse>,
-) {
-    let result = handle_client_message(message, db, id_counter).await;
+    ids: &IdCounters,
+    users: &UserStore,
+    blobs: &BlobStore,
+    session: &mut Session,
+    outbound: &Outbound,
+) -> Result<(), io::Error> {
+    let request: ClientRequest = match rmp_serde::decode::from_slice(message) {
+        Ok(request) => request,
+        Err(e) => {
+            log::error!("Error decoding request: {e:?}");
+            let error = EventDBErrorSerde::from(EventDBError::RmpDecode(e));
+            let response = ServerMessage::Connection(ServerResponse::Error(error));
+            return outbound.reply(response).await;
+        }
+    };
+
+    let id = request.id;
+    let result = handle_client_message(request.message, db, ids, users, blobs, session).await;
     match result {
-        Ok(db_events) => {
-            for db_event in db_events {
-                let response = ServerResponse::Event(db_event);
-                sender_writer.send(response).unwrap();
+        Ok(handled) => {
+            for response in handled.responses {
+                if handled.changed {
+                    outbound.broadcast(ServerMessage::Broadcast(response.clone()));
+                }
+                outbound
+                    .reply(ServerMessage::Reply { id, response })
+                    .await?;
             }
         }
         Err(e) => {
-            log::error!("Error responding to request: {e:?}");
+            log::error!("Error responding to request {id}: {e:?}");
             let response = ServerResponse::Error(EventDBErrorSerde::from(e));
-            sender_writer.send(response).unwrap();
+            outbound
+                .reply(ServerMessage::Reply { id, response })
+                .await?;
         }
     }
+
+    Ok(())
 }
 
-type ClientWsSink = SplitSink<WebSocketStream<tokio::net::TcpStream>, Message>;
-type ClientWsFaucet = SplitStream<WebSocketStream<tokio::net::TcpStream>>;
+type ClientWsSink = WsSink;
+type ClientWsFaucet = WsFaucet;
+
+#[derive(Debug)]
+enum HandshakeError {
+    Tls(native_tls::Error),
+    WebSocket(tungstenite::Error),
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tls(e) => write!(f, "TLS handshake failed: {e}"),
+            Self::WebSocket(e) => write!(f, "websocket handshake failed: {e}"),
+        }
+    }
+}
+
+/// Performs the TLS handshake, if the server is configured for it, and the
+/// websocket handshake on a freshly accepted connection.
+async fn accept(
+    stream: TcpStream,
+    tls: Option<&TlsAcceptor>,
+    ws_config: WebSocketConfig,
+) -> Result<WsStream, HandshakeError> {
+    let stream = match tls {
+        Some(tls) => {
+            MaybeTlsStream::NativeTls(tls.accept(stream).await.map_err(HandshakeError::Tls)?)
+        }
+        None => MaybeTlsStream::Plain(stream),
+    };
+
+    tokio_tungstenite::accept_async_with_config(stream, Some(ws_config))
+        .await
+        .map_err(HandshakeError::WebSocket)
+}
 
 pub async fn start<T: surrealdb::Connection>(
     db: &mut Surreal<T>,
-    addr: &str,
+    config: &Config,
+    tls: Option<TlsAcceptor>,
+) -> Result<(), io::Error> {
+    let socket = TcpListener::bind(config.bind).await?;
+    log::info!(
+        "Listening on {}://{}",
+        if tls.is_some() { "wss" } else { "ws" },
+        config.bind
+    );
+    serve(socket, db, config, tls).await
+}
+
+/// Serves clients on an already bound [TcpListener], see [start].
+pub async fn serve<T: surrealdb::Connection>(
+    socket: TcpListener,
+    db: &mut Surreal<T>,
+    config: &Config,
+    tls: Option<TlsAcceptor>,
 ) -> Result<(), io::Error> {
-    let socket = TcpListener::bind(addr).await?;
-    db::start_db(db).await.unwrap();
-    let id_counter = Arc::new(AtomicU64::new(0));
-    let (tx, rx) = mpsc::channel::<ServerResponse>();
-    let clients: Arc<sync::Mutex<Vec<ClientWsSink>>> = Arc::new(sync::Mutex::new(Vec::new()));
-
-    let sender_clients = clients.clone();
-    tokio::spawn(async move {
-        start_sender(rx, sender_clients).await;
+    db::start_db(db, &config.namespace, &config.database)
+        .await
+        .map_err(io::Error::other)?;
+    let next_ids = db::next_event_ids(db)
+        .await
+        .map_err(|e| io::Error::other(format!("{e:?}")))?;
+    log::info!("Next event ids are {next_ids:?}");
+    let ids = Arc::new(IdCounters::new(next_ids));
+    let users = Arc::new(config.users.clone());
+    if !users.is_enabled() {
+        log::warn!("No users are configured, events are logged anonymously");
+    }
+    let blobs = Arc::new(match &config.blobs {
+        Some(path) => BlobStore::directory(path, config.max_attachment_size).await?,
+        None => {
+            log::warn!("No blob directory is configured, attachments are kept in memory");
+            BlobStore::memory(config.max_attachment_size)
+        }
     });
+    let ws_config = WebSocketConfig {
+        max_message_size: Some(config.max_message_size),
+        max_frame_size: Some(config.max_message_size),
+        ..Default::default()
+    };
+    let (broadcast, _) = broadcast::channel(BROADCAST_QUEUE);
+    let mut next_connection = 0;
 
     while let Ok((stream, addr)) = socket.accept().await {
-        log::info!("Accepted connection from: {addr:?}");
-        let ws_stream = tokio_tungstenite::accept_async(stream)
-            .await
-            .expect("Error during websocket handshake");
+        let connection = next_connection;
+        next_connection += 1;
+        log::info!("Accepted connection {connection} from: {addr:?}");
 
-        let (write, read) = ws_stream.split();
-        let mut clients_unlocked = clients.lock().await;
-        clients_unlocked.push(write);
-
-        let tx_clone = tx.clone();
+        let tls = tls.clone();
+        let broadcast = broadcast.clone();
         let db_clone = db.clone();
-        let id_counter_clone = id_counter.clone();
+        let ids_clone = ids.clone();
+        let users_clone = users.clone();
+        let blobs_clone = blobs.clone();
         tokio::spawn(async move {
-            handle_connection(read, tx_clone, db_clone, id_counter_clone)
-                .await
-                .expect("Error handling connection");
+            let ws_stream = match accept(stream, tls.as_ref(), ws_config).await {
+                Ok(ws_stream) => ws_stream,
+                Err(e) => {
+                    log::warn!("Error during handshake with {addr:?}: {e}");
+                    return;
+                }
+            };
+
+            let (sink, read) = ws_stream.split();
+            let (write, replies) = mpsc::channel(OUTBOUND_QUEUE);
+            let broadcasts = broadcast.subscribe();
+            let session = Session::default();
+            // Without users nothing is kept from anyone
+            if !users_clone.is_enabled() {
+                session.subscriptions.authenticate();
+            }
+            let sender = tokio::spawn(start_sender(
+                sink,
+                replies,
+                broadcasts,
+                session.subscriptions.clone(),
+                connection,
+            ));
+
+            let outbound = Outbound {
+                connection,
+                write,
+                broadcast,
+            };
+            let result = handle_connection(
+                read,
+                outbound,
+                session,
+                db_clone,
+                ids_clone,
+                users_clone,
+                blobs_clone,
+            )
+            .await;
+            if let Err(e) = result {
+                log::warn!("Error handling connection {connection}: {e:?}");
+            }
+            // The sender stops once the queue it reads from is dropped
+            let _ = sender.await;
+            log::info!("Connection {connection} from {addr:?} closed");
         });
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod websocket_tests {
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    use bucface_utils::{ClientMessage, Event, EventFilter, Severity, DEFAULT_CHANNEL};
+    use serde::{Deserialize, Serialize};
+    use sha2::{Digest, Sha256};
+    use surrealdb::engine::local::Mem;
+    use tokio_native_tls::native_tls::{Certificate, TlsConnector};
+    use tokio_tungstenite::Connector;
+
+    use super::*;
+    use crate::auth::User;
+    use crate::tls;
+
+    /// Writes a self-signed certificate for localhost and its key to temporary
+    /// files, returning their paths and the PEM encoded certificate.
+    fn self_signed() -> (PathBuf, PathBuf, Vec<u8>) {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let dir = std::env::temp_dir();
+        let id = rand::random::<u64>();
+        let cert_path = dir.join(format!("bucface-{id}.crt"));
+        let key_path = dir.join(format!("bucface-{id}.key"));
+        // Every serialization signs the certificate again, so only do it once
+        let cert_pem = cert.serialize_pem().unwrap();
+        std::fs::write(&cert_path, &cert_pem).unwrap();
+        std::fs::write(&key_path, cert.serialize_private_key_pem()).unwrap();
+
+        (cert_path, key_path, cert_pem.into_bytes())
+    }
+
+    /// An acceptor for a self-signed certificate, and the PEM encoded
+    /// certificate.
+    fn self_signed_acceptor() -> (TlsAcceptor, Vec<u8>) {
+        let (cert_path, key_path, pem) = self_signed();
+        let acceptor = tls::acceptor(&cert_path, &key_path).unwrap();
+        std::fs::remove_file(cert_path).unwrap();
+        std::fs::remove_file(key_path).unwrap();
+
+        (acceptor, pem)
+    }
+
+    /// Starts a TLS server on a random port, returning the port and the PEM
+    /// encoded certificate it serves.
+    async fn start_tls_server() -> (u16, Vec<u8>) {
+        start_tls_server_with(Config::default()).await
+    }
+
+    /// Like [start_tls_server], with the given configuration.
+    async fn start_tls_server_with(config: Config) -> (u16, Vec<u8>) {
+        let (acceptor, pem) = self_signed_acceptor();
+
+        let socket = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = socket.local_addr().unwrap().port();
+        let mut db = Surreal::new::<Mem>(()).await.unwrap();
+        tokio::spawn(async move {
+            serve(socket, &mut db, &config, Some(acceptor))
+                .await
+                .unwrap();
+        });
+
+        (port, pem)
+    }
+
+    /// Starts a TLS server over the `storage` on a random port in a runtime
+    /// of its own, like the server process does, returning the port, the PEM
+    /// encoded certificate it serves and the runtime. Dropping the runtime
+    /// drops every task of the server, closing its database.
+    #[cfg(feature = "rocksdb")]
+    fn serve_storage(storage: &db::Storage) -> (u16, Vec<u8>, tokio::runtime::Runtime) {
+        let (acceptor, pem) = self_signed_acceptor();
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let (socket, mut db) = runtime.block_on(async {
+            let socket = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let db = db::connect(storage)
+                .await
+                .expect("Failed to open the database");
+            (socket, db)
+        });
+        let port = socket.local_addr().unwrap().port();
+        runtime.spawn(async move {
+            serve(socket, &mut db, &Config::default(), Some(acceptor))
+                .await
+                .unwrap();
+        });
+
+        (port, pem, runtime)
+    }
+
+    async fn connect(port: u16, pem: &[u8]) -> WsStream {
+        let connector = TlsConnector::builder()
+            .add_root_certificate(Certificate::from_pem(pem).unwrap())
+            .build()
+            .unwrap();
+        let (stream, _) = tokio_tungstenite::connect_async_tls_with_config(
+            format!("wss://localhost:{port}"),
+            None,
+            false,
+            Some(Connector::NativeTls(connector)),
+        )
+        .await
+        .expect("Failed to connect over TLS");
+
+        stream
+    }
+
+    async fn send(stream: &mut WsStream, id: u64, message: ClientMessage) {
+        let message = rmp_serde::to_vec(&ClientRequest { id, message }).unwrap();
+        stream.send(Message::Binary(message)).await.unwrap();
+    }
+
+    async fn receive(stream: &mut WsStream) -> ServerMessage {
+        while let Some(message) = stream.next().await {
+            if let Message::Binary(data) = message.unwrap() {
+                return rmp_serde::from_slice(&data).unwrap();
+            }
+        }
+        panic!("Connection closed without a response");
+    }
+
+    #[tokio::test]
+    async fn test_tls_round_trip() {
+        let _ = env_logger::try_init();
+        let (port, pem) = start_tls_server().await;
+        let mut stream = connect(port, &pem).await;
+
+        let event: Event = rand::random();
+        send(&mut stream, 42, ClientMessage::NewEvent(event.clone())).await;
+
+        match receive(&mut stream).await {
+            ServerMessage::Reply {
+                id,
+                response: ServerResponse::Event(db_event),
+            } => {
+                assert_eq!(id, 42);
+                assert_eq!(db_event.event, event.event);
+            }
+            other => panic!("Unexpected message {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_old_clients() {
+        #[derive(Serialize)]
+        struct OldRequest {
+            id: u64,
+            message: OldMessage,
+        }
+        #[derive(Serialize)]
+        enum OldMessage {
+            NewEvent(OldEvent),
+        }
+        /// An [Event] and an [EventDB] as clients before tags and severities
+        /// knew them.
+        #[derive(Debug, Serialize, Deserialize, PartialEq)]
+        struct OldEvent {
+            author: String,
+            machine: String,
+            event: String,
+            time: chrono::NaiveDateTime,
+        }
+        #[derive(Debug, Deserialize)]
+        enum OldServerMessage {
+            Reply { id: u64, response: OldResponse },
+        }
+        #[derive(Debug, Deserialize)]
+        enum OldResponse {
+            Event(OldEvent),
+        }
+
+        let _ = env_logger::try_init();
+        let (port, pem) = start_tls_server().await;
+        let mut stream = connect(port, &pem).await;
+
+        // Old clients encode positionally, without the fields added since
+        let event = OldEvent {
+            author: crate::auth::ANONYMOUS.into(),
+            machine: "buc-07".into(),
+            event: "Pump failed".into(),
+            time: chrono::Utc::now().naive_utc(),
+        };
+        let request = OldRequest {
+            id: 7,
+            message: OldMessage::NewEvent(event),
+        };
+        let encoded = rmp_serde::to_vec(&request).unwrap();
+        stream.send(Message::Binary(encoded)).await.unwrap();
+
+        let Some(Ok(Message::Binary(reply))) = stream.next().await else {
+            panic!("Expected a reply");
+        };
+        // and skip the fields they do not know when decoding
+        let OldServerMessage::Reply { id, response } = rmp_serde::from_slice(&reply).unwrap();
+        let OldResponse::Event(inserted) = response;
+        assert_eq!(id, 7);
+        let OldMessage::NewEvent(event) = request.message;
+        assert_eq!(inserted, event);
+        let ServerMessage::Reply {
+            response: ServerResponse::Event(inserted),
+            ..
+        } = rmp_serde::from_slice(&reply).unwrap()
+        else {
+            panic!("Expected an event");
+        };
+        assert_eq!(inserted.severity, Severity::Info);
+        assert!(inserted.tags.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_reply_routing() {
+        let _ = env_logger::try_init();
+        let (port, pem) = start_tls_server().await;
+        let mut alice = connect(port, &pem).await;
+        let mut bob = connect(port, &pem).await;
+
+        send(&mut alice, 1, ClientMessage::GetEvent(0)).await;
+        assert_eq!(
+            receive(&mut alice).await,
+            ServerMessage::Reply {
+                id: 1,
+                response: ServerResponse::Error(EventDBErrorSerde::NotFound)
+            }
+        );
+
+        let event: Event = rand::random();
+        send(&mut alice, 2, ClientMessage::NewEvent(event.clone())).await;
+        let ServerMessage::Reply {
+            id: 2,
+            response: ServerResponse::Event(inserted),
+        } = receive(&mut alice).await
+        else {
+            panic!("Expected the inserted event");
+        };
+        assert_eq!(inserted.event, event.event);
+
+        // Bob only hears of the new event, not of Alice's error
+        assert_eq!(
+            receive(&mut bob).await,
+            ServerMessage::Broadcast(ServerResponse::Event(inserted.clone()))
+        );
+
+        send(&mut bob, 1, ClientMessage::GetSince(0)).await;
+        assert_eq!(
+            receive(&mut bob).await,
+            ServerMessage::Reply {
+                id: 1,
+                response: ServerResponse::Events {
+                    events: vec![*inserted],
+                    next: None
+                }
+            }
+        );
+
+        let nothing = tokio::time::timeout(Duration::from_millis(200), receive(&mut alice)).await;
+        assert!(nothing.is_err(), "Alice received {nothing:?}");
+    }
+
+    #[tokio::test]
+    async fn test_unauthenticated_broadcasts() {
+        let _ = env_logger::try_init();
+        let users = ["alice", "bob"]
+            .map(|name| User {
+                name: name.into(),
+                token_sha256: Sha256::digest(format!("{name}-token")).into(),
+                admin: false,
+            })
+            .to_vec();
+        let config = Config {
+            users: UserStore::new(users),
+            ..Default::default()
+        };
+        let (port, pem) = start_tls_server_with(config).await;
+        let mut alice = connect(port, &pem).await;
+        let mut bob = connect(port, &pem).await;
+        let mut eve = connect(port, &pem).await;
+
+        for (stream, name) in [(&mut alice, "alice"), (&mut bob, "bob")] {
+            send(
+                stream,
+                1,
+                ClientMessage::Authenticate(format!("{name}-token")),
+            )
+            .await;
+            assert_eq!(
+                receive(stream).await,
+                ServerMessage::Reply {
+                    id: 1,
+                    response: ServerResponse::Authenticated(name.into())
+                }
+            );
+        }
+
+        send(&mut alice, 2, ClientMessage::NewEvent(rand::random())).await;
+        let ServerMessage::Reply {
+            id: 2,
+            response: ServerResponse::Event(inserted),
+        } = receive(&mut alice).await
+        else {
+            panic!("Expected the inserted event");
+        };
+        assert_eq!(
+            receive(&mut bob).await,
+            ServerMessage::Broadcast(ServerResponse::Event(inserted))
+        );
+
+        // Eve never authenticated and hears of nothing
+        let nothing = tokio::time::timeout(Duration::from_millis(200), receive(&mut eve)).await;
+        assert!(nothing.is_err(), "Eve received {nothing:?}");
+    }
+
+    #[tokio::test]
+    async fn test_subscribed_broadcasts() {
+        let _ = env_logger::try_init();
+        let (port, pem) = start_tls_server().await;
+        let mut alice = connect(port, &pem).await;
+        let mut bob = connect(port, &pem).await;
+
+        let filter = EventFilter {
+            machines: ["buc-07".to_string()].into(),
+            keywords: ["pump".to_string()].into(),
+            ..Default::default()
+        };
+        send(&mut bob, 1, ClientMessage::Subscribe(filter)).await;
+        let ServerMessage::Reply {
+            id: 1,
+            response: ServerResponse::Subscribed(subscription),
+        } = receive(&mut bob).await
+        else {
+            panic!("Expected a subscription");
+        };
+
+        let events = [
+            ("buc-08", "Pump failed"),
+            ("buc-07", "Cooling failed"),
+            ("buc-07", "Pump failed"),
+        ];
+        let mut inserted = Vec::new();
+        for (id, (machine, text)) in events.into_iter().enumerate() {
+            let event = Event {
+                machine: machine.into(),
+                event: text.into(),
+                ..rand::random()
+            };
+            send(&mut alice, id as u64, ClientMessage::NewEvent(event)).await;
+            match receive(&mut alice).await {
+                ServerMessage::Reply {
+                    response: ServerResponse::Event(event),
+                    ..
+                } => inserted.push(*event),
+                other => panic!("Unexpected message {other:?}"),
+            }
+        }
+
+        // Only the event matching the filter reaches Bob
+        assert_eq!(
+            receive(&mut bob).await,
+            ServerMessage::Broadcast(ServerResponse::Event(Box::new(inserted[2].clone())))
+        );
+
+        send(&mut bob, 2, ClientMessage::Unsubscribe(subscription)).await;
+        assert_eq!(
+            receive(&mut bob).await,
+            ServerMessage::Reply {
+                id: 2,
+                response: ServerResponse::Unsubscribed(subscription)
+            }
+        );
+
+        send(&mut alice, 3, ClientMessage::NewEvent(rand::random())).await;
+        let ServerMessage::Reply {
+            response: ServerResponse::Event(unfiltered),
+            ..
+        } = receive(&mut alice).await
+        else {
+            panic!("Expected the inserted event");
+        };
+        assert_eq!(
+            receive(&mut bob).await,
+            ServerMessage::Broadcast(ServerResponse::Event(unfiltered))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_keyed_events() {
+        let _ = env_logger::try_init();
+        let (port, pem) = start_tls_server().await;
+        let mut alice = connect(port, &pem).await;
+        let mut bob = connect(port, &pem).await;
+
+        let message = ClientMessage::NewEventOnce {
+            channel: DEFAULT_CHANNEL.into(),
+            key: "outbox-1".into(),
+            event: rand::random(),
+        };
+        send(&mut alice, 1, message.clone()).await;
+        let ServerMessage::Reply {
+            id: 1,
+            response: ServerResponse::Event(inserted),
+        } = receive(&mut alice).await
+        else {
+            panic!("Expected the inserted event");
+        };
+        assert_eq!(
+            receive(&mut bob).await,
+            ServerMessage::Broadcast(ServerResponse::Event(inserted.clone()))
+        );
+
+        // Sent again over a new connection, as if the reply had been lost
+        drop(alice);
+        let mut alice = connect(port, &pem).await;
+        send(&mut alice, 1, message).await;
+        assert_eq!(
+            receive(&mut alice).await,
+            ServerMessage::Reply {
+                id: 1,
+                response: ServerResponse::Event(inserted)
+            }
+        );
+        let nothing = tokio::time::timeout(Duration::from_millis(200), receive(&mut bob)).await;
+        assert!(nothing.is_err(), "Bob received {nothing:?}");
+    }
+
+    #[tokio::test]
+    async fn test_channel_broadcasts() {
+        let _ = env_logger::try_init();
+        let (port, pem) = start_tls_server().await;
+        let mut alice = connect(port, &pem).await;
+        let mut bob = connect(port, &pem).await;
+
+        send(&mut alice, 1, ClientMessage::CreateChannel("rig-2".into())).await;
+        let ServerMessage::Reply {
+            id: 1,
+            response: ServerResponse::Channel(channel),
+        } = receive(&mut alice).await
+        else {
+            panic!("Expected the created channel");
+        };
+        assert_eq!(
+            receive(&mut bob).await,
+            ServerMessage::Broadcast(ServerResponse::Channel(channel.clone()))
+        );
+
+        send(&mut bob, 1, ClientMessage::JoinChannel("rig-2".into())).await;
+        assert_eq!(
+            receive(&mut bob).await,
+            ServerMessage::Reply {
+                id: 1,
+                response: ServerResponse::Channel(channel)
+            }
+        );
+
+        // Bob only hears of the events of the joined channel
+        send(&mut alice, 2, ClientMessage::NewEvent(rand::random())).await;
+        receive(&mut alice).await;
+        send(&mut alice, 3, ClientMessage::JoinChannel("rig-2".into())).await;
+        receive(&mut alice).await;
+        send(&mut alice, 4, ClientMessage::NewEvent(rand::random())).await;
+        let ServerMessage::Reply {
+            id: 4,
+            response: ServerResponse::Event(inserted),
+        } = receive(&mut alice).await
+        else {
+            panic!("Expected the inserted event");
+        };
+        assert_eq!(inserted.channel, "rig-2");
+        assert_eq!(
+            receive(&mut bob).await,
+            ServerMessage::Broadcast(ServerResponse::Event(inserted))
+        );
+    }
+
+    #[cfg(feature = "rocksdb")]
+    #[test]
+    fn test_restart() {
+        let _ = env_logger::try_init();
+        let dir = std::env::temp_dir().join(format!("bucface-{}", rand::random::<u64>()));
+        let storage = db::Storage::RocksDb(dir.clone());
+        let client = tokio::runtime::Runtime::new().unwrap();
+
+        let (port, pem, server) = serve_storage(&storage);
+        let inserted = client.block_on(async {
+            let mut stream = connect(port, &pem).await;
+            let mut inserted = Vec::new();
+            for id in 0..10 {
+                send(&mut stream, id, ClientMessage::NewEvent(rand::random())).await;
+                match receive(&mut stream).await {
+                    ServerMessage::Reply {
+                        response: ServerResponse::Event(event),
+                        ..
+                    } => inserted.push(*event),
+                    other => panic!("Unexpected message {other:?}"),
+                }
+            }
+            inserted
+        });
+        drop(server);
+
+        let (port, pem, server) = serve_storage(&storage);
+        client.block_on(async {
+            let mut stream = connect(port, &pem).await;
+            send(&mut stream, 1, ClientMessage::GetSince(0)).await;
+            assert_eq!(
+                receive(&mut stream).await,
+                ServerMessage::Reply {
+                    id: 1,
+                    response: ServerResponse::Events {
+                        events: inserted,
+                        next: None
+                    }
+                }
+            );
+
+            // New events are numbered on from the stored ones
+            send(&mut stream, 2, ClientMessage::NewEvent(rand::random())).await;
+            let ServerMessage::Reply {
+                id: 2,
+                response: ServerResponse::Event(event),
+            } = receive(&mut stream).await
+            else {
+                panic!("Expected the inserted event");
+            };
+            assert_eq!(event._id, 10);
+        });
+        drop(server);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn test_tls_untrusted() {
+        let _ = env_logger::try_init();
+        let (port, _) = start_tls_server().await;
+
+        let result = tokio_tungstenite::connect_async(format!("wss://localhost:{port}")).await;
+        assert!(result.is_err());
+
+        let result = tokio_tungstenite::connect_async(format!("ws://localhost:{port}")).await;
+        assert!(result.is_err());
+    }
+}
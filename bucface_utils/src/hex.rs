@@ -0,0 +1,20 @@
+/// Encodes bytes as uppercase hex.
+pub fn encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02X}")).collect()
+}
+
+/// Decodes a SHA-256 digest written as hex, optionally separated by colons
+/// like `openssl x509 -fingerprint -sha256` prints it.
+pub fn decode_sha256(hex: &str) -> Option<[u8; 32]> {
+    let hex = hex.trim().chars().filter(|c| *c != ':').collect::<String>();
+    if hex.len() != 64 || !hex.is_ascii() {
+        return None;
+    }
+
+    let mut bytes = [0; 32];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+
+    Some(bytes)
+}
@@ -1,14 +1,60 @@
+pub mod hex;
 pub mod ws;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ops::Range;
+use std::str::FromStr;
+
 use rand::distributions::{Alphanumeric, Distribution, Standard};
 use rand::Rng;
 use serde::{Deserialize, Serialize};
 
+/// The channel every connection starts in, which holds the events logged
+/// before there were channels.
+pub const DEFAULT_CHANNEL: &str = "main";
+
+/// The version of the protocol spoken by this crate, exchanged with
+/// [ClientMessage::Hello]. Since version 2 messages are [encode]d with the
+/// names of their fields, so either side skips the fields it does not know
+/// and fields can be added without breaking older peers. The positional
+/// encoding of version 1 is still decoded, the fields it lacks defaulted.
+/// Version 3 added [Attachment]s, version 4 [Event::fields] and version 5
+/// [ClientMessage::NewEventOnce].
+pub const PROTOCOL_VERSION: u32 = 5;
+
+/// The most bytes of a blob sent in a single [ClientMessage::UploadChunk] or
+/// [ServerResponse::Blob], well below the websocket message size limit of the
+/// server.
+pub const BLOB_CHUNK_SIZE: usize = 256 << 10;
+
+/// Encodes a message the way [PROTOCOL_VERSION] expects.
+pub fn encode<T: Serialize + ?Sized>(message: &T) -> Result<Vec<u8>, rmp_serde::encode::Error> {
+    rmp_serde::to_vec_named(message)
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct Event {
     pub author: String,
     pub machine: String,
     pub event: String,
     pub time: chrono::NaiveDateTime,
+    /// The id of the event of the same channel this one replies to.
+    #[serde(default)]
+    pub parent: Option<u64>,
+    /// Labels to find the event by.
+    #[serde(default)]
+    pub tags: BTreeSet<String>,
+    #[serde(default)]
+    pub severity: Severity,
+    /// Files uploaded with [ClientMessage::UploadChunk] beforehand.
+    #[serde(default)]
+    pub attachments: Vec<Attachment>,
+    /// Measurements and other data for machines to read, by keys that are
+    /// [valid_field_key]s.
+    #[serde(default)]
+    pub fields: BTreeMap<String, FieldValue>,
 }
 
 impl Default for Event {
@@ -18,6 +64,11 @@ impl Default for Event {
             machine: "Default Machine".into(),
             event: "Default Event".into(),
             time: chrono::Utc::now().naive_utc(),
+            parent: None,
+            tags: BTreeSet::new(),
+            severity: Severity::Info,
+            attachments: Vec::new(),
+            fields: BTreeMap::new(),
         }
     }
 }
@@ -29,6 +80,11 @@ impl Distribution<Event> for Standard {
             machine: random_string(rng.gen_range(1..3)),
             event: random_string(rng.gen_range(1..3)),
             time: chrono::Utc::now().naive_utc(),
+            parent: None,
+            tags: BTreeSet::new(),
+            severity: Severity::Info,
+            attachments: Vec::new(),
+            fields: BTreeMap::new(),
         }
     }
 }
@@ -40,20 +96,589 @@ impl From<EventDB> for Event {
             machine: event.machine,
             event: event.event,
             time: event.time,
+            parent: event.parent,
+            tags: event.tags,
+            severity: event.severity,
+            attachments: event.attachments,
+            fields: event.fields,
+        }
+    }
+}
+
+/// A file attached to an event, whose contents are stored on the server as
+/// the blob with the SHA-256 `digest`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Attachment {
+    /// The file name, without any directories.
+    pub name: String,
+    /// The hex SHA-256 digest of the contents.
+    pub digest: String,
+    /// The size of the contents in bytes.
+    pub size: u64,
+}
+
+/// The value of a field of an [Event], encoded as the plain value.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum FieldValue {
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    String(String),
+}
+
+impl FieldValue {
+    /// Reads a value the way it is written in a [FieldCondition]: `true` and
+    /// `false` are booleans, numbers are numbers and anything else is a
+    /// string, which may be quoted to keep it one.
+    pub fn parse(text: &str) -> Self {
+        let text = text.trim();
+        if let Some(quoted) = ['"', '\'']
+            .into_iter()
+            .find_map(|quote| text.strip_prefix(quote)?.strip_suffix(quote))
+        {
+            return FieldValue::String(quoted.into());
+        }
+
+        match text {
+            "true" => FieldValue::Bool(true),
+            "false" => FieldValue::Bool(false),
+            _ => match (text.parse::<i64>(), text.parse::<f64>()) {
+                (Ok(int), _) => FieldValue::Int(int),
+                (_, Ok(float)) if float.is_finite() => FieldValue::Float(float),
+                _ => FieldValue::String(text.into()),
+            },
+        }
+    }
+
+    /// How the value compares to another of the same kind, integers and
+    /// floats both being numbers. Values of different kinds do not compare.
+    pub fn compare(&self, other: &FieldValue) -> Option<Ordering> {
+        match (self, other) {
+            (FieldValue::Bool(a), FieldValue::Bool(b)) => Some(a.cmp(b)),
+            (FieldValue::Int(a), FieldValue::Int(b)) => Some(a.cmp(b)),
+            (FieldValue::String(a), FieldValue::String(b)) => Some(a.cmp(b)),
+            (a, b) => a.as_f64()?.partial_cmp(&b.as_f64()?),
+        }
+    }
+
+    fn as_f64(&self) -> Option<f64> {
+        match self {
+            FieldValue::Int(int) => Some(*int as f64),
+            FieldValue::Float(float) => Some(*float),
+            _ => None,
+        }
+    }
+
+    /// The position of the kind of the value among the kinds, for [Ord].
+    fn kind(&self) -> u8 {
+        match self {
+            FieldValue::Bool(_) => 0,
+            FieldValue::Int(_) => 1,
+            FieldValue::Float(_) => 2,
+            FieldValue::String(_) => 3,
+        }
+    }
+}
+
+// Floats are compared by their bits, so values can be keys and events hashed
+impl PartialEq for FieldValue {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for FieldValue {}
+
+impl PartialOrd for FieldValue {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for FieldValue {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (FieldValue::Bool(a), FieldValue::Bool(b)) => a.cmp(b),
+            (FieldValue::Int(a), FieldValue::Int(b)) => a.cmp(b),
+            (FieldValue::Float(a), FieldValue::Float(b)) => a.total_cmp(b),
+            (FieldValue::String(a), FieldValue::String(b)) => a.cmp(b),
+            (a, b) => a.kind().cmp(&b.kind()),
+        }
+    }
+}
+
+impl Hash for FieldValue {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.kind().hash(state);
+        match self {
+            FieldValue::Bool(bool) => bool.hash(state),
+            FieldValue::Int(int) => int.hash(state),
+            FieldValue::Float(float) => float.to_bits().hash(state),
+            FieldValue::String(string) => string.hash(state),
+        }
+    }
+}
+
+impl fmt::Display for FieldValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FieldValue::Bool(bool) => write!(f, "{bool}"),
+            FieldValue::Int(int) => write!(f, "{int}"),
+            FieldValue::Float(float) => write!(f, "{float}"),
+            FieldValue::String(string) => f.write_str(string),
+        }
+    }
+}
+
+/// Whether the key can name a field of an [Event]: an ASCII letter or
+/// underscore followed by at most 63 more letters, digits or underscores.
+pub fn valid_field_key(key: &str) -> bool {
+    let mut chars = key.chars();
+    chars
+        .next()
+        .is_some_and(|first| first.is_ascii_alphabetic() || first == '_')
+        && key.len() <= 64
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// How a [FieldCondition] compares a field with its value.
+#[derive(
+    Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash, PartialOrd, Ord,
+)]
+pub enum Comparison {
+    #[default]
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Comparison {
+    pub const ALL: [Comparison; 6] = [
+        Comparison::Eq,
+        Comparison::Ne,
+        Comparison::Lt,
+        Comparison::Le,
+        Comparison::Gt,
+        Comparison::Ge,
+    ];
+
+    /// The operator the comparison is written with, in a [FieldCondition] as
+    /// in SurrealQL.
+    pub fn operator(self) -> &'static str {
+        match self {
+            Comparison::Eq => "==",
+            Comparison::Ne => "!=",
+            Comparison::Lt => "<",
+            Comparison::Le => "<=",
+            Comparison::Gt => ">",
+            Comparison::Ge => ">=",
+        }
+    }
+
+    /// Whether the comparison holds for a field ordered so against the value.
+    pub fn holds(self, ordering: Ordering) -> bool {
+        match self {
+            Comparison::Eq => ordering.is_eq(),
+            Comparison::Ne => ordering.is_ne(),
+            Comparison::Lt => ordering.is_lt(),
+            Comparison::Le => ordering.is_le(),
+            Comparison::Gt => ordering.is_gt(),
+            Comparison::Ge => ordering.is_ge(),
+        }
+    }
+}
+
+/// A condition on a field of an [Event], written like `exit_code != 0` or
+/// `fields.exit_code != 0`. Only events that have the field with a value of
+/// the same kind, see [FieldValue::compare], meet it.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct FieldCondition {
+    pub key: String,
+    pub comparison: Comparison,
+    pub value: FieldValue,
+}
+
+impl FieldCondition {
+    pub fn matches(&self, fields: &BTreeMap<String, FieldValue>) -> bool {
+        fields
+            .get(&self.key)
+            .and_then(|field| field.compare(&self.value))
+            .is_some_and(|ordering| self.comparison.holds(ordering))
+    }
+}
+
+impl fmt::Display for FieldCondition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} ", self.key, self.comparison.operator())?;
+        match &self.value {
+            FieldValue::String(string) => write!(f, "{string:?}"),
+            value => write!(f, "{value}"),
+        }
+    }
+}
+
+/// Why text is not a [FieldCondition].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FieldConditionError {
+    /// There is no comparison operator.
+    NoComparison,
+    /// The key is not a [valid_field_key].
+    InvalidKey(String),
+    /// Nothing follows the operator.
+    NoValue,
+}
+
+impl fmt::Display for FieldConditionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoComparison => write!(f, "expected one of == != < <= > >="),
+            Self::InvalidKey(key) => write!(f, "`{key}` is not a field name"),
+            Self::NoValue => write!(f, "expected a value to compare with"),
+        }
+    }
+}
+
+impl std::error::Error for FieldConditionError {}
+
+impl FromStr for FieldCondition {
+    type Err = FieldConditionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // The first operator, preferring the longer one at a position so
+        // `<=` is not read as `<`
+        let (start, operator, comparison) = s
+            .char_indices()
+            .find_map(|(i, _)| {
+                let rest = &s[i..];
+                [("=", Comparison::Eq)]
+                    .into_iter()
+                    .chain(Comparison::ALL.map(|c| (c.operator(), c)))
+                    .filter(|(operator, _)| rest.starts_with(operator))
+                    .max_by_key(|(operator, _)| operator.len())
+                    .map(|(operator, comparison)| (i, operator, comparison))
+            })
+            .ok_or(FieldConditionError::NoComparison)?;
+
+        let key = s[..start].trim();
+        let key = key.strip_prefix("fields.").unwrap_or(key);
+        if !valid_field_key(key) {
+            return Err(FieldConditionError::InvalidKey(key.into()));
         }
+        let value = s[start + operator.len()..].trim();
+        if value.is_empty() {
+            return Err(FieldConditionError::NoValue);
+        }
+
+        Ok(FieldCondition {
+            key: key.into(),
+            comparison,
+            value: FieldValue::parse(value),
+        })
+    }
+}
+
+/// How serious an event is, from the least to the most.
+#[derive(
+    Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash, PartialOrd, Ord,
+)]
+pub enum Severity {
+    #[default]
+    Info,
+    Warn,
+    Error,
+    Critical,
+}
+
+impl Severity {
+    pub const ALL: [Severity; 4] = [
+        Severity::Info,
+        Severity::Warn,
+        Severity::Error,
+        Severity::Critical,
+    ];
+
+    /// The severities at least as serious as this one.
+    pub fn and_above(self) -> impl Iterator<Item = Severity> {
+        Self::ALL
+            .into_iter()
+            .filter(move |severity| *severity >= self)
+    }
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Severity::Info => "info",
+            Severity::Warn => "warn",
+            Severity::Error => "error",
+            Severity::Critical => "critical",
+        })
+    }
+}
+
+/// A name that is not the [Display](fmt::Display) of any [Severity].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownSeverity(pub String);
+
+impl fmt::Display for UnknownSeverity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "unknown severity `{}`, expected info, warn, error or critical",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for UnknownSeverity {}
+
+impl FromStr for Severity {
+    type Err = UnknownSeverity;
+
+    /// Reads a severity by its name, ignoring case.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Severity::ALL
+            .into_iter()
+            .find(|severity| severity.to_string().eq_ignore_ascii_case(s.trim()))
+            .ok_or_else(|| UnknownSeverity(s.into()))
     }
 }
 #[derive(Debug, Serialize, Deserialize, PartialEq, Clone, Eq, Hash, PartialOrd, Ord)]
 pub enum ClientMessage {
+    /// Authenticates the connection with a token from the server's user
+    /// store. The server stamps the user's name onto every [Event] sent
+    /// afterwards, overriding [Event::author].
+    Authenticate(String),
+    /// Tells the server the [PROTOCOL_VERSION] of the client, which it
+    /// answers with its own in [ServerResponse::Hello]. Servers before
+    /// version 2 answer with an error instead.
+    Hello(u32),
     /// A message to add to the database.
     NewEvent(Event),
+    /// Adds the event like [ClientMessage::NewEvent], but to the `channel`
+    /// it was written in rather than the one joined, unless its author
+    /// already added one to the channel with the same idempotency `key`,
+    /// which is then answered instead. Sending an event again after the
+    /// connection was lost before the answer came thus never adds it twice.
+    NewEventOnce {
+        channel: String,
+        key: String,
+        event: Event,
+    },
     /// A message that requests the event with the given id.
     GetEvent(u64),
     /// A message that requests all events since the given id.
     GetSince(u64),
+    /// A message that requests a single page of events.
+    GetPage(PageQuery),
+    /// A message that requests the events logged within a [TimeRange], oldest
+    /// first, optionally at most `limit` of them.
+    GetTimeRange {
+        range: TimeRange,
+        limit: Option<u32>,
+    },
+    /// A message that requests the events matching an [EventFilter] in
+    /// ascending id order, optionally at most `limit` of them.
+    Query {
+        filter: EventFilter,
+        limit: Option<u32>,
+    },
+    /// A message that requests at most `limit` events whose [Event::event]
+    /// contains all of the words in `terms`, best matches first.
+    Search {
+        terms: String,
+        limit: u32,
+    },
+    /// Narrows the new events pushed to the connection as
+    /// [ServerMessage::Broadcast]s to those matching any of its subscribed
+    /// [EventFilter]s. A connection without subscriptions gets every event.
+    Subscribe(EventFilter),
+    /// Drops the subscription with the id from [ServerResponse::Subscribed].
+    Unsubscribe(u64),
+    /// Creates a [Channel] with the given name.
+    CreateChannel(String),
+    /// Requests every [Channel], archived ones included.
+    ListChannels,
+    /// Archives the [Channel] with the given name, after which no more events
+    /// can be logged to it.
+    ArchiveChannel(String),
+    /// Switches the connection to the [Channel] with the given name. Every
+    /// other message then only concerns the events of that channel, and only
+    /// its new events are broadcast to the connection.
+    JoinChannel(String),
+    /// Replaces the text of the event with the id by `event`, keeping the
+    /// previous text as a [Revision]. Only the event's author may edit it.
+    EditEvent {
+        id: u64,
+        event: String,
+    },
+    /// Requests every [Revision] of the event with the id, oldest first.
+    GetRevisions(u64),
+    /// Requests the thread the event with the id belongs to: the event it
+    /// started with, which replies to none, and every reply to it however
+    /// deeply nested, in ascending id order.
+    GetThread(u64),
+    /// Hides the event with the id from every query, keeping it with a
+    /// [Retraction] giving the `reason`. Only the event's author or an admin
+    /// may retract it.
+    RetractEvent {
+        id: u64,
+        reason: String,
+    },
+    /// Whether the connection's queries include retracted events, for
+    /// auditing by admins. They are left out by default.
+    ShowRetracted(bool),
+    /// Uploads the part of a blob of `size` bytes with the hex SHA-256
+    /// `digest` that starts `offset` bytes in, to attach it to an [Event]
+    /// afterwards. Chunks have to be sent in order and at most
+    /// [BLOB_CHUNK_SIZE] bytes at a time. The server keeps a single upload per
+    /// connection, a chunk at offset 0 starting a new one.
+    UploadChunk {
+        digest: String,
+        size: u64,
+        offset: u64,
+        data: Vec<u8>,
+    },
+    /// Requests the blob with the hex SHA-256 digest, which is sent in
+    /// [ServerResponse::Blob] chunks.
+    GetBlob(String),
     Ping(String),
 }
 
+/// One end of a [TimeRange].
+#[derive(
+    Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash, PartialOrd, Ord,
+)]
+pub enum TimeBound {
+    Inclusive(chrono::NaiveDateTime),
+    Exclusive(chrono::NaiveDateTime),
+    #[default]
+    Unbounded,
+}
+
+/// The [Event::time]s between `start` and `end`.
+#[derive(
+    Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash, PartialOrd, Ord,
+)]
+pub struct TimeRange {
+    pub start: TimeBound,
+    pub end: TimeBound,
+}
+
+impl TimeRange {
+    pub fn contains(&self, time: &chrono::NaiveDateTime) -> bool {
+        let after_start = match &self.start {
+            TimeBound::Inclusive(start) => time >= start,
+            TimeBound::Exclusive(start) => time > start,
+            TimeBound::Unbounded => true,
+        };
+        let before_end = match &self.end {
+            TimeBound::Inclusive(end) => time <= end,
+            TimeBound::Exclusive(end) => time < end,
+            TimeBound::Unbounded => true,
+        };
+
+        after_start && before_end
+    }
+}
+
+/// The ids from `start` up to but excluding `end`. A missing end is
+/// unbounded.
+#[derive(
+    Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash, PartialOrd, Ord,
+)]
+pub struct IdRange {
+    pub start: Option<u64>,
+    pub end: Option<u64>,
+}
+
+impl IdRange {
+    pub fn contains(&self, id: u64) -> bool {
+        self.start.is_none_or(|start| id >= start) && self.end.is_none_or(|end| id < end)
+    }
+}
+
+/// Which events a query matches: those by any of the `authors` from any of
+/// the `machines` whose [Event::event] contains any of the `keywords`,
+/// ignoring case, tagged with any of the `tags`, at least as serious as the
+/// `severity` and meeting all of the `fields` conditions, within both ranges.
+/// An empty set matches everything.
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct EventFilter {
+    pub authors: BTreeSet<String>,
+    pub machines: BTreeSet<String>,
+    pub keywords: BTreeSet<String>,
+    pub time: TimeRange,
+    pub ids: IdRange,
+    #[serde(default)]
+    pub tags: BTreeSet<String>,
+    #[serde(default)]
+    pub severity: Option<Severity>,
+    #[serde(default)]
+    pub fields: Vec<FieldCondition>,
+}
+
+impl EventFilter {
+    pub fn matches(&self, event: &EventDB) -> bool {
+        (self.authors.is_empty() || self.authors.contains(&event.author))
+            && (self.machines.is_empty() || self.machines.contains(&event.machine))
+            && (self.keywords.is_empty() || self.contains_keyword(&event.event))
+            && self.time.contains(&event.time)
+            && self.ids.contains(event._id)
+            && (self.tags.is_empty() || !self.tags.is_disjoint(&event.tags))
+            && self
+                .severity
+                .is_none_or(|severity| event.severity >= severity)
+            && self
+                .fields
+                .iter()
+                .all(|condition| condition.matches(&event.fields))
+    }
+
+    fn contains_keyword(&self, text: &str) -> bool {
+        let text = text.to_lowercase();
+        self.keywords
+            .iter()
+            .any(|keyword| text.contains(&keyword.to_lowercase()))
+    }
+}
+
+/// The order events are paged through in.
+#[derive(
+    Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash, PartialOrd, Ord,
+)]
+pub enum Direction {
+    /// Oldest first.
+    #[default]
+    Ascending,
+    /// Newest first.
+    Descending,
+}
+
+/// A page of events: at most `limit` events in the given [Direction],
+/// starting at and including the id `start`. Without a `start`, the page
+/// starts at the oldest or newest event.
+#[derive(
+    Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash, PartialOrd, Ord,
+)]
+pub struct PageQuery {
+    pub start: Option<u64>,
+    pub direction: Direction,
+    pub limit: u32,
+}
+
+/// A [ClientMessage] tagged with an id chosen by the client, which the server
+/// echoes in every [ServerMessage::Reply] to it.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, Eq, Hash, PartialOrd, Ord)]
+pub struct ClientRequest {
+    pub id: u64,
+    pub message: ClientMessage,
+}
+
 fn random_string(len: usize) -> String {
     rand::thread_rng()
         .sample_iter(&Alphanumeric)
@@ -64,14 +689,36 @@ fn random_string(len: usize) -> String {
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct EventDB {
+    /// The id of the event within its `channel`.
     pub _id: u64,
     pub author: String,
     pub machine: String,
     pub event: String,
     pub time: chrono::NaiveDateTime,
+    #[serde(default = "default_channel")]
+    pub channel: String,
+    /// How often the event was edited, the latest [Revision].
+    #[serde(default)]
+    pub revision: u32,
+    /// Why the event was retracted, if it was.
+    #[serde(default)]
+    pub retraction: Option<Retraction>,
+    /// The id of the event of the same channel this one replies to, which is
+    /// always lower than its own.
+    #[serde(default)]
+    pub parent: Option<u64>,
+    #[serde(default)]
+    pub tags: BTreeSet<String>,
+    #[serde(default)]
+    pub severity: Severity,
+    #[serde(default)]
+    pub attachments: Vec<Attachment>,
+    #[serde(default)]
+    pub fields: BTreeMap<String, FieldValue>,
 }
 
 impl EventDB {
+    /// An event of the [DEFAULT_CHANNEL].
     pub fn from(event: Event, id: u64) -> Self {
         Self {
             _id: id,
@@ -79,22 +726,164 @@ impl EventDB {
             machine: event.machine,
             event: event.event,
             time: event.time,
+            channel: default_channel(),
+            revision: 0,
+            retraction: None,
+            parent: event.parent,
+            tags: event.tags,
+            severity: event.severity,
+            attachments: event.attachments,
+            fields: event.fields,
         }
     }
 }
 
+fn default_channel() -> String {
+    DEFAULT_CHANNEL.into()
+}
+
+/// Who retracted an [EventDB], when and why.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Retraction {
+    pub author: String,
+    pub time: chrono::NaiveDateTime,
+    pub reason: String,
+}
+
+/// A version of the text of an [EventDB], the original being revision 0.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Revision {
+    pub revision: u32,
+    /// Who wrote this version, which is the event's author for the original.
+    pub author: String,
+    pub time: chrono::NaiveDateTime,
+    pub event: String,
+    /// The changes from the previous revision, so the original is all
+    /// inserted.
+    pub diff: Vec<Change>,
+}
+
+/// A piece of a diff between two texts.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Change {
+    Equal(String),
+    Insert(String),
+    Delete(String),
+}
+
+/// A separate logbook on the server, with its own sequence of event ids.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Channel {
+    pub name: String,
+    pub created: chrono::NaiveDateTime,
+    /// When the channel was archived, if it was.
+    pub archived: Option<chrono::NaiveDateTime>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub enum ServerResponse {
-    Event(EventDB),
+    Event(Box<EventDB>),
+    /// A batch of [EventDB]s in ascending id order, or descending for a
+    /// [Direction::Descending] page. A reply may be split over several
+    /// batches, all but the last of which have the id to continue from as
+    /// `next`. For a page, `next` is the start of the following page, if there
+    /// is one.
+    Events {
+        events: Vec<EventDB>,
+        next: Option<u64>,
+    },
+    /// The connection is authenticated as the given user.
+    Authenticated(String),
+    /// The [PROTOCOL_VERSION] of the server.
+    Hello(u32),
+    /// The events found by a [ClientMessage::Search], best matches first.
+    Search(Vec<SearchHit>),
+    /// The id of the subscription a [ClientMessage::Subscribe] made.
+    Subscribed(u64),
+    /// The subscription with the id was dropped.
+    Unsubscribed(u64),
+    /// A [Channel] that was created, archived or joined.
+    Channel(Channel),
+    /// Every [Channel], by name.
+    Channels(Vec<Channel>),
+    /// Every [Revision] of the event with the id, oldest first.
+    Revisions {
+        id: u64,
+        revisions: Vec<Revision>,
+    },
+    /// Whether retracted events are now included, see
+    /// [ClientMessage::ShowRetracted].
+    ShowRetracted(bool),
+    /// How many bytes of the blob with the digest the server has of an
+    /// upload. Once it has all of them the blob is stored, which may already
+    /// be the case for the first chunk if another upload stored it before.
+    Uploaded {
+        digest: String,
+        received: u64,
+    },
+    /// The part of the blob with the digest that starts `offset` bytes in,
+    /// the blob being `size` bytes in total.
+    Blob {
+        digest: String,
+        size: u64,
+        offset: u64,
+        data: Vec<u8>,
+    },
     Error(EventDBErrorSerde),
     Pong(Vec<u8>),
     Close(Vec<u8>),
 }
 
+/// An event found by a search.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct SearchHit {
+    pub event: EventDB,
+    /// How well the event matches, relative to the other hits of a search.
+    pub score: f64,
+    pub snippet: Snippet,
+}
+
+/// An excerpt of [EventDB::event] around the words a search matched, which are
+/// at the byte ranges `highlights` of `text`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Snippet {
+    pub text: String,
+    pub highlights: Vec<Range<usize>>,
+}
+
+/// Everything the server sends to a client.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub enum ServerMessage {
+    /// Answers the [ClientRequest] with the same id.
+    Reply { id: u64, response: ServerResponse },
+    /// Not an answer to a request of this client, like an event another
+    /// client logged.
+    Broadcast(ServerResponse),
+    /// About the connection itself rather than a request, like a message that
+    /// could not be decoded or the connection closing.
+    Connection(ServerResponse),
+}
+
 #[derive(Debug)]
 pub enum EventDBError {
     Db(surrealdb::Error),
     NotFound,
+    Unauthorized,
+    /// A channel with the name already exists.
+    AlreadyExists,
+    /// The channel is archived and takes no more events.
+    Archived,
+    /// The name is not allowed for a channel.
+    InvalidName,
+    /// The attachment is larger than the server accepts.
+    TooLarge,
+    /// The chunk does not continue the upload, or the uploaded blob does not
+    /// match its digest.
+    InvalidUpload,
+    /// A field of the event is not a [valid_field_key] or not a finite
+    /// number.
+    InvalidField,
+    Blob(std::io::Error),
     RmpEncode(rmp_serde::encode::Error),
     RmpDecode(rmp_serde::decode::Error),
 }
@@ -103,6 +892,14 @@ pub enum EventDBError {
 pub enum EventDBErrorSerde {
     Db(String),
     NotFound,
+    Unauthorized,
+    AlreadyExists,
+    Archived,
+    InvalidName,
+    TooLarge,
+    InvalidUpload,
+    InvalidField,
+    Blob(String),
     Rmp,
 }
 
@@ -111,6 +908,14 @@ impl From<EventDBError> for EventDBErrorSerde {
         match e {
             EventDBError::Db(e) => Self::Db(e.to_string()),
             EventDBError::NotFound => Self::NotFound,
+            EventDBError::Unauthorized => Self::Unauthorized,
+            EventDBError::AlreadyExists => Self::AlreadyExists,
+            EventDBError::Archived => Self::Archived,
+            EventDBError::InvalidName => Self::InvalidName,
+            EventDBError::TooLarge => Self::TooLarge,
+            EventDBError::InvalidUpload => Self::InvalidUpload,
+            EventDBError::InvalidField => Self::InvalidField,
+            EventDBError::Blob(e) => Self::Blob(e.to_string()),
             EventDBError::RmpEncode(_) | EventDBError::RmpDecode(_) => Self::Rmp,
         }
     }